@@ -0,0 +1,640 @@
+use std::{fs, io::{self, Read}, net::IpAddr, path::{Path, PathBuf}, sync::Arc};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{storage::{blob_store::open_unencrypted_blob_store, database::email_is_valid, encryption::{reencrypt_protocols, ProtocolCipher}, protocol_store::{CatalogEntity, Pagination, ProtocolCreation, ProtocolStore, SearchFilter}}, structs::{configuration::{Authorization, BlobStorage, Configuration, DatabaseBackend}, get_outputs::{SearchResponse, SelectionIdentifierPair}, post_inputs::RepairMode}, DEFAULT_SEARCH_LIMIT};
+
+/// FSMED ProtocolDB (Backend)
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path of the config file
+    #[arg(long, global = true, default_value = "config.toml")]
+    pub config: PathBuf,
+
+    /// Without a subcommand the API is started
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Starts the API
+    Serve,
+    /// Manages the admins directly in the database, without a login
+    #[command(subcommand)]
+    Admin(AdminCommand),
+    /// Examiners, subjects, stex and seasons
+    #[command(subcommand)]
+    Entity(EntityCommand),
+    #[command(subcommand)]
+    Protocol(ProtocolCommand),
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    /// Maintenance of the database and the protocol files
+    #[command(subcommand)]
+    Db(DbCommand),
+    #[command(subcommand)]
+    Config(ConfigCommand)
+}
+
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Grants admin rights to the email
+    Add { email: String },
+    /// Revokes the admin rights of the email
+    Remove { email: String },
+    /// Prints all admins, one per line
+    List
+}
+
+#[derive(Subcommand)]
+pub enum EntityCommand {
+    /// Prints the ID of the entity, it's only created if there is none with that name yet
+    Create { kind: EntityKind, display_name: String },
+    /// Prints ID and name of the entities, all kinds if none is given
+    List { kind: Option<EntityKind> }
+}
+
+#[derive(Subcommand)]
+pub enum ProtocolCommand {
+    /// Saves a protocol and prints its UUID
+    Save(SaveProtocol),
+    /// Prints the matching protocols as JSON, like /api/v1/search
+    Search(Box<SearchProtocols>)
+}
+
+#[derive(Subcommand)]
+pub enum SessionsCommand {
+    /// Removes all expired sessions
+    Purge,
+    /// Prints the sessions of the email that haven't expired yet as JSON
+    List { email: String },
+    /// Ends all sessions of the email, e.g. after a token was stolen
+    Revoke { email: String }
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Compares the protocol files with the database and prints the findings as JSON.
+    /// Fails if something was found and not repaired
+    Check {
+        /// Repairs the findings, `quarantine` moves orphaned files to quarantine/ in the protocol_location, `delete` removes them and unused entities
+        #[arg(long)]
+        repair: Option<RepairKind>
+    },
+    /// Encrypts all protocol texts and their revisions with the active_protocol_key, or decrypts them if there is none.
+    /// Stop the API first, texts written meanwhile could be missed
+    Reencrypt
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Writes a config with default values
+    Init {
+        /// Overwrite an existing config
+        #[arg(long)]
+        force: bool
+    },
+    /// Checks the config without starting anything
+    Validate
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum EntityKind {
+    Examiner,
+    Subject,
+    Stex,
+    Season
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum RepairKind {
+    Quarantine,
+    Delete
+}
+
+impl From<RepairKind> for RepairMode {
+    fn from(kind: RepairKind) -> Self {
+        match kind {
+            RepairKind::Quarantine => RepairMode::Quarantine,
+            RepairKind::Delete => RepairMode::Delete,
+        }
+    }
+}
+
+impl From<EntityKind> for CatalogEntity {
+    fn from(kind: EntityKind) -> Self {
+        match kind {
+            EntityKind::Examiner => CatalogEntity::Examiner,
+            EntityKind::Subject => CatalogEntity::Subject,
+            EntityKind::Stex => CatalogEntity::Stex,
+            EntityKind::Season => CatalogEntity::Season,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct SaveProtocol {
+    /// Examiner and subject ID as `examiner:subject`, can be given multiple times
+    #[arg(long = "pair", required = true, value_parser = parse_pair)]
+    pub pairs: Vec<(i64, i64)>,
+    #[arg(long)]
+    pub stex: i64,
+    #[arg(long)]
+    pub season: i64,
+    #[arg(long)]
+    pub year: i64,
+    /// File with the text of the protocol, read from stdin if left out
+    #[arg(long)]
+    pub file: Option<PathBuf>,
+    /// Recorded as author of the first revision
+    #[arg(long, default_value = "cli")]
+    pub author: String
+}
+
+/// The ID lists are comma separated, like in the API
+#[derive(Args)]
+pub struct SearchProtocols {
+    #[arg(long, value_delimiter = ',')]
+    pub examiners: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub subjects: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub stex: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub seasons: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub years: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_examiners: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_subjects: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_stex: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_seasons: Option<Vec<i64>>,
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_years: Option<Vec<i64>>,
+    #[arg(long)]
+    pub year_from: Option<i64>,
+    #[arg(long)]
+    pub year_to: Option<i64>,
+    /// Full-text search
+    #[arg(long)]
+    pub q: Option<String>,
+    /// Unlike the API there is no upper bound, so everything can be exported at once
+    #[arg(long, default_value_t = DEFAULT_SEARCH_LIMIT)]
+    pub limit: i64,
+    #[arg(long, default_value_t = 0)]
+    pub offset: i64
+}
+
+fn parse_pair(input: &str) -> Result<(i64, i64), String> {
+    let (examiner, subject) = match input.split_once(':') {
+        Some(pair) => pair,
+        None => return Err("expected examiner:subject".to_string()),
+    };
+
+    match (examiner.trim().parse::<i64>(), subject.trim().parse::<i64>()) {
+        (Ok(examiner), Ok(subject)) => Ok((examiner, subject)),
+        _ => Err("expected two numeric IDs".to_string()),
+    }
+}
+
+pub async fn run_admin_command(command: AdminCommand, database: Arc<dyn ProtocolStore>) -> Result<(), String> {
+
+    match command {
+        AdminCommand::Add { email } => {
+            match database.add_admin(&email).await {
+                Ok(true) => println!("{} is now an admin", email),
+                Ok(false) => return Err(format!("Invalid Email: {:?}", email)),
+                Err(err) => return Err(format!("Failed to add Admin!: {}", err)),
+            }
+        },
+        AdminCommand::Remove { email } => {
+            match database.remove_admin(&email).await {
+                Ok(true) => println!("{} is no admin anymore", email),
+                Ok(false) => return Err(format!("Invalid Email: {:?}", email)),
+                Err(err) => return Err(format!("Failed to remove Admin!: {}", err)),
+            }
+        },
+        AdminCommand::List => {
+            let admins = match database.get_admins().await {
+                Ok(admins) => admins,
+                Err(err) => return Err(format!("Failed to list Admins!: {}", err)),
+            };
+
+            for admin in admins {
+                println!("{}", admin);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+pub async fn run_entity_command(command: EntityCommand, database: Arc<dyn ProtocolStore>) -> Result<(), String> {
+
+    match command {
+        EntityCommand::Create { kind, display_name } => {
+            match database.create_item(kind.into(), display_name.clone()).await {
+                Ok(Some(id)) => println!("{}", id),
+                Ok(None) => return Err(format!("Invalid display name: {:?}", display_name)),
+                Err(err) => return Err(format!("Failed to create Entity!: {}", err)),
+            }
+        },
+        EntityCommand::List { kind } => {
+            let identifiers = match database.get_selection_identifiers().await {
+                Ok(identifiers) => identifiers,
+                Err(err) => return Err(format!("Failed to list Entities!: {}", err)),
+            };
+
+            let all_kinds = [
+                (EntityKind::Examiner, "Examiners", identifiers.examiners),
+                (EntityKind::Subject, "Subjects", identifiers.subjects),
+                (EntityKind::Stex, "Stex", identifiers.stex),
+                (EntityKind::Season, "Seasons", identifiers.seasons)
+            ];
+
+            for (entity_kind, heading, items) in all_kinds {
+                match kind {
+                    Some(kind) if kind != entity_kind => continue,
+                    Some(_) => print_entities(&items),
+                    None => {
+                        println!("{}:", heading);
+                        print_entities(&items);
+                    },
+                }
+            }
+        },
+    }
+
+    Ok(())
+}
+
+fn print_entities(items: &[SelectionIdentifierPair]) {
+    for item in items {
+        println!("{}\t{}", item.id, item.display_name);
+    }
+}
+
+pub async fn run_protocol_command(command: ProtocolCommand, database: Arc<dyn ProtocolStore>) -> Result<(), String> {
+    match command {
+        ProtocolCommand::Save(protocol) => {
+            let text = match &protocol.file {
+                Some(path) => fs::read_to_string(path),
+                None => {
+                    let mut text = String::new();
+                    io::stdin().read_to_string(&mut text).map(|_| text)
+                },
+            };
+
+            let text = match text {
+                Ok(text) => text,
+                Err(err) => return Err(format!("Failed to read the protocol text!: {}", err)),
+            };
+
+
+            match database.save_protocol(protocol.pairs, protocol.stex, protocol.season, protocol.year, text, &protocol.author).await {
+                Ok(ProtocolCreation::Saved(protocol_uuid)) => println!("{}", protocol_uuid),
+                Ok(ProtocolCreation::UnknownEntity(entity, id)) => return Err(format!("There is no {} with the ID {}", entity.label(), id)),
+                Err(err) => return Err(format!("Failed to save Protocol!: {}", err)),
+            }
+        },
+        ProtocolCommand::Search(search) => {
+            if search.limit < 1 || search.offset < 0 {
+                return Err("limit has to be positive and offset can't be negative".to_string());
+            }
+
+            let filter = SearchFilter {
+                examiner_ids: search.examiners,
+                subject_ids: search.subjects,
+                stex_ids: search.stex,
+                seasons: search.seasons,
+                years: search.years,
+                exclude_examiner_ids: search.exclude_examiners,
+                exclude_subject_ids: search.exclude_subjects,
+                exclude_stex_ids: search.exclude_stex,
+                exclude_seasons: search.exclude_seasons,
+                exclude_years: search.exclude_years,
+                year_from: search.year_from,
+                year_to: search.year_to,
+                text: search.q.map(|q| q.trim().to_string()).filter(|q| !q.is_empty())
+            };
+
+
+            let results = match database.search_for_protocol(filter, Pagination { limit: search.limit, offset: search.offset }).await {
+                Ok(results) => results,
+                Err(err) => return Err(format!("Failed to search for Protocols!: {}", err)),
+            };
+
+            let response = SearchResponse { total: results.total, limit: search.limit, offset: search.offset, protocols: results.protocols };
+
+            match serde_json::to_string_pretty(&response) {
+                Ok(json) => println!("{}", json),
+                Err(err) => return Err(format!("Failed to serialize Search Results!: {}", err)),
+            }
+        },
+    }
+
+    Ok(())
+}
+
+pub async fn run_sessions_command(command: SessionsCommand, database: Arc<dyn ProtocolStore>) -> Result<(), String> {
+
+    match command {
+        SessionsCommand::Purge => {
+            match database.remove_expired_sessions().await {
+                Ok(_) => println!("Removed the expired sessions"),
+                Err(err) => return Err(format!("Failed to remove expired Sessions!: {}", err)),
+            }
+        },
+        SessionsCommand::List { email } => {
+            let sessions = match database.get_sessions(&email).await {
+                Ok(sessions) => sessions,
+                Err(err) => return Err(format!("Failed to list Sessions!: {}", err)),
+            };
+
+            match serde_json::to_string_pretty(&sessions) {
+                Ok(json) => println!("{}", json),
+                Err(err) => return Err(format!("Failed to serialize Sessions!: {}", err)),
+            }
+        },
+        SessionsCommand::Revoke { email } => {
+            match database.remove_sessions(&email).await {
+                Ok(revoked) => println!("Revoked {} sessions of {}", revoked, email),
+                Err(err) => return Err(format!("Failed to revoke Sessions!: {}", err)),
+            }
+        },
+    }
+
+    Ok(())
+}
+
+pub async fn run_db_command(command: DbCommand, database: Arc<dyn ProtocolStore>, configuration: &Configuration) -> Result<(), String> {
+
+    match command {
+        DbCommand::Check { repair } => {
+            let report = match database.check_consistency(repair.map(RepairMode::from)).await {
+                Ok(report) => report,
+                Err(err) => return Err(format!("Failed to check the Consistency!: {}", err)),
+            };
+
+            match serde_json::to_string_pretty(&report) {
+                Ok(json) => println!("{}", json),
+                Err(err) => return Err(format!("Failed to serialize the Report!: {}", err)),
+            }
+
+            if repair.is_none() && !report.is_consistent() {
+                return Err("Found inconsistencies, run again with --repair to fix them".to_string());
+            }
+        },
+        DbCommand::Reencrypt => {
+            let cipher = match ProtocolCipher::from_configuration(&configuration.encryption) {
+                Ok(Some(cipher)) => cipher,
+                Ok(None) => return Err("There are no encryption.protocol_keys in the config".to_string()),
+                Err(err) => return Err(format!("Invalid Encryption-Config!: {}", err)),
+            };
+
+            let blobs = match open_unencrypted_blob_store(configuration).await {
+                Ok(blobs) => blobs,
+                Err(err) => return Err(format!("Failed to open the Blob-Store!: {}", err)),
+            };
+
+            match reencrypt_protocols(blobs.as_ref(), &cipher).await {
+                Ok(report) => println!("Re-encrypted {} protocol(s), {} already used {}", report.reencrypted, report.unchanged, cipher.active_key_id().unwrap_or("no key")),
+                Err(err) => return Err(format!("Failed to re-encrypt the Protocols!: {}", err)),
+            }
+
+            match database.reencrypt_revisions().await {
+                Ok(reencrypted) => println!("Re-encrypted {} revision(s)", reencrypted),
+                Err(err) => return Err(format!("Failed to re-encrypt the Revisions!: {}", err)),
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Works without a valid config, so unlike the other commands it runs before the config is loaded
+pub fn run_config_command(command: ConfigCommand, config_path: &Path) -> Result<(), String> {
+    match command {
+        ConfigCommand::Init { force } => {
+            if config_path.exists() && !force {
+                return Err(format!("{} already exists, use --force to overwrite it", config_path.display()));
+            }
+
+            let config_default = match toml::to_string(&Configuration::default()) {
+                Ok(config) => config,
+                Err(err) => return Err(format!("Failed to Serialize Default Configuration!: {}", err)),
+            };
+
+            match fs::write(config_path, config_default) {
+                Ok(_) => println!("Wrote {}, please populate it", config_path.display()),
+                Err(err) => return Err(format!("Failed to write config file!: {}", err)),
+            }
+        },
+        ConfigCommand::Validate => {
+            let config_str = match fs::read_to_string(config_path) {
+                Ok(config) => config,
+                Err(err) => return Err(format!("Failed to read {}!: {}", config_path.display(), err)),
+            };
+
+            let configuration = match toml::from_str::<Configuration>(&config_str) {
+                Ok(configuration) => configuration,
+                Err(err) => return Err(format!("Failed to deserialize Configuration!: {}", err)),
+            };
+
+            let problems = configuration_problems(&configuration);
+
+            if !problems.is_empty() {
+                for problem in &problems {
+                    println!("- {}", problem);
+                }
+                return Err(format!("{} has {} problem(s)", config_path.display(), problems.len()));
+            }
+
+            println!("{} is valid", config_path.display());
+        },
+    }
+
+    Ok(())
+}
+
+/// Things that would only fail once the API is running, or not fail at all but be insecure
+fn configuration_problems(configuration: &Configuration) -> Vec<String> {
+    let mut problems = vec![];
+
+    if configuration.api.bind_addr.parse::<IpAddr>().is_err() {
+        problems.push(format!("api.bind_addr {:?} is no IP address", configuration.api.bind_addr));
+    }
+
+    if configuration.encryption.token_encryption_secret.len() < 10 {
+        problems.push("encryption.token_encryption_secret should be at least 10 characters long".to_string());
+    }
+
+    match &configuration.database_type {
+        DatabaseBackend::SQLLite { file_location } if file_location.trim().is_empty() => problems.push("database_type.SQLLite.file_location is empty".to_string()),
+        DatabaseBackend::PostgeSQL { hostname, database, .. } if hostname.trim().is_empty() || database.trim().is_empty() => problems.push("database_type.PostgeSQL needs a hostname and a database".to_string()),
+        _ => {},
+    }
+
+    if let DatabaseBackend::PostgeSQL { ssl_root_certificate: Some(path), .. } = &configuration.database_type {
+        if !Path::new(path).is_file() {
+            problems.push(format!("database_type.PostgeSQL.ssl_root_certificate {:?} is no file", path));
+        }
+    }
+
+    if let Authorization::OpenIdConnect { self_root_url, issuer, token_url, auth_url, jwks_url, revoke_url, userinfo_url, end_session_url, client_secret, return_urls, .. } = &configuration.authorization {
+        let urls = [("self_root_url", Some(self_root_url)), ("issuer", Some(issuer)), ("token_url", token_url.as_ref()), ("auth_url", auth_url.as_ref()), ("jwks_url", jwks_url.as_ref()), ("revoke_url", revoke_url.as_ref()), ("userinfo_url", userinfo_url.as_ref()), ("end_session_url", end_session_url.as_ref())];
+
+        for (name, url) in urls {
+            if let Some(url) = url.filter(|url| !url.starts_with("http://") && !url.starts_with("https://")) {
+                problems.push(format!("authorization.OpenIdConnect.{} {:?} is no http(s) URL", name, url));
+            }
+        }
+
+        if client_secret.as_ref().is_some_and(|secret| secret.trim().is_empty()) {
+            problems.push("authorization.OpenIdConnect.client_secret is empty, leave it out for a public client".to_string());
+        }
+
+        // An entry like "https://" would allow every URL, so each one needs a host
+        for return_url in return_urls {
+            let has_host = url::Url::parse(return_url).ok().filter(|url| url.scheme() == "http" || url.scheme() == "https").and_then(|url| url.host_str().map(|host| !host.is_empty()));
+
+            if has_host != Some(true) || return_url.contains('#') {
+                problems.push(format!("authorization.OpenIdConnect.return_urls contains {:?}, which is no http(s) URL without fragment", return_url));
+            }
+        }
+    }
+
+    if let Err(err) = ProtocolCipher::from_configuration(&configuration.encryption) {
+        problems.push(err.to_string());
+    }
+
+    match &configuration.blob_storage {
+        BlobStorage::Local if configuration.general.protocol_location.trim().is_empty() => problems.push("general.protocol_location is empty".to_string()),
+        BlobStorage::S3 { endpoint, region, bucket, access_key_id, secret_access_key, .. } => {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                problems.push(format!("blob_storage.S3.endpoint {:?} is no http(s) URL", endpoint));
+            }
+
+            if [region, bucket, access_key_id, secret_access_key].iter().any(|value| value.trim().is_empty()) {
+                problems.push("blob_storage.S3 needs a region, a bucket, an access_key_id and a secret_access_key".to_string());
+            }
+        },
+        _ => {},
+    }
+
+    for email in &configuration.general.initial_admins {
+        if !email_is_valid(email) {
+            problems.push(format!("general.initial_admins contains the invalid email {:?}", email));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{error::ErrorKind, CommandFactory};
+
+    use super::*;
+
+    fn parse(arguments: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(["protocoldb-backend"].iter().chain(arguments))
+    }
+
+    fn parse_error(arguments: &[&str]) -> ErrorKind {
+        parse(arguments).err().expect("The arguments were accepted").kind()
+    }
+
+    #[test]
+    fn cli_definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn serves_without_subcommand() {
+        let cli = parse(&[]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.config, PathBuf::from("config.toml"));
+
+        // The config path can come after the subcommand as well
+        let cli = parse(&["serve", "--config", "/etc/protocoldb.toml"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Serve)));
+        assert_eq!(cli.config, PathBuf::from("/etc/protocoldb.toml"));
+
+        assert_eq!(parse_error(&["start"]), ErrorKind::InvalidSubcommand);
+    }
+
+    #[test]
+    fn parses_admin_and_sessions_commands() {
+        assert!(matches!(parse(&["admin", "add", "admin@example.org"]).unwrap().command, Some(Command::Admin(AdminCommand::Add { email })) if email == "admin@example.org"));
+        assert!(matches!(parse(&["admin", "remove", "admin@example.org"]).unwrap().command, Some(Command::Admin(AdminCommand::Remove { email })) if email == "admin@example.org"));
+        assert!(matches!(parse(&["admin", "list"]).unwrap().command, Some(Command::Admin(AdminCommand::List))));
+        assert_eq!(parse_error(&["admin", "add"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(parse_error(&["admin"]), ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand);
+
+        assert!(matches!(parse(&["sessions", "purge"]).unwrap().command, Some(Command::Sessions(SessionsCommand::Purge))));
+        assert!(matches!(parse(&["sessions", "list", "user@example.org"]).unwrap().command, Some(Command::Sessions(SessionsCommand::List { email })) if email == "user@example.org"));
+        assert!(matches!(parse(&["sessions", "revoke", "user@example.org"]).unwrap().command, Some(Command::Sessions(SessionsCommand::Revoke { email })) if email == "user@example.org"));
+    }
+
+    #[test]
+    fn parses_entity_commands() {
+        assert!(matches!(parse(&["entity", "create", "examiner", "Dr. Muster"]).unwrap().command, Some(Command::Entity(EntityCommand::Create { kind: EntityKind::Examiner, display_name })) if display_name == "Dr. Muster"));
+        assert!(matches!(parse(&["entity", "list"]).unwrap().command, Some(Command::Entity(EntityCommand::List { kind: None }))));
+        assert!(matches!(parse(&["entity", "list", "season"]).unwrap().command, Some(Command::Entity(EntityCommand::List { kind: Some(EntityKind::Season) }))));
+        assert_eq!(parse_error(&["entity", "create", "teacher", "Dr. Muster"]), ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn parses_protocol_commands() {
+        let save = match parse(&["protocol", "save", "--pair", "1:2", "--pair", " 3 : 4", "--stex", "1", "--season", "2", "--year", "2023"]).unwrap().command {
+            Some(Command::Protocol(ProtocolCommand::Save(save))) => save,
+            _ => panic!("Not parsed as protocol save"),
+        };
+        assert_eq!(save.pairs, vec![(1, 2), (3, 4)]);
+        assert_eq!((save.stex, save.season, save.year), (1, 2, 2023));
+        assert_eq!(save.file, None);
+        assert_eq!(save.author, "cli");
+
+        assert_eq!(parse_error(&["protocol", "save", "--stex", "1", "--season", "2", "--year", "2023"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(parse_error(&["protocol", "save", "--pair", "1-2", "--stex", "1", "--season", "2", "--year", "2023"]), ErrorKind::ValueValidation);
+        assert_eq!(parse_error(&["protocol", "save", "--pair", "1:a", "--stex", "1", "--season", "2", "--year", "2023"]), ErrorKind::ValueValidation);
+
+        let search = match parse(&["protocol", "search", "--examiners", "1,2", "--exclude-years", "2020", "--year-from", "2019", "--q", "plexus"]).unwrap().command {
+            Some(Command::Protocol(ProtocolCommand::Search(search))) => search,
+            _ => panic!("Not parsed as protocol search"),
+        };
+        assert_eq!(search.examiners, Some(vec![1, 2]));
+        assert_eq!(search.exclude_years, Some(vec![2020]));
+        assert_eq!(search.year_from, Some(2019));
+        assert_eq!(search.subjects, None);
+        assert_eq!(search.q.as_deref(), Some("plexus"));
+        assert_eq!((search.limit, search.offset), (DEFAULT_SEARCH_LIMIT, 0));
+    }
+
+    #[test]
+    fn parses_db_and_config_commands() {
+        assert!(matches!(parse(&["db", "check"]).unwrap().command, Some(Command::Db(DbCommand::Check { repair: None }))));
+        assert!(matches!(parse(&["db", "check", "--repair", "quarantine"]).unwrap().command, Some(Command::Db(DbCommand::Check { repair: Some(RepairKind::Quarantine) }))));
+        assert!(matches!(parse(&["db", "check", "--repair", "delete"]).unwrap().command, Some(Command::Db(DbCommand::Check { repair: Some(RepairKind::Delete) }))));
+        assert_eq!(parse_error(&["db", "check", "--repair", "everything"]), ErrorKind::InvalidValue);
+        assert!(matches!(parse(&["db", "reencrypt"]).unwrap().command, Some(Command::Db(DbCommand::Reencrypt))));
+
+        assert!(matches!(parse(&["config", "init"]).unwrap().command, Some(Command::Config(ConfigCommand::Init { force: false }))));
+        assert!(matches!(parse(&["config", "init", "--force"]).unwrap().command, Some(Command::Config(ConfigCommand::Init { force: true }))));
+        assert!(matches!(parse(&["config", "validate"]).unwrap().command, Some(Command::Config(ConfigCommand::Validate))));
+    }
+
+    #[test]
+    fn invalid_initial_admins_are_reported() {
+        let mut configuration = Configuration::default();
+        configuration.general.initial_admins = vec!["admin@example.org".to_string(), "admin@localhost".to_string()];
+
+        assert_eq!(configuration_problems(&configuration), vec!["general.initial_admins contains the invalid email \"admin@localhost\"".to_string()]);
+    }
+}
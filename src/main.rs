@@ -1,35 +1,104 @@
-use std::{fs, sync::Arc};
+use std::{fs, path::Path, sync::Arc};
 
 use actix_web::{web::{self}, App, HttpServer};
-use storage::database::Database;
-use tokio::sync::Mutex;
+use clap::Parser;
+use cli::{run_admin_command, run_config_command, run_db_command, run_entity_command, run_protocol_command, run_sessions_command, Cli, Command};
+use storage::{database::{open_database, seed_initial_admins}, protocol_store::ProtocolStore};
 
-use crate::{services::{admin::{self}, display::{home, info, invalid_auth}, openidconnect, user::{self}}, structs::configuration::{Authorization, Configuration}};
+use crate::{services::{admin::{self}, discovery::OidcProvider, display::{home, info, invalid_auth}, error::ApiError, openidconnect, user::{self}}, structs::configuration::{Authorization, Configuration}};
 
 
 mod storage;
 mod structs;
 mod services;
+mod cli;
 
 pub const TOKEN_VALID_LENGTH: u64 = 86400;
+pub const DEFAULT_SEARCH_LIMIT: i64 = 50;
+pub const MAX_SEARCH_LIMIT: i64 = 200;
 
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
 
-    let config_str = match fs::read_to_string("config.toml") {
+    let cli = Cli::parse();
+
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    if let Command::Config(command) = command {
+        return exit_with(run_config_command(command, &cli.config));
+    }
+
+    let configuration = load_configuration(&cli.config)?;
+
+    if let Command::Serve = command {
+        return serve(configuration).await;
+    }
+
+    let database = open_configured_database(&configuration).await?;
+
+    let result = match command {
+        Command::Admin(command) => run_admin_command(command, database).await,
+        Command::Entity(command) => run_entity_command(command, database).await,
+        Command::Protocol(command) => run_protocol_command(command, database).await,
+        Command::Sessions(command) => run_sessions_command(command, database).await,
+        Command::Db(command) => run_db_command(command, database, &configuration).await,
+        Command::Serve | Command::Config(_) => Ok(()),
+    };
+
+    exit_with(result)
+}
+
+/// Turns the result of a command into the exit status of the binary
+fn exit_with(result: Result<(), String>) -> std::io::Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            println!("{}", err);
+            Err(std::io::Error::other(err))
+        },
+    }
+}
+
+fn load_configuration(config_path: &Path) -> std::io::Result<Configuration> {
+    let config_str = match fs::read_to_string(config_path) {
         Ok(file) => file,
         Err(err) => {
-            println!("please Populate the config.toml config!");
+            println!("please Populate the {} config!", config_path.display());
             let config_default = toml::to_string(&Configuration::default()).expect("Failed to Serialize Default Configuration!");
-            fs::write("config.toml", config_default).expect("Failed to write config file!");
+            fs::write(config_path, config_default).expect("Failed to write config file!");
             return Result::Err(err)
         },
     };
 
-    let configuration = toml::from_str::<Configuration>(&config_str).expect("Failed to deserialize Configuration.");
+    match toml::from_str::<Configuration>(&config_str) {
+        Ok(configuration) => Ok(configuration),
+        Err(err) => {
+            println!("Failed to deserialize Configuration!: {}", err);
+            Err(std::io::Error::other(err))
+        },
+    }
+}
 
-    let _ = fs::create_dir_all("protocols/");
+/// Opens the database from the config and grants the initial admins their rights
+async fn open_configured_database(configuration: &Configuration) -> std::io::Result<Arc<dyn ProtocolStore>> {
+    let database = match open_database(configuration).await {
+        Ok(database) => database,
+        Err(err) => {
+            println!("Failed to open the database!: {}", err);
+            return Result::Err(std::io::Error::other(err.to_string()))
+        },
+    };
+
+    if let Err(err) = seed_initial_admins(&database, &configuration.general.initial_admins).await {
+        println!("Failed to add the initial admins!: {}", err);
+        return Result::Err(std::io::Error::other(err.to_string()))
+    }
+
+    Ok(database)
+}
+
+async fn serve(configuration: Configuration) -> std::io::Result<()> {
 
     println!("
      __  __     ______     __     ______     ______     ______     ______    
@@ -44,33 +113,77 @@ async fn main() -> std::io::Result<()> {
 
     println!("\n\nStarting API!\n");
 
+    let database = web::Data::new(open_configured_database(&configuration).await?);
+
+    if configuration.encryption.active_protocol_key.is_some() {
+        println!("The protocol texts are encrypted, so the full-text search is off. Its index would keep them as plaintext");
+    }
+
+    let provider = match OidcProvider::from_configuration(&configuration.authorization).await {
+        Ok(provider) => provider,
+        Err(err) => {
+            println!("Failed to set up OpenIDConnect!: {}", err);
+            return Result::Err(std::io::Error::other(err))
+        },
+    };
+
+    if let Some(provider) = &provider {
+        if let Authorization::OpenIdConnect { idp_logout: true, .. } = &configuration.authorization {
+            if provider.endpoints().end_session_url.is_none() {
+                println!("idp_logout is set, but the provider has no end_session_endpoint. Logging out only ends the session of the API");
+            }
+        }
+
+        tokio::spawn(OidcProvider::keep_fresh(provider.clone()));
+    }
+
     let movable_config = configuration.clone();//ToDo: Make this less strange...
 
     HttpServer::new(move || {
         let mov_config = movable_config.clone();
         let app = App::new()
-            .app_data(web::Data::new(Arc::new(Mutex::new(Database::new(None)))))
+            .app_data(database.clone())
             .app_data(web::Data::new(mov_config))
+            .app_data(web::JsonConfig::default().error_handler(|err, _| ApiError::InvalidInput(err.to_string()).into()))
+            .app_data(web::PathConfig::default().error_handler(|err, _| ApiError::InvalidInput(err.to_string()).into()))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| ApiError::InvalidInput(err.to_string()).into()))
             .service(invalid_auth)
             .service(home)
             .service(info)
             .service(admin::save_protocol)
+            .service(admin::edit_protocol)
+            .service(admin::delete_protocol)
+            .service(admin::replace_protocol_text)
+            .service(admin::list_revisions)
+            .service(admin::get_revision)
+            .service(admin::diff_revisions)
+            .service(admin::restore_revision)
             .service(admin::create)
+            .service(admin::rename_entity)
+            .service(admin::merge_entity)
+            .service(admin::delete_entity)
             .service(admin::add_admin)
             .service(admin::remove_admin)
             .service(admin::list_admins)
+            .service(admin::list_sessions)
+            .service(admin::revoke_sessions)
+            .service(admin::check_consistency)
+            .service(admin::repair_consistency)
             .service(user::get_selection_identifiers)
-            .service(user::search_for_protocol);
+            .service(user::search_for_protocol)
+            .service(user::get_protocol);
 
 
-        match movable_config.authorization {
-            Authorization::OpenIdConnect { .. } => {
+        match (&movable_config.authorization, &provider) {
+            (Authorization::OpenIdConnect { .. }, Some(provider)) => {
                 app
+                    .app_data(web::Data::from(provider.clone()))
                     .service(openidconnect::login)
                     .service(openidconnect::redirect)
                     .service(openidconnect::finish)
+                    .service(openidconnect::logout)
             },
-            Authorization::None => {
+            _ => {
                 app
             },
         }
@@ -80,21 +193,3 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
-
-
-
-
-#[macro_export]
-macro_rules! expose_error {
-    ($err:expr) => {
-        return HttpResponse::InternalServerError().content_type(ContentType::json()).body("{\"error\":\"<E>\"}".to_string().replace("<E>", $err))
-    };
-}
-
-
-#[macro_export]
-macro_rules! invalid_input {
-    ($err:expr) => {
-        return HttpResponse::InternalServerError().content_type(ContentType::json()).body("{\"error\":\"<E>\"}".to_string().replace("<E>", $err))
-    };
-}
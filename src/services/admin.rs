@@ -1,130 +1,719 @@
 use std::sync::Arc;
 
-use actix_web::{delete, get, http::header::ContentType, post, web::{self, Json}, HttpRequest, HttpResponse, Responder};
-use tokio::sync::Mutex;
+use actix_web::{delete, get, http::header::ContentType, post, put, web::{self, Json, Path, Query}, HttpRequest, HttpResponse};
 
-use crate::{authenticate_admin, expose_error, invalid_input, services::common::authenticate_admin, storage::database::Database, structs::{configuration::Configuration, post_inputs::{ChangeAdmin, Create, CreateField, Protocol}}};
+use crate::{authenticate_admin, services::{common::authenticate_admin, diff::diff_lines, error::ApiError}, storage::{database::email_is_valid, protocol_store::{CatalogEntity, EntityChange, ProtocolChange, ProtocolCreation, ProtocolStore, ProtocolUpdate}}, structs::{configuration::Configuration, get_inputs::DiffRevisions, get_outputs::{AdminList, RevisionDiff}, post_inputs::{ChangeAdmin, Create, CreateField, EditProtocol, MergeEntity, Protocol, RenameEntity, RepairConsistency, RepairMode, ReplaceText}}};
 
 
 #[post("/api/admin/v1/save")]
-pub async fn save_protocol(request: HttpRequest, protocol: Json<Protocol>, data: web::Data<Arc<Mutex<Database>>>, configuration: web::Data<Configuration>) -> impl Responder {
+pub async fn save_protocol(request: HttpRequest, protocol: Json<Protocol>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    let admin_email = authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    if protocol.examiner_subject_ids.is_empty() {
+        return Err(ApiError::InvalidInput("A protocol needs at least one examiner/subject pair".to_string()));
+    }
+
+    let database = data.get_ref(); 
+    let creation = match database.save_protocol(protocol.examiner_subject_ids.clone(), protocol.stex_id, protocol.season_id, protocol.year, protocol.text.clone(), &admin_email).await {
+        Ok(creation) => creation,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to save Protocol!: {:?}", err)));
+        },
+    };
+
+
+    let protocol_uuid = match creation {
+        ProtocolCreation::Saved(id) => id,
+        ProtocolCreation::UnknownEntity(entity, id) => {
+            return Err(ApiError::InvalidInput(format!("There is no {} with the ID {}", entity.label(), id)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body("{\"protocol_uuid\":\"<ID>\"}".replace("<ID>", &protocol_uuid)))
+}
+
+#[put("/api/admin/v1/protocol/{uuid}")]
+pub async fn edit_protocol(request: HttpRequest, protocol_uuid: Path<String>, edit: Json<EditProtocol>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    let admin_email = authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let edit = edit.into_inner();
+
+    if let Some(examiner_subject_ids) = &edit.examiner_subject_ids {
+        if examiner_subject_ids.is_empty() {
+            return Err(ApiError::InvalidInput("A protocol needs at least one examiner/subject pair".to_string()));
+        }
+    }
+
+    let update = ProtocolUpdate {
+        examiner_subject_ids: edit.examiner_subject_ids,
+        stex_id: edit.stex_id,
+        season_id: edit.season_id,
+        year: edit.year,
+        text: edit.text
+    };
+
+    if !update.changes_metadata() && update.text.is_none() {
+        return Err(ApiError::InvalidInput("Nothing to change".to_string()));
+    }
+
+    apply_protocol_update(&data, &protocol_uuid, update, &admin_email).await
+}
+
+#[post("/api/admin/v1/protocol/{uuid}/text")]
+pub async fn replace_protocol_text(request: HttpRequest, protocol_uuid: Path<String>, replacement: Json<ReplaceText>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    let admin_email = authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let update = ProtocolUpdate {
+        examiner_subject_ids: None,
+        stex_id: None,
+        season_id: None,
+        year: None,
+        text: Some(replacement.into_inner().text)
+    };
+
+    apply_protocol_update(&data, &protocol_uuid, update, &admin_email).await
+}
+
+#[get("/api/admin/v1/protocol/{uuid}/revisions")]
+pub async fn list_revisions(request: HttpRequest, protocol_uuid: Path<String>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
 
     authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
 
-    let mut database = data.lock().await; 
-    let potential_protocol_uuid = match database.save_protocol(protocol.examiner_subject_ids.clone(), protocol.stex_id, protocol.season_id, protocol.year, protocol.text.clone()) {
-        Ok(pot_id) => pot_id,
+    let database = data.get_ref();
+
+    let potential_revisions = match database.get_revisions(&protocol_uuid).await {
+        Ok(revisions) => revisions,
         Err(err) => {
-            expose_error!(&err.to_string());
+            return Err(ApiError::Internal(format!("Failed to list Revisions!: {:?}", err)));
         },
     };
 
-    let protocol_uuid = match potential_protocol_uuid {
-        Some(id) => id,
+
+    let revisions = match potential_revisions {
+        Some(revisions) => revisions,
+        None => {
+            return Err(ApiError::NotFound("Protocol not found".to_string()));
+        },
+    };
+
+    let return_str = match serde_json::to_string(&revisions) {
+        Ok(str) => str,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to serialize Revisions!: {:?}", err)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(return_str))
+}
+
+#[get("/api/admin/v1/protocol/{uuid}/revisions/{revision}")]
+pub async fn get_revision(request: HttpRequest, path: Path<(String, i64)>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let (protocol_uuid, revision_number) = path.into_inner();
+
+    let database = data.get_ref();
+
+    let potential_revision = match database.get_revision(&protocol_uuid, revision_number).await {
+        Ok(revision) => revision,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to get Revision!: {:?}", err)));
+        },
+    };
+
+
+    let revision = match potential_revision {
+        Some(revision) => revision,
+        None => {
+            return Err(ApiError::NotFound("Revision not found".to_string()));
+        },
+    };
+
+    let return_str = match serde_json::to_string(&revision) {
+        Ok(str) => str,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to serialize Revision!: {:?}", err)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(return_str))
+}
+
+#[get("/api/admin/v1/protocol/{uuid}/diff")]
+pub async fn diff_revisions(request: HttpRequest, protocol_uuid: Path<String>, revisions: Query<DiffRevisions>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let database = data.get_ref();
+
+    let potential_revisions = match (database.get_revision(&protocol_uuid, revisions.from).await, database.get_revision(&protocol_uuid, revisions.to).await) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => {
+            return Err(ApiError::Internal(format!("Failed to get Revisions!: {:?}", err)));
+        },
+    };
+
+
+    let (mut from, mut to) = match potential_revisions {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            return Err(ApiError::NotFound("Revision not found".to_string()));
+        },
+    };
+
+    let lines = diff_lines(&from.text.take().unwrap_or_default(), &to.text.take().unwrap_or_default());
+
+    let return_str = match serde_json::to_string(&RevisionDiff { from, to, lines }) {
+        Ok(str) => str,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to serialize Diff!: {:?}", err)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(return_str))
+}
+
+/// Makes the text and metadata of an old revision the current state, recorded as a new revision
+#[post("/api/admin/v1/protocol/{uuid}/revisions/{revision}/restore")]
+pub async fn restore_revision(request: HttpRequest, path: Path<(String, i64)>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    let admin_email = authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let (protocol_uuid, revision_number) = path.into_inner();
+
+    let database = data.get_ref();
+
+    let potential_revision = match database.get_revision(&protocol_uuid, revision_number).await {
+        Ok(revision) => revision,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to get Revision!: {:?}", err)));
+        },
+    };
+
+
+    let revision = match potential_revision {
+        Some(revision) => revision,
         None => {
-            expose_error!("No Protocol Saved.");
+            return Err(ApiError::NotFound("Revision not found".to_string()));
+        },
+    };
+
+    let update = ProtocolUpdate {
+        examiner_subject_ids: Some(revision.examiner_subject_ids),
+        stex_id: Some(revision.stex_id),
+        season_id: Some(revision.season_id),
+        year: Some(revision.year),
+        text: revision.text
+    };
+
+    apply_protocol_update(&data, &protocol_uuid, update, &admin_email).await
+}
+
+/// Deletes the protocol together with all of its revisions, this can't be undone
+#[delete("/api/admin/v1/protocol/{uuid}")]
+pub async fn delete_protocol(request: HttpRequest, protocol_uuid: Path<String>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    let admin_email = authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let database = data.get_ref();
+
+    let found = match database.delete_protocol(&protocol_uuid).await {
+        Ok(found) => found,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to delete Protocol!: {:?}", err)));
+        },
+    };
+
+
+    if !found {
+        return Err(ApiError::NotFound("Protocol not found".to_string()));
+    }
+
+    println!("Admin {} deleted Protocol {} with all of its revisions", admin_email, protocol_uuid.as_str());
+
+    Ok(HttpResponse::Ok().body(""))
+}
+
+async fn apply_protocol_update(data: &web::Data<Arc<dyn ProtocolStore>>, protocol_uuid: &str, update: ProtocolUpdate, admin_email: &str) -> Result<HttpResponse, ApiError> {
+    let database = data.get_ref();
+
+    let change = match database.update_protocol(protocol_uuid, update, admin_email).await {
+        Ok(change) => change,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to edit Protocol!: {:?}", err)));
         },
     };
 
-    HttpResponse::Ok().content_type(ContentType::json()).body("{\"protocol_uuid\":\"<ID>\"}".replace("<ID>", &protocol_uuid))
+
+    match change {
+        ProtocolChange::Updated => {},
+        ProtocolChange::NotFound => {
+            return Err(ApiError::NotFound("Protocol not found".to_string()));
+        },
+        ProtocolChange::UnknownEntity(entity, id) => {
+            return Err(ApiError::InvalidInput(format!("There is no {} with the ID {}", entity.label(), id)));
+        },
+    }
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body("{\"protocol_uuid\":\"<ID>\"}".replace("<ID>", protocol_uuid)))
 }
 
 #[post("/api/admin/v1/create")]
-pub async fn create(request: HttpRequest, creation: Json<Create>, data: web::Data<Arc<Mutex<Database>>>, configuration: web::Data<Configuration>)  -> impl Responder {
+pub async fn create(request: HttpRequest, creation: Json<Create>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>)  -> Result<HttpResponse, ApiError> {
     authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
 
-    let mut database = data.lock().await;
+    let database = data.get_ref();
 
     let potential_id = match creation.field {
         CreateField::Examiner => {
-            match database.create_examiner(creation.display_name.clone()) {
+            match database.create_item(CatalogEntity::Examiner, creation.display_name.clone()).await {
                 Ok(id) => id,
                 Err(err) => {
-                    expose_error!(&format!("Failed to create Examiner!: {:?}", err));
+                    return Err(ApiError::Internal(format!("Failed to create Examiner!: {:?}", err)));
                 },
             }
         },
         CreateField::Subject => {
-            match database.create_subject(creation.display_name.clone()) {
+            match database.create_item(CatalogEntity::Subject, creation.display_name.clone()).await {
                 Ok(id) => id,
                 Err(err) => {
-                    expose_error!(&format!("Failed to create Subject!: {:?}", err));
+                    return Err(ApiError::Internal(format!("Failed to create Subject!: {:?}", err)));
                 },
             }
         },
         CreateField::Season => {
-            match database.create_season(creation.display_name.clone()) {
+            match database.create_item(CatalogEntity::Season, creation.display_name.clone()).await {
                 Ok(id) => id,
                 Err(err) => {
-                    expose_error!(&format!("Failed to create Season!: {:?}", err));
+                    return Err(ApiError::Internal(format!("Failed to create Season!: {:?}", err)));
                 },
             }
         },
         CreateField::Stex => {
-            match database.create_stex(creation.display_name.clone()) {
+            match database.create_item(CatalogEntity::Stex, creation.display_name.clone()).await {
                 Ok(id) => id,
                 Err(err) => {
-                    expose_error!(&format!("Failed to create Stex!: {:?}", err));
+                    return Err(ApiError::Internal(format!("Failed to create Stex!: {:?}", err)));
                 },
             }
         },
     };
 
-    drop(database);
 
     let id = match potential_id {
         Some(id) => id,
         None => {
-            expose_error!("No ID created!");
+            return Err(ApiError::InvalidInput("Invalid display name".to_string()));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body("{\"created_id\":\"<ID>\"}".replace("<ID>", &id.to_string())))
+
+}
+
+#[put("/api/admin/v1/entity/{field}/{id}")]
+pub async fn rename_entity(request: HttpRequest, path: Path<(CreateField, i64)>, rename: Json<RenameEntity>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+    authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let (field, id) = path.into_inner();
+
+    let database = data.get_ref();
+
+    let change = match database.rename_item(CatalogEntity::from(&field), id, rename.into_inner().display_name).await {
+        Ok(change) => change,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to rename Entity!: {:?}", err)));
+        },
+    };
+
+
+    entity_change_response(change)
+}
+
+/// Merges the entity from the path into the one given in the body, e.g. to fix duplicates that differ only in spelling
+#[post("/api/admin/v1/entity/{field}/{id}/merge")]
+pub async fn merge_entity(request: HttpRequest, path: Path<(CreateField, i64)>, merge: Json<MergeEntity>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+    authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let (field, id) = path.into_inner();
+
+    if id == merge.into_id {
+        return Err(ApiError::InvalidInput("Can't merge an entity into itself".to_string()));
+    }
+
+    let database = data.get_ref();
+
+    let change = match database.merge_items(CatalogEntity::from(&field), id, merge.into_id).await {
+        Ok(change) => change,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to merge Entities!: {:?}", err)));
         },
     };
 
-    HttpResponse::Ok().content_type(ContentType::json()).body("{\"created_id\":\"<ID>\"}".replace("<ID>", &id.to_string()))
 
+    entity_change_response(change)
+}
+
+#[delete("/api/admin/v1/entity/{field}/{id}")]
+pub async fn delete_entity(request: HttpRequest, path: Path<(CreateField, i64)>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+    authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let (field, id) = path.into_inner();
+
+    let database = data.get_ref();
+
+    let change = match database.delete_item(CatalogEntity::from(&field), id).await {
+        Ok(change) => change,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to delete Entity!: {:?}", err)));
+        },
+    };
+
+
+    entity_change_response(change)
+}
+
+fn entity_change_response(change: EntityChange) -> Result<HttpResponse, ApiError> {
+    match change {
+        EntityChange::Done => Ok(HttpResponse::Ok().body("")),
+        EntityChange::NotFound => Err(ApiError::NotFound("Entity not found".to_string())),
+        EntityChange::InvalidName => Err(ApiError::InvalidInput("Invalid display name".to_string())),
+        EntityChange::NameTaken => Err(ApiError::Conflict("Another entity already has this name, merge them instead".to_string())),
+        EntityChange::InUse => Err(ApiError::Conflict("Entity is still used by protocols".to_string())),
+    }
 }
 
 #[post("/api/admin/v1/addadmin")]
-pub async fn add_admin(request: HttpRequest, admin: Json<ChangeAdmin>, data: web::Data<Arc<Mutex<Database>>>, configuration: web::Data<Configuration>) -> impl Responder {
+pub async fn add_admin(request: HttpRequest, admin: Json<ChangeAdmin>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
     authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
 
-    let mut database = data.lock().await;
+    let database = data.get_ref();
 
-    if let Err(err) = database.add_admin(&admin.email_addr) {
-        expose_error!(&format!("Failed to add Admin!: {:?}", err));
+    let accepted = match database.add_admin(&admin.email_addr).await {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to add Admin!: {:?}", err)));
+        },
     };
-    
-    HttpResponse::Ok().body("")
+
+    if !accepted {
+        return Err(ApiError::InvalidInput("Invalid Email".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().body(""))
 }
 
 
 #[delete("/api/admin/v1/removeadmin")]
-pub async fn remove_admin(request: HttpRequest, admin: Json<ChangeAdmin>, data: web::Data<Arc<Mutex<Database>>>, configuration: web::Data<Configuration>) -> impl Responder {
+pub async fn remove_admin(request: HttpRequest, admin: Json<ChangeAdmin>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
     authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
 
-    let mut database = data.lock().await;
+    let database = data.get_ref();
 
-    if let Err(err) = database.remove_admin(&admin.email_addr) {
-        expose_error!(&format!("Failed to remove Admin!: {:?}", err));
+    let accepted = match database.remove_admin(&admin.email_addr).await {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to remove Admin!: {:?}", err)));
+        },
     };
-    
-    HttpResponse::Ok().body("")
+
+    if !accepted {
+        return Err(ApiError::InvalidInput("Invalid Email".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().body(""))
 }
 
 #[get("/api/admin/v1/getadmins")]
-pub async fn list_admins(request: HttpRequest, data: web::Data<Arc<Mutex<Database>>>, configuration: web::Data<Configuration>) -> impl Responder {
+pub async fn list_admins(request: HttpRequest, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
     authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
 
 
-    let database = data.lock().await;
+    let database = data.get_ref();
 
-    let admins = match database.get_admins() {
+    let admins = match database.get_admins().await {
         Ok(admins) => admins,
         Err(err) => {
-            expose_error!(&format!("Failed to list Admins!: {:?}", err));
+            return Err(ApiError::Internal(format!("Failed to list Admins!: {:?}", err)));
+        },
+    };
+
+    let return_str = match serde_json::to_string(&AdminList { admins }) {
+        Ok(str) => str,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to serialize Admins!: {:?}", err)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(return_str))
+}
+
+#[get("/api/admin/v1/sessions/{email}")]
+pub async fn list_sessions(request: HttpRequest, email: Path<String>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+    authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    if !email_is_valid(&email) {
+        return Err(ApiError::InvalidInput("Invalid Email".to_string()));
+    }
+
+    let database = data.get_ref();
+
+    let sessions = match database.get_sessions(&email).await {
+        Ok(sessions) => sessions,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to list Sessions!: {:?}", err)));
+        },
+    };
+
+    let return_str = match serde_json::to_string(&sessions) {
+        Ok(str) => str,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to serialize Sessions!: {:?}", err)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(return_str))
+}
+
+/// Logs the user out everywhere, e.g. after a token was stolen. Their next login works as usual
+#[delete("/api/admin/v1/sessions/{email}")]
+pub async fn revoke_sessions(request: HttpRequest, email: Path<String>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+    let admin_email = authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    if !email_is_valid(&email) {
+        return Err(ApiError::InvalidInput("Invalid Email".to_string()));
+    }
+
+    let database = data.get_ref();
+
+    let revoked = match database.remove_sessions(&email).await {
+        Ok(revoked) => revoked,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to revoke Sessions!: {:?}", err)));
+        },
+    };
+
+    println!("{} revoked {} sessions of {}", admin_email, revoked, email.as_str());
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(format!("{{\"revoked\": {} }}", revoked)))
+}
+
+#[get("/api/admin/v1/fsck")]
+pub async fn check_consistency(request: HttpRequest, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+    authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    consistency_response(&data, None).await
+}
+
+#[post("/api/admin/v1/fsck")]
+pub async fn repair_consistency(request: HttpRequest, repair: Json<RepairConsistency>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+    let admin_email = authenticate_admin!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    println!("{} repairs the database with {:?}", admin_email, repair.repair);
+
+    consistency_response(&data, Some(repair.repair)).await
+}
+
+async fn consistency_response(data: &web::Data<Arc<dyn ProtocolStore>>, repair: Option<RepairMode>) -> Result<HttpResponse, ApiError> {
+    let database = data.get_ref();
+
+    let report = match database.check_consistency(repair).await {
+        Ok(report) => report,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to check the Consistency!: {:?}", err)));
         },
     };
 
-    let return_str = format!("{{\"admins\": {:?} }}", admins);
-    
-    HttpResponse::Ok().content_type(ContentType::json()).body(return_str)
+
+    let return_str = match serde_json::to_string(&report) {
+        Ok(str) => str,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to serialize the Report!: {:?}", err)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(return_str))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::{json, Value};
+
+    use crate::{services::common::test_token, storage::{memory::MemoryDatabase, testing::create_catalog}};
+
+    use super::*;
+
+    const ADMIN: &str = "admin@example.org";
+
+    /// A memory store with one admin and one entity of every kind, all with the ID 1
+    async fn store() -> Arc<dyn ProtocolStore> {
+        let database: Arc<dyn ProtocolStore> = Arc::new(MemoryDatabase::new());
+        database.add_admin(ADMIN).await.unwrap();
+        create_catalog(database.as_ref()).await;
+        database
+    }
+
+    macro_rules! app {
+        ($database:expr, $configuration:expr) => {
+            test::init_service(App::new()
+                .app_data(web::Data::new($database.clone()))
+                .app_data(web::Data::new($configuration.clone()))
+                .app_data(web::JsonConfig::default().error_handler(|err, _| ApiError::InvalidInput(err.to_string()).into()))
+                .service(save_protocol)
+                .service(edit_protocol)
+                .service(list_revisions)
+                .service(delete_protocol)
+                .service(list_admins)
+                .service(list_sessions)
+                .service(revoke_sessions)).await
+        };
+    }
+
+    fn protocol(stex_id: i64) -> Value {
+        json!({"examiner_subject_ids": [[1, 1]], "stex_id": stex_id, "season_id": 1, "year": 2023, "text": "Gefragt wurde nach dem Plexus brachialis"})
+    }
+
+    #[actix_web::test]
+    async fn save_needs_token() {
+        let (database, configuration) = (store().await, Configuration::default());
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::post().uri("/api/admin/v1/save").set_json(protocol(1)).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "unauthenticated");
+    }
+
+    #[actix_web::test]
+    async fn save_needs_admin() {
+        let (database, configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, "user@example.org", &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::post().uri("/api/admin/v1/save").insert_header(("Authorization", format!("Bearer {}", token))).set_json(protocol(1)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn token_signed_with_other_secret_is_rejected() {
+        let (database, configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, ADMIN, "another secret").await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::post().uri("/api/admin/v1/save").insert_header(("Authorization", format!("Bearer {}", token))).set_json(protocol(1)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn save_rejects_unknown_entity_and_malformed_json() {
+        let (database, configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, ADMIN, &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::post().uri("/api/admin/v1/save").insert_header(("Authorization", format!("Bearer {}", token))).set_json(protocol(9)).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "invalid_input");
+        assert_eq!(body["message"], "There is no stex with the ID 9");
+
+        let request = test::TestRequest::post().uri("/api/admin/v1/save").insert_header(("Authorization", format!("Bearer {}", token))).set_json(json!({"text": "Nur Text"})).to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "invalid_input");
+    }
+
+    #[actix_web::test]
+    async fn save_edit_and_delete_protocol() {
+        let (database, configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, ADMIN, &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+        let authorization = ("Authorization", format!("Bearer {}", token));
+
+        let request = test::TestRequest::post().uri("/api/admin/v1/save").insert_header(authorization.clone()).set_json(protocol(1)).to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        let uuid = body["protocol_uuid"].as_str().unwrap().to_string();
+
+        let request = test::TestRequest::put().uri(&format!("/api/admin/v1/protocol/{}", uuid)).insert_header(authorization.clone()).set_json(json!({"year": 2024})).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+        let request = test::TestRequest::get().uri(&format!("/api/admin/v1/protocol/{}/revisions", uuid)).insert_header(authorization.clone()).to_request();
+        let revisions: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(revisions.as_array().unwrap().len(), 2);
+        assert_eq!(revisions[1]["year"], 2024);
+        assert_eq!(revisions[1]["author"], ADMIN);
+
+        let request = test::TestRequest::delete().uri(&format!("/api/admin/v1/protocol/{}", uuid)).insert_header(authorization.clone()).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+        let request = test::TestRequest::delete().uri(&format!("/api/admin/v1/protocol/{}", uuid)).insert_header(authorization).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn admins_are_listed_as_json() {
+        let (database, configuration) = (store().await, Configuration::default());
+        for email in ["josé.o'neill@universität.de", "\"quoted\"@example.org"] {
+            assert!(database.add_admin(email).await.unwrap());
+        }
+        let token = test_token(&database, ADMIN, &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::get().uri("/api/admin/v1/getadmins").insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body, json!({"admins": [ADMIN, "josé.o'neill@universität.de", "\"quoted\"@example.org"]}));
+    }
+
+    #[actix_web::test]
+    async fn sessions_of_one_email_are_listed_and_revoked() {
+        let (database, configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, ADMIN, &configuration.encryption.token_encryption_secret).await;
+        for _ in 0..2 {
+            test_token(&database, "user@example.org", &configuration.encryption.token_encryption_secret).await;
+        }
+        test_token(&database, "other@example.org", &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+        let authorization = ("Authorization", format!("Bearer {}", token));
+
+        let request = test::TestRequest::get().uri("/api/admin/v1/sessions/user@example.org").insert_header(authorization.clone()).to_request();
+        let sessions: Value = test::call_and_read_body_json(&app, request).await;
+        let sessions = sessions.as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0]["expires"].as_i64().unwrap() - sessions[0]["created"].as_i64().unwrap(), crate::TOKEN_VALID_LENGTH as i64);
+
+        let request = test::TestRequest::delete().uri("/api/admin/v1/sessions/user@example.org").insert_header(authorization.clone()).to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body, json!({"revoked": 2}));
+
+        assert!(database.get_sessions("user@example.org").await.unwrap().is_empty());
+        assert_eq!(database.get_sessions("other@example.org").await.unwrap().len(), 1);
+        assert_eq!(database.get_sessions(ADMIN).await.unwrap().len(), 1);
+
+        let request = test::TestRequest::delete().uri("/api/admin/v1/sessions/no-email").insert_header(authorization).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn sessions_are_only_revoked_by_admins() {
+        let (database, configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, "user@example.org", &configuration.encryption.token_encryption_secret).await;
+        test_token(&database, "other@example.org", &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::delete().uri("/api/admin/v1/sessions/other@example.org").insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::FORBIDDEN);
+
+        let request = test::TestRequest::get().uri("/api/admin/v1/sessions/other@example.org").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(database.get_sessions("other@example.org").await.unwrap().len(), 1);
+    }
 }
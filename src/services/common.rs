@@ -1,39 +1,63 @@
 use std::{collections::BTreeMap, sync::Arc};
 
-use actix_web::web;
+use actix_web::{web, HttpRequest};
 use hmac::{Hmac, Mac};
 use jwt::VerifyWithKey;
 use sha2::Sha256;
-use tokio::sync::Mutex;
 
-use crate::storage::database::{get_current_time_seconds, Database};
+use crate::storage::{database::get_current_time_seconds, protocol_store::ProtocolStore};
 
-pub async fn authenticate(token: &str, data: web::Data<Arc<Mutex<Database>>>, token_secret: String) -> Result<(bool, Option<String>), String> { // authenticated, email
+use super::error::ApiError;
+
+/// Holds the session token after a login with `token_delivery = "cookie"`
+pub const SESSION_COOKIE: &str = "protocoldb_session";
+
+/// The session token of a request, from the Authorization header or else from the session cookie
+pub fn request_token(request: &HttpRequest) -> Result<Option<String>, ApiError> {
+    match request.headers().get("Authorization") {
+        Some(header) => {
+            match header.to_str() {
+                Ok(header) => Ok(Some(header.replace("Bearer ", ""))),
+                Err(err) => Err(ApiError::Unauthenticated(format!("Unreadable Authentication Header!: {}", err))),
+            }
+        },
+        None => Ok(request.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_string())),
+    }
+}
+
+/// The claims of a token signed by us. Neither the expiry nor the session are checked
+pub fn verified_claims(token: &str, token_secret: &str) -> Result<BTreeMap<String, String>, ApiError> {
     let token_key: Hmac<Sha256> = match Hmac::new_from_slice(token_secret.as_bytes()) {
         Ok(token) => token,
         Err(err) => {
-            return Err(format!("Failed to create HMAC!: {:?}", err));
+            return Err(ApiError::Internal(format!("Failed to create HMAC!: {:?}", err)));
         },
     };
 
-    let claims: BTreeMap<String, String> = match token.verify_with_key(&token_key) {
-        Ok(claims) => claims,
+    match token.verify_with_key(&token_key) {
+        Ok(claims) => Ok(claims),
         Err(err) => {
-            return Err(format!("Authentication Failed: {:?}", err));
+            println!("Authentication Failed: {:?}", err);
+            Err(ApiError::Unauthenticated("Invalid Credentials".to_string()))
         },
-    };
+    }
+}
+
+pub async fn authenticate(token: &str, data: web::Data<Arc<dyn ProtocolStore>>, token_secret: String) -> Result<(bool, Option<String>), ApiError> { // authenticated, email
+    let claims = verified_claims(token, &token_secret)?;
 
     let expiry_time = match claims.get("exp") {
         Some(time) => {
             match time.parse::<u64>() {
                 Ok(result) => result,
                 Err(err) => {
-                    return Err(format!("Was presented with malformed Token: {:?}", err));
+                    println!("Was presented with malformed Token: {:?}", err);
+                    return Err(ApiError::Unauthenticated("Malformed Token".to_string()));
                 },
             }
         },
         None => {
-            return Err("Was presented with malformed Token!".to_string());
+            return Err(ApiError::Unauthenticated("Malformed Token".to_string()));
         },
     };
 
@@ -41,14 +65,13 @@ pub async fn authenticate(token: &str, data: web::Data<Arc<Mutex<Database>>>, to
     //Database
     if get_current_time_seconds() > expiry_time {
         tokio::spawn(async move {
-            let mut database = data.lock().await;
-            match database.remove_expired_sessions() {
+            let database = data.get_ref();
+            match database.remove_expired_sessions().await {
                 Ok(_) => {},
                 Err(err) => {
                     println!("Failed to remove expired Sessions!: {:?}", err); 
                 },
             }
-            drop(database);
         });
         return Ok((false, None));
     }
@@ -56,24 +79,24 @@ pub async fn authenticate(token: &str, data: web::Data<Arc<Mutex<Database>>>, to
     let uuid = match claims.get("sessionid") {
         Some(id) => id,
         None => {
-            return Err("Malformed Token!".to_string());
+            return Err(ApiError::Unauthenticated("Malformed Token".to_string()));
         },
     };
 
     let mail = match claims.get("sub") {
         Some(id) => id,
         None => {
-            return Err("Malformed Token!".to_string());
+            return Err(ApiError::Unauthenticated("Malformed Token".to_string()));
         },
     };
 
 
-    let mut database = data.lock().await;
+    let database = data.get_ref();
 
-    let valid = match database.is_session_valid(uuid) {
+    let valid = match database.is_session_valid(uuid).await {
         Ok(valid) => valid,
         Err(err) => {
-            return Err(format!("Failed to fetch Mail from UUID: {:?}", err));
+            return Err(ApiError::Internal(format!("Failed to fetch Mail from UUID: {:?}", err)));
         },
     };
 
@@ -81,7 +104,7 @@ pub async fn authenticate(token: &str, data: web::Data<Arc<Mutex<Database>>>, to
 
 }
 
-pub async fn authenticate_admin(token: &str, data: web::Data<Arc<Mutex<Database>>>, token_secret: String) -> Result<(bool, Option<String>), String> {
+pub async fn authenticate_admin(token: &str, data: web::Data<Arc<dyn ProtocolStore>>, token_secret: String) -> Result<(bool, Option<String>), ApiError> {
     let auth = match authenticate(token, data.clone(), token_secret).await {
         Ok(auth) => auth,
         Err(err) => return Err(err),
@@ -94,84 +117,102 @@ pub async fn authenticate_admin(token: &str, data: web::Data<Arc<Mutex<Database>
     let mail = match auth.1 {
         Some(mail) => mail,
         None => {
-            return Err("No Mail in validated Request!".to_string());
+            return Err(ApiError::Internal("No Mail in validated Request!".to_string()));
         },
     };
 
-    let mut database = data.lock().await;
+    let database = data.get_ref();
 
-    match database.check_if_user_admin(&mail) {
+    match database.check_if_user_admin(&mail).await {
         Ok(admin) => {
             Ok((admin, Some(mail)))
         },
         Err(err) => {
-            Err(format!("Failed to get Admin Status from Database!: {:?}", err))
+            Err(ApiError::Internal(format!("Failed to get Admin Status from Database!: {:?}", err)))
         },
     }
 }
 
+/// Returns an error from the handler unless the request carries a valid session
 #[macro_export]
 macro_rules! authenticate {
     ($request:expr, $data:expr, $encryption_secret:expr) => {
-        let auth_header = match $request.headers().get("Authorization") {
-            Some(header) => header,
-            None => {
-                invalid_input!("Missing Authentication Header!");
+        let token = match $crate::services::common::request_token(&$request) {
+            Ok(Some(token)) => token,
+            Ok(None) => {
+                return Err($crate::services::error::ApiError::Unauthenticated("Missing Authentication Header!".to_string()));
             },
-        };
-
-        let mut token = match auth_header.to_str() {
-            Ok(header) => header.to_string(),
             Err(err) => {
-                invalid_input!(&format!("Missing Authentication Header!: {:?}", err));
+                return Err(err);
             },
         };
 
-        token = token.replace("Bearer ", "");
-
         match authenticate(&token, $data.clone(), $encryption_secret).await {
             Ok((valid, _)) => {
                 if !valid {
-                    return HttpResponse::Forbidden().content_type(ContentType::json()).body("{\"error\":\"Invalid Credentials\"}");
+                    return Err($crate::services::error::ApiError::Unauthenticated("Invalid Credentials".to_string()));
                 }
             },
             Err(err) => {
-                invalid_input!(&format!("Failed to Authenticate!: {:?}", err));
+                return Err(err);
             },
         }
     };
 }
 
-
-
+/// Evaluates to the email of the admin, so handlers can record who made a change.
+/// A valid session of someone who isn't an admin is forbidden, not unauthenticated
 #[macro_export]
 macro_rules! authenticate_admin {
-    ($request:expr, $data:expr, $encryption_secret:expr) => {
-        let auth_header = match $request.headers().get("Authorization") {
-            Some(header) => header,
-            None => {
-                invalid_input!("Missing Authentication Header!");
+    ($request:expr, $data:expr, $encryption_secret:expr) => {{
+        let token = match $crate::services::common::request_token(&$request) {
+            Ok(Some(token)) => token,
+            Ok(None) => {
+                return Err($crate::services::error::ApiError::Unauthenticated("Missing Authentication Header!".to_string()));
             },
-        };
-
-        let mut token = match auth_header.to_str() {
-            Ok(header) => header.to_string(),
             Err(err) => {
-                invalid_input!(&format!("Missing Authentication Header!: {:?}", err));
+                return Err(err);
             },
         };
 
-        token = token.replace("Bearer ", "");
-
         match authenticate_admin(&token, $data.clone(), $encryption_secret).await {
-            Ok((valid, _)) => {
-                if !valid {
-                    return HttpResponse::Forbidden().content_type(ContentType::json()).body("{\"error\":\"Invalid Credentials\"}");
+            Ok((valid, mail)) => {
+                match (valid, mail) {
+                    (true, Some(mail)) => mail,
+                    (false, Some(_)) => {
+                        return Err($crate::services::error::ApiError::Forbidden("Admin rights required".to_string()));
+                    },
+                    _ => {
+                        return Err($crate::services::error::ApiError::Unauthenticated("Invalid Credentials".to_string()));
+                    },
                 }
             },
             Err(err) => {
-                invalid_input!(&format!("Failed to Authenticate!: {:?}", err));
+                return Err(err);
             },
         }
-    };
+    }};
+}
+
+/// Session tokens for the handler tests, signed like the ones handed out after a login
+#[cfg(test)]
+pub async fn test_token(database: &Arc<dyn ProtocolStore>, email: &str, token_secret: &str) -> String {
+    test_token_expiring_at(database, email, token_secret, get_current_time_seconds() + crate::TOKEN_VALID_LENGTH).await
+}
+
+/// Like test_token, the expiry may also lie in the past
+#[cfg(test)]
+pub async fn test_token_expiring_at(database: &Arc<dyn ProtocolStore>, email: &str, token_secret: &str, expiry: u64) -> String {
+    use jwt::SignWithKey;
+
+    let session_id = database.save_access_token(email).await.unwrap().unwrap();
+
+    let mut claims = BTreeMap::new();
+    claims.insert("sub", email.to_string());
+    claims.insert("iss", "ProtocolDB".to_string());
+    claims.insert("exp", format!("{}", expiry));
+    claims.insert("sessionid", session_id);
+
+    let token_key: Hmac<Sha256> = Hmac::new_from_slice(token_secret.as_bytes()).unwrap();
+    claims.sign_with_key(&token_key).unwrap()
 }
@@ -0,0 +1,130 @@
+use crate::structs::get_outputs::{DiffLine, DiffOperation};
+
+/// Above this many line comparisons the changed block is shown as removed and re-added as a whole,
+/// so diffing two huge texts can't stall the server
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Line based diff of two protocol texts, the lines come in the order of the new text
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    // Usually only a few lines change, the common start and end need no comparison table
+    let prefix = old_lines.iter().zip(&new_lines).take_while(|(a, b)| a == b).count();
+    let suffix = old_lines[prefix..].iter().rev().zip(new_lines[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+
+    let old_middle = &old_lines[prefix..old_lines.len() - suffix];
+    let new_middle = &new_lines[prefix..new_lines.len() - suffix];
+
+    let mut lines: Vec<DiffLine> = old_lines[..prefix].iter().map(|line| diff_line(DiffOperation::Equal, line)).collect();
+
+    if old_middle.len() * new_middle.len() > MAX_DIFF_CELLS {
+        lines.extend(old_middle.iter().map(|line| diff_line(DiffOperation::Delete, line)));
+        lines.extend(new_middle.iter().map(|line| diff_line(DiffOperation::Insert, line)));
+    } else {
+        lines.extend(longest_common_subsequence_diff(old_middle, new_middle));
+    }
+
+    lines.extend(old_lines[old_lines.len() - suffix..].iter().map(|line| diff_line(DiffOperation::Equal, line)));
+
+    lines
+}
+
+fn longest_common_subsequence_diff(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    // lengths[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = match old[i] == new[j] {
+                true => lengths[i + 1][j + 1] + 1,
+                false => lengths[i + 1][j].max(lengths[i][j + 1]),
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(diff_line(DiffOperation::Equal, old[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            lines.push(diff_line(DiffOperation::Delete, old[i]));
+            i += 1;
+        } else {
+            lines.push(diff_line(DiffOperation::Insert, new[j]));
+            j += 1;
+        }
+    }
+
+    lines.extend(old[i..].iter().map(|line| diff_line(DiffOperation::Delete, line)));
+    lines.extend(new[j..].iter().map(|line| diff_line(DiffOperation::Insert, line)));
+
+    lines
+}
+
+fn diff_line(operation: DiffOperation, text: &str) -> DiffLine {
+    DiffLine { operation, text: text.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operations(lines: &[DiffLine]) -> Vec<(DiffOperation, &str)> {
+        lines.iter().map(|line| (line.operation, line.text.as_str())).collect()
+    }
+
+    #[test]
+    fn equal_texts_have_only_equal_lines() {
+        let lines = diff_lines("a\nb", "a\nb");
+        assert_eq!(operations(&lines), vec![(DiffOperation::Equal, "a"), (DiffOperation::Equal, "b")]);
+        assert!(diff_lines("", "").is_empty());
+    }
+
+    #[test]
+    fn changed_line_is_deleted_and_inserted() {
+        let lines = diff_lines("a\nb\nc", "a\nB\nc\nd");
+        assert_eq!(operations(&lines), vec![
+            (DiffOperation::Equal, "a"),
+            (DiffOperation::Delete, "b"),
+            (DiffOperation::Insert, "B"),
+            (DiffOperation::Equal, "c"),
+            (DiffOperation::Insert, "d"),
+        ]);
+    }
+
+    #[test]
+    fn keeps_longest_common_subsequence() {
+        let lines = diff_lines("x\na\nb\nc\ny", "x\nb\nc\na\ny");
+        assert_eq!(operations(&lines), vec![
+            (DiffOperation::Equal, "x"),
+            (DiffOperation::Delete, "a"),
+            (DiffOperation::Equal, "b"),
+            (DiffOperation::Equal, "c"),
+            (DiffOperation::Insert, "a"),
+            (DiffOperation::Equal, "y"),
+        ]);
+    }
+
+    #[test]
+    fn everything_removed_or_added() {
+        assert_eq!(operations(&diff_lines("a\nb", "")), vec![(DiffOperation::Delete, "a"), (DiffOperation::Delete, "b")]);
+        assert_eq!(operations(&diff_lines("", "a")), vec![(DiffOperation::Insert, "a")]);
+    }
+
+    #[test]
+    fn huge_changes_are_replaced_as_a_block() {
+        let old: String = (0..3000).map(|line| format!("alt {}\n", line)).collect();
+        let new: String = (0..3000).map(|line| format!("neu {}\n", line)).collect();
+
+        let lines = diff_lines(&format!("Anfang\n{}Ende", old), &format!("Anfang\n{}Ende", new));
+        assert_eq!(lines.len(), 6002);
+        assert_eq!(operations(&lines[..2]), vec![(DiffOperation::Equal, "Anfang"), (DiffOperation::Delete, "alt 0")]);
+        assert_eq!(operations(&lines[3000..3002]), vec![(DiffOperation::Delete, "alt 2999"), (DiffOperation::Insert, "neu 0")]);
+        assert_eq!(operations(&lines[6001..]), vec![(DiffOperation::Equal, "Ende")]);
+    }
+}
@@ -0,0 +1,272 @@
+use std::{sync::{Arc, RwLock}, time::Duration};
+
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+
+use crate::structs::configuration::{Authorization, ClientAuthMethod};
+
+use super::jwks::{IdTokenClaims, KeyCache};
+
+/// How often the discovery document and the keys are fetched again, so endpoints the provider moved are picked up without a restart
+pub const DISCOVERY_REFRESH_SECONDS: u64 = 3600;
+
+/// The part of `/.well-known/openid-configuration` the login flow needs
+#[derive(Deserialize)]
+struct DiscoveryDocument {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    userinfo_endpoint: Option<String>,
+    revocation_endpoint: Option<String>,
+    end_session_endpoint: Option<String>
+}
+
+/// The endpoints the login flow talks to
+#[derive(Clone)]
+pub struct ProviderEndpoints {
+    /// As the provider writes it into its ID tokens
+    pub issuer: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub jwks_url: String,
+    /// Only asked for the email if the ID token doesn't contain it
+    pub userinfo_url: Option<String>,
+    /// Not every provider can revoke tokens, without this the access token simply expires
+    pub revoke_url: Option<String>,
+    /// For logging out at the provider, see RP-Initiated Logout
+    pub end_session_url: Option<String>
+}
+
+/// The identity provider behind `Authorization::OpenIdConnect`. The endpoints are discovered from the `issuer`
+/// and refreshed every DISCOVERY_REFRESH_SECONDS, endpoints in the config always take precedence
+pub struct OidcProvider {
+    client: Client,
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    client_auth_method: ClientAuthMethod,
+    configured: ConfiguredEndpoints,
+    endpoints: RwLock<ProviderEndpoints>,
+    keys: KeyCache
+}
+
+/// The endpoints as they are written in the config
+struct ConfiguredEndpoints {
+    auth_url: Option<String>,
+    token_url: Option<String>,
+    jwks_url: Option<String>,
+    userinfo_url: Option<String>,
+    revoke_url: Option<String>,
+    end_session_url: Option<String>
+}
+
+impl OidcProvider {
+    /// Fetches the discovery document and the keys once, the API doesn't start without them.
+    /// None if the API runs without OpenIDConnect
+    pub async fn from_configuration(authorization: &Authorization) -> Result<Option<Arc<OidcProvider>>, String> {
+        let (issuer, client_id, client_secret, client_auth_method, configured) = match authorization {
+            Authorization::OpenIdConnect { issuer, client_id, client_secret, client_auth_method, auth_url, token_url, jwks_url, userinfo_url, revoke_url, end_session_url, .. } => {
+                (issuer.clone(), client_id.clone(), client_secret.clone(), *client_auth_method, ConfiguredEndpoints { auth_url: auth_url.clone(), token_url: token_url.clone(), jwks_url: jwks_url.clone(), userinfo_url: userinfo_url.clone(), revoke_url: revoke_url.clone(), end_session_url: end_session_url.clone() })
+            },
+            Authorization::None => return Ok(None),
+        };
+
+        let client = match Client::builder().timeout(Duration::from_secs(10)).build() {
+            Ok(client) => client,
+            Err(err) => return Err(format!("Failed to create HTTP-Client!: {}", err)),
+        };
+
+        let document = fetch_discovery_document(&client, &issuer).await?;
+        let endpoints = configured.merge(&document);
+
+        let keys = KeyCache::default();
+        keys.refresh(&client, &endpoints.jwks_url).await?;
+
+        Ok(Some(Arc::new(OidcProvider { client, issuer, client_id, client_secret, client_auth_method, configured, endpoints: RwLock::new(endpoints), keys })))
+    }
+
+    pub fn endpoints(&self) -> ProviderEndpoints {
+        match self.endpoints.read() {
+            Ok(endpoints) => endpoints.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Fetches the discovery document and the keys again. If that fails everything stays as it is
+    pub async fn refresh(&self) -> Result<(), String> {
+        let document = fetch_discovery_document(&self.client, &self.issuer).await?;
+        let endpoints = self.configured.merge(&document);
+
+        self.keys.refresh(&self.client, &endpoints.jwks_url).await?;
+
+        match self.endpoints.write() {
+            Ok(mut current) => *current = endpoints,
+            Err(poisoned) => *poisoned.into_inner() = endpoints,
+        }
+
+        Ok(())
+    }
+
+    /// Runs until the API stops
+    pub async fn keep_fresh(provider: Arc<OidcProvider>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(DISCOVERY_REFRESH_SECONDS));
+        // The first tick fires right away, but everything was just fetched on startup
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = provider.refresh().await {
+                println!("Failed to refresh the OpenIDConnect discovery, keeping the known endpoints!: {}", err);
+            }
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// A form POST to the token or revocation endpoint, with the client ID and, for confidential clients, the secret.
+    /// Basic authentication form-encodes both first, as RFC 6749 wants it
+    pub fn client_request(&self, url: &str, mut form: Vec<(String, String)>) -> RequestBuilder {
+        form.push(("client_id".to_string(), self.client_id.clone()));
+
+        let request = self.client.post(url);
+
+        match (&self.client_secret, self.client_auth_method) {
+            (Some(secret), ClientAuthMethod::ClientSecretBasic) => {
+                request.basic_auth(form_encode(&self.client_id), Some(form_encode(secret))).form(&form)
+            },
+            (Some(secret), ClientAuthMethod::ClientSecretPost) => {
+                form.push(("client_secret".to_string(), secret.clone()));
+                request.form(&form)
+            },
+            (None, _) => request.form(&form),
+        }
+    }
+
+    /// Checks that the ID token was signed by the provider for us and hasn't expired yet
+    pub async fn verify_id_token(&self, id_token: &str) -> Result<IdTokenClaims, String> {
+        let endpoints = self.endpoints();
+        self.keys.verify(&self.client, &endpoints.jwks_url, id_token, &endpoints.issuer, &self.client_id).await
+    }
+}
+
+impl ConfiguredEndpoints {
+    fn merge(&self, document: &DiscoveryDocument) -> ProviderEndpoints {
+        ProviderEndpoints {
+            issuer: document.issuer.clone(),
+            auth_url: self.auth_url.clone().unwrap_or_else(|| document.authorization_endpoint.clone()),
+            token_url: self.token_url.clone().unwrap_or_else(|| document.token_endpoint.clone()),
+            jwks_url: self.jwks_url.clone().unwrap_or_else(|| document.jwks_uri.clone()),
+            userinfo_url: self.userinfo_url.clone().or_else(|| document.userinfo_endpoint.clone()),
+            revoke_url: self.revoke_url.clone().or_else(|| document.revocation_endpoint.clone()),
+            end_session_url: self.end_session_url.clone().or_else(|| document.end_session_endpoint.clone()),
+        }
+    }
+}
+
+fn form_encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// The issuer in the document has to be the configured one, otherwise it's not the provider we were told to trust
+async fn fetch_discovery_document(client: &Client, issuer: &str) -> Result<DiscoveryDocument, String> {
+    let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+
+    let response = match client.get(&url).send().await {
+        Ok(response) => response,
+        Err(err) => return Err(format!("Failed to fetch {}!: {}", url, err)),
+    };
+
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch {}!: Status {}", url, response.status()));
+    }
+
+    let document = match response.json::<DiscoveryDocument>().await {
+        Ok(document) => document,
+        Err(err) => return Err(format!("Failed to deserialize {}!: {}", url, err)),
+    };
+
+    if document.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+        return Err(format!("{} announces the issuer {:?} instead of {:?}", url, document.issuer, issuer));
+    }
+
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use crate::services::test_provider::TestProvider;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn discovers_endpoints_from_issuer() {
+        let test_provider = TestProvider::start();
+        let provider = OidcProvider::from_configuration(&test_provider.authorization()).await.unwrap().unwrap();
+
+        let endpoints = provider.endpoints();
+        assert_eq!(endpoints.issuer, test_provider.issuer);
+        assert_eq!(endpoints.auth_url, format!("{}/auth", test_provider.issuer));
+        assert_eq!(endpoints.token_url, format!("{}/token", test_provider.issuer));
+        assert_eq!(endpoints.jwks_url, format!("{}/certs", test_provider.issuer));
+        assert_eq!(endpoints.userinfo_url, Some(format!("{}/userinfo", test_provider.issuer)));
+        assert_eq!(endpoints.revoke_url, None);
+        assert_eq!(endpoints.end_session_url, Some(format!("{}/logout", test_provider.issuer)));
+        assert_eq!(test_provider.state.jwks_fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn configured_endpoints_take_precedence() {
+        let test_provider = TestProvider::start();
+
+        let mut authorization = test_provider.authorization();
+        if let Authorization::OpenIdConnect { token_url, revoke_url, .. } = &mut authorization {
+            *token_url = Some("https://idp.example.org/token".to_string());
+            *revoke_url = Some("https://idp.example.org/revoke".to_string());
+        }
+
+        let endpoints = OidcProvider::from_configuration(&authorization).await.unwrap().unwrap().endpoints();
+        assert_eq!(endpoints.token_url, "https://idp.example.org/token");
+        assert_eq!(endpoints.revoke_url.as_deref(), Some("https://idp.example.org/revoke"));
+        assert_eq!(endpoints.auth_url, format!("{}/auth", test_provider.issuer));
+    }
+
+    #[actix_web::test]
+    async fn refresh_picks_up_moved_endpoints() {
+        let test_provider = TestProvider::start();
+        let provider = OidcProvider::from_configuration(&test_provider.authorization()).await.unwrap().unwrap();
+
+        test_provider.state.moved.store(true, Ordering::SeqCst);
+        provider.refresh().await.unwrap();
+
+        assert_eq!(provider.endpoints().token_url, format!("{}/token2", test_provider.issuer));
+        assert_eq!(test_provider.state.discovery_fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn rejects_document_of_other_issuer() {
+        let test_provider = TestProvider::start();
+
+        // The trailing slash doesn't make it another issuer
+        let mut authorization = test_provider.authorization();
+        if let Authorization::OpenIdConnect { issuer, .. } = &mut authorization {
+            *issuer = format!("{}/", issuer);
+        }
+        assert!(OidcProvider::from_configuration(&authorization).await.is_ok());
+
+        test_provider.state.foreign_issuer.store(true, Ordering::SeqCst);
+        let err = OidcProvider::from_configuration(&authorization).await.err().unwrap();
+        assert!(err.contains("announces the issuer"), "{}", err);
+    }
+
+    #[test]
+    fn form_encodes_client_credentials() {
+        assert_eq!(form_encode("client id"), "client+id");
+        assert_eq!(form_encode("p@ss:w/rd&"), "p%40ss%3Aw%2Frd%26");
+    }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+
+/// Everything a handler can fail with. Clients always get `{"code": ..., "message": ...}`,
+/// the codes stay the same between releases so frontends can match on them.
+/// Internal failures only tell the client that something went wrong, the details go to the log
+#[derive(Debug)]
+pub enum ApiError {
+    /// Something about the request is wrong, the message says what
+    InvalidInput(String),
+    /// No or no valid credentials
+    Unauthenticated(String),
+    /// Valid credentials without the rights for the request
+    Forbidden(String),
+    NotFound(String),
+    /// The request clashes with the current state, e.g. a name that is already taken
+    Conflict(String),
+    /// The detail is logged, never sent
+    Internal(String)
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidInput(_) => "invalid_input",
+            ApiError::Unauthenticated(_) => "unauthenticated",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidInput(message) |
+            ApiError::Unauthenticated(message) |
+            ApiError::Forbidden(message) |
+            ApiError::NotFound(message) |
+            ApiError::Conflict(message) => write!(f, "{}", message),
+            ApiError::Internal(_) => write!(f, "Something went wrong on our side"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let ApiError::Internal(detail) = self {
+            println!("{}", detail);
+        }
+
+        HttpResponse::build(self.status_code()).json(ErrorBody { code: self.code(), message: self.to_string() })
+    }
+}
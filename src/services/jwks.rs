@@ -0,0 +1,214 @@
+use std::sync::RwLock;
+
+use jsonwebtoken::{decode, decode_header, jwk::{Jwk, PublicKeyUse}, Algorithm, DecodingKey, Validation};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::storage::database::get_current_time_seconds;
+
+/// A token signed with an unknown key makes us fetch the JWKS again, but at most this often.
+/// Otherwise anyone could make us hammer the provider with made up key IDs
+const MIN_REFETCH_SECONDS: u64 = 60;
+
+/// How far the clocks of the provider and the API may drift apart
+const CLOCK_SKEW_SECONDS: u64 = 60;
+
+/// The claims of an ID token the login needs, the ones jsonwebtoken checks itself are left out
+#[derive(Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>
+}
+
+/// The JSON Web Key Set of the provider. Keys are kept until the next fetch, which happens periodically
+/// and whenever a token names a key that isn't known yet, so a rotated key is picked up right away
+#[derive(Default)]
+pub struct KeyCache {
+    keys: RwLock<Vec<Jwk>>,
+    /// When the keys were fetched last, held during a fetch so concurrent logins wait for one fetch
+    last_fetch: Mutex<u64>
+}
+
+#[derive(Deserialize)]
+struct JwkSetDocument {
+    keys: Vec<serde_json::Value>
+}
+
+impl KeyCache {
+    pub async fn refresh(&self, client: &Client, jwks_url: &str) -> Result<(), String> {
+        let mut last_fetch = self.last_fetch.lock().await;
+        self.fetch(client, jwks_url).await?;
+        *last_fetch = get_current_time_seconds();
+        Ok(())
+    }
+
+    /// Checks the signature, `iss`, `aud` and `exp` of the token, the nonce is left to the caller
+    pub async fn verify(&self, client: &Client, jwks_url: &str, token: &str, issuer: &str, client_id: &str) -> Result<IdTokenClaims, String> {
+        let header = match decode_header(token) {
+            Ok(header) => header,
+            Err(err) => return Err(format!("Malformed ID token!: {}", err)),
+        };
+
+        // HMAC would need the client secret as key, and "none" isn't accepted by jsonwebtoken anyway
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(format!("ID token is signed with {:?}, only asymmetric signatures are accepted", header.alg));
+        }
+
+        let mut candidates = self.candidates(header.kid.as_deref(), header.alg);
+
+        if candidates.is_empty() {
+            let mut last_fetch = self.last_fetch.lock().await;
+
+            // Someone else may have fetched the keys while we were waiting for the lock
+            candidates = self.candidates(header.kid.as_deref(), header.alg);
+
+            if candidates.is_empty() && get_current_time_seconds() >= *last_fetch + MIN_REFETCH_SECONDS {
+                println!("ID token is signed with the unknown key {:?}, fetching the JWKS again", header.kid);
+                self.fetch(client, jwks_url).await?;
+                *last_fetch = get_current_time_seconds();
+                candidates = self.candidates(header.kid.as_deref(), header.alg);
+            }
+        }
+
+        if candidates.is_empty() {
+            return Err(format!("The provider has no {:?} key with the ID {:?}", header.alg, header.kid));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[issuer]);
+        validation.set_audience(&[client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = CLOCK_SKEW_SECONDS;
+
+        let mut last_error = String::new();
+
+        for key in candidates {
+            match decode::<IdTokenClaims>(token, &key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(err) => last_error = err.to_string(),
+            }
+        }
+
+        Err(format!("Invalid ID token!: {}", last_error))
+    }
+
+    /// The signing keys the token could have been signed with. Without a key ID every key of the algorithm is tried
+    fn candidates(&self, kid: Option<&str>, algorithm: Algorithm) -> Vec<DecodingKey> {
+        let keys = match self.keys.read() {
+            Ok(keys) => keys,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        keys.iter()
+            .filter(|key| kid.is_none() || key.common.key_id.as_deref() == kid)
+            .filter(|key| key.common.public_key_use.as_ref().is_none_or(|key_use| *key_use == PublicKeyUse::Signature))
+            .filter(|key| key.common.key_algorithm.is_none_or(|key_algorithm| key_algorithm.to_string() == format!("{:?}", algorithm)))
+            .filter_map(|key| DecodingKey::from_jwk(key).ok())
+            .collect()
+    }
+
+    async fn fetch(&self, client: &Client, jwks_url: &str) -> Result<(), String> {
+        let response = match client.get(jwks_url).send().await {
+            Ok(response) => response,
+            Err(err) => return Err(format!("Failed to fetch {}!: {}", jwks_url, err)),
+        };
+
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch {}!: Status {}", jwks_url, response.status()));
+        }
+
+        let document = match response.json::<JwkSetDocument>().await {
+            Ok(document) => document,
+            Err(err) => return Err(format!("Failed to deserialize {}!: {}", jwks_url, err)),
+        };
+
+        // Providers also publish encryption keys or key types jsonwebtoken doesn't know, those are skipped
+        let keys: Vec<Jwk> = document.keys.into_iter()
+            .filter_map(|key| serde_json::from_value::<Jwk>(key).ok())
+            .collect();
+
+        if keys.is_empty() {
+            return Err(format!("{} contains no usable keys", jwks_url));
+        }
+
+        match self.keys.write() {
+            Ok(mut current) => *current = keys,
+            Err(poisoned) => *poisoned.into_inner() = keys,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    use crate::services::test_provider::{sign_id_token, TestProvider, CLIENT_ID, EMAIL};
+
+    use super::*;
+
+    async fn verify(test_provider: &TestProvider, keys: &KeyCache, token: &str) -> Result<IdTokenClaims, String> {
+        keys.verify(&Client::new(), &format!("{}/certs", test_provider.issuer), token, &test_provider.issuer, CLIENT_ID).await
+    }
+
+    #[actix_web::test]
+    async fn accepts_token_of_provider() {
+        let test_provider = TestProvider::start();
+        let keys = KeyCache::default();
+
+        let claims = verify(&test_provider, &keys, &sign_id_token(&test_provider.claims("nonce"), "signing")).await.unwrap();
+        assert_eq!(claims.sub, "user-1");
+        assert_eq!(claims.nonce.as_deref(), Some("nonce"));
+        assert_eq!(claims.email.as_deref(), Some(EMAIL));
+        assert_eq!(claims.email_verified, Some(true));
+    }
+
+    #[actix_web::test]
+    async fn rejects_wrong_claims() {
+        let test_provider = TestProvider::start();
+        let keys = KeyCache::default();
+        keys.refresh(&Client::new(), &format!("{}/certs", test_provider.issuer)).await.unwrap();
+
+        for (claim, value) in [("aud", "another-client".into()), ("iss", "https://idp.example.org".into()), ("exp", (get_current_time_seconds() - CLOCK_SKEW_SECONDS - 10).into())] {
+            let mut claims = test_provider.claims("nonce");
+            claims[claim] = value;
+            assert!(verify(&test_provider, &keys, &sign_id_token(&claims, "signing")).await.is_err(), "{} wasn't checked", claim);
+        }
+
+        let mut token = sign_id_token(&test_provider.claims("nonce"), "signing");
+        token.push('A');
+        assert!(verify(&test_provider, &keys, &token).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn rejects_hmac_and_encryption_keys() {
+        let test_provider = TestProvider::start();
+        let keys = KeyCache::default();
+
+        let hmac_token = encode(&Header::default(), &test_provider.claims("nonce"), &EncodingKey::from_secret(b"client secret")).unwrap();
+        assert!(verify(&test_provider, &keys, &hmac_token).await.err().unwrap().contains("only asymmetric"));
+
+        // The provider publishes the same key for encryption, it mustn't be accepted for signatures
+        assert!(verify(&test_provider, &keys, &sign_id_token(&test_provider.claims("nonce"), "encryption")).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn unknown_key_fetches_keys_again_at_most_once_a_minute() {
+        let test_provider = TestProvider::start();
+        let keys = KeyCache::default();
+
+        // Nothing fetched yet, so the first unknown key leads to a fetch
+        assert!(verify(&test_provider, &keys, &sign_id_token(&test_provider.claims("nonce"), "signing")).await.is_ok());
+        assert_eq!(test_provider.state.jwks_fetches.load(Ordering::SeqCst), 1);
+
+        assert!(verify(&test_provider, &keys, &sign_id_token(&test_provider.claims("nonce"), "rotated")).await.is_err());
+        assert!(verify(&test_provider, &keys, &sign_id_token(&test_provider.claims("nonce"), "made-up")).await.is_err());
+        assert_eq!(test_provider.state.jwks_fetches.load(Ordering::SeqCst), 1);
+    }
+}
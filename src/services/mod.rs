@@ -3,3 +3,9 @@ pub mod admin;
 pub mod user;
 pub mod display;
 pub mod common;
+pub mod diff;
+pub mod error;
+pub mod discovery;
+pub mod jwks;
+#[cfg(test)]
+pub mod test_provider;
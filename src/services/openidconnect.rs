@@ -1,38 +1,88 @@
 
 use std::{collections::BTreeMap, sync::Arc};
 
-use actix_web::{cookie::{Cookie, CookieJar}, get, http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
+use actix_web::{cookie::{time::Duration, Cookie, SameSite}, get, http::header::{self, ContentType}, post, web, HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use hmac::{Hmac, Mac};
 use jwt::SignWithKey;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Deserialize;
-use sha2::Sha256;
-use tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-use crate::{expose_error, storage::database::{get_current_time_seconds, Database}, structs::configuration::{Authorization, Configuration}, TOKEN_VALID_LENGTH};
+use super::{common::{request_token, verified_claims, SESSION_COOKIE}, discovery::OidcProvider, error::ApiError};
 
+use crate::{storage::{database::get_current_time_seconds, protocol_store::ProtocolStore}, structs::{configuration::{Authorization, Configuration, TokenDelivery}, get_outputs::LogoutResponse}, TOKEN_VALID_LENGTH};
 
 
+
+/// RFC 7636 allows 43 to 128 characters
+const PKCE_VERIFIER_LENGTH: usize = 64;
+
+#[derive(Deserialize, Debug)]
+struct LoginParams {
+    return_to: Option<String>
+}
+
 #[get("/login")]
-pub async fn login(configuration: web::Data<Configuration>) -> impl Responder {
-    if let Authorization::OpenIdConnect {token_url: _, auth_url, revoke_url: _, userinfo_url: _, client_id, self_root_url } = &configuration.authorization {
+pub async fn login(query: web::Query<LoginParams>, configuration: web::Data<Configuration>, provider: web::Data<OidcProvider>) -> Result<HttpResponse, ApiError> {
+    if let Authorization::OpenIdConnect { client_id, self_root_url, return_urls, .. } = &configuration.authorization {
+
+        let return_to = match &query.return_to {
+            Some(return_to) => return_to.clone(),
+            None => done_url(self_root_url),
+        };
+
+        if !return_url_allowed(&return_to, return_urls, self_root_url) {
+            return Err(ApiError::InvalidInput(format!("{} isn't one of the configured return_urls", return_to)));
+        }
+
+        let auth_url = provider.endpoints().auth_url;
 
         let verification_uuid = Uuid::new_v4().to_string();
 
-        let cookie = Cookie::new("oidc_validation", verification_uuid.clone());
+        // Lax, because the browser comes back from the provider with a top-level navigation
+        let cookie = Cookie::build("oidc_validation", verification_uuid.clone()).path("/").http_only(true).same_site(SameSite::Lax).finish();
+
+        let code_verifier: String = thread_rng().sample_iter(&Alphanumeric).take(PKCE_VERIFIER_LENGTH).map(char::from).collect();
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
 
-        let assembled_redirect_url = format!("{}?response_type=code&scope=openid%20profile%20email&client_id={}&state={}&redirect_uri={}auth/openidconnect", auth_url, client_id, verification_uuid, self_root_url);
+        let pkce_cookie = Cookie::build("oidc_pkce", code_verifier).path("/").http_only(true).same_site(SameSite::Lax).finish();
+
+        // The provider hands the state back untouched, so the return URL travels with it
+        let state = format!("{}.{}", verification_uuid, URL_SAFE_NO_PAD.encode(return_to.as_bytes()));
+
+        let assembled_redirect_url = format!("{}?response_type=code&scope=openid%20profile%20email&client_id={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256&redirect_uri={}auth/openidconnect", auth_url, client_id, state, login_nonce(&verification_uuid), code_challenge, self_root_url);
 
         let client_redirect_html = format!("<html><head><meta http-equiv=\"refresh\" content=\"0; url='{}'\"></head><body></body></html>", assembled_redirect_url);
 
-        HttpResponse::Ok().content_type(ContentType::html()).cookie(cookie).body(client_redirect_html)
+        Ok(HttpResponse::Ok().content_type(ContentType::html()).cookie(cookie).cookie(pkce_cookie).body(client_redirect_html))
 
         //Redirect::to(assembled_redirect_url).temporary()
     } else {
-        HttpResponse::Ok().content_type(ContentType::html()).body("<html><h1>Invalid Authentication Configuration!</h1><html>")
+        Err(ApiError::Internal("Authorization isn't set to openidconnect!".to_string()))
     }
 }
 
+/// Where the browser ends up if `/login` was called without `return_to`
+fn done_url(self_root_url: &str) -> String {
+    format!("{}auth/openidconnect/done", self_root_url)
+}
+
+/// The URL has to be one of the `return_urls` or lie below an entry ending with a slash.
+/// A fragment is refused, the token is delivered in one
+fn return_url_allowed(url: &str, return_urls: &[String], self_root_url: &str) -> bool {
+    if url.contains('#') {
+        return false;
+    }
+
+    if url == done_url(self_root_url) {
+        return true;
+    }
+
+    return_urls.iter().any(|allowed| url == allowed || (allowed.ends_with('/') && url.starts_with(allowed.as_str())))
+}
+
 #[derive(Deserialize, Debug)]
 struct RedirectParams {
     code: String,
@@ -41,152 +91,199 @@ struct RedirectParams {
 
 #[derive(Deserialize, Debug)]
 struct TokenResponse {
-    access_token: String
+    access_token: String,
+    id_token: String
 }
 
 #[derive(Deserialize, Debug)]
 struct UserInfo {
-    email: String
+    sub: String,
+    email: String,
+    email_verified: Option<bool>
+}
+
+/// The nonce is derived from the secret in the `oidc_validation` cookie, so an ID token is only accepted
+/// by the browser that started the login. The cookie itself never leaves the browser and the API
+fn login_nonce(verification: &str) -> String {
+    hex::encode(Sha256::digest(format!("nonce:{}", verification).as_bytes()))
 }
 
 #[get("/auth/openidconnect")]
-pub async fn redirect(request: HttpRequest, query: web::Query<RedirectParams>, configuration: web::Data<Configuration>, data: web::Data<Arc<Mutex<Database>>>) -> impl Responder {
+pub async fn redirect(request: HttpRequest, query: web::Query<RedirectParams>, configuration: web::Data<Configuration>, provider: web::Data<OidcProvider>, data: web::Data<Arc<dyn ProtocolStore>>) -> Result<HttpResponse, ApiError> {
 
     let code = &query.code;
-    let state = &query.state; // Used to Verify Request Origin
-
-    let cookie_hdr = match request.headers().get("cookie") {
-        Some(cookie) =>  {
-            match cookie.to_str() {
-                Ok(cookie_hdr) => cookie_hdr,
-                Err(_) => {
-                    return HttpResponse::InternalServerError().body("{\"error\": \"failed to stringify Cookie-String!\"}");
-                },
-            }
-        },
+
+    // Used to Verify Request Origin, followed by the return URL
+    let (state, encoded_return_to) = match query.state.split_once('.') {
+        Some(parts) => parts,
         None => {
-            expose_error!("Failed to get Cookie set by /login Endpoint!. Invalid Request!");
+            return Err(ApiError::InvalidInput("Malformed state! Invalid Request!".to_string()));
         },
     };
 
-    let cookies: Vec<&str> = cookie_hdr.split('&').collect();
+    let verification = match request.cookie("oidc_validation") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return Err(ApiError::InvalidInput("Failed to get Cookie set by /login Endpoint!. Invalid Request!".to_string()));
+        },
+    };
 
-    let mut valid_cookie = false;
+    if !verification.eq(state) {
+        return Err(ApiError::InvalidInput("Failed to verify state! Invalid Request!".to_string()));
+    }
 
-    for cookie in cookies {
-        let cook_val = match Cookie::parse_encoded(cookie) {
-            Ok(cookie) => cookie,
-            Err(_err) => {
-                expose_error!("Failed to deserialize Cookie!");
-            },
-        };
+    // Only the browser that started the login knows the verifier, so an intercepted code is worthless
+    let code_verifier = match request.cookie("oidc_pkce") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return Err(ApiError::InvalidInput("Failed to get the PKCE-Cookie set by /login Endpoint!. Invalid Request!".to_string()));
+        },
+    };
 
-        if cook_val.name().eq("oidc_validation") && cook_val.value().eq(state) {
-            valid_cookie = true;
-        }
-    }
+    // The login can't be replayed with the same cookies
+    let mut used_cookie = Cookie::build("oidc_validation", "").path("/").finish();
+    used_cookie.make_removal();
+    let mut used_pkce_cookie = Cookie::build("oidc_pkce", "").path("/").finish();
+    used_pkce_cookie.make_removal();
 
-    if !valid_cookie {
-            expose_error!("Failed to verify state! Invalid Request!");
-    }
+    let client = provider.client();
 
-    let mut cookie_jar = CookieJar::new();
+    if let Authorization::OpenIdConnect { self_root_url, return_urls, token_delivery, .. } = &configuration.authorization {
 
-    cookie_jar.remove(Cookie::named("oidc_validation"));
+        // Checked again, the state came back through the browser
+        let return_to = match URL_SAFE_NO_PAD.decode(encoded_return_to).ok().and_then(|bytes| String::from_utf8(bytes).ok()) {
+            Some(return_to) => return_to,
+            None => {
+                return Err(ApiError::InvalidInput("Malformed state! Invalid Request!".to_string()));
+            },
+        };
 
-    let client = reqwest::Client::new();
+        if !return_url_allowed(&return_to, return_urls, self_root_url) {
+            return Err(ApiError::InvalidInput(format!("{} isn't one of the configured return_urls", return_to)));
+        }
 
-    if let Authorization::OpenIdConnect {token_url, auth_url:_, revoke_url, userinfo_url, client_id, self_root_url } = &configuration.authorization {
+        let endpoints = provider.endpoints();
 
         let mut map = vec![];
 
         map.push(("grant_type".to_string(), "authorization_code".to_string()));
         map.push(("code".to_string(), code.to_string()));
-        map.push(("client_id".to_string(), client_id.to_string()));
+        map.push(("code_verifier".to_string(), code_verifier));
         map.push(("redirect_uri".to_string(), format!("{}auth/openidconnect", self_root_url)));
 
                                                     
-        let response = match client.post(token_url.clone()).form(&map).send().await {
+        let response = match provider.client_request(&endpoints.token_url, map).send().await {
             Ok(response) => response, 
             Err(err) => {
-                expose_error!(&format!("{:?}", err));
+                return Err(ApiError::Internal(format!("{:?}", err)));
             }
         };
 
+        let status = response.status();
+
         let response_bytes = match response.bytes().await {
             Ok(bytes) => bytes,
             Err(err) => {
-                expose_error!(&format!("{:?}", err));
+                return Err(ApiError::Internal(format!("{:?}", err)));
             },
         };
 
         let response_str = String::from_utf8_lossy(&response_bytes).to_string();
+
+        // E.g. a code that was already used, a wrong PKCE verifier or a wrong client secret
+        if !status.is_success() {
+            println!("The provider refused the code with status {}!: {}", status, response_str);
+            return Err(ApiError::Unauthenticated("The identity provider refused the login".to_string()));
+        }
+
         let token_response = match serde_json::from_str::<TokenResponse>(&response_str) {
             Ok(response) => response,
             Err(err) => {
-                expose_error!(&format!("Failed to deserialize Response: {:?}", err));
+                return Err(ApiError::Internal(format!("Failed to deserialize Response: {:?}", err)));
             },
         };
 
-        // Here, we override the Variables for response with the Response for /userinfo request.
-
-        let response = match client.get(userinfo_url).bearer_auth(&token_response.access_token).send().await {
-            Ok(response) => response,
+        let id_claims = match provider.verify_id_token(&token_response.id_token).await {
+            Ok(claims) => claims,
             Err(err) => {
-                expose_error!(&format!("Failed to get Userinfo: {:?}", err));
+                println!("Rejected ID token!: {}", err);
+                return Err(ApiError::Unauthenticated("Invalid ID token".to_string()));
             },
         };
 
+        if id_claims.nonce.as_deref() != Some(login_nonce(&verification).as_str()) {
+            println!("Rejected ID token with the nonce {:?}!", id_claims.nonce);
+            return Err(ApiError::Unauthenticated("The ID token doesn't belong to this login".to_string()));
+        }
 
-        let map = vec![("client_id".to_string(), client_id.to_string()), ("token".to_string(), token_response.access_token.clone())];
+        // Some providers only put the email into the userinfo, it has to be about the same user as the ID token
+        let (email, email_verified) = match (id_claims.email, &endpoints.userinfo_url) {
+            (Some(email), _) => (email, id_claims.email_verified),
+            (None, Some(userinfo_url)) => {
+                let response = match client.get(userinfo_url).bearer_auth(&token_response.access_token).send().await {
+                    Ok(response) => response,
+                    Err(err) => {
+                        return Err(ApiError::Internal(format!("Failed to get Userinfo: {:?}", err)));
+                    },
+                };
 
-        match client.post(revoke_url).form(&map).send().await {
-            Ok(_) => {},
-            Err(err) => {
-                expose_error!(&format!("Failed to Revoke Token!: {:?}", err));
-            },
-        };
+                let user_info = match response.json::<UserInfo>().await {
+                    Ok(user_info) => user_info,
+                    Err(err) => {
+                        return Err(ApiError::Internal(format!("Failed to construct Userdata{:?}", err)));
+                    },
+                };
 
+                if user_info.sub != id_claims.sub {
+                    println!("Rejected Userinfo about {:?} instead of {:?}!", user_info.sub, id_claims.sub);
+                    return Err(ApiError::Unauthenticated("The userinfo doesn't belong to this login".to_string()));
+                }
 
-        let response_bytes = match response.bytes().await {
-            Ok(bytes) => bytes,
-            Err(err) => {
-                expose_error!(&format!("Failed to Bytify Response: {:?}", err));
+                (user_info.email, user_info.email_verified)
             },
-        };
-
-        let response_str = String::from_utf8_lossy(&response_bytes).to_string();
-        let response = match serde_json::from_str::<UserInfo>(&response_str) {
-            Ok(response) => response,
-            Err(err) => {
-                expose_error!(&format!("Failed to construct Userdata{:?}", err));
+            (None, None) => {
+                return Err(ApiError::Internal("The provider sent no email address".to_string()));
             },
         };
 
+        if email_verified != Some(true) {
+            return Err(ApiError::Forbidden("The email address isn't verified by the identity provider".to_string()));
+        }
+
+        if let Some(revoke_url) = &endpoints.revoke_url {
+            let map = vec![("token".to_string(), token_response.access_token.clone())];
+
+            match provider.client_request(revoke_url, map).send().await {
+                Ok(_) => {},
+                Err(err) => {
+                    return Err(ApiError::Internal(format!("Failed to Revoke Token!: {:?}", err)));
+                },
+            };
+        }
+
 
-        let mut database = data.lock().await;
+        let database = data.get_ref();
 
         
-        let uuid = match database.save_access_token() {
+        let uuid = match database.save_access_token(&email).await {
             Ok(uuid) => {
                 match uuid {
                     Some(uuid) => uuid,
                     None => {
-                        return HttpResponse::InternalServerError().content_type(ContentType::json()).body("{\"error\":\"failed to get new UUID\"}")
+                        return Err(ApiError::Internal("Failed to get new Session-UUID".to_string()));
                     },
                 }
             },
             Err(err) => {
-                expose_error!(&format!("{:?}", err));
+                return Err(ApiError::Internal(format!("{:?}", err)));
             },
         };
 
-        drop(database);
 
         
         let mut claims = BTreeMap::new();
 
-        claims.insert("sub", response.email.clone());
+        claims.insert("sub", email);
         claims.insert("iss", "ProtocolDB".to_string());
         claims.insert("exp", format!("{}", get_current_time_seconds() + TOKEN_VALID_LENGTH));
         claims.insert("sessionid", uuid);
@@ -194,27 +291,338 @@ pub async fn redirect(request: HttpRequest, query: web::Query<RedirectParams>, c
         let token_key: Hmac<Sha256> = match Hmac::new_from_slice(configuration.encryption.token_encryption_secret.clone().as_bytes()) {
             Ok(token) => token,
             Err(err) => {
-                expose_error!(&format!("{:?}", err));
+                return Err(ApiError::Internal(format!("{:?}", err)));
             },
         };
 
         let token_str = match claims.sign_with_key(&token_key) {
             Ok(token) => token,
             Err(err) => {
-                expose_error!(&format!("{:?}", err));
+                return Err(ApiError::Internal(format!("{:?}", err)));
             },
         };
 
-        //Todo Redirect to frontend 
+        let mut response = HttpResponse::SeeOther();
+        response.cookie(used_cookie).cookie(used_pkce_cookie);
 
-        HttpResponse::Ok().body(token_str)
+        match token_delivery {
+            TokenDelivery::Fragment => {
+                // Browsers don't send the fragment to the frontend's server, so the token doesn't end up in its logs
+                response.insert_header((header::LOCATION, format!("{}#token={}", return_to, token_str)));
+            },
+            TokenDelivery::Cookie => {
+                let session_cookie = Cookie::build(SESSION_COOKIE, token_str)
+                    .path("/")
+                    .http_only(true)
+                    .same_site(SameSite::Strict)
+                    .secure(self_root_url.starts_with("https://"))
+                    .max_age(Duration::seconds(TOKEN_VALID_LENGTH as i64))
+                    .finish();
+
+                response.cookie(session_cookie).insert_header((header::LOCATION, return_to));
+            },
+        }
+
+        Ok(response.finish())
     } else { //These values are Returned, because rust returns when there is no trailing semicolon
-        HttpResponse::Unauthorized().body("{\"error\":\"Authorization isn't set to openidconnect!'\"}")
+        Err(ApiError::Internal("Authorization isn't set to openidconnect!".to_string()))
     }
     //HttpResponse::Ok().body(format!("{:?}", query))
 }
 
+#[derive(Deserialize, Debug)]
+struct LogoutParams {
+    return_to: Option<String>
+}
+
+/// Ends the session of the token right away, instead of when the token expires.
+/// With `idp_logout` the response contains the URL that ends the login at the provider, the frontend sends the browser there
+#[post("/logout")]
+pub async fn logout(request: HttpRequest, query: web::Query<LogoutParams>, configuration: web::Data<Configuration>, provider: web::Data<OidcProvider>, data: web::Data<Arc<dyn ProtocolStore>>) -> Result<HttpResponse, ApiError> {
+    if let Authorization::OpenIdConnect { client_id, self_root_url, return_urls, idp_logout, .. } = &configuration.authorization {
+
+        if let Some(return_to) = &query.return_to {
+            if !return_url_allowed(return_to, return_urls, self_root_url) {
+                return Err(ApiError::InvalidInput(format!("{} isn't one of the configured return_urls", return_to)));
+            }
+        }
+
+        let token = match request_token(&request)? {
+            Some(token) => token,
+            None => {
+                return Err(ApiError::Unauthenticated("Missing Authentication Header!".to_string()));
+            },
+        };
+
+        // An expired token may still be logged out, its session is gone either way afterwards
+        let claims = verified_claims(&token, &configuration.encryption.token_encryption_secret)?;
+
+        let session_id = match claims.get("sessionid") {
+            Some(id) => id,
+            None => {
+                return Err(ApiError::Unauthenticated("Malformed Token".to_string()));
+            },
+        };
+
+        let database = data.get_ref();
+
+        match database.remove_session(session_id).await {
+            Ok(_) => {},
+            Err(err) => {
+                return Err(ApiError::Internal(format!("Failed to remove Session!: {:?}", err)));
+            },
+        }
+
+        let end_session_url = match (idp_logout, provider.endpoints().end_session_url) {
+            (true, Some(end_session_url)) => {
+                let mut url = match url::Url::parse(&end_session_url) {
+                    Ok(url) => url,
+                    Err(err) => {
+                        return Err(ApiError::Internal(format!("Invalid end_session_url {:?}!: {}", end_session_url, err)));
+                    },
+                };
+
+                url.query_pairs_mut().append_pair("client_id", client_id);
+
+                if let Some(return_to) = &query.return_to {
+                    url.query_pairs_mut().append_pair("post_logout_redirect_uri", return_to);
+                }
+
+                Some(url.to_string())
+            },
+            _ => None,
+        };
+
+        let mut session_cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+        session_cookie.make_removal();
+
+        let return_str = match serde_json::to_string(&LogoutResponse { end_session_url }) {
+            Ok(str) => str,
+            Err(err) => {
+                return Err(ApiError::Internal(format!("Failed to serialize Logout!: {:?}", err)));
+            },
+        };
+
+        Ok(HttpResponse::Ok().content_type(ContentType::json()).cookie(session_cookie).body(return_str))
+    } else {
+        Err(ApiError::Internal("Authorization isn't set to openidconnect!".to_string()))
+    }
+}
+
+/// The default return URL, for logins without a frontend
 #[get("/auth/openidconnect/done")]
 pub async fn finish() -> impl Responder {
-    HttpResponse::Ok().body("")
+    HttpResponse::Ok().content_type(ContentType::html()).body("<html><head><meta charset=\"utf-8\"><title>ProtokollDB</title></head><body><h1>Login erfolgreich</h1><p>Du kannst dieses Fenster jetzt schließen.</p></body></html>")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use actix_web::{dev::ServiceResponse, http::StatusCode, test::{call_service, init_service, TestRequest}, App};
+
+    use crate::{services::{common::{test_token, test_token_expiring_at}, test_provider::{TestProvider, CLIENT_ID, EMAIL}}, storage::memory::MemoryDatabase};
+
+    use super::*;
+
+    const SELF_ROOT_URL: &str = "https://api.example.org/";
+
+    fn return_urls() -> Vec<String> {
+        vec!["https://protokolle.example.org/".to_string(), "https://example.org/login".to_string()]
+    }
+
+    #[test]
+    fn allows_configured_return_urls() {
+        assert!(return_url_allowed("https://example.org/login", &return_urls(), SELF_ROOT_URL));
+        assert!(return_url_allowed("https://protokolle.example.org/", &return_urls(), SELF_ROOT_URL));
+        assert!(return_url_allowed("https://protokolle.example.org/suche?q=plexus", &return_urls(), SELF_ROOT_URL));
+        assert!(return_url_allowed("https://api.example.org/auth/openidconnect/done", &[], SELF_ROOT_URL));
+    }
+
+    #[test]
+    fn refuses_other_return_urls() {
+        // Only entries ending with a slash allow anything below them
+        assert!(!return_url_allowed("https://example.org/login/evil", &return_urls(), SELF_ROOT_URL));
+        assert!(!return_url_allowed("https://protokolle.example.org.evil.com/", &return_urls(), SELF_ROOT_URL));
+        assert!(!return_url_allowed("https://protokolle.example.org", &return_urls(), SELF_ROOT_URL));
+        assert!(!return_url_allowed("https://evil.com/", &return_urls(), SELF_ROOT_URL));
+        assert!(!return_url_allowed("https://protokolle.example.org/#token=stolen", &return_urls(), SELF_ROOT_URL));
+        assert!(!return_url_allowed("", &return_urls(), SELF_ROOT_URL));
+    }
+
+    #[test]
+    fn nonce_depends_on_the_verification() {
+        assert_eq!(login_nonce("a"), login_nonce("a"));
+        assert_ne!(login_nonce("a"), login_nonce("b"));
+    }
+
+    macro_rules! app {
+        ($database:expr, $configuration:expr) => {{
+            let provider = OidcProvider::from_configuration(&$configuration.authorization).await.unwrap().unwrap();
+
+            init_service(App::new()
+                .app_data(web::Data::new($database.clone()))
+                .app_data(web::Data::new($configuration.clone()))
+                .app_data(web::Data::from(provider))
+                .service(login)
+                .service(redirect)
+                .service(logout)).await
+        }};
+    }
+
+    fn configuration(test_provider: &TestProvider) -> Configuration {
+        let mut configuration = Configuration::default();
+        configuration.authorization = test_provider.authorization();
+
+        if let Authorization::OpenIdConnect { return_urls, .. } = &mut configuration.authorization {
+            return_urls.push("https://protokolle.example.org/".to_string());
+        }
+
+        configuration
+    }
+
+    fn cookie(response: &ServiceResponse, name: &str) -> Cookie<'static> {
+        response.response().cookies().find(|cookie| cookie.name() == name).unwrap().into_owned()
+    }
+
+    /// Comes back from the provider with the given code, which the test provider turns into the nonce of the ID token
+    macro_rules! finish_login {
+        ($app:expr, $code:expr) => {{
+            let request = TestRequest::get().uri("/login?return_to=https%3A%2F%2Fprotokolle.example.org%2Fsuche").to_request();
+            let response = call_service(&$app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let (validation, pkce) = (cookie(&response, "oidc_validation"), cookie(&response, "oidc_pkce"));
+            let state = format!("{}.{}", validation.value(), URL_SAFE_NO_PAD.encode("https://protokolle.example.org/suche"));
+
+            let request = TestRequest::get()
+                .uri(&format!("/auth/openidconnect?code={}&state={}", $code(validation.value()), state))
+                .cookie(validation)
+                .cookie(pkce)
+                .to_request();
+            call_service(&$app, request).await
+        }};
+    }
+
+    #[actix_web::test]
+    async fn login_hands_out_session_token() {
+        let test_provider = TestProvider::start();
+        let (database, configuration): (Arc<dyn ProtocolStore>, _) = (Arc::new(MemoryDatabase::new()), configuration(&test_provider));
+        let app = app!(database, configuration);
+
+        let response = finish_login!(app, login_nonce);
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let location = response.headers().get(header::LOCATION).unwrap().to_str().unwrap();
+        let token = location.strip_prefix("https://protokolle.example.org/suche#token=").unwrap();
+
+        let claims = verified_claims(token, &configuration.encryption.token_encryption_secret).unwrap();
+        assert_eq!(claims["sub"], EMAIL);
+        assert!(database.is_session_valid(&claims["sessionid"]).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn login_rejects_id_token_of_another_login() {
+        let test_provider = TestProvider::start();
+        let (database, configuration): (Arc<dyn ProtocolStore>, _) = (Arc::new(MemoryDatabase::new()), configuration(&test_provider));
+        let app = app!(database, configuration);
+
+        let response = finish_login!(app, |_| login_nonce("another login"));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(database.get_sessions(EMAIL).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn login_needs_verified_email() {
+        let test_provider = TestProvider::start();
+        let (database, configuration): (Arc<dyn ProtocolStore>, _) = (Arc::new(MemoryDatabase::new()), configuration(&test_provider));
+        let app = app!(database, configuration);
+
+        test_provider.state.unverified_email.store(true, Ordering::SeqCst);
+
+        let response = finish_login!(app, login_nonce);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn login_refuses_other_return_url() {
+        let test_provider = TestProvider::start();
+        let (database, configuration): (Arc<dyn ProtocolStore>, _) = (Arc::new(MemoryDatabase::new()), configuration(&test_provider));
+        let app = app!(database, configuration);
+
+        let request = TestRequest::get().uri("/login?return_to=https%3A%2F%2Fevil.com%2F").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// Logs out with the token, the response has to remove the session cookie
+    macro_rules! logout {
+        ($app:expr, $uri:expr, $token:expr) => {{
+            let request = TestRequest::post().uri($uri).insert_header(("Authorization", format!("Bearer {}", $token))).to_request();
+            let response = call_service(&$app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let session_cookie = cookie(&response, SESSION_COOKIE);
+            assert_eq!(session_cookie.value(), "");
+            assert_eq!(session_cookie.max_age(), Some(Duration::ZERO));
+
+            let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+            body
+        }};
+    }
+
+    #[actix_web::test]
+    async fn logout_ends_the_session() {
+        let test_provider = TestProvider::start();
+        let (database, configuration): (Arc<dyn ProtocolStore>, _) = (Arc::new(MemoryDatabase::new()), configuration(&test_provider));
+        let token = test_token(&database, EMAIL, &configuration.encryption.token_encryption_secret).await;
+        let other_token = test_token(&database, EMAIL, &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let body = logout!(app, "/logout", token);
+        assert_eq!(body["end_session_url"], serde_json::Value::Null);
+
+        let session_id = |token: &str| verified_claims(token, &configuration.encryption.token_encryption_secret).unwrap()["sessionid"].clone();
+        assert!(!database.is_session_valid(&session_id(&token)).await.unwrap());
+        assert!(database.is_session_valid(&session_id(&other_token)).await.unwrap());
+        assert_eq!(database.get_sessions(EMAIL).await.unwrap().len(), 1);
+
+        let request = TestRequest::post().uri("/logout").to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn logout_accepts_expired_token() {
+        let test_provider = TestProvider::start();
+        let (database, configuration): (Arc<dyn ProtocolStore>, _) = (Arc::new(MemoryDatabase::new()), configuration(&test_provider));
+        let token = test_token_expiring_at(&database, EMAIL, &configuration.encryption.token_encryption_secret, get_current_time_seconds() - 60).await;
+        let app = app!(database, configuration);
+
+        logout!(app, "/logout", token);
+        assert!(database.get_sessions(EMAIL).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn logout_at_the_provider() {
+        let test_provider = TestProvider::start();
+        let (database, mut configuration): (Arc<dyn ProtocolStore>, _) = (Arc::new(MemoryDatabase::new()), configuration(&test_provider));
+        if let Authorization::OpenIdConnect { idp_logout, .. } = &mut configuration.authorization {
+            *idp_logout = true;
+        }
+        let token = test_token(&database, EMAIL, &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = TestRequest::post().uri("/logout?return_to=https%3A%2F%2Fevil.com%2F").insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        assert_eq!(call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(database.get_sessions(EMAIL).await.unwrap().len(), 1);
+
+        let body = logout!(app, "/logout?return_to=https%3A%2F%2Fprotokolle.example.org%2F", token);
+        let end_session_url = url::Url::parse(body["end_session_url"].as_str().unwrap()).unwrap();
+        assert_eq!(end_session_url.as_str().split('?').next(), Some(format!("{}/logout", test_provider.issuer).as_str()));
+
+        let query: BTreeMap<String, String> = end_session_url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["post_logout_redirect_uri"], "https://protokolle.example.org/");
+        assert!(database.get_sessions(EMAIL).await.unwrap().is_empty());
+    }
 }
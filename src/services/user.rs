@@ -1,35 +1,35 @@
-use std::{num::ParseIntError, sync::Arc};
+use std::{io, num::ParseIntError, sync::Arc};
 
-use actix_web::{get, http::header::ContentType, web::{self, Query}, HttpRequest, HttpResponse, Responder};
-use tokio::sync::Mutex;
+use actix_web::{get, http::header::ContentType, web::{self, Bytes, Path, Query}, HttpRequest, HttpResponse};
+use futures_util::{stream, Stream};
+use tokio::io::AsyncReadExt;
 
-use crate::{authenticate, expose_error, invalid_input, storage::database::Database, structs::{configuration::Configuration, get_inputs::Search}};
+use crate::{authenticate, storage::protocol_store::{Pagination, ProtocolStore, ProtocolText, SearchFilter}, structs::{configuration::Configuration, get_inputs::Search, get_outputs::SearchResponse}, DEFAULT_SEARCH_LIMIT, MAX_SEARCH_LIMIT};
 
-use super::common::authenticate;
+use super::{common::authenticate, error::ApiError};
 
 
 #[get("/api/v1/identifiers")]
-async fn get_selection_identifiers(request: HttpRequest, data: web::Data<Arc<Mutex<Database>>>, configuration: web::Data<Configuration>) -> impl Responder {
+async fn get_selection_identifiers(request: HttpRequest, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
 
     authenticate!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
 
-    let database = data.lock().await;
+    let database = data.get_ref();
 
-    let identifiers = match database.get_selection_identifiers() {
+    let identifiers = match database.get_selection_identifiers().await {
         Ok(idents) => idents,
         Err(err) => {
-            expose_error!(&format!("Failed to fetch Selection IDs!: {:?}", err));
+            return Err(ApiError::Internal(format!("Failed to fetch Selection IDs!: {:?}", err)));
         },
     };
 
-    drop(database);
 
-    HttpResponse::Ok().content_type(ContentType::json()).json(identifiers)
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).json(identifiers))
 }
 
 
 #[get("/api/v1/search")]
-async fn search_for_protocol(request: HttpRequest, search_terms: Query<Search>, data: web::Data<Arc<Mutex<Database>>>, configuration: web::Data<Configuration>) -> impl Responder {
+async fn search_for_protocol(request: HttpRequest, search_terms: Query<Search>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
 
     authenticate!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
     
@@ -37,66 +37,223 @@ async fn search_for_protocol(request: HttpRequest, search_terms: Query<Search>,
     let subjects = match parse_input_to_id_vec(&search_terms.subjects) {
         Ok(val) => val,
         Err(err) => {
-            invalid_input!(&err.to_string());
+            return Err(ApiError::InvalidInput(err.to_string()));
         },
     };
 
     let examiners = match parse_input_to_id_vec(&search_terms.examiners) {
         Ok(val) => val,
         Err(err) => {
-            invalid_input!(&err.to_string());
+            return Err(ApiError::InvalidInput(err.to_string()));
         },
     };
 
     let stex = match parse_input_to_id_vec(&search_terms.stex) {
         Ok(val) => val,
         Err(err) => {
-            invalid_input!(&err.to_string());
+            return Err(ApiError::InvalidInput(err.to_string()));
         },
     };
 
     let seasons = match parse_input_to_id_vec(&search_terms.seasons) {
         Ok(val) => val,
         Err(err) => {
-            invalid_input!(&err.to_string());
+            return Err(ApiError::InvalidInput(err.to_string()));
         },
     };
 
     let years = match parse_input_to_id_vec(&search_terms.years) {
         Ok(val) => val,
         Err(err) => {
-            invalid_input!(&err.to_string());
+            return Err(ApiError::InvalidInput(err.to_string()));
         },
     };
 
-    if examiners.is_none() && subjects.is_none() && stex.is_none() && seasons.is_none() && years.is_none() {
-        return HttpResponse::NotFound().content_type(ContentType::json()).body("{\"error\":\"No Search Parameters Provided\"}");
+    let exclude_subjects = match parse_input_to_id_vec(&search_terms.exclude_subjects) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(ApiError::InvalidInput(err.to_string()));
+        },
+    };
+
+    let exclude_examiners = match parse_input_to_id_vec(&search_terms.exclude_examiners) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(ApiError::InvalidInput(err.to_string()));
+        },
+    };
+
+    let exclude_stex = match parse_input_to_id_vec(&search_terms.exclude_stex) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(ApiError::InvalidInput(err.to_string()));
+        },
+    };
+
+    let exclude_seasons = match parse_input_to_id_vec(&search_terms.exclude_seasons) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(ApiError::InvalidInput(err.to_string()));
+        },
+    };
+
+    let exclude_years = match parse_input_to_id_vec(&search_terms.exclude_years) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(ApiError::InvalidInput(err.to_string()));
+        },
+    };
+
+    if let (Some(year_from), Some(year_to)) = (search_terms.year_from, search_terms.year_to) {
+        if year_from > year_to {
+            return Err(ApiError::InvalidInput("year_from can't be after year_to".to_string()));
+        }
+    }
+
+    // Blank search text is treated as if there was none
+    let text = search_terms.q.as_ref().map(|q| q.trim().to_string()).filter(|q| !q.is_empty());
+
+    let limit = search_terms.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    let offset = search_terms.offset.unwrap_or(0);
+
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Err(ApiError::InvalidInput(format!("limit has to be between 1 and {}", MAX_SEARCH_LIMIT)));
+    }
+
+    if offset < 0 {
+        return Err(ApiError::InvalidInput("offset can't be negative".to_string()));
     }
-    
-    let database = data.lock().await;
 
-    let potential_results = match database.search_for_protocol(examiners, subjects, stex,seasons, years) {
+    // Without any parameter all protocols are listed, page by page
+    let filter = SearchFilter {
+        examiner_ids: examiners,
+        subject_ids: subjects,
+        stex_ids: stex,
+        seasons,
+        years,
+        exclude_examiner_ids: exclude_examiners,
+        exclude_subject_ids: exclude_subjects,
+        exclude_stex_ids: exclude_stex,
+        exclude_seasons,
+        exclude_years,
+        year_from: search_terms.year_from,
+        year_to: search_terms.year_to,
+        text
+    };
+
+    let database = data.get_ref();
+
+    let results = match database.search_for_protocol(filter, Pagination { limit, offset }).await {
         Ok(results) => results,
         Err(err) => {
-            expose_error!(&err.to_string());
+            return Err(ApiError::Internal(format!("Failed to search Protocols!: {:?}", err)));
+        },
+    };
+
+
+    let response = SearchResponse { total: results.total, limit, offset, protocols: results.protocols };
+
+    let serialized_return_val = match serde_json::to_string(&response) {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to serialize Search Results!: {:?}", err)));
+        },
+    };
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(serialized_return_val))
+}
+
+
+#[get("/api/v1/protocol/{uuid}")]
+async fn get_protocol(request: HttpRequest, protocol_uuid: Path<String>, data: web::Data<Arc<dyn ProtocolStore>>, configuration: web::Data<Configuration>) -> Result<HttpResponse, ApiError> {
+
+    authenticate!(request, data.clone(), configuration.encryption.token_encryption_secret.clone());
+
+    let database = data.get_ref();
+
+    let potential_protocol = match database.get_protocol(&protocol_uuid).await {
+        Ok(protocol) => protocol,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to fetch Protocol!: {:?}", err)));
         },
     };
 
-    let results = match potential_results {
-        Some(results) => results,
-        None => return HttpResponse::NotFound().content_type(ContentType::json()).body("{\"error\":\"Found no protocols matching provided Parameters\"}"),
+    let protocol = match potential_protocol {
+        Some(protocol) => protocol,
+        None => return Err(ApiError::NotFound("Found no protocol with the provided UUID".to_string())),
+    };
+
+    let text = match database.open_protocol_text(&protocol.uuid).await {
+        Ok(text) => text,
+        Err(err) => {
+            return Err(ApiError::Internal(format!("Failed to open Protocol-Text!: {:?}", err)));
+        },
     };
 
-    let serialized_return_val = match serde_json::to_string(&results) {
+
+    let serialized_metadata = match serde_json::to_string(&protocol) {
         Ok(val) => val,
         Err(err) => {
-            expose_error!(&err.to_string());
+            return Err(ApiError::Internal(format!("Failed to serialize Protocol!: {:?}", err)));
         },
     };
 
-    HttpResponse::Ok().content_type(ContentType::json()).body(serialized_return_val)
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).streaming(stream_protocol(serialized_metadata, text)))
+}
+
+
+enum ProtocolStreamState {
+    Metadata(String, ProtocolText),
+    Text(ProtocolText, Vec<u8>),
+    Done
 }
 
+/// Streams {"metadata": <metadata>, "text": "<protocol text>"} without reading the whole text into memory.
+/// The text is read in chunks, every chunk is escaped as a JSON string on its own. Bytes of a UTF-8
+/// character that got split between two chunks are carried over into the next one.
+fn stream_protocol(serialized_metadata: String, text: ProtocolText) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    stream::unfold(ProtocolStreamState::Metadata(serialized_metadata, text), |state| async move {
+        match state {
+            ProtocolStreamState::Metadata(metadata, text) => {
+                let head = format!("{{\"metadata\":{},\"text\":\"", metadata);
+                Some((Ok(Bytes::from(head)), ProtocolStreamState::Text(text, vec![])))
+            },
+            ProtocolStreamState::Text(mut text, mut carry) => {
+                let mut buffer = vec![0u8; 64 * 1024];
+                let read = match text.read(&mut buffer).await {
+                    Ok(read) => read,
+                    Err(err) => return Some((Err(err), ProtocolStreamState::Done)),
+                };
+
+                if read == 0 {
+                    if !carry.is_empty() {
+                        return Some((Err(io::Error::new(io::ErrorKind::InvalidData, "Protocol ends with an incomplete UTF-8 character!")), ProtocolStreamState::Done));
+                    }
+                    return Some((Ok(Bytes::from_static(b"\"}")), ProtocolStreamState::Done));
+                }
+
+                carry.extend_from_slice(&buffer[..read]);
+
+                let valid_up_to = match std::str::from_utf8(&carry) {
+                    Ok(_) => carry.len(),
+                    Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                    Err(err) => return Some((Err(io::Error::new(io::ErrorKind::InvalidData, err)), ProtocolStreamState::Done)),
+                };
+
+                let remainder = carry.split_off(valid_up_to);
+                let chunk = String::from_utf8_lossy(&carry);
+
+                let escaped = match serde_json::to_string(&chunk) {
+                    Ok(escaped) => escaped,
+                    Err(err) => return Some((Err(io::Error::new(io::ErrorKind::InvalidData, err)), ProtocolStreamState::Done)),
+                };
+
+                Some((Ok(Bytes::from(escaped[1..escaped.len() - 1].to_string())), ProtocolStreamState::Text(text, remainder)))
+            },
+            ProtocolStreamState::Done => None,
+        }
+    })
+}
 
 
 fn parse_input_to_id_vec(input: &Option<String>) -> Result<Option<Vec<i64>>, ParseIntError> {
@@ -106,12 +263,7 @@ fn parse_input_to_id_vec(input: &Option<String>) -> Result<Option<Vec<i64>>, Par
             let mut assembled_vec = vec![];
 
             for element in split {
-                let num = match element.parse::<i64>() {
-                    Ok(number) => number, 
-                    Err(err) => {
-                        return Result::Err(err)
-                    }
-                };
+                let num = element.parse::<i64>()?;
                 assembled_vec.push(num)
             };
 
@@ -120,3 +272,92 @@ fn parse_input_to_id_vec(input: &Option<String>) -> Result<Option<Vec<i64>>, Par
         None => Result::Ok(None),
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{cookie::Cookie, http::StatusCode, test, App};
+    use futures_util::StreamExt;
+    use serde_json::Value;
+
+    use crate::{services::common::{test_token, SESSION_COOKIE}, storage::{memory::MemoryDatabase, testing::{create_catalog, save_protocol}}};
+
+    use super::*;
+
+    /// A memory store with one saved protocol, returns its UUID
+    async fn store() -> (Arc<dyn ProtocolStore>, String) {
+        let database: Arc<dyn ProtocolStore> = Arc::new(MemoryDatabase::new());
+        create_catalog(database.as_ref()).await;
+
+        let uuid = save_protocol(database.as_ref(), 2023, "Plexus \"brachialis\"").await;
+        (database, uuid)
+    }
+
+    macro_rules! app {
+        ($database:expr, $configuration:expr) => {
+            test::init_service(App::new()
+                .app_data(web::Data::new($database.clone()))
+                .app_data(web::Data::new($configuration.clone()))
+                .service(search_for_protocol)
+                .service(get_protocol)).await
+        };
+    }
+
+    #[actix_web::test]
+    async fn get_protocol_returns_metadata_and_text() {
+        let ((database, uuid), configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, "user@example.org", &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::get().uri(&format!("/api/v1/protocol/{}", uuid)).insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["metadata"]["uuid"], uuid.as_str());
+        assert_eq!(body["metadata"]["examiners"][0], "Dr. Muster");
+        assert_eq!(body["text"], "Plexus \"brachialis\"");
+
+        let request = test::TestRequest::get().uri("/api/v1/protocol/unknown").insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn session_cookie_and_revoked_sessions() {
+        let ((database, uuid), configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, "user@example.org", &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::get().uri(&format!("/api/v1/protocol/{}", uuid)).cookie(Cookie::new(SESSION_COOKIE, token.clone())).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::OK);
+
+        database.remove_sessions("user@example.org").await.unwrap();
+
+        let request = test::TestRequest::get().uri(&format!("/api/v1/protocol/{}", uuid)).cookie(Cookie::new(SESSION_COOKIE, token)).to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn search_filters_by_text_and_ids() {
+        let ((database, uuid), configuration) = (store().await, Configuration::default());
+        let token = test_token(&database, "user@example.org", &configuration.encryption.token_encryption_secret).await;
+        let app = app!(database, configuration);
+
+        let request = test::TestRequest::get().uri("/api/v1/search?q=plexus&examiners=1").insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["protocols"][0]["uuid"], uuid.as_str());
+
+        let request = test::TestRequest::get().uri("/api/v1/search?exclude_examiners=1").insert_header(("Authorization", format!("Bearer {}", token))).to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(body["total"], 0);
+    }
+
+    #[actix_web::test]
+    async fn stream_keeps_characters_split_between_chunks() {
+        // The "ä" starts at the last byte of the first 64 KiB chunk
+        let text = format!("{}ä\"\n", "a".repeat(64 * 1024 - 1));
+        let reader: ProtocolText = Box::new(io::Cursor::new(text.clone().into_bytes()));
+
+        let chunks: Vec<Bytes> = stream_protocol("{}".to_string(), reader).map(|chunk| chunk.unwrap()).collect().await;
+        let body: Value = serde_json::from_slice(&chunks.concat()).unwrap();
+        assert_eq!(body["text"], text.as_str());
+    }
+}
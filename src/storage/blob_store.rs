@@ -0,0 +1,165 @@
+use std::{io::{self, Cursor}, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::structs::configuration::{BlobStorage, Configuration};
+
+use super::{encryption::{EncryptedBlobStore, ProtocolCipher}, protocol_store::ProtocolText, s3::S3BlobStore};
+
+/// Blobs below this prefix were set aside by the consistency check and are left alone otherwise
+pub const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// Where the protocol texts are kept, chosen with `blob_storage` in the config.
+/// Keys are flat names like `<uuid>.txt`, the database backends only ever see the keys
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()>;
+
+    /// Fails with NotFound if there is no such blob
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// Opens the blob for reading, so it can be streamed without loading it completely
+    async fn open(&self, key: &str) -> io::Result<ProtocolText>;
+
+    /// Replaces a blob that already exists under `to`
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Deleting a blob that doesn't exist is no error
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// All keys outside of the quarantine
+    async fn list(&self) -> io::Result<Vec<String>>;
+
+    /// When the blob was last written, fails with NotFound if there is no such blob
+    async fn modified(&self, key: &str) -> io::Result<SystemTime>;
+
+    /// Makes sure blobs can be written, so a read-only mount or wrong credentials show up at startup instead of with the first save
+    async fn check_writable(&self) -> io::Result<()>;
+
+    async fn get_text(&self, key: &str) -> io::Result<String> {
+        String::from_utf8(self.get(key).await?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+pub fn text_key(protocol_uuid: &str) -> String {
+    format!("{}.txt", protocol_uuid)
+}
+
+/// Where a new text is written before it replaces the old one
+pub fn new_text_key(protocol_uuid: &str) -> String {
+    format!("{}.txt.new", protocol_uuid)
+}
+
+/// Creates the blob store from the config and checks that it's writable.
+/// With a cipher the texts are encrypted and decrypted on the way
+pub async fn open_blob_store(configuration: &Configuration, cipher: Option<Arc<ProtocolCipher>>) -> io::Result<Arc<dyn BlobStore>> {
+    let store = open_unencrypted_blob_store(configuration).await?;
+
+    match cipher {
+        Some(cipher) => Ok(Arc::new(EncryptedBlobStore::new(store, cipher))),
+        None => Ok(store),
+    }
+}
+
+/// The blob store as it is, for looking at the stored bytes themselves
+pub async fn open_unencrypted_blob_store(configuration: &Configuration) -> io::Result<Arc<dyn BlobStore>> {
+    let store: Arc<dyn BlobStore> = match &configuration.blob_storage {
+        BlobStorage::Local => Arc::new(LocalBlobStore::new(&configuration.general.protocol_location)),
+        BlobStorage::S3 { endpoint, region, bucket, access_key_id, secret_access_key, prefix } => {
+            Arc::new(S3BlobStore::new(endpoint, region, bucket, access_key_id, secret_access_key, prefix)?)
+        },
+    };
+
+    store.check_writable().await?;
+
+    Ok(store)
+}
+
+/// The files in `general.protocol_location`.
+/// Relative paths are resolved against the working directory, like before the setting was honoured
+pub struct LocalBlobStore {
+    root: PathBuf
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> LocalBlobStore {
+        LocalBlobStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        fs::write(self.path(key), data).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)).await
+    }
+
+    async fn open(&self, key: &str) -> io::Result<ProtocolText> {
+        Ok(Box::new(fs::File::open(self.path(key)).await?))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let target = self.path(to);
+
+        if let Some(directory) = target.parent() {
+            fs::create_dir_all(directory).await?;
+        }
+
+        fs::rename(self.path(from), target).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut entries = fs::read_dir(&self.root).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+
+            if let Ok(key) = entry.file_name().into_string() {
+                keys.push(key);
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn modified(&self, key: &str) -> io::Result<SystemTime> {
+        fs::metadata(self.path(key)).await?.modified()
+    }
+
+    async fn check_writable(&self) -> io::Result<()> {
+        ensure_writable_directory(&self.root).map_err(|err| io::Error::new(err.kind(), format!("{} is not a writable directory: {}", self.root.display(), err)))
+    }
+}
+
+/// Creates the directory if needed and writes and removes a probe file
+pub fn ensure_writable_directory(directory: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(directory)?;
+
+    let probe = directory.join(format!(".write-check-{}", Uuid::new_v4()));
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+/// For blob stores that can't stream, the whole blob is read first
+pub fn in_memory_text(data: Vec<u8>) -> ProtocolText {
+    Box::new(Cursor::new(data))
+}
@@ -0,0 +1,88 @@
+use std::{collections::HashSet, io, time::{Duration, SystemTime}};
+
+use uuid::Uuid;
+
+use crate::structs::post_inputs::RepairMode;
+
+use super::{blob_store::{text_key, BlobStore, QUARANTINE_PREFIX}, protocol_store::CatalogEntity};
+
+/// A save writes its temporary blob before it waits for the write lock and an edit renames it after the commit,
+/// so younger temporary blobs may still be in use
+pub const TEMPORARY_BLOB_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// The queries below are plain SQL shared by both database backends
+pub const UNUSED_RELATIONS_QUERY: &str = "SELECT id FROM subject_relations WHERE id NOT IN (SELECT relation_id FROM protocols) ORDER BY id;";
+
+pub const DELETE_UNUSED_RELATIONS: &str = "DELETE FROM subject_relations WHERE id NOT IN (SELECT relation_id FROM protocols);";
+
+/// Entities that neither a relation with a protocol nor a revision refers to, as id and display_name
+pub fn unused_entities_query(entity: CatalogEntity) -> String {
+    format!(
+        "SELECT id, display_name FROM {table} WHERE id NOT IN (SELECT subject_relations.{column} FROM subject_relations JOIN protocols ON protocols.relation_id = subject_relations.id) AND id NOT IN (SELECT {column} FROM {revisions}) ORDER BY id;",
+        table = entity.table_name(), column = entity.column_name(), revisions = entity.revision_table_name()
+    )
+}
+
+/// Takes the ID as only parameter. Has to run after the unused relations are gone, entities still in use are kept
+pub fn delete_unused_entity_query(entity: CatalogEntity, placeholder: &str) -> String {
+    format!(
+        "DELETE FROM {table} WHERE id = {placeholder} AND id NOT IN (SELECT {column} FROM subject_relations) AND id NOT IN (SELECT {column} FROM {revisions});",
+        table = entity.table_name(), column = entity.column_name(), revisions = entity.revision_table_name(), placeholder = placeholder
+    )
+}
+
+/// Leaves out the temporary blobs that are younger than the grace period or already gone again
+pub async fn settled_keys(blobs: &dyn BlobStore, keys: Vec<String>) -> io::Result<Vec<String>> {
+    let mut settled = vec![];
+
+    for key in keys {
+        if key.ends_with(".txt.new") {
+            let modified = match blobs.modified(&key).await {
+                Ok(modified) => modified,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            match SystemTime::now().duration_since(modified) {
+                Ok(age) if age >= TEMPORARY_BLOB_GRACE_PERIOD => {},
+                _ => continue,
+            }
+        }
+
+        settled.push(key);
+    }
+
+    Ok(settled)
+}
+
+/// Blobs that don't belong to any of the given protocols.
+/// Temporary blobs of saves and edits that never finished are always orphans, so the keys have to come from `settled_keys`
+pub fn files_without_rows(keys: &[String], protocol_uuids: &HashSet<String>) -> Vec<String> {
+    let mut orphans: Vec<String> = keys.iter().filter(|key| match (key.strip_suffix(".txt.new"), key.strip_suffix(".txt")) {
+        (Some(uuid), _) => Uuid::parse_str(uuid).is_ok(),
+        (None, Some(uuid)) => Uuid::parse_str(uuid).is_ok() && !protocol_uuids.contains(uuid),
+        (None, None) => false,
+    }).cloned().collect();
+
+    orphans.sort();
+
+    orphans
+}
+
+/// Protocols whose text is not among the blobs
+pub fn rows_without_files(keys: &[String], protocol_uuids: &HashSet<String>) -> Vec<String> {
+    let keys: HashSet<&String> = keys.iter().collect();
+
+    let mut missing: Vec<String> = protocol_uuids.iter().filter(|uuid| !keys.contains(&text_key(uuid))).cloned().collect();
+    missing.sort();
+
+    missing
+}
+
+/// Moves the blob into the quarantine or removes it
+pub async fn repair_orphaned_file(blobs: &dyn BlobStore, key: &str, repair: RepairMode) -> io::Result<()> {
+    match repair {
+        RepairMode::Quarantine => blobs.rename(key, &format!("{}{}", QUARANTINE_PREFIX, key)).await,
+        RepairMode::Delete => blobs.delete(key).await,
+    }
+}
@@ -1,528 +1,419 @@
-use std::{collections::HashMap, fs, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::HashMap, fmt, io, path::Path, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 use regex::Regex;
-use sqlite::{Connection, Error, State};
-use uuid::Uuid;
 
-use crate::{structs::get_outputs::{OutputProtocol, SelectionIdentifier, SelectionIdentifierPair}, TOKEN_VALID_LENGTH};
+use crate::structs::{configuration::{Configuration, DatabaseBackend, PostgresSslMode}, get_outputs::OutputProtocol};
 
-pub struct Database {
-    connection: Connection
-}
+use super::{blob_store::{ensure_writable_directory, open_blob_store}, encryption::ProtocolCipher, memory::MemoryDatabase, postgres::PostgresDatabase, protocol_store::{CatalogEntity, ProtocolStore, ProtocolUpdate}, sqlite::SQLiteDatabase};
+
+/// How many connections the SQL backends keep open, so requests don't have to wait for each other
+pub const DATABASE_POOL_SIZE: usize = 8;
 
 pub struct DatabaseConnectionInfo {
-    pub hostname: String, 
-    pub port: u16, 
-    pub username: String, 
-    pub password: String, 
-    pub database: String
+    pub hostname: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub database: String,
+    pub sslmode: PostgresSslMode,
+    pub ssl_root_certificate: Option<String>
 }
 
-impl Database {
-    pub fn new(_conn_info: Option<DatabaseConnectionInfo>) -> Database {//ToDo: Actually make this usable
-        let connection = sqlite::open("index.db").expect("Failed to connect to local database?!?!!?");
-        
-        let setup_query = "
-            CREATE TABLE IF NOT EXISTS 'sessions' (id INTEGER not null\nconstraint tokens_pk\nprimary key autoincrement, uuid TEXT not null, created INT not null);
-            CREATE TABLE IF NOT EXISTS 'admins' (id INTEGER not null\nconstraint admins_pk\nprimary key autoincrement, email TEXT not null);
-            CREATE TABLE IF NOT EXISTS 'examiners' (id INTEGER not null\nconstraint examiners_pk\nprimary key autoincrement, display_name TEXT not null);
-            CREATE TABLE IF NOT EXISTS 'subjects' (id INTEGER not null\nconstraint subjects_pk\nprimary key autoincrement, display_name TEXT not null);
-            CREATE TABLE IF NOT EXISTS 'stex' (id INTEGER not null\nconstraint stex_pk\nprimary key autoincrement, display_name TEXT not null);
-            CREATE TABLE IF NOT EXISTS 'seasons' (id INTEGER not null\nconstraint seasons_pk\nprimary key autoincrement, display_name TEXT not null);
-            CREATE TABLE IF NOT EXISTS 'subject_relations' (
-                id INTEGER not null\nconstraint subject_relations_pk\nprimary key autoincrement,
-                examiner_id INTEGER not null\nconstraint subject_relations_examiners_id_fk\nreferences examiners,
-                subject_id INTEGER not null\nconstraint subject_relations_subjects_id_fk\nreferences subjects,
-                stex_id INTERGER not null\nconstraint subject_relations_stex_id_fk\nreferences stex,
-                season_id INTEGER not null\nconstraint subject_relations_seasons_id_fk\nreferences seasons,
-                year INTEGER not null
-            );
-            CREATE TABLE IF NOT EXISTS 'protocols' (
-                id INTEGER not null\nconstraint protocols_pk\nprimary key autoincrement,
-                relation_id INTEGER not null\nconstraint protocols_subject_relations_id_fk\nreferences subject_relations,
-                protocol_uuid VARCHAR(36) not null
-            );
-            "; 
-
-
-        connection.execute(setup_query).expect("Failed to execute Setup-Instructions!");
-
-        Database {
-            connection
-        }
-    }
+#[derive(Debug)]
+pub enum DatabaseError {
+    SQLite(sqlite::Error),
+    PostgreSQL(tokio_postgres::Error),
+    Io(io::Error),
+    SchemaTooNew { database_version: i64, supported_version: i64 }
+}
 
-    //Authentication
-
-    pub fn save_access_token(&mut self) -> Result<Option<String>, Error> {
-        let uuid = match self.get_new_token_uuid() {
-            Some(uuid) => uuid,
-            None => {
-                return Ok(None);
-            },
-        };
-        let query = format!("INSERT INTO sessions(uuid, created) VALUES ('{}', {})", uuid, get_current_time_seconds());
-        match self.connection.execute(query) {
-            Ok(_) => Ok(Some(uuid)),
-            Err(err) => Err(err),
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::SQLite(err) => write!(f, "{}", err),
+            DatabaseError::PostgreSQL(err) => write!(f, "{}", err),
+            DatabaseError::Io(err) => write!(f, "{}", err),
+            DatabaseError::SchemaTooNew { database_version, supported_version } => write!(f, "The database has schema version {}, but this binary only knows up to version {}. Refusing to touch it!", database_version, supported_version),
         }
     }
+}
 
-    pub fn remove_expired_sessions(&mut self) -> Result<(), Error> {
-        let query = format!("DELETE FROM sessions WHERE created < {};", get_current_time_seconds() - TOKEN_VALID_LENGTH);
-        match self.connection.execute(query) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
-        }
+impl From<sqlite::Error> for DatabaseError {
+    fn from(err: sqlite::Error) -> Self {
+        DatabaseError::SQLite(err)
     }
+}
 
-    pub fn is_session_valid(&mut self, session_id: &str) -> Result<bool, Error> {
-        let query = format!("SELECT uuid FROM sessions WHERE uuid = '{}';", session_id); 
-        let mut statement = self.connection.prepare(&query)?;
-
-        if let Ok(State::Row) = statement.next() {
-            match statement.read::<String, _>("uuid") {
-                Ok(uuid) => {
-                    Ok(uuid.eq(session_id))
-                },
-                Err(err) => Err(err),
-            }
-        } else {
-            Ok(false)
-        }
+impl From<tokio_postgres::Error> for DatabaseError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        DatabaseError::PostgreSQL(err)
     }
+}
 
-    pub fn check_if_user_admin(&mut self, email: &str) -> Result<bool, Error> {
+impl From<io::Error> for DatabaseError {
+    fn from(err: io::Error) -> Self {
+        DatabaseError::Io(err)
+    }
+}
 
-        if !email_is_safe(email) {
-            println!("Got invalid Email!: {:?}", email);
-            return Ok(false);
-        }
+/// Opens the store the API runs against, chosen from `database_type` in the config at startup.
+/// The directories and the blob store it writes to are checked for write access first,
+/// pending migrations are applied before the store is handed out.
+/// The SQL backends open their connections here, requests share them instead of waiting for one another
+pub async fn open_database(configuration: &Configuration) -> Result<Arc<dyn ProtocolStore>, DatabaseError> {
+    // Shared by the blob store and the revisions in the database
+    let cipher = ProtocolCipher::from_configuration(&configuration.encryption)?.map(Arc::new);
+
+    match &configuration.database_type {
+        DatabaseBackend::SQLLite { file_location } => {
+            let file_location = Path::new(file_location);
+
+            // A bare file name has an empty parent, which means the working directory
+            if let Some(directory) = file_location.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+                ensure_writable_directory(directory).map_err(|err| directory_error(directory, err))?;
+            }
+            let blobs = open_blob_store(configuration, cipher.clone()).await?;
 
-        let query = format!("SELECT email FROM admins WHERE email = '{}';", email); 
-        let mut statement = self.connection.prepare(&query)?;
+            Ok(Arc::new(SQLiteDatabase::new(file_location, blobs, cipher).await?))
+        },
+        DatabaseBackend::PostgeSQL { hostname, port, username, password, database, sslmode, ssl_root_certificate } => {
+            let blobs = open_blob_store(configuration, cipher.clone()).await?;
 
-        if let Ok(State::Row) = statement.next() {
-            match statement.read::<String, _>("email") {
-                Ok(database_email) => {
-                    Ok(database_email.eq(&email))
-                },
-                Err(err) => Err(err),
-            }
-        } else {
-            Ok(false) 
-        }
+            let conn_info = DatabaseConnectionInfo { hostname: hostname.clone(), port: *port, username: username.clone(), password: password.clone(), database: database.clone(), sslmode: *sslmode, ssl_root_certificate: ssl_root_certificate.clone() };
+            Ok(Arc::new(PostgresDatabase::new(conn_info, blobs, cipher).await?))
+        },
+        DatabaseBackend::InMemory => Ok(Arc::new(MemoryDatabase::new())),
     }
+}
 
-    //Data Manipulation
-    
-    pub fn add_admin(&mut self, email: &str) -> Result<(), Error> {
+fn directory_error(directory: &Path, err: io::Error) -> DatabaseError {
+    DatabaseError::Io(io::Error::new(err.kind(), format!("{} is not a writable directory: {}", directory.display(), err)))
+}
 
-        if !email_is_safe(email) {
-            println!("Got invalid Email!: {:?}", email);
-            return Ok(());
-        }
+/// Grants the `initial_admins` from the config their rights, but only while there is no admin at all.
+/// That way an admin that was removed later on doesn't come back with the next restart
+pub async fn seed_initial_admins(database: &Arc<dyn ProtocolStore>, initial_admins: &[String]) -> Result<(), DatabaseError> {
+    if initial_admins.is_empty() || !database.get_admins().await?.is_empty() {
+        return Ok(());
+    }
 
-        let query = format!("INSERT INTO admins(email) VALUES ('{}');", email);
-        match self.connection.execute(query) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
+    for email in initial_admins {
+        match database.add_admin(email).await? {
+            true => println!("Added initial admin {}", email),
+            false => println!("Skipped invalid initial admin {:?}!", email),
         }
     }
 
-    pub fn remove_admin(&mut self, email: &str) -> Result<(), Error> {
+    Ok(())
+}
 
-        if !email_is_safe(email) {
-            println!("Got invalid Email!: {:?}", email);
-            return Ok(());
-        }
+pub fn get_current_time_seconds() -> u64 {
+    let start = SystemTime::now();
+    let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("naja lolm, die Zeit hat sich zurückbewegt...");
+    since_the_epoch.as_secs()
+}
 
-        let query = format!("DELETE FROM admins WHERE VALUES ('{}');", email);
-        match self.connection.execute(query) {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
+/// The databases put these around the hits in a snippet. They're characters from the private use area,
+/// so they can't be confused with markup in the protocol text. snippet_html turns them into <mark></mark>
+pub const HIGHLIGHT_START: char = '\u{E000}';
+pub const HIGHLIGHT_END: char = '\u{E001}';
+
+/// HTML-escapes the snippet and only then marks the hits, so a frontend can render it without running markup from the text
+pub fn snippet_html(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+
+    for c in snippet.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            HIGHLIGHT_START => html.push_str("<mark>"),
+            HIGHLIGHT_END => html.push_str("</mark>"),
+            c => html.push(c),
         }
     }
 
-    pub fn create_examiner(&mut self, display_name: String) -> Result<Option<i64>, Error> {
-        self.create_item("examiners".to_string(), display_name)
-    }
+    html
+}
 
-    pub fn create_subject(&mut self, display_name: String) -> Result<Option<i64>, Error> {
-        self.create_item("subjects".to_string(), display_name)
-    }
+/// One row of a protocol query
+pub struct ProtocolRow {
+    pub uuid: String,
+    pub examiner: String,
+    pub subject: String,
+    pub stex: String,
+    pub season: String,
+    pub year: i64
+}
 
-    pub fn create_stex(&mut self, display_name: String) -> Result<Option<i64>, Error> {
-        self.create_item("stex".to_string(), display_name)
-    }
+/// Where a protocol is filed: the relations it belongs to and the metadata they all share
+pub struct ProtocolFiling {
+    pub relation_ids: Vec<i64>,
+    pub examiner_subject_ids: Vec<(i64, i64)>,
+    pub stex_id: i64,
+    pub season_id: i64,
+    pub year: i64
+}
 
-    pub fn create_season(&mut self, display_name: String) -> Result<Option<i64>, Error> {
-        self.create_item("seasons".to_string(), display_name)
-    }
+impl ProtocolFiling {
+    /// Builds the filing from the (relation_id, examiner_id, subject_id, stex_id, season_id, year) rows of a protocol.
+    /// None if there are no rows, so the protocol doesn't exist
+    pub fn from_rows(rows: Vec<(i64, i64, i64, i64, i64, i64)>) -> Option<ProtocolFiling> {
+        let (_, _, _, stex_id, season_id, year) = *rows.first()?;
 
-    fn create_item(&mut self, table_name: String, display_name: String) -> Result<Option<i64>, Error> {
+        let mut filing = ProtocolFiling { relation_ids: vec![], examiner_subject_ids: vec![], stex_id, season_id, year };
 
-        let validate_regex = Regex::new(r"^([a-zA-Z0-9äöüÄÖÜ]|\.|-|_| )*$").expect("Failed to Assemble Hardcoded Regex!");
-        
-        if validate_regex.captures(&display_name).is_none() {
-            println!("Got unsafe Input: {:?}", display_name);
-            return Ok(None);
+        for (relation_id, examiner_id, subject_id, _, _, _) in rows {
+            filing.relation_ids.push(relation_id);
+            if !filing.examiner_subject_ids.contains(&(examiner_id, subject_id)) {
+                filing.examiner_subject_ids.push((examiner_id, subject_id));
+            }
         }
 
-        let query = format!("SELECT id FROM {} WHERE display_name = '{}';", table_name, display_name);
+        Some(filing)
+    }
 
-        let potential_id = match self.if_exists(&query) {
-            Ok(exists) => exists,
-            Err(err) => {
-                return Result::Err(err)
-            }
-        };
+    /// Every catalog entity the filing refers to, the examiners and subjects in the order of their pairs
+    pub fn referenced_entities(&self) -> Vec<(CatalogEntity, i64)> {
+        let mut entities = vec![];
 
-        if let Some(id) = potential_id {
-            return Result::Ok(Some(id));
+        for (examiner_id, subject_id) in &self.examiner_subject_ids {
+            entities.push((CatalogEntity::Examiner, *examiner_id));
+            entities.push((CatalogEntity::Subject, *subject_id));
         }
 
-        match self.connection.execute(format!("INSERT INTO {}(display_name) VALUES ('{}');", table_name, display_name)) {
-            Ok(_) => {},
-            Err(err) => return Result::Err(err),
-        };
-
+        entities.push((CatalogEntity::Stex, self.stex_id));
+        entities.push((CatalogEntity::Season, self.season_id));
 
-        let potential_id = match self.if_exists(&query) {
-            Ok(exists) => exists,
-            Err(err) => {
-                return Result::Err(err)
-            }
-        };
+        entities
+    }
 
-        match potential_id {
-            Some(id) => Result::Ok(Some(id)),
-            None => Result::Ok(None),
+    /// Takes over the metadata of the update, the relation_ids stay the old ones so they can be cleaned up
+    pub fn apply(&mut self, update: &ProtocolUpdate) {
+        if let Some(examiner_subject_ids) = &update.examiner_subject_ids {
+            self.examiner_subject_ids = examiner_subject_ids.clone();
         }
 
-    }
-
+        if let Some(stex_id) = update.stex_id {
+            self.stex_id = stex_id;
+        }
 
-    pub fn save_protocol(&mut self, examiner_subject_relation_ids: Vec<(i64, i64)>, stex_id: i64, season_id: i64, year: i64, protocol: String) -> Result<Option<String>, Error> {
-        let protocol_uuid = match self.get_new_uuid() {
-            Some(uuid) => uuid,
-            None => return Result::Ok(None),
-        };
-        
-        match fs::write(format!("protocols/{}.txt", protocol_uuid), protocol) {
-            Ok(_) => {},
-            Err(err) => {
-                println!("Failed to write protocol to Disk!: {:?}", err); 
-                return Result::Ok(None);
-            },
-        };
-
-
-        for rel in examiner_subject_relation_ids {
-            let potential_relation_id = match self.create_relation_if_not_exist(rel.0, rel.1, stex_id, season_id, year) {
-                Ok(pot) => pot,
-                Err(err) => return Result::Err(err),
-            };
-
-            let relation_id = match potential_relation_id {
-                Some(id) => id,
-                None => return Result::Ok(None),
-            };
-
-            match self.connection.execute(format!("INSERT INTO protocols(relation_id, protocol_uuid) VALUES ({}, '{}')", relation_id, protocol_uuid)) {
-                Ok(_) => {},
-                Err(err) => return Result::Err(err),
-            };
+        if let Some(season_id) = update.season_id {
+            self.season_id = season_id;
         }
 
-        Result::Ok(Some(protocol_uuid.to_string()))
+        if let Some(year) = update.year {
+            self.year = year;
+        }
     }
+}
 
+/// A protocol that matched a search. The backends first look up the hits of the requested page
+/// in order and only then load the metadata of those protocols
+pub struct SearchHit {
+    pub uuid: String,
+    pub snippet: Option<String>,
+    pub rank: Option<f64>
+}
 
-    //Data Reading
-
-    pub fn search_for_protocol(&self, examiner_ids: Option<Vec<i64>>, subject_ids: Option<Vec<i64>>, stex_ids: Option<Vec<i64>>, seasons: Option<Vec<i64>>, years: Option<Vec<i64>>) -> Result<Option<Vec<OutputProtocol>>, Error> {
-        
-        let mut search_clause = "".to_string();
-        let mut need_and = false;
-
-        need_and = self.build_search_criteria(examiner_ids, &mut search_clause, need_and, "examiner_id");
-        need_and = self.build_search_criteria(subject_ids, &mut search_clause, need_and, "subject_id");
-        need_and = self.build_search_criteria(stex_ids, &mut search_clause, need_and, "stex_id");
-        need_and = self.build_search_criteria(seasons, &mut search_clause, need_and, "season_id");
-        let _ = self.build_search_criteria(years, &mut search_clause, need_and, "year");
-
-        let query = format!("
-            SELECT protocol_uuid          AS uuid,
-                   examiners.display_name AS examiner,
-                   subjects.display_name  AS subject,
-                   stex.display_name      AS stex,
-                   seasons.display_name   AS season,
-                   year
-            FROM (SELECT protocol_uuid, examiner_id, subject_id, season_id, stex_id, year
-                  FROM (SELECT examiner_id, subject_id, season_id, stex_id, year, protocol_uuid
-                        FROM subject_relations
-                                 JOIN protocols ON subject_relations.id = protocols.relation_id)
-                  WHERE {})
-                     JOIN examiners ON examiner_id = examiners.id
-                     JOIN subjects ON subject_id = subjects.id
-                     JOIN stex ON stex_id = stex.id
-                     JOIN seasons ON season_id = seasons.id;
-        ", search_clause);
-
-        let mut statement = self.connection.prepare(&query)?;
-
-        let mut working_search_results: HashMap<String, OutputProtocol> = HashMap::new();
-
-        while let Ok(State::Row) = statement.next() {
-
-            let uuid = statement.read::<String, _>("uuid")?;
-            let examiner = statement.read::<String, _>("examiner")?;
-            let subject = statement.read::<String, _>("subject")?;
-            let stex = statement.read::<String, _>("stex")?;
-            let season = statement.read::<String, _>("season")?;
-            let year = statement.read::<i64, _>("year")?;
-
-            match working_search_results.get_mut(&uuid) {
-                Some(protocol) => {
-                    if !protocol.examiners.contains(&examiner) {
-                        protocol.examiners.push(examiner);
-                    }
-
-                    if !protocol.subjects.contains(&subject) {
-                        protocol.subjects.push(subject);
-                    }
-
-                    if !protocol.stex.contains(&stex) {
-                        protocol.stex.push(stex);
-                    }
-
-                    if !protocol.season.contains(&season) {
-                        protocol.season.push(season);
-                    }
-
-                    if !protocol.years.contains(&year) {
-                        protocol.years.push(year)
-                    }
-                },
-                None => {
-                    working_search_results.insert(uuid.clone(), OutputProtocol { uuid, examiners: vec![examiner], subjects: vec![subject], stex: vec![stex], season: vec![season], years: vec![year] });
-                },
-            };
-        }
+/// A protocol comes out of the database as one row per examiner/subject pair.
+/// This merges such a row into the OutputProtocol with the same UUID (or creates it)
+pub fn merge_protocol_row(protocols: &mut HashMap<String, OutputProtocol>, row: ProtocolRow) {
+    match protocols.get_mut(&row.uuid) {
+        Some(protocol) => {
+            if !protocol.examiners.contains(&row.examiner) {
+                protocol.examiners.push(row.examiner);
+            }
 
-        let mut search_results = vec![];
+            if !protocol.subjects.contains(&row.subject) {
+                protocol.subjects.push(row.subject);
+            }
 
-        for (_, result) in working_search_results {
-            search_results.push(result);
-        }
+            if !protocol.stex.contains(&row.stex) {
+                protocol.stex.push(row.stex);
+            }
 
-        Result::Ok(Some(search_results))
-    }
+            if !protocol.season.contains(&row.season) {
+                protocol.season.push(row.season);
+            }
 
+            if !protocol.years.contains(&row.year) {
+                protocol.years.push(row.year)
+            }
+        },
+        None => {
+            protocols.insert(row.uuid.clone(), OutputProtocol { uuid: row.uuid, examiners: vec![row.examiner], subjects: vec![row.subject], stex: vec![row.stex], season: vec![row.season], years: vec![row.year], snippet: None, rank: None });
+        },
+    }
+}
 
-    pub fn get_selection_identifiers(&self) -> Result<SelectionIdentifier, Error> {
-        
-        let mut identifiers = SelectionIdentifier { examiners: vec![], subjects: vec![], stex: vec![], seasons: vec![] };
+/// Puts the merged protocols into the order of the hits and adds the snippet and rank of the hit.
+/// Hits without metadata are left out
+pub fn order_search_hits(mut protocols: HashMap<String, OutputProtocol>, hits: Vec<SearchHit>) -> Vec<OutputProtocol> {
+    let mut ordered = vec![];
 
-        match self.request_selection_identifiers("examiners" , &mut identifiers.examiners) {
-            Ok(_) => {},
-            Err(err) => return Err(err),
-        };
+    for hit in hits {
+        if let Some(mut protocol) = protocols.remove(&hit.uuid) {
+            protocol.snippet = hit.snippet.as_deref().map(snippet_html);
+            protocol.rank = hit.rank;
+            ordered.push(protocol);
+        }
+    }
 
-        match self.request_selection_identifiers("subjects" , &mut identifiers.subjects) {
-            Ok(_) => {},
-            Err(err) => return Err(err),
-        };
+    ordered
+}
 
-        match self.request_selection_identifiers("stex" , &mut identifiers.stex) {
-            Ok(_) => {},
-            Err(err) => return Err(err),
-        };
+/// Run after merging two catalog entities, in this order. Relations that became identical are collapsed into the
+/// one with the lowest ID, and so are the protocol links and revision pairs that now appear twice.
+/// Plain SQL that works for SQLite and PostgreSQL alike
+pub const COLLAPSE_DUPLICATES: [&str; 4] = [
+    "UPDATE protocols SET relation_id = (
+        SELECT MIN(twin.id)
+        FROM subject_relations own
+                 JOIN subject_relations twin ON twin.examiner_id = own.examiner_id AND twin.subject_id = own.subject_id AND twin.stex_id = own.stex_id AND twin.season_id = own.season_id AND twin.year = own.year
+        WHERE own.id = protocols.relation_id
+    ) WHERE relation_id IN (SELECT id FROM subject_relations);",
+    "DELETE FROM subject_relations WHERE EXISTS (
+        SELECT 1 FROM subject_relations twin
+        WHERE twin.id < subject_relations.id AND twin.examiner_id = subject_relations.examiner_id AND twin.subject_id = subject_relations.subject_id AND twin.stex_id = subject_relations.stex_id AND twin.season_id = subject_relations.season_id AND twin.year = subject_relations.year
+    );",
+    "DELETE FROM protocols WHERE EXISTS (
+        SELECT 1 FROM protocols twin
+        WHERE twin.id < protocols.id AND twin.protocol_uuid = protocols.protocol_uuid AND twin.relation_id = protocols.relation_id
+    );",
+    "DELETE FROM protocol_revision_pairs WHERE EXISTS (
+        SELECT 1 FROM protocol_revision_pairs twin
+        WHERE twin.id < protocol_revision_pairs.id AND twin.revision_id = protocol_revision_pairs.revision_id AND twin.examiner_id = protocol_revision_pairs.examiner_id AND twin.subject_id = protocol_revision_pairs.subject_id
+    );"
+];
+
+/// Longest display name accepted for examiners, subjects, stex and seasons
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 200;
+
+/// Longest email accepted for admins, same as the RFC 5321 path limit
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
+/// An address has one @, no whitespace and a domain with at least one dot.
+/// Everything else like umlauts or apostrophes is left to the identity provider
+pub fn email_is_valid(email: &str) -> bool {
+    let regex = Regex::new(r"^[^@\s]+@[^@\s.]+(\.[^@\s.]+)+$").expect("Failed to Construct hardcoded email Regex!");
+
+    email.len() <= MAX_EMAIL_LENGTH && regex.is_match(email)
+}
 
-        match self.request_selection_identifiers("seasons" , &mut identifiers.seasons) {
-            Ok(_) => {},
-            Err(err) => return Err(err),
-        };
+/// Names of people and subjects, like "Prof. Dr. José O'Neill" or "Innere Medizin (Gastroenterologie)".
+/// Letters of any script, digits and the usual punctuation of names are fine, control characters and markup aren't
+pub fn display_name_is_valid(display_name: &str) -> bool {
+    let regex = Regex::new(r"^[\p{L}\p{M}\p{N} .,'’\-_()&/]+$").expect("Failed to Assemble Hardcoded Regex!");
 
-        Ok(identifiers)
-    }
+    !display_name.trim().is_empty() && display_name.chars().count() <= MAX_DISPLAY_NAME_LENGTH && regex.is_match(display_name)
+}
 
-    pub fn get_admins(&self) -> Result<Vec<String>, Error> {
-        let mut statement = match self.connection.prepare("SELECT email FROM admins;") {
-            Ok(statement) => statement,
-            Err(err) => return Err(err),
-        };
+#[cfg(test)]
+mod tests {
+    use crate::{storage::testing::{create_catalog, save_protocol, TestDirectory}, structs::configuration::{Configuration, DatabaseBackend}};
 
+    use super::*;
 
-        let mut admins = vec![];
+    /// SQLite with the database file and the protocols at the given places
+    fn sqlite_configuration(file_location: &Path, protocol_location: &Path) -> Configuration {
+        let mut configuration = Configuration::default();
+        configuration.database_type = DatabaseBackend::SQLLite { file_location: file_location.to_string_lossy().to_string() };
+        configuration.general.protocol_location = protocol_location.to_string_lossy().to_string();
+        configuration
+    }
 
-        while let Ok(State::Row) = statement.next() {
-            let mail = match statement.read::<String, _>("email") {
-                Ok(mail) => mail,
-                Err(err) => return Err(err),
-            };
-            admins.push(mail);
+    /// The error of a failed open, the store itself can't be printed
+    async fn open_error(configuration: &Configuration) -> String {
+        match open_database(configuration).await {
+            Ok(_) => panic!("The database was opened"),
+            Err(err) => err.to_string(),
         }
-
-        Ok(admins)
     }
 
-    //Helper Methods
+    #[tokio::test]
+    async fn sqlite_is_opened_at_the_configured_locations() {
+        let directory = TestDirectory::create();
+        let file_location = directory.0.join("data/index.db");
+        let protocol_location = directory.0.join("texts");
 
-    fn request_selection_identifiers(&self, target_table: &str, identifiers: &mut Vec<SelectionIdentifierPair>) -> Result<(), Error> {
-        let query = format!("SELECT * FROM {};", target_table);
-        let mut statement = match self.connection.prepare(&query) {
-            Ok(statement) => statement,
-            Err(err) => return Err(err),
-        };
+        let database = open_database(&sqlite_configuration(&file_location, &protocol_location)).await.unwrap();
+        create_catalog(database.as_ref()).await;
+        let uuid = save_protocol(database.as_ref(), 2023, "Plexus brachialis").await;
 
-        while let Ok(State::Row) = statement.next() {
-            let display_name = match statement.read::<String, _>("display_name") {
-                Ok(id) => id,
-                Err(err) => return Err(err),
-            };
+        assert!(file_location.is_file());
+        assert_eq!(std::fs::read_to_string(protocol_location.join(format!("{}.txt", uuid))).unwrap(), "Plexus brachialis");
+        assert_eq!(std::fs::read_dir(directory.0.join("protocols")).unwrap().count(), 0);
+    }
 
-            let id = match statement.read::<i64, _>("id") {
-                Ok(id) => id,
-                Err(err) => return Err(err),
-            };
+    #[tokio::test]
+    async fn unwritable_directories_are_named_in_the_error() {
+        let directory = TestDirectory::create();
+        // A file where a directory should be, that even root can't write into
+        let blocker = directory.0.join("blocker");
+        std::fs::write(&blocker, "").unwrap();
 
-            identifiers.push(SelectionIdentifierPair { id, display_name });
-        }
+        let error = open_error(&sqlite_configuration(&blocker.join("index.db"), &directory.0.join("protocols"))).await;
+        assert!(error.contains(&format!("{} is not a writable directory", blocker.display())), "{}", error);
 
-        Ok(())
+        let error = open_error(&sqlite_configuration(&directory.0.join("index.db"), &blocker.join("texts"))).await;
+        assert!(error.contains(&format!("{} is not a writable directory", blocker.join("texts").display())), "{}", error);
+        assert!(!directory.0.join("index.db").exists());
     }
 
-    #[allow(unused_assignments)]//<- The linter doesn't like what "need_and" does... 
-    fn build_search_criteria(&self, input_ids: Option<Vec<i64>>, search_clause: &mut String, mut need_and: bool, search_criteria: &str) -> bool{
-        if let Some(ids) = input_ids { 
-            if need_and {
-                need_and = false;
-                search_clause.push_str(" AND ")
-            }
-            search_clause.push('(');
-            for i in 0..ids.len() {
-                search_clause.push_str(&format!("{} = {}", search_criteria, &ids[i].to_string()));
-                if i + 1 < (ids.len()) {
-                    search_clause.push_str(" OR ")
-                }
-            }
-            search_clause.push(')');
-            need_and = true;
-        } else {
-            need_and = false;
-        }
+    #[tokio::test]
+    async fn initial_admins_are_seeded_into_an_empty_store_only() {
+        let database: Arc<dyn ProtocolStore> = Arc::new(MemoryDatabase::new());
+        let initial_admins = vec!["admin@example.org".to_string(), "no email".to_string(), "zweite@example.org".to_string()];
 
-        need_and
-    }
+        seed_initial_admins(&database, &[]).await.unwrap();
+        assert!(database.get_admins().await.unwrap().is_empty());
 
-    /// This method returns a UUID that is Unique (in this database)
-    /// Potentially it can recourse an infinite amount of times, but thats statistically VERY VERY
-    /// VERY UNLIKELY
-    fn get_new_uuid(&self) -> Option<String> {
-        let potential_uuid = Uuid::new_v4().to_string();
-
-        let potential_id = match self.if_exists(&format!("SELECT id FROM protocols WHERE protocol_uuid = '{}';", potential_uuid)) {
-            Ok(exists) => exists,
-            Err(err) => {
-                println!("Failed to check if uuid exists: {:?}", err); 
-                return None;
-            }
-        };
+        // The invalid email is skipped, the others still get their rights
+        seed_initial_admins(&database, &initial_admins).await.unwrap();
+        assert_eq!(database.get_admins().await.unwrap(), vec!["admin@example.org".to_string(), "zweite@example.org".to_string()]);
 
-        match potential_id {
-            Some(_) => self.get_new_uuid(),
-            None => Some(potential_uuid),
-        }
+        // Once an admin was removed, a restart doesn't bring them back
+        assert!(database.remove_admin("zweite@example.org").await.unwrap());
+        seed_initial_admins(&database, &initial_admins).await.unwrap();
+        assert_eq!(database.get_admins().await.unwrap(), vec!["admin@example.org".to_string()]);
     }
 
-    /// This method returns a UUID that is Unique (in this database)
-    /// Potentially it can recourse an infinite amount of times, but thats statistically VERY VERY
-    /// VERY UNLIKELY
-    fn get_new_token_uuid(&self) -> Option<String> {
-        let potential_uuid = Uuid::new_v4().to_string();
-
-        let potential_id = match self.if_exists(&format!("SELECT id FROM sessions WHERE uuid = '{}';", potential_uuid)) {
-            Ok(exists) => exists,
-            Err(err) => {
-                println!("Failed to check if uuid exists: {:?}", err); 
-                return None;
-            }
-        };
+    #[tokio::test]
+    async fn initial_admins_are_not_added_to_existing_admins() {
+        let database: Arc<dyn ProtocolStore> = Arc::new(MemoryDatabase::new());
+        assert!(database.add_admin("bestehend@example.org").await.unwrap());
 
-        match potential_id {
-            Some(_) => self.get_new_uuid(),
-            None => Some(potential_uuid),
-        }
+        seed_initial_admins(&database, &["admin@example.org".to_string()]).await.unwrap();
+        assert_eq!(database.get_admins().await.unwrap(), vec!["bestehend@example.org".to_string()]);
     }
-    
-
-    ///This method Creates a Relation in the Relation-Table if it doesn't exist
-    ///If it does, it just returns that relation
-    fn create_relation_if_not_exist(&mut self, examiner_id: i64, subject_id: i64, stex_id: i64, season_id: i64, year: i64) -> Result<Option<i64>, Error> {
-        let query = format!("SELECT id FROM subject_relations WHERE examiner_id = {} AND subject_id = {} AND stex_id = {} AND season_id = {} AND year = {};", examiner_id, subject_id, stex_id, season_id, year);
-    
-        let potential_id = match self.if_exists(&query) {
-            Ok(exists) => exists,
-            Err(err) => {
-                return Result::Err(err)
-            }
-        };
 
-        if let Some(id) = potential_id {
-            return Result::Ok(Some(id))
+    #[test]
+    fn emails_with_umlauts_and_apostrophes_are_valid() {
+        for email in ["admin@example.org", "josé.o'neill@example.org", "straße@universität.de", "a+b@mail.example.org"] {
+            assert!(email_is_valid(email), "{}", email);
         }
 
-        match self.connection.execute(format!("INSERT INTO subject_relations(examiner_id, subject_id, stex_id, season_id, year) VALUES ({}, {}, {}, {}, {});", examiner_id, subject_id, stex_id, season_id, year)) {
-            Ok(_) => {},
-            Err(err) => return Result::Err(err),
-        }
-
-        let potential_id = match self.if_exists(&query) {
-            Ok(exists) => exists,
-            Err(err) => {
-                return Result::Err(err)
-            }
-        };
-
-        match potential_id {
-            Some(id) => Result::Ok(Some(id)),
-            None => Result::Ok(None),
+        for email in ["", "admin", "admin@example", "admin@@example.org", "ad min@example.org", "admin@example.org\n", "admin@.org", "admin@example..org"] {
+            assert!(!email_is_valid(email), "{:?}", email);
         }
 
+        assert!(email_is_valid(&format!("{}@example.org", "a".repeat(MAX_EMAIL_LENGTH - 12))));
+        assert!(!email_is_valid(&format!("{}@example.org", "a".repeat(MAX_EMAIL_LENGTH - 11))));
     }
 
-    fn if_exists(&self, query: &str) -> Result<Option<i64>, Error> {
-        let mut statement = match self.connection.prepare(query) {
-            Ok(statement) => statement,
-            Err(err) => {
-                return Result::Err(err);
-            },
-        };
-
-        if let Ok(State::Row) = statement.next() {
-            Result::Ok(
-                match statement.read::<i64, _>("id") {
-                    Ok(id) => Some(id),
-                    Err(err) => {
-                        return Result::Err(err)
-                    },
-                }
-            )
-        } else {
-            Result::Ok(None)
+    #[test]
+    fn display_names_allow_the_punctuation_of_names() {
+        for name in ["Prof. Dr. José O'Neill", "Dr. Meißner", "Müller-Lüdenscheidt, Jörg", "O’Brien", "Innere Medizin (Gastroenterologie)", "Frühjahr 2024", "HNO & Augen / Haut", "Ærøskøbing"] {
+            assert!(display_name_is_valid(name), "{}", name);
         }
-    }
-}
 
-pub fn get_current_time_seconds() -> u64 {
-    let start = SystemTime::now();
-    let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("naja lolm, die Zeit hat sich zurückbewegt...");
-    since_the_epoch.as_secs()
-}
-
-fn email_is_safe(potentially_unsafe_email: &str) -> bool {
-    let regex = Regex::new(r"^(([a-zA-Z]|[0-9]|-|_)*(\.)?)*\+?([a-zA-Z]|[0-9])*@(([a-zA-Z]|[0-9]|-)*(\.)?)*([a-zA-Z]|[0-9])*\.([a-zA-Z]|[0-9])*$").expect("Failed to Construct hardcoded email Regex!");
+        for name in ["", "   ", "<b>Dr. Muster</b>", "Dr. Muster\n", "Dr.\tMuster", "Muster; DROP TABLE", "\"Muster\""] {
+            assert!(!display_name_is_valid(name), "{:?}", name);
+        }
 
-    regex.captures(potentially_unsafe_email).is_some()
+        assert!(display_name_is_valid(&"é".repeat(MAX_DISPLAY_NAME_LENGTH)));
+        assert!(!display_name_is_valid(&"é".repeat(MAX_DISPLAY_NAME_LENGTH + 1)));
+    }
 }
@@ -0,0 +1,450 @@
+use std::{collections::HashMap, io, sync::Arc, time::SystemTime};
+
+use aes_gcm::{aead::{Aead, KeyInit, Payload}, Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::{thread_rng, RngCore};
+use tokio::io::AsyncReadExt;
+
+use crate::structs::configuration::Encryption;
+
+use super::{blob_store::{in_memory_text, new_text_key, BlobStore}, protocol_store::ProtocolText};
+
+/// Encrypted texts start with this, followed by the length of the key ID, the key ID, the nonce and the ciphertext
+const MAGIC: &[u8] = b"PDBENC1";
+const NONCE_LENGTH: usize = 12;
+const KEY_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 16;
+
+/// Encrypted texts are decrypted in memory as a whole, so no text may be larger than this
+pub const MAX_TEXT_LENGTH: usize = 8 * 1024 * 1024;
+/// The header with the longest possible key ID, the nonce and the tag
+const MAX_OVERHEAD: usize = MAGIC.len() + 1 + u8::MAX as usize + NONCE_LENGTH + TAG_LENGTH;
+
+/// AES-256-GCM with the `protocol_keys` from the config.
+/// Every text names the ID of the key it was encrypted with, so old keys keep working for reading after a rotation
+pub struct ProtocolCipher {
+    keys: HashMap<String, Aes256Gcm>,
+    active_key_id: Option<String>,
+}
+
+impl ProtocolCipher {
+    /// None if there are no protocol keys, the texts are stored as plaintext then
+    pub fn from_configuration(encryption: &Encryption) -> io::Result<Option<ProtocolCipher>> {
+        if encryption.protocol_keys.is_empty() {
+            return match &encryption.active_protocol_key {
+                Some(key_id) => Err(invalid_key(format!("encryption.active_protocol_key {:?} is not one of the protocol_keys", key_id))),
+                None => Ok(None),
+            };
+        }
+
+        let mut keys = HashMap::new();
+
+        for (key_id, key) in &encryption.protocol_keys {
+            if key_id.is_empty() || key_id.len() > u8::MAX as usize {
+                return Err(invalid_key(format!("encryption.protocol_keys contains the ID {:?}, IDs need 1 to 255 bytes", key_id)));
+            }
+
+            let key = match hex::decode(key.trim()) {
+                Ok(key) if key.len() == KEY_LENGTH => key,
+                _ => return Err(invalid_key(format!("encryption.protocol_keys.{} has to be {} hex characters", key_id, KEY_LENGTH * 2))),
+            };
+
+            match Aes256Gcm::new_from_slice(&key) {
+                Ok(cipher) => keys.insert(key_id.clone(), cipher),
+                Err(_) => return Err(invalid_key(format!("encryption.protocol_keys.{} is not a valid key", key_id))),
+            };
+        }
+
+        if let Some(key_id) = &encryption.active_protocol_key {
+            if !keys.contains_key(key_id) {
+                return Err(invalid_key(format!("encryption.active_protocol_key {:?} is not one of the protocol_keys", key_id)));
+            }
+        }
+
+        Ok(Some(ProtocolCipher { keys, active_key_id: encryption.active_protocol_key.clone() }))
+    }
+
+    pub fn active_key_id(&self) -> Option<&str> {
+        self.active_key_id.as_deref()
+    }
+
+    /// The ID of the key the text is encrypted with, None for plaintext
+    pub fn key_id_of(data: &[u8]) -> Option<String> {
+        let (key_id, _) = split_header(data)?;
+
+        Some(String::from_utf8_lossy(key_id).to_string())
+    }
+
+    /// Encrypts with the active key, without one the text stays plaintext.
+    /// The protocol UUID is authenticated along with it, so a text can't be passed off as another protocol
+    pub fn encrypt(&self, protocol_uuid: &str, plaintext: Vec<u8>) -> io::Result<Vec<u8>> {
+        let key_id = match &self.active_key_id {
+            Some(key_id) => key_id,
+            None => return Ok(plaintext),
+        };
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let aad = associated_data(key_id.as_bytes(), protocol_uuid);
+        let ciphertext = self.keys[key_id].encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad: &aad })
+            .map_err(|_| io::Error::other(format!("Failed to encrypt protocol {}", protocol_uuid)))?;
+
+        let mut data = Vec::with_capacity(MAGIC.len() + 1 + key_id.len() + NONCE_LENGTH + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.push(key_id.len() as u8);
+        data.extend_from_slice(key_id.as_bytes());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+
+        Ok(data)
+    }
+
+    /// Plaintext is passed through, so texts from before the encryption was turned on can still be read
+    pub fn decrypt(&self, protocol_uuid: &str, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let (key_id, rest) = match split_header(&data) {
+            Some(header) => header,
+            None => return Ok(data),
+        };
+
+        let key_id = String::from_utf8_lossy(key_id).to_string();
+
+        let cipher = match self.keys.get(&key_id) {
+            Some(cipher) => cipher,
+            None => return Err(invalid_key(format!("protocol {} is encrypted with the unknown key {:?}", protocol_uuid, key_id))),
+        };
+
+        if rest.len() < NONCE_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("protocol {} is truncated", protocol_uuid)));
+        }
+
+        let (nonce, ciphertext) = rest.split_at(NONCE_LENGTH);
+        let aad = associated_data(key_id.as_bytes(), protocol_uuid);
+
+        cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("protocol {} failed to decrypt with key {:?}, it was changed or belongs to another protocol", protocol_uuid, key_id)))
+    }
+}
+
+/// Revision texts are kept in the database as base64 of the encrypted text, next to the ID of their key.
+/// Without a cipher or an active key they are stored as they are and get no key ID
+pub fn seal_revision_text(cipher: Option<&ProtocolCipher>, protocol_uuid: &str, text: &str) -> io::Result<(String, Option<String>)> {
+    match cipher.and_then(|cipher| cipher.active_key_id().map(|key_id| (cipher, key_id))) {
+        Some((cipher, key_id)) => Ok((STANDARD.encode(cipher.encrypt(protocol_uuid, text.as_bytes().to_vec())?), Some(key_id.to_string()))),
+        None => Ok((text.to_string(), None)),
+    }
+}
+
+/// The reverse of seal_revision_text, a revision without key ID is plaintext
+pub fn open_revision_text(cipher: Option<&ProtocolCipher>, protocol_uuid: &str, stored: String, key_id: Option<String>) -> io::Result<String> {
+    let key_id = match key_id {
+        Some(key_id) => key_id,
+        None => return Ok(stored),
+    };
+
+    let cipher = match cipher {
+        Some(cipher) => cipher,
+        None => return Err(invalid_key(format!("a revision of protocol {} is encrypted with the key {:?}, but there are no protocol_keys", protocol_uuid, key_id))),
+    };
+
+    let data = match STANDARD.decode(stored) {
+        Ok(data) if ProtocolCipher::key_id_of(&data).as_deref() == Some(key_id.as_str()) => data,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("a revision of protocol {} is not encrypted with the key {:?} it names", protocol_uuid, key_id))),
+    };
+
+    String::from_utf8(cipher.decrypt(protocol_uuid, data)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// The full-text index has to keep the texts as plaintext to search them, so it stays empty while new texts are encrypted
+pub fn full_text_index_allowed(cipher: Option<&ProtocolCipher>) -> bool {
+    cipher.and_then(ProtocolCipher::active_key_id).is_none()
+}
+
+fn invalid_key(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn split_header(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let rest = data.strip_prefix(MAGIC)?;
+    let (key_id_length, rest) = rest.split_first()?;
+
+    if rest.len() < *key_id_length as usize {
+        return None;
+    }
+
+    Some(rest.split_at(*key_id_length as usize))
+}
+
+fn associated_data(key_id: &[u8], protocol_uuid: &str) -> Vec<u8> {
+    [key_id, b"\n", protocol_uuid.as_bytes()].concat()
+}
+
+/// `<uuid>.txt`, `<uuid>.txt.new` and `quarantine/<uuid>.txt` all belong to the same protocol
+fn protocol_uuid_of(key: &str) -> &str {
+    let file_name = key.rsplit('/').next().unwrap_or(key);
+
+    file_name.split('.').next().unwrap_or(file_name)
+}
+
+/// Encrypts everything written to the wrapped store and decrypts everything read from it
+pub struct EncryptedBlobStore {
+    inner: Arc<dyn BlobStore>,
+    cipher: Arc<ProtocolCipher>,
+}
+
+impl EncryptedBlobStore {
+    pub fn new(inner: Arc<dyn BlobStore>, cipher: Arc<ProtocolCipher>) -> EncryptedBlobStore {
+        EncryptedBlobStore { inner, cipher }
+    }
+}
+
+#[async_trait]
+impl BlobStore for EncryptedBlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        if data.len() > MAX_TEXT_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("protocol {} has {} bytes, encrypted protocols can have at most {}", protocol_uuid_of(key), data.len(), MAX_TEXT_LENGTH)));
+        }
+
+        let data = self.cipher.encrypt(protocol_uuid_of(key), data)?;
+        self.inner.put(key, data).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let data = read_limited(self.inner.as_ref(), key).await?;
+        self.cipher.decrypt(protocol_uuid_of(key), data)
+    }
+
+    /// AES-GCM only vouches for a text once all of it was checked, so it's decrypted completely before anything is passed on.
+    /// That keeps every text in memory while it's sent, which is why no text can be larger than MAX_TEXT_LENGTH
+    async fn open(&self, key: &str) -> io::Result<ProtocolText> {
+        Ok(in_memory_text(self.get(key).await?))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.inner.delete(key).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<String>> {
+        self.inner.list().await
+    }
+
+    async fn modified(&self, key: &str) -> io::Result<SystemTime> {
+        self.inner.modified(key).await
+    }
+
+    async fn check_writable(&self) -> io::Result<()> {
+        self.inner.check_writable().await
+    }
+}
+
+/// Reads a stored text, but stops before a blob larger than any encrypted text could be ends up in memory
+async fn read_limited(blobs: &dyn BlobStore, key: &str) -> io::Result<Vec<u8>> {
+    let limit = MAX_TEXT_LENGTH + MAX_OVERHEAD;
+    let mut data = vec![];
+    blobs.open(key).await?.take(limit as u64 + 1).read_to_end(&mut data).await?;
+
+    if data.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("protocol {} is larger than {} bytes and can't be decrypted in memory", protocol_uuid_of(key), limit)));
+    }
+
+    Ok(data)
+}
+
+/// How many texts `reencrypt_protocols` rewrote
+pub struct ReencryptionReport {
+    pub reencrypted: usize,
+    pub unchanged: usize,
+}
+
+/// Rewrites every protocol text that isn't encrypted with the active key, without an active key they are decrypted.
+/// The revisions in the database are left to ProtocolStore::reencrypt_revisions. Meant to run while the API is stopped, each text is replaced through a temporary blob so it's never half written
+pub async fn reencrypt_protocols(blobs: &dyn BlobStore, cipher: &ProtocolCipher) -> io::Result<ReencryptionReport> {
+    let mut report = ReencryptionReport { reencrypted: 0, unchanged: 0 };
+
+    for key in blobs.list().await? {
+        let protocol_uuid = match key.strip_suffix(".txt") {
+            Some(protocol_uuid) => protocol_uuid,
+            None => continue,
+        };
+
+        let data = read_limited(blobs, &key).await?;
+
+        if ProtocolCipher::key_id_of(&data).as_deref() == cipher.active_key_id() {
+            report.unchanged += 1;
+            continue;
+        }
+
+        let plaintext = cipher.decrypt(protocol_uuid, data)?;
+        let temporary_key = new_text_key(protocol_uuid);
+
+        blobs.put(&temporary_key, cipher.encrypt(protocol_uuid, plaintext)?).await?;
+        blobs.rename(&temporary_key, &key).await?;
+
+        println!("Re-encrypted protocol {}", protocol_uuid);
+        report.reencrypted += 1;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::storage::blob_store::LocalBlobStore;
+
+    use super::*;
+
+    const KEY_2023: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2024: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+    const UUID: &str = "4b1d3a1e-7c5f-4a55-9a3e-0d3c2f1b6a77";
+
+    fn encryption(keys: &[(&str, &str)], active: Option<&str>) -> Encryption {
+        Encryption {
+            token_encryption_secret: "secret".to_string(),
+            protocol_keys: keys.iter().map(|(key_id, key)| (key_id.to_string(), key.to_string())).collect::<BTreeMap<String, String>>(),
+            active_protocol_key: active.map(str::to_string),
+        }
+    }
+
+    fn cipher(keys: &[(&str, &str)], active: Option<&str>) -> ProtocolCipher {
+        ProtocolCipher::from_configuration(&encryption(keys, active)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn checks_configuration() {
+        assert!(ProtocolCipher::from_configuration(&encryption(&[], None)).unwrap().is_none());
+        assert!(ProtocolCipher::from_configuration(&encryption(&[], Some("2023"))).is_err());
+        assert!(ProtocolCipher::from_configuration(&encryption(&[("2023", "abcd")], Some("2023"))).is_err());
+        assert!(ProtocolCipher::from_configuration(&encryption(&[("2023", KEY_2023)], Some("2024"))).is_err());
+        assert!(ProtocolCipher::from_configuration(&encryption(&[("", KEY_2023)], None)).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let cipher = cipher(&[("2023", KEY_2023)], Some("2023"));
+
+        let data = cipher.encrypt(UUID, b"Plexus brachialis".to_vec()).unwrap();
+        assert!(data.starts_with(MAGIC));
+        assert!(!data.windows(6).any(|window| window == b"Plexus"));
+        assert_eq!(ProtocolCipher::key_id_of(&data).as_deref(), Some("2023"));
+
+        assert_eq!(cipher.decrypt(UUID, data).unwrap(), b"Plexus brachialis");
+    }
+
+    #[test]
+    fn rejects_text_of_another_protocol() {
+        let cipher = cipher(&[("2023", KEY_2023)], Some("2023"));
+        let data = cipher.encrypt(UUID, b"Plexus brachialis".to_vec()).unwrap();
+
+        let err = cipher.decrypt("0f7c2a4e-1111-4d2b-8c3a-5e6f7a8b9c0d", data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_changed_text_and_wrong_key() {
+        let data = cipher(&[("2023", KEY_2023)], Some("2023")).encrypt(UUID, b"Plexus brachialis".to_vec()).unwrap();
+
+        let mut changed = data.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert!(cipher(&[("2023", KEY_2023)], None).decrypt(UUID, changed).is_err());
+
+        // Same key ID, but another key behind it
+        assert_eq!(cipher(&[("2023", KEY_2024)], None).decrypt(UUID, data.clone()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(cipher(&[("2024", KEY_2024)], None).decrypt(UUID, data).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn old_keys_and_plaintext_stay_readable() {
+        let old = cipher(&[("2023", KEY_2023)], Some("2023")).encrypt(UUID, b"alt".to_vec()).unwrap();
+        let rotated = cipher(&[("2023", KEY_2023), ("2024", KEY_2024)], Some("2024"));
+
+        assert_eq!(rotated.decrypt(UUID, old).unwrap(), b"alt");
+        assert_eq!(rotated.decrypt(UUID, b"Klartext".to_vec()).unwrap(), b"Klartext");
+        assert_eq!(ProtocolCipher::key_id_of(&rotated.encrypt(UUID, b"neu".to_vec()).unwrap()).as_deref(), Some("2024"));
+
+        // Without an active key new texts are written as plaintext
+        assert_eq!(cipher(&[("2023", KEY_2023)], None).encrypt(UUID, b"neu".to_vec()).unwrap(), b"neu");
+    }
+
+    #[test]
+    fn revision_texts_round_trip() {
+        let cipher = cipher(&[("2023", KEY_2023)], Some("2023"));
+
+        let (stored, key_id) = seal_revision_text(Some(&cipher), UUID, "Plexus brachialis").unwrap();
+        assert_eq!(key_id.as_deref(), Some("2023"));
+        assert!(!stored.contains("Plexus"));
+        assert_eq!(open_revision_text(Some(&cipher), UUID, stored.clone(), key_id.clone()).unwrap(), "Plexus brachialis");
+
+        assert!(open_revision_text(Some(&cipher), "0f7c2a4e-1111-4d2b-8c3a-5e6f7a8b9c0d", stored.clone(), key_id.clone()).is_err());
+        assert!(open_revision_text(Some(&cipher), UUID, stored.clone(), Some("2024".to_string())).is_err());
+        assert!(open_revision_text(None, UUID, stored, key_id).is_err());
+
+        assert_eq!(seal_revision_text(None, UUID, "Klartext").unwrap(), ("Klartext".to_string(), None));
+        assert_eq!(open_revision_text(Some(&cipher), UUID, "Klartext".to_string(), None).unwrap(), "Klartext");
+    }
+
+    #[test]
+    fn keys_belong_to_their_protocol() {
+        assert_eq!(protocol_uuid_of("4b1d.txt"), "4b1d");
+        assert_eq!(protocol_uuid_of("4b1d.txt.new"), "4b1d");
+        assert_eq!(protocol_uuid_of("quarantine/4b1d.txt"), "4b1d");
+    }
+
+    #[tokio::test]
+    async fn texts_are_limited_in_size() {
+        let directory = std::env::temp_dir().join(format!("protocoldb-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let local: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(&directory));
+        let encrypted = EncryptedBlobStore::new(local.clone(), Arc::new(cipher(&[("2023", KEY_2023)], Some("2023"))));
+        let key = format!("{}.txt", UUID);
+
+        encrypted.put(&key, vec![b'a'; MAX_TEXT_LENGTH]).await.unwrap();
+        assert_eq!(encrypted.get(&key).await.unwrap().len(), MAX_TEXT_LENGTH);
+
+        assert_eq!(encrypted.put(&key, vec![b'a'; MAX_TEXT_LENGTH + 1]).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(encrypted.get(&key).await.unwrap().len(), MAX_TEXT_LENGTH);
+
+        // A plaintext blob from before the encryption that is too large to be read in one piece
+        local.put(&key, vec![b'a'; MAX_TEXT_LENGTH + MAX_OVERHEAD + 1]).await.unwrap();
+        assert_eq!(encrypted.get(&key).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(encrypted.open(&key).await.is_err());
+        assert!(reencrypt_protocols(local.as_ref(), &cipher(&[("2023", KEY_2023)], Some("2023"))).await.is_err());
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn reencrypts_stored_protocols() {
+        let directory = std::env::temp_dir().join(format!("protocoldb-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let local: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(&directory));
+
+        let old_cipher = Arc::new(cipher(&[("2023", KEY_2023)], Some("2023")));
+        EncryptedBlobStore::new(local.clone(), old_cipher).put(&format!("{}.txt", UUID), b"Plexus brachialis".to_vec()).await.unwrap();
+        local.put("plain.txt", b"Klartext".to_vec()).await.unwrap();
+
+        let rotated = Arc::new(cipher(&[("2023", KEY_2023), ("2024", KEY_2024)], Some("2024")));
+        let report = reencrypt_protocols(local.as_ref(), &rotated).await.unwrap();
+        assert_eq!((report.reencrypted, report.unchanged), (2, 0));
+
+        let stored = local.get(&format!("{}.txt", UUID)).await.unwrap();
+        assert_eq!(ProtocolCipher::key_id_of(&stored).as_deref(), Some("2024"));
+        assert_eq!(ProtocolCipher::key_id_of(&local.get("plain.txt").await.unwrap()).as_deref(), Some("2024"));
+
+        let encrypted = EncryptedBlobStore::new(local.clone(), rotated.clone());
+        assert_eq!(encrypted.get(&format!("{}.txt", UUID)).await.unwrap(), b"Plexus brachialis");
+        assert_eq!(encrypted.get("plain.txt").await.unwrap(), b"Klartext");
+        assert!(local.list().await.unwrap().iter().all(|key| key.ends_with(".txt")));
+
+        let report = reencrypt_protocols(local.as_ref(), &rotated).await.unwrap();
+        assert_eq!((report.reencrypted, report.unchanged), (0, 2));
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
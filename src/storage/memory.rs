@@ -0,0 +1,920 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::{self, Cursor}};
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{structs::{get_outputs::{ConsistencyReport, OutputProtocol, OutputRevision, OutputSession, SelectionIdentifier, SelectionIdentifierPair, UnusedEntity}, post_inputs::RepairMode}, TOKEN_VALID_LENGTH};
+
+use super::{database::{display_name_is_valid, email_is_valid, get_current_time_seconds, merge_protocol_row, order_search_hits, DatabaseError, ProtocolFiling, ProtocolRow, SearchHit, HIGHLIGHT_END, HIGHLIGHT_START}, protocol_store::{CatalogEntity, EntityChange, Pagination, ProtocolChange, ProtocolCreation, ProtocolStore, ProtocolText, ProtocolUpdate, SearchFilter, SearchResults}};
+
+/// Keeps everything in plain collections, nothing ever touches the disk.
+/// Behaves like the SQL backends, including the input validation. Requests are served one after another
+pub struct MemoryDatabase {
+    state: Mutex<MemoryState>
+}
+
+struct MemoryState {
+    /// Creation time and email by session ID
+    sessions: HashMap<String, (u64, String)>,
+    admins: Vec<String>,
+    entities: HashMap<CatalogEntity, BTreeMap<i64, String>>,
+    relations: BTreeMap<i64, Relation>,
+    protocols: Vec<(i64, String)>,
+    protocol_texts: HashMap<String, String>,
+    revisions: HashMap<String, Vec<OutputRevision>>
+}
+
+#[derive(PartialEq)]
+struct Relation {
+    examiner_id: i64,
+    subject_id: i64,
+    stex_id: i64,
+    season_id: i64,
+    year: i64
+}
+
+impl Relation {
+    fn entity_id(&self, entity: CatalogEntity) -> i64 {
+        match entity {
+            CatalogEntity::Examiner => self.examiner_id,
+            CatalogEntity::Subject => self.subject_id,
+            CatalogEntity::Stex => self.stex_id,
+            CatalogEntity::Season => self.season_id,
+        }
+    }
+
+    fn entity_id_mut(&mut self, entity: CatalogEntity) -> &mut i64 {
+        match entity {
+            CatalogEntity::Examiner => &mut self.examiner_id,
+            CatalogEntity::Subject => &mut self.subject_id,
+            CatalogEntity::Stex => &mut self.stex_id,
+            CatalogEntity::Season => &mut self.season_id,
+        }
+    }
+}
+
+impl MemoryDatabase {
+    pub fn new() -> MemoryDatabase {
+        MemoryDatabase { state: Mutex::new(MemoryState::new()) }
+    }
+}
+
+#[async_trait]
+impl ProtocolStore for MemoryDatabase {
+    //Sessions
+
+    async fn save_access_token(&self, email: &str) -> Result<Option<String>, DatabaseError> {
+        self.state.lock().await.save_access_token(email)
+    }
+
+    async fn remove_expired_sessions(&self) -> Result<(), DatabaseError> {
+        self.state.lock().await.remove_expired_sessions()
+    }
+
+    async fn is_session_valid(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        self.state.lock().await.is_session_valid(session_id)
+    }
+
+    async fn remove_session(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        self.state.lock().await.remove_session(session_id)
+    }
+
+    async fn get_sessions(&self, email: &str) -> Result<Vec<OutputSession>, DatabaseError> {
+        self.state.lock().await.get_sessions(email)
+    }
+
+    async fn remove_sessions(&self, email: &str) -> Result<u64, DatabaseError> {
+        self.state.lock().await.remove_sessions(email)
+    }
+
+    //Admins
+
+    async fn check_if_user_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        self.state.lock().await.check_if_user_admin(email)
+    }
+
+    async fn add_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        self.state.lock().await.add_admin(email)
+    }
+
+    async fn remove_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        self.state.lock().await.remove_admin(email)
+    }
+
+    async fn get_admins(&self) -> Result<Vec<String>, DatabaseError> {
+        self.state.lock().await.get_admins()
+    }
+
+    //Catalog Entities
+
+    async fn create_item(&self, entity: CatalogEntity, display_name: String) -> Result<Option<i64>, DatabaseError> {
+        self.state.lock().await.create_item(entity, display_name)
+    }
+
+    async fn get_selection_identifiers(&self) -> Result<SelectionIdentifier, DatabaseError> {
+        self.state.lock().await.get_selection_identifiers()
+    }
+
+    async fn rename_item(&self, entity: CatalogEntity, id: i64, display_name: String) -> Result<EntityChange, DatabaseError> {
+        self.state.lock().await.rename_item(entity, id, display_name)
+    }
+
+    async fn merge_items(&self, entity: CatalogEntity, from_id: i64, into_id: i64) -> Result<EntityChange, DatabaseError> {
+        self.state.lock().await.merge_items(entity, from_id, into_id)
+    }
+
+    async fn delete_item(&self, entity: CatalogEntity, id: i64) -> Result<EntityChange, DatabaseError> {
+        self.state.lock().await.delete_item(entity, id)
+    }
+
+    //Protocols
+
+    async fn save_protocol(&self, examiner_subject_relation_ids: Vec<(i64, i64)>, stex_id: i64, season_id: i64, year: i64, protocol: String, author: &str) -> Result<ProtocolCreation, DatabaseError> {
+        self.state.lock().await.save_protocol(examiner_subject_relation_ids, stex_id, season_id, year, protocol, author)
+    }
+
+    async fn update_protocol(&self, protocol_uuid: &str, update: ProtocolUpdate, author: &str) -> Result<ProtocolChange, DatabaseError> {
+        self.state.lock().await.update_protocol(protocol_uuid, update, author)
+    }
+
+    async fn delete_protocol(&self, protocol_uuid: &str) -> Result<bool, DatabaseError> {
+        self.state.lock().await.delete_protocol(protocol_uuid)
+    }
+
+    //Revisions
+
+    async fn get_revisions(&self, protocol_uuid: &str) -> Result<Option<Vec<OutputRevision>>, DatabaseError> {
+        self.state.lock().await.get_revisions(protocol_uuid)
+    }
+
+    async fn get_revision(&self, protocol_uuid: &str, revision: i64) -> Result<Option<OutputRevision>, DatabaseError> {
+        self.state.lock().await.get_revision(protocol_uuid, revision)
+    }
+
+    async fn search_for_protocol(&self, filter: SearchFilter, page: Pagination) -> Result<SearchResults, DatabaseError> {
+        self.state.lock().await.search_for_protocol(filter, page)
+    }
+
+    async fn get_protocol(&self, protocol_uuid: &str) -> Result<Option<OutputProtocol>, DatabaseError> {
+        self.state.lock().await.get_protocol(protocol_uuid)
+    }
+
+    async fn open_protocol_text(&self, protocol_uuid: &str) -> Result<ProtocolText, DatabaseError> {
+        self.state.lock().await.open_protocol_text(protocol_uuid)
+    }
+
+    //Maintenance
+
+    async fn check_consistency(&self, repair: Option<RepairMode>) -> Result<ConsistencyReport, DatabaseError> {
+        self.state.lock().await.check_consistency(repair)
+    }
+
+    /// Nothing is ever written anywhere, so there is nothing to encrypt
+    async fn reencrypt_revisions(&self) -> Result<usize, DatabaseError> {
+        Ok(0)
+    }
+}
+
+impl MemoryState {
+    fn new() -> MemoryState {
+        MemoryState {
+            sessions: HashMap::new(),
+            admins: vec![],
+            entities: HashMap::new(),
+            relations: BTreeMap::new(),
+            protocols: vec![],
+            protocol_texts: HashMap::new(),
+            revisions: HashMap::new()
+        }
+    }
+
+    //Authentication
+
+    fn save_access_token(&mut self, email: &str) -> Result<Option<String>, DatabaseError> {
+        let uuid = loop {
+            let potential_uuid = Uuid::new_v4().to_string();
+            if !self.sessions.contains_key(&potential_uuid) {
+                break potential_uuid;
+            }
+        };
+
+        self.sessions.insert(uuid.clone(), (get_current_time_seconds(), email.to_string()));
+        Ok(Some(uuid))
+    }
+
+    fn remove_expired_sessions(&mut self) -> Result<(), DatabaseError> {
+        let oldest_valid = get_current_time_seconds() - TOKEN_VALID_LENGTH;
+        self.sessions.retain(|_, (created, _)| *created >= oldest_valid);
+        Ok(())
+    }
+
+    fn is_session_valid(&mut self, session_id: &str) -> Result<bool, DatabaseError> {
+        Ok(self.sessions.contains_key(session_id))
+    }
+
+    fn remove_session(&mut self, session_id: &str) -> Result<bool, DatabaseError> {
+        Ok(self.sessions.remove(session_id).is_some())
+    }
+
+    fn get_sessions(&mut self, email: &str) -> Result<Vec<OutputSession>, DatabaseError> {
+        let oldest_valid = get_current_time_seconds() - TOKEN_VALID_LENGTH;
+
+        let mut sessions: Vec<OutputSession> = self.sessions.iter()
+            .filter(|(_, (created, session_email))| session_email == email && *created >= oldest_valid)
+            .map(|(session_id, (created, _))| OutputSession { session_id: session_id.clone(), created: *created as i64, expires: (*created + TOKEN_VALID_LENGTH) as i64 })
+            .collect();
+
+        sessions.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.session_id.cmp(&b.session_id)));
+
+        Ok(sessions)
+    }
+
+    fn remove_sessions(&mut self, email: &str) -> Result<u64, DatabaseError> {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, (_, session_email)| session_email != email);
+        Ok((before - self.sessions.len()) as u64)
+    }
+
+    fn check_if_user_admin(&mut self, email: &str) -> Result<bool, DatabaseError> {
+
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        Ok(self.admins.iter().any(|admin| admin.eq(email)))
+    }
+
+    //Data Manipulation
+
+    fn add_admin(&mut self, email: &str) -> Result<bool, DatabaseError> {
+
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        if !self.admins.iter().any(|admin| admin.eq(email)) {
+            self.admins.push(email.to_string());
+        }
+        Ok(true)
+    }
+
+    fn remove_admin(&mut self, email: &str) -> Result<bool, DatabaseError> {
+
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        self.admins.retain(|admin| !admin.eq(email));
+        Ok(true)
+    }
+
+    fn create_item(&mut self, entity: CatalogEntity, display_name: String) -> Result<Option<i64>, DatabaseError> {
+
+        if !display_name_is_valid(&display_name) {
+            println!("Got invalid Input: {:?}", display_name);
+            return Ok(None);
+        }
+
+        let items = self.entities.entry(entity).or_default();
+
+        if let Some((id, _)) = items.iter().find(|(_, name)| name.eq(&&display_name)) {
+            return Ok(Some(*id));
+        }
+
+        let id = next_id(items.keys().last());
+        items.insert(id, display_name);
+
+        Ok(Some(id))
+    }
+
+    fn rename_item(&mut self, entity: CatalogEntity, id: i64, display_name: String) -> Result<EntityChange, DatabaseError> {
+
+        if !display_name_is_valid(&display_name) {
+            println!("Got invalid Input: {:?}", display_name);
+            return Ok(EntityChange::InvalidName);
+        }
+
+        let items = self.entities.entry(entity).or_default();
+
+        if !items.contains_key(&id) {
+            return Ok(EntityChange::NotFound);
+        }
+
+        if items.iter().any(|(other_id, name)| *other_id != id && name.eq(&display_name)) {
+            return Ok(EntityChange::NameTaken);
+        }
+
+        items.insert(id, display_name);
+
+        Ok(EntityChange::Done)
+    }
+
+    fn merge_items(&mut self, entity: CatalogEntity, from_id: i64, into_id: i64) -> Result<EntityChange, DatabaseError> {
+
+        if self.display_name(entity, from_id).is_none() || self.display_name(entity, into_id).is_none() {
+            return Ok(EntityChange::NotFound);
+        }
+
+        if from_id == into_id {
+            return Ok(EntityChange::Done);
+        }
+
+        for relation in self.relations.values_mut() {
+            let entity_id = relation.entity_id_mut(entity);
+            if *entity_id == from_id {
+                *entity_id = into_id;
+            }
+        }
+
+        // Relations that became identical are collapsed into the one with the lowest ID
+        let mut duplicates: HashMap<i64, i64> = HashMap::new();
+        for (id, relation) in &self.relations {
+            if let Some((twin_id, _)) = self.relations.iter().find(|(twin_id, twin)| twin_id < &id && *twin == relation) {
+                duplicates.insert(*id, *twin_id);
+            }
+        }
+
+        self.relations.retain(|id, _| !duplicates.contains_key(id));
+
+        for (relation_id, _) in self.protocols.iter_mut() {
+            if let Some(twin_id) = duplicates.get(relation_id) {
+                *relation_id = *twin_id;
+            }
+        }
+
+        let mut seen = HashSet::new();
+        self.protocols.retain(|protocol| seen.insert(protocol.clone()));
+
+        for revision in self.revisions.values_mut().flatten() {
+            match entity {
+                CatalogEntity::Examiner | CatalogEntity::Subject => {
+                    let mut pairs: Vec<(i64, i64)> = vec![];
+
+                    for (mut examiner_id, mut subject_id) in revision.examiner_subject_ids.drain(..) {
+                        let entity_id = match entity {
+                            CatalogEntity::Examiner => &mut examiner_id,
+                            _ => &mut subject_id,
+                        };
+
+                        if *entity_id == from_id {
+                            *entity_id = into_id;
+                        }
+
+                        if !pairs.contains(&(examiner_id, subject_id)) {
+                            pairs.push((examiner_id, subject_id));
+                        }
+                    }
+
+                    revision.examiner_subject_ids = pairs;
+                },
+                CatalogEntity::Stex if revision.stex_id == from_id => revision.stex_id = into_id,
+                CatalogEntity::Season if revision.season_id == from_id => revision.season_id = into_id,
+                _ => {},
+            }
+        }
+
+        self.entities.entry(entity).or_default().remove(&from_id);
+
+        Ok(EntityChange::Done)
+    }
+
+    fn delete_item(&mut self, entity: CatalogEntity, id: i64) -> Result<EntityChange, DatabaseError> {
+
+        if self.display_name(entity, id).is_none() {
+            return Ok(EntityChange::NotFound);
+        }
+
+        if self.entity_in_use(entity, id) {
+            return Ok(EntityChange::InUse);
+        }
+
+        self.relations.retain(|_, relation| relation.entity_id(entity) != id);
+        self.entities.entry(entity).or_default().remove(&id);
+
+        Ok(EntityChange::Done)
+    }
+
+    fn create_relation_if_not_exist(&mut self, examiner_id: i64, subject_id: i64, stex_id: i64, season_id: i64, year: i64) -> Result<Option<i64>, DatabaseError> {
+        let existing = self.relations.iter().find(|(_, rel)| {
+            rel.examiner_id == examiner_id && rel.subject_id == subject_id && rel.stex_id == stex_id && rel.season_id == season_id && rel.year == year
+        });
+
+        if let Some((id, _)) = existing {
+            return Ok(Some(*id));
+        }
+
+        let id = next_id(self.relations.keys().last());
+        self.relations.insert(id, Relation { examiner_id, subject_id, stex_id, season_id, year });
+
+        Ok(Some(id))
+    }
+
+    fn save_protocol(&mut self, examiner_subject_relation_ids: Vec<(i64, i64)>, stex_id: i64, season_id: i64, year: i64, protocol: String, author: &str) -> Result<ProtocolCreation, DatabaseError> {
+        let filing = ProtocolFiling { relation_ids: vec![], examiner_subject_ids: examiner_subject_relation_ids.clone(), stex_id, season_id, year };
+
+        for (entity, id) in filing.referenced_entities() {
+            if self.display_name(entity, id).is_none() {
+                println!("Got unknown {:?}-ID!: {}", entity, id);
+                return Ok(ProtocolCreation::UnknownEntity(entity, id));
+            }
+        }
+
+        let protocol_uuid = loop {
+            let potential_uuid = Uuid::new_v4().to_string();
+            if !self.protocol_texts.contains_key(&potential_uuid) {
+                break potential_uuid;
+            }
+        };
+
+        self.protocol_texts.insert(protocol_uuid.clone(), protocol.clone());
+        self.insert_revision(&protocol_uuid, &filing, protocol, author);
+
+        for (examiner_id, subject_id) in examiner_subject_relation_ids {
+            if let Some(relation_id) = self.create_relation_if_not_exist(examiner_id, subject_id, stex_id, season_id, year)? {
+                self.protocols.push((relation_id, protocol_uuid.clone()));
+            }
+        }
+
+        Ok(ProtocolCreation::Saved(protocol_uuid))
+    }
+
+    fn update_protocol(&mut self, protocol_uuid: &str, update: ProtocolUpdate, author: &str) -> Result<ProtocolChange, DatabaseError> {
+        let mut filing = match self.protocol_filing(protocol_uuid) {
+            Some(filing) => filing,
+            None => return Ok(ProtocolChange::NotFound),
+        };
+
+        filing.apply(&update);
+
+        for (entity, id) in filing.referenced_entities() {
+            if self.display_name(entity, id).is_none() {
+                println!("Got unknown {:?}-ID!: {}", entity, id);
+                return Ok(ProtocolChange::UnknownEntity(entity, id));
+            }
+        }
+
+        if update.changes_metadata() {
+            self.protocols.retain(|(_, uuid)| !uuid.eq(protocol_uuid));
+
+            for (examiner_id, subject_id) in &filing.examiner_subject_ids {
+                if let Some(relation_id) = self.create_relation_if_not_exist(*examiner_id, *subject_id, filing.stex_id, filing.season_id, filing.year)? {
+                    self.protocols.push((relation_id, protocol_uuid.to_string()));
+                }
+            }
+
+            self.remove_unused_relations(&filing.relation_ids);
+        }
+
+        if let Some(text) = update.text {
+            self.protocol_texts.insert(protocol_uuid.to_string(), text);
+        }
+
+        let text = self.protocol_texts.get(protocol_uuid).cloned().unwrap_or_default();
+        self.insert_revision(protocol_uuid, &filing, text, author);
+
+        Ok(ProtocolChange::Updated)
+    }
+
+    fn delete_protocol(&mut self, protocol_uuid: &str) -> Result<bool, DatabaseError> {
+        let filing = match self.protocol_filing(protocol_uuid) {
+            Some(filing) => filing,
+            None => return Ok(false),
+        };
+
+        self.protocols.retain(|(_, uuid)| !uuid.eq(protocol_uuid));
+        self.protocol_texts.remove(protocol_uuid);
+        self.revisions.remove(protocol_uuid);
+        self.remove_unused_relations(&filing.relation_ids);
+
+        Ok(true)
+    }
+
+    //Revisions
+
+    fn get_revisions(&self, protocol_uuid: &str) -> Result<Option<Vec<OutputRevision>>, DatabaseError> {
+        Ok(self.revisions.get(protocol_uuid).map(|revisions| {
+            revisions.iter().map(|revision| OutputRevision { text: None, ..revision.clone() }).collect()
+        }))
+    }
+
+    fn get_revision(&self, protocol_uuid: &str, revision: i64) -> Result<Option<OutputRevision>, DatabaseError> {
+        Ok(self.revisions.get(protocol_uuid).and_then(|revisions| revisions.iter().find(|stored| stored.revision == revision)).cloned())
+    }
+
+    //Data Reading
+
+    fn search_for_protocol(&self, filter: SearchFilter, page: Pagination) -> Result<SearchResults, DatabaseError> {
+
+        let matches = |ids: &Option<Vec<i64>>, value: i64| match ids {
+            Some(ids) => ids.contains(&value),
+            None => true,
+        };
+
+        let terms: Vec<Vec<char>> = match &filter.text {
+            Some(text) => text.split_whitespace().map(|term| term.chars().map(lowercase_char).collect()).collect(),
+            None => vec![],
+        };
+
+        let excluded = |ids: &Option<Vec<i64>>, value: i64| match ids {
+            Some(ids) => ids.contains(&value),
+            None => false,
+        };
+
+        // An exclusion drops the whole protocol, not just the examiner/subject pair
+        let excluded_protocols: HashSet<&str> = self.protocols.iter().filter_map(|(relation_id, uuid)| {
+            let rel = self.relations.get(relation_id)?;
+            let is_excluded = excluded(&filter.exclude_examiner_ids, rel.examiner_id) || excluded(&filter.exclude_subject_ids, rel.subject_id) || excluded(&filter.exclude_stex_ids, rel.stex_id) || excluded(&filter.exclude_seasons, rel.season_id) || excluded(&filter.exclude_years, rel.year);
+            is_excluded.then_some(uuid.as_str())
+        }).collect();
+
+        let in_year_range = |year: i64| filter.year_from.map(|from| year >= from).unwrap_or(true) && filter.year_to.map(|to| year <= to).unwrap_or(true);
+
+        let matching_rows = self.collect_output_protocols(|uuid, rel| {
+            matches(&filter.examiner_ids, rel.examiner_id) && matches(&filter.subject_ids, rel.subject_id) && matches(&filter.stex_ids, rel.stex_id) && matches(&filter.seasons, rel.season_id) && matches(&filter.years, rel.year)
+                && in_year_range(rel.year) && !excluded_protocols.contains(uuid)
+        });
+
+        let mut hits = vec![];
+
+        for (uuid, protocol) in matching_rows {
+            let (snippet, rank) = if terms.is_empty() {
+                (None, None)
+            } else {
+                match self.protocol_texts.get(&uuid).and_then(|text| text_match(text, &terms)) {
+                    Some((snippet, rank)) => (Some(snippet), Some(rank)),
+                    None => continue,
+                }
+            };
+
+            let year = protocol.years.iter().max().copied().unwrap_or(0);
+            let season = protocol.season.iter().min().cloned().unwrap_or_default();
+            let created = self.protocols.iter().position(|(_, protocol_uuid)| protocol_uuid.eq(&uuid)).unwrap_or(0);
+
+            hits.push((SearchHit { uuid, snippet, rank }, year, season, created));
+        }
+
+        // Same order as the SQL backends
+        hits.sort_by(|(a, a_year, a_season, a_created), (b, b_year, b_season, b_created)| {
+            b.rank.unwrap_or(0.0).total_cmp(&a.rank.unwrap_or(0.0))
+                .then(b_year.cmp(a_year))
+                .then(a_season.cmp(b_season))
+                .then(a_created.cmp(b_created))
+        });
+
+        let total = hits.len() as i64;
+
+        let hits: Vec<SearchHit> = hits.into_iter().skip(page.offset as usize).take(page.limit as usize).map(|(hit, _, _, _)| hit).collect();
+
+        let protocols = self.collect_output_protocols(|uuid, _| hits.iter().any(|hit| hit.uuid.eq(uuid)));
+
+        Ok(SearchResults { protocols: order_search_hits(protocols, hits), total })
+    }
+
+    fn get_protocol(&self, protocol_uuid: &str) -> Result<Option<OutputProtocol>, DatabaseError> {
+        Ok(self.collect_output_protocols(|uuid, _| uuid.eq(protocol_uuid)).remove(protocol_uuid))
+    }
+
+    fn open_protocol_text(&self, protocol_uuid: &str) -> Result<ProtocolText, DatabaseError> {
+        match self.protocol_texts.get(protocol_uuid) {
+            Some(text) => Ok(Box::new(Cursor::new(text.clone().into_bytes()))),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No text stored for this protocol!").into()),
+        }
+    }
+
+    fn get_selection_identifiers(&self) -> Result<SelectionIdentifier, DatabaseError> {
+        Ok(SelectionIdentifier {
+            examiners: self.selection_identifiers(CatalogEntity::Examiner),
+            subjects: self.selection_identifiers(CatalogEntity::Subject),
+            stex: self.selection_identifiers(CatalogEntity::Stex),
+            seasons: self.selection_identifiers(CatalogEntity::Season),
+        })
+    }
+
+    fn get_admins(&self) -> Result<Vec<String>, DatabaseError> {
+        Ok(self.admins.clone())
+    }
+
+    //Maintenance
+
+    /// The texts live next to the rows, so only relations and entities can be orphaned
+    fn check_consistency(&mut self, repair: Option<RepairMode>) -> Result<ConsistencyReport, DatabaseError> {
+        let relations_without_protocols: Vec<i64> = self.relations.keys()
+            .filter(|relation_id| !self.protocols.iter().any(|(id, _)| id == *relation_id))
+            .copied()
+            .collect();
+
+        let mut unused = vec![];
+
+        for entity in CatalogEntity::ALL {
+            for (id, display_name) in self.entities.get(&entity).into_iter().flatten() {
+                if !self.entity_in_use(entity, *id) {
+                    unused.push((entity, *id, display_name.clone()));
+                }
+            }
+        }
+
+        if let Some(repair) = repair {
+            for relation_id in &relations_without_protocols {
+                self.relations.remove(relation_id);
+            }
+
+            if repair == RepairMode::Delete {
+                for (entity, id, _) in &unused {
+                    if let Some(items) = self.entities.get_mut(entity) {
+                        items.remove(id);
+                    }
+                }
+            }
+        }
+
+        Ok(ConsistencyReport {
+            files_without_rows: vec![],
+            rows_without_files: vec![],
+            relations_without_protocols,
+            entities_without_relations: unused.into_iter().map(|(entity, id, display_name)| UnusedEntity { kind: entity.label().to_string(), id, display_name }).collect(),
+            repair,
+            restored_files: vec![]
+        })
+    }
+}
+
+impl MemoryState {
+
+    //Helper Methods
+
+    fn selection_identifiers(&self, entity: CatalogEntity) -> Vec<SelectionIdentifierPair> {
+        match self.entities.get(&entity) {
+            Some(items) => items.iter().map(|(id, display_name)| SelectionIdentifierPair { id: *id, display_name: display_name.clone() }).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Whether a protocol or a revision refers to the entity, relations without protocols don't count
+    fn entity_in_use(&self, entity: CatalogEntity, id: i64) -> bool {
+        let used_by_protocol = self.protocols.iter().any(|(relation_id, _)| self.relations.get(relation_id).map(|relation| relation.entity_id(entity) == id).unwrap_or(false));
+
+        let used_by_revision = self.revisions.values().flatten().any(|revision| match entity {
+            CatalogEntity::Examiner => revision.examiner_subject_ids.iter().any(|(examiner_id, _)| *examiner_id == id),
+            CatalogEntity::Subject => revision.examiner_subject_ids.iter().any(|(_, subject_id)| *subject_id == id),
+            CatalogEntity::Stex => revision.stex_id == id,
+            CatalogEntity::Season => revision.season_id == id,
+        });
+
+        used_by_protocol || used_by_revision
+    }
+
+    fn display_name(&self, entity: CatalogEntity, id: i64) -> Option<String> {
+        self.entities.get(&entity).and_then(|items| items.get(&id)).cloned()
+    }
+
+    fn protocol_filing(&self, protocol_uuid: &str) -> Option<ProtocolFiling> {
+        let rows = self.protocols.iter()
+            .filter(|(_, uuid)| uuid.eq(protocol_uuid))
+            .filter_map(|(relation_id, _)| {
+                let rel = self.relations.get(relation_id)?;
+                Some((*relation_id, rel.examiner_id, rel.subject_id, rel.stex_id, rel.season_id, rel.year))
+            })
+            .collect();
+
+        ProtocolFiling::from_rows(rows)
+    }
+
+    /// Records the given state of the protocol as its next revision
+    fn insert_revision(&mut self, protocol_uuid: &str, filing: &ProtocolFiling, text: String, author: &str) {
+        let revisions = self.revisions.entry(protocol_uuid.to_string()).or_default();
+
+        revisions.push(OutputRevision {
+            revision: revisions.len() as i64 + 1,
+            created: get_current_time_seconds() as i64,
+            author: Some(author.to_string()),
+            examiner_subject_ids: filing.examiner_subject_ids.clone(),
+            stex_id: filing.stex_id,
+            season_id: filing.season_id,
+            year: filing.year,
+            text: Some(text)
+        });
+    }
+
+    /// Removes those of the relations that no protocol belongs to anymore
+    fn remove_unused_relations(&mut self, relation_ids: &[i64]) {
+        for relation_id in relation_ids {
+            if !self.protocols.iter().any(|(id, _)| id == relation_id) {
+                self.relations.remove(relation_id);
+            }
+        }
+    }
+
+    /// The in-memory equivalent of the protocol JOIN in the SQL backends
+    fn collect_output_protocols(&self, filter: impl Fn(&str, &Relation) -> bool) -> HashMap<String, OutputProtocol> {
+        let mut working_search_results: HashMap<String, OutputProtocol> = HashMap::new();
+
+        for (relation_id, uuid) in &self.protocols {
+            let relation = match self.relations.get(relation_id) {
+                Some(relation) => relation,
+                None => continue,
+            };
+
+            if !filter(uuid, relation) {
+                continue;
+            }
+
+            // Same as the INNER JOINs of the SQL backends, relations pointing to missing entities are skipped
+            let (Some(examiner), Some(subject), Some(stex), Some(season)) = (
+                self.display_name(CatalogEntity::Examiner, relation.examiner_id),
+                self.display_name(CatalogEntity::Subject, relation.subject_id),
+                self.display_name(CatalogEntity::Stex, relation.stex_id),
+                self.display_name(CatalogEntity::Season, relation.season_id),
+            ) else {
+                continue;
+            };
+
+            merge_protocol_row(&mut working_search_results, ProtocolRow { uuid: uuid.clone(), examiner, subject, stex, season, year: relation.year });
+        }
+
+        working_search_results
+    }
+}
+
+fn next_id(last_id: Option<&i64>) -> i64 {
+    last_id.map(|id| id + 1).unwrap_or(1)
+}
+
+/// Case-insensitive, so every char maps to exactly one char and positions stay the same as in the original text
+fn lowercase_char(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Checks that every term appears in the text at the start of a word, so it matches words it is a prefix of like in the SQL backends.
+/// Returns a snippet around the first hit and the number of hits as rank
+fn text_match(text: &str, terms: &[Vec<char>]) -> Option<(String, f64)> {
+    const SNIPPET_CONTEXT: usize = 40;
+
+    let original: Vec<char> = text.chars().collect();
+    let lowercase: Vec<char> = original.iter().copied().map(lowercase_char).collect();
+
+    let mut hits = 0;
+    let mut first_hit: Option<(usize, usize)> = None;
+
+    for term in terms {
+        let positions: Vec<usize> = lowercase.windows(term.len()).enumerate().filter(|(position, window)| *window == term.as_slice() && (*position == 0 || !lowercase[position - 1].is_alphanumeric())).map(|(position, _)| position).collect();
+
+        let first_position = *positions.first()?;
+        hits += positions.len();
+
+        if first_hit.map(|(position, _)| first_position < position).unwrap_or(true) {
+            first_hit = Some((first_position, term.len()));
+        }
+    }
+
+    let (position, length) = first_hit?;
+    let start = position.saturating_sub(SNIPPET_CONTEXT);
+    let end = (position + length + SNIPPET_CONTEXT).min(original.len());
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(&original[start..position]);
+    snippet.push(HIGHLIGHT_START);
+    snippet.extend(&original[position..position + length]);
+    snippet.push(HIGHLIGHT_END);
+    snippet.extend(&original[position + length..end]);
+    if end < original.len() {
+        snippet.push('…');
+    }
+
+    Some((snippet, hits as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::storage::testing::{create_catalog, save_protocol, text_filter};
+
+    use super::*;
+
+    /// A store with one examiner, subject, stex and season, all with the ID 1
+    async fn catalog() -> MemoryDatabase {
+        let database = MemoryDatabase::new();
+        create_catalog(&database).await;
+        database
+    }
+
+    async fn read_text(database: &MemoryDatabase, uuid: &str) -> String {
+        let mut text = String::new();
+        database.open_protocol_text(uuid).await.unwrap().read_to_string(&mut text).await.unwrap();
+        text
+    }
+
+    const PAGE: Pagination = Pagination { limit: 50, offset: 0 };
+
+    #[tokio::test]
+    async fn create_item_returns_existing_id_for_same_name() {
+        let database = catalog().await;
+        assert_eq!(database.create_item(CatalogEntity::Examiner, "Dr. Muster".to_string()).await.unwrap(), Some(1));
+        assert_eq!(database.create_item(CatalogEntity::Examiner, "Dr. Beispiel".to_string()).await.unwrap(), Some(2));
+        assert_eq!(database.create_item(CatalogEntity::Examiner, "".to_string()).await.unwrap(), None);
+        assert_eq!(database.get_selection_identifiers().await.unwrap().examiners.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn save_protocol_rejects_unknown_entity() {
+        let database = catalog().await;
+        let creation = database.save_protocol(vec![(1, 1)], 7, 1, 2023, "Text".to_string(), "admin@example.org").await.unwrap();
+        assert_eq!(creation, ProtocolCreation::UnknownEntity(CatalogEntity::Stex, 7));
+        assert_eq!(database.search_for_protocol(text_filter(None), PAGE).await.unwrap().total, 0);
+    }
+
+    #[tokio::test]
+    async fn saved_protocol_can_be_fetched_and_searched() {
+        let database = catalog().await;
+        let uuid = save_protocol(&database, 2023, "Gefragt wurde nach dem Plexus brachialis").await;
+
+        let protocol = database.get_protocol(&uuid).await.unwrap().unwrap();
+        assert_eq!(protocol.examiners, vec!["Dr. Muster".to_string()]);
+        assert_eq!(protocol.years, vec![2023]);
+        assert_eq!(read_text(&database, &uuid).await, "Gefragt wurde nach dem Plexus brachialis");
+
+        let results = database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap();
+        assert_eq!(results.total, 1);
+        assert!(results.protocols[0].snippet.as_ref().unwrap().contains("Plexus"));
+
+        assert_eq!(database.search_for_protocol(text_filter(Some("niere")), PAGE).await.unwrap().total, 0);
+        assert!(database.get_protocol("unknown").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn search_terms_match_at_word_starts() {
+        let database = catalog().await;
+        save_protocol(&database, 2023, "Fragen zur Anatomie, dann Nieren-Physiologie").await;
+
+        assert_eq!(database.search_for_protocol(text_filter(Some("anat")), PAGE).await.unwrap().total, 1);
+        assert_eq!(database.search_for_protocol(text_filter(Some("physio")), PAGE).await.unwrap().total, 1);
+        assert_eq!(database.search_for_protocol(text_filter(Some("atom")), PAGE).await.unwrap().total, 0);
+        assert_eq!(database.search_for_protocol(text_filter(Some("ologie")), PAGE).await.unwrap().total, 0);
+    }
+
+    #[tokio::test]
+    async fn update_protocol_adds_revision() {
+        let database = catalog().await;
+        let uuid = save_protocol(&database, 2023, "Erste Fassung").await;
+
+        let update = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: Some(2024), text: Some("Zweite Fassung".to_string()) };
+        assert_eq!(database.update_protocol(&uuid, update, "admin@example.org").await.unwrap(), ProtocolChange::Updated);
+
+        assert_eq!(read_text(&database, &uuid).await, "Zweite Fassung");
+        let revisions = database.get_revisions(&uuid).await.unwrap().unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(database.get_revision(&uuid, 1).await.unwrap().unwrap().year, 2023);
+        assert_eq!(database.get_revision(&uuid, 2).await.unwrap().unwrap().year, 2024);
+
+        let unknown_season = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: Some(9), year: None, text: None };
+        assert_eq!(database.update_protocol(&uuid, unknown_season, "admin@example.org").await.unwrap(), ProtocolChange::UnknownEntity(CatalogEntity::Season, 9));
+
+        let missing = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: Some(2020), text: None };
+        assert_eq!(database.update_protocol("unknown", missing, "admin@example.org").await.unwrap(), ProtocolChange::NotFound);
+    }
+
+    #[tokio::test]
+    async fn delete_protocol_removes_revisions() {
+        let database = catalog().await;
+        let uuid = save_protocol(&database, 2023, "Text").await;
+
+        assert!(database.delete_protocol(&uuid).await.unwrap());
+        assert!(!database.delete_protocol(&uuid).await.unwrap());
+        assert!(database.get_protocol(&uuid).await.unwrap().is_none());
+        assert!(database.get_revisions(&uuid).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn entity_in_use_cant_be_deleted() {
+        let database = catalog().await;
+        save_protocol(&database, 2023, "Text").await;
+
+        assert_eq!(database.delete_item(CatalogEntity::Stex, 1).await.unwrap(), EntityChange::InUse);
+        assert_eq!(database.delete_item(CatalogEntity::Stex, 5).await.unwrap(), EntityChange::NotFound);
+        assert_eq!(database.rename_item(CatalogEntity::Stex, 1, "M2".to_string()).await.unwrap(), EntityChange::Done);
+        assert_eq!(database.get_selection_identifiers().await.unwrap().stex[0].display_name, "M2");
+    }
+
+    #[tokio::test]
+    async fn sessions_and_admins() {
+        let database = MemoryDatabase::new();
+
+        let session_id = database.save_access_token("user@example.org").await.unwrap().unwrap();
+        assert!(database.is_session_valid(&session_id).await.unwrap());
+        assert_eq!(database.get_sessions("user@example.org").await.unwrap().len(), 1);
+        assert!(database.remove_session(&session_id).await.unwrap());
+        assert!(!database.is_session_valid(&session_id).await.unwrap());
+
+        assert!(!database.check_if_user_admin("user@example.org").await.unwrap());
+        assert!(database.add_admin("user@example.org").await.unwrap());
+        assert!(!database.add_admin("no email").await.unwrap());
+        assert!(database.check_if_user_admin("user@example.org").await.unwrap());
+        assert_eq!(database.get_admins().await.unwrap(), vec!["user@example.org".to_string()]);
+    }
+}
@@ -0,0 +1,304 @@
+use super::database::DatabaseError;
+
+/// A numbered change to the schema. Migrations are applied in order on startup, every one of them in
+/// its own transaction. Once released, a migration must never change again, add a new one instead.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sqlite: &'static str,
+    pub postgres: &'static str
+}
+
+pub const SCHEMA_VERSION_TABLE_SQLITE: &str = "CREATE TABLE IF NOT EXISTS 'schema_version' (version INTEGER not null\nconstraint schema_version_pk\nprimary key, description TEXT not null, applied INTEGER not null);";
+
+pub const SCHEMA_VERSION_TABLE_POSTGRES: &str = "CREATE TABLE IF NOT EXISTS schema_version (version BIGINT constraint schema_version_pk primary key, description TEXT not null, applied BIGINT not null);";
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        // Databases from before the migrations existed already have these tables, hence IF NOT EXISTS
+        sqlite: "
+            CREATE TABLE IF NOT EXISTS 'sessions' (id INTEGER not null\nconstraint tokens_pk\nprimary key autoincrement, uuid TEXT not null, created INT not null);
+            CREATE TABLE IF NOT EXISTS 'admins' (id INTEGER not null\nconstraint admins_pk\nprimary key autoincrement, email TEXT not null);
+            CREATE TABLE IF NOT EXISTS 'examiners' (id INTEGER not null\nconstraint examiners_pk\nprimary key autoincrement, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS 'subjects' (id INTEGER not null\nconstraint subjects_pk\nprimary key autoincrement, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS 'stex' (id INTEGER not null\nconstraint stex_pk\nprimary key autoincrement, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS 'seasons' (id INTEGER not null\nconstraint seasons_pk\nprimary key autoincrement, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS 'subject_relations' (
+                id INTEGER not null\nconstraint subject_relations_pk\nprimary key autoincrement,
+                examiner_id INTEGER not null\nconstraint subject_relations_examiners_id_fk\nreferences examiners,
+                subject_id INTEGER not null\nconstraint subject_relations_subjects_id_fk\nreferences subjects,
+                stex_id INTERGER not null\nconstraint subject_relations_stex_id_fk\nreferences stex,
+                season_id INTEGER not null\nconstraint subject_relations_seasons_id_fk\nreferences seasons,
+                year INTEGER not null
+            );
+            CREATE TABLE IF NOT EXISTS 'protocols' (
+                id INTEGER not null\nconstraint protocols_pk\nprimary key autoincrement,
+                relation_id INTEGER not null\nconstraint protocols_subject_relations_id_fk\nreferences subject_relations,
+                protocol_uuid VARCHAR(36) not null
+            );
+        ",
+        postgres: "
+            CREATE TABLE IF NOT EXISTS sessions (id BIGSERIAL constraint tokens_pk primary key, uuid TEXT not null, created BIGINT not null);
+            CREATE TABLE IF NOT EXISTS admins (id BIGSERIAL constraint admins_pk primary key, email TEXT not null);
+            CREATE TABLE IF NOT EXISTS examiners (id BIGSERIAL constraint examiners_pk primary key, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS subjects (id BIGSERIAL constraint subjects_pk primary key, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS stex (id BIGSERIAL constraint stex_pk primary key, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS seasons (id BIGSERIAL constraint seasons_pk primary key, display_name TEXT not null);
+            CREATE TABLE IF NOT EXISTS subject_relations (
+                id BIGSERIAL constraint subject_relations_pk primary key,
+                examiner_id BIGINT not null constraint subject_relations_examiners_id_fk references examiners,
+                subject_id BIGINT not null constraint subject_relations_subjects_id_fk references subjects,
+                stex_id BIGINT not null constraint subject_relations_stex_id_fk references stex,
+                season_id BIGINT not null constraint subject_relations_seasons_id_fk references seasons,
+                year BIGINT not null
+            );
+            CREATE TABLE IF NOT EXISTS protocols (
+                id BIGSERIAL constraint protocols_pk primary key,
+                relation_id BIGINT not null constraint protocols_subject_relations_id_fk references subject_relations,
+                protocol_uuid VARCHAR(36) not null
+            );
+        "
+    },
+    Migration {
+        version: 2,
+        description: "Fix type of subject_relations.stex_id",
+        // SQLite can't change the type of a column, so the table is rebuilt
+        sqlite: "
+            CREATE TABLE 'subject_relations_new' (
+                id INTEGER not null\nconstraint subject_relations_pk\nprimary key autoincrement,
+                examiner_id INTEGER not null\nconstraint subject_relations_examiners_id_fk\nreferences examiners,
+                subject_id INTEGER not null\nconstraint subject_relations_subjects_id_fk\nreferences subjects,
+                stex_id INTEGER not null\nconstraint subject_relations_stex_id_fk\nreferences stex,
+                season_id INTEGER not null\nconstraint subject_relations_seasons_id_fk\nreferences seasons,
+                year INTEGER not null
+            );
+            INSERT INTO subject_relations_new(id, examiner_id, subject_id, stex_id, season_id, year)
+                SELECT id, examiner_id, subject_id, stex_id, season_id, year FROM subject_relations;
+            DROP TABLE subject_relations;
+            ALTER TABLE subject_relations_new RENAME TO subject_relations;
+        ",
+        // The PostgreSQL schema never had the typo
+        postgres: ""
+    },
+    Migration {
+        version: 3,
+        description: "Full-text index of the protocol texts",
+        // Filled with the existing protocols on startup, see index_missing_protocol_texts
+        sqlite: "
+            CREATE VIRTUAL TABLE IF NOT EXISTS protocol_texts USING fts5(protocol_uuid UNINDEXED, text, tokenize = 'unicode61 remove_diacritics 2');
+        ",
+        postgres: "
+            CREATE TABLE IF NOT EXISTS protocol_texts (
+                protocol_uuid VARCHAR(36) constraint protocol_texts_pk primary key,
+                text TEXT not null,
+                search_vector tsvector GENERATED ALWAYS AS (to_tsvector('german', text)) STORED
+            );
+            CREATE INDEX IF NOT EXISTS protocol_texts_search_idx ON protocol_texts USING GIN (search_vector);
+        "
+    },
+    Migration {
+        version: 4,
+        description: "Revision history of protocols",
+        // The examiner/subject pairs get their own table, so merging catalog entities can re-point them
+        sqlite: "
+            CREATE TABLE 'protocol_revisions' (
+                id INTEGER not null\nconstraint protocol_revisions_pk\nprimary key autoincrement,
+                protocol_uuid VARCHAR(36) not null,
+                revision INTEGER not null,
+                created INTEGER not null,
+                author TEXT,
+                stex_id INTEGER not null\nconstraint protocol_revisions_stex_id_fk\nreferences stex,
+                season_id INTEGER not null\nconstraint protocol_revisions_seasons_id_fk\nreferences seasons,
+                year INTEGER not null,
+                text TEXT not null,
+                constraint protocol_revisions_revision_uq unique (protocol_uuid, revision)
+            );
+            CREATE TABLE 'protocol_revision_pairs' (
+                id INTEGER not null\nconstraint protocol_revision_pairs_pk\nprimary key autoincrement,
+                revision_id INTEGER not null\nconstraint protocol_revision_pairs_protocol_revisions_id_fk\nreferences protocol_revisions,
+                examiner_id INTEGER not null\nconstraint protocol_revision_pairs_examiners_id_fk\nreferences examiners,
+                subject_id INTEGER not null\nconstraint protocol_revision_pairs_subjects_id_fk\nreferences subjects
+            );
+        ",
+        postgres: "
+            CREATE TABLE protocol_revisions (
+                id BIGSERIAL constraint protocol_revisions_pk primary key,
+                protocol_uuid VARCHAR(36) not null,
+                revision BIGINT not null,
+                created BIGINT not null,
+                author TEXT,
+                stex_id BIGINT not null constraint protocol_revisions_stex_id_fk references stex,
+                season_id BIGINT not null constraint protocol_revisions_seasons_id_fk references seasons,
+                year BIGINT not null,
+                text TEXT not null,
+                constraint protocol_revisions_revision_uq unique (protocol_uuid, revision)
+            );
+            CREATE TABLE protocol_revision_pairs (
+                id BIGSERIAL constraint protocol_revision_pairs_pk primary key,
+                revision_id BIGINT not null constraint protocol_revision_pairs_protocol_revisions_id_fk references protocol_revisions,
+                examiner_id BIGINT not null constraint protocol_revision_pairs_examiners_id_fk references examiners,
+                subject_id BIGINT not null constraint protocol_revision_pairs_subjects_id_fk references subjects
+            );
+        "
+    },
+    Migration {
+        version: 5,
+        description: "Email of the user in every session",
+        // Sessions from before have no email, so an admin couldn't revoke them. They are ended, their users log in again
+        sqlite: "
+            DELETE FROM sessions;
+            ALTER TABLE sessions ADD COLUMN email TEXT;
+            CREATE INDEX sessions_email_idx ON sessions(email);
+        ",
+        postgres: "
+            DELETE FROM sessions;
+            ALTER TABLE sessions ADD COLUMN email TEXT;
+            CREATE INDEX sessions_email_idx ON sessions(email);
+        "
+    },
+    Migration {
+        version: 6,
+        description: "Key of encrypted revision texts",
+        // NULL means the text is plaintext, which all revisions from before are until `db reencrypt` runs
+        sqlite: "
+            ALTER TABLE protocol_revisions ADD COLUMN text_key_id TEXT;
+        ",
+        postgres: "
+            ALTER TABLE protocol_revisions ADD COLUMN text_key_id TEXT;
+        "
+    },
+];
+
+pub fn latest_schema_version() -> i64 {
+    MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+/// Refuses to work with a database that was already migrated by a newer version of this software,
+/// as we can't know what changed
+pub fn check_schema_version(database_version: i64) -> Result<(), DatabaseError> {
+    let supported_version = latest_schema_version();
+
+    if database_version > supported_version {
+        return Err(DatabaseError::SchemaTooNew { database_version, supported_version });
+    }
+
+    Ok(())
+}
+
+pub fn pending_migrations(database_version: i64) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS.iter().filter(move |migration| migration.version > database_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlite::State;
+
+    use crate::storage::{blob_store::{BlobStore, LocalBlobStore}, database::DatabaseError, protocol_store::{Pagination, ProtocolStore}, sqlite::SQLiteDatabase, testing::{text_filter, TestDirectory}};
+
+    use super::*;
+
+    async fn open(directory: &TestDirectory) -> Result<SQLiteDatabase, DatabaseError> {
+        let blobs: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(directory.0.join("protocols")));
+        SQLiteDatabase::new(&directory.0.join("index.db"), blobs, None).await
+    }
+
+    fn applied_versions(directory: &TestDirectory) -> Vec<i64> {
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        let mut statement = connection.prepare("SELECT version FROM schema_version ORDER BY version;").unwrap();
+        let mut versions = vec![];
+
+        while let State::Row = statement.next().unwrap() {
+            versions.push(statement.read::<i64, _>("version").unwrap());
+        }
+
+        versions
+    }
+
+    #[test]
+    fn versions_are_ascending_without_gaps() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1);
+        }
+
+        assert_eq!(pending_migrations(0).count(), MIGRATIONS.len());
+        assert_eq!(pending_migrations(latest_schema_version()).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn baseline_schema_is_migrated_with_its_data() {
+        let directory = TestDirectory::create();
+
+        // A database like the ones from before the migrations existed, with the stex_id typo
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        connection.execute(MIGRATIONS[0].sqlite).unwrap();
+        connection.execute("
+            INSERT INTO examiners(display_name) VALUES ('Dr. Muster');
+            INSERT INTO subjects(display_name) VALUES ('Anatomie');
+            INSERT INTO stex(display_name) VALUES ('M1');
+            INSERT INTO seasons(display_name) VALUES ('Frühjahr');
+            INSERT INTO subject_relations(examiner_id, subject_id, stex_id, season_id, year) VALUES (1, 1, 1, 1, 2019);
+            INSERT INTO protocols(relation_id, protocol_uuid) VALUES (1, 'a3c5e7f9-0000-4000-8000-000000000001');
+            INSERT INTO sessions(uuid, created) VALUES ('5e55104e-0000-4000-8000-000000000001', 0);
+        ").unwrap();
+        drop(connection);
+        std::fs::write(directory.0.join("protocols/a3c5e7f9-0000-4000-8000-000000000001.txt"), "Plexus brachialis").unwrap();
+
+        let database = open(&directory).await.unwrap();
+        assert_eq!(applied_versions(&directory), (1..=latest_schema_version()).collect::<Vec<i64>>());
+
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        let mut statement = connection.prepare("SELECT type FROM pragma_table_info('subject_relations') WHERE name = 'stex_id';").unwrap();
+        assert_eq!(statement.next().unwrap(), State::Row);
+        assert_eq!(statement.read::<String, _>("type").unwrap(), "INTEGER");
+        drop(statement);
+
+        // Without an email the old session couldn't be revoked, so it was ended
+        let mut statement = connection.prepare("SELECT COUNT(*) AS count FROM sessions;").unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<i64, _>("count").unwrap(), 0);
+
+        let protocol = database.get_protocol("a3c5e7f9-0000-4000-8000-000000000001").await.unwrap().unwrap();
+        assert_eq!(protocol.examiners, vec!["Dr. Muster".to_string()]);
+        assert_eq!(protocol.years, vec![2019]);
+
+        // The later migrations filled the search index and the revision history from the old protocol
+        let results = database.search_for_protocol(text_filter(Some("plexus")), Pagination { limit: 50, offset: 0 }).await.unwrap();
+        assert_eq!(results.total, 1);
+        assert_eq!(database.get_revisions("a3c5e7f9-0000-4000-8000-000000000001").await.unwrap().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn migrating_twice_changes_nothing() {
+        let directory = TestDirectory::create();
+
+        drop(open(&directory).await.unwrap());
+        let versions = applied_versions(&directory);
+
+        drop(open(&directory).await.unwrap());
+        assert_eq!(applied_versions(&directory), versions);
+        assert_eq!(versions.len(), MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn newer_schema_is_refused() {
+        let directory = TestDirectory::create();
+        drop(open(&directory).await.unwrap());
+
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        connection.execute(format!("INSERT INTO schema_version(version, description, applied) VALUES ({}, 'From the future', 0);", latest_schema_version() + 1)).unwrap();
+        drop(connection);
+
+        match open(&directory).await {
+            Err(DatabaseError::SchemaTooNew { database_version, supported_version }) => {
+                assert_eq!(database_version, latest_schema_version() + 1);
+                assert_eq!(supported_version, latest_schema_version());
+            },
+            Err(err) => panic!("Wrong error: {:?}", err),
+            Ok(_) => panic!("Newer schema was accepted"),
+        }
+    }
+}
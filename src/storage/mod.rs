@@ -1 +1,12 @@
 pub mod database;
+pub mod protocol_store;
+pub mod migrations;
+pub mod sqlite;
+pub mod postgres;
+pub mod memory;
+pub mod consistency;
+pub mod blob_store;
+pub mod s3;
+pub mod encryption;
+#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,1192 @@
+use std::{collections::{HashMap, HashSet}, io, sync::Arc};
+use async_trait::async_trait;
+use deadpool::unmanaged::{Object, Pool};
+use futures_util::FutureExt;
+use native_tls::{Certificate, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use tokio_postgres::{config::SslMode, types::ToSql, Client, Error, GenericClient, NoTls, Row, Transaction};
+use uuid::Uuid;
+
+use crate::{structs::{configuration::PostgresSslMode, get_outputs::{ConsistencyReport, OutputProtocol, OutputRevision, OutputSession, SelectionIdentifier, SelectionIdentifierPair, UnusedEntity}, post_inputs::RepairMode}, TOKEN_VALID_LENGTH};
+
+use super::{blob_store::{new_text_key, text_key, BlobStore}, encryption::{full_text_index_allowed, open_revision_text, seal_revision_text, ProtocolCipher}, consistency::{delete_unused_entity_query, files_without_rows, repair_orphaned_file, rows_without_files, settled_keys, unused_entities_query, DELETE_UNUSED_RELATIONS, UNUSED_RELATIONS_QUERY}, database::{display_name_is_valid, DATABASE_POOL_SIZE, email_is_valid, get_current_time_seconds, merge_protocol_row, order_search_hits, DatabaseConnectionInfo, DatabaseError, ProtocolFiling, ProtocolRow, SearchHit, HIGHLIGHT_END, HIGHLIGHT_START, COLLAPSE_DUPLICATES}, migrations::{check_schema_version, pending_migrations, SCHEMA_VERSION_TABLE_POSTGRES}, protocol_store::{CatalogEntity, EntityChange, Pagination, ProtocolChange, ProtocolCreation, ProtocolStore, ProtocolText, ProtocolUpdate, SearchFilter, SearchResults}};
+
+/// Key of the advisory lock that write transactions hold
+const WRITE_LOCK: i64 = 0x50726f746f636f6c;
+
+/// Each request gets one of a fixed number of connections that are opened at startup.
+/// A connection the server closed is opened again the next time it's taken from the pool
+pub struct PostgresDatabase {
+    pool: Pool<Client>,
+    conn_info: DatabaseConnectionInfo,
+    blobs: Arc<dyn BlobStore>,
+    /// Encrypts and decrypts the revision texts
+    cipher: Option<Arc<ProtocolCipher>>
+}
+
+impl PostgresDatabase {
+    pub async fn new(conn_info: DatabaseConnectionInfo, blobs: Arc<dyn BlobStore>, cipher: Option<Arc<ProtocolCipher>>) -> Result<PostgresDatabase, DatabaseError> {
+        let mut clients = vec![];
+
+        for _ in 0..DATABASE_POOL_SIZE {
+            clients.push(connect(&conn_info).await?);
+        }
+
+        let database = PostgresDatabase {
+            pool: Pool::from(clients),
+            conn_info,
+            blobs,
+            cipher
+        };
+
+        database.migrate().await?;
+        database.index_missing_protocol_texts().await?;
+        database.record_missing_revisions().await?;
+
+        Ok(database)
+    }
+
+    /// Waits until one of the connections is free and reconnects it if it was lost, e.g. after a restart of the server
+    async fn connection(&self) -> Result<Object<Client>, DatabaseError> {
+        let mut connection = match self.pool.get().await {
+            Ok(connection) => connection,
+            Err(err) => return Err(io::Error::other(format!("Failed to get a database connection: {}", err)).into()),
+        };
+
+        if connection.is_closed() {
+            println!("Reconnecting to PostgreSQL database...");
+            // If this fails the closed client goes back to the pool and the next request tries again
+            *connection = connect(&self.conn_info).await?;
+        }
+
+        Ok(connection)
+    }
+
+    /// Brings the schema up to date, every pending migration is applied in its own transaction
+    async fn migrate(&self) -> Result<(), DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        client.batch_execute(SCHEMA_VERSION_TABLE_POSTGRES).await?;
+
+        let database_version: i64 = client.query_one("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version;", &[]).await?.get("version");
+
+        check_schema_version(database_version)?;
+
+        for migration in pending_migrations(database_version) {
+            println!("Applying database migration {}: {}", migration.version, migration.description);
+
+            let transaction = client.transaction().await?;
+            transaction.batch_execute(migration.postgres).await?;
+            transaction.execute("INSERT INTO schema_version(version, description, applied) VALUES ($1, $2, $3);", &[&migration.version, &migration.description, &(get_current_time_seconds() as i64)]).await?;
+            transaction.commit().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds protocols that were saved before the full-text index existed to it.
+    /// While new texts are encrypted the index is emptied instead, with the texts indexed before
+    async fn index_missing_protocol_texts(&self) -> Result<(), DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+
+        if !full_text_index_allowed(self.cipher.as_deref()) {
+            client.execute("DELETE FROM protocol_texts;", &[]).await?;
+            return Ok(());
+        }
+
+        let rows = client.query("SELECT DISTINCT protocol_uuid FROM protocols WHERE protocol_uuid NOT IN (SELECT protocol_uuid FROM protocol_texts);", &[]).await?;
+
+        for row in rows {
+            let protocol_uuid: String = row.get("protocol_uuid");
+
+            let text = match self.blobs.get_text(&text_key(&protocol_uuid)).await {
+                Ok(text) => text,
+                Err(err) => {
+                    println!("Failed to index protocol {}!: {:?}", protocol_uuid, err);
+                    continue;
+                },
+            };
+
+            client.execute("INSERT INTO protocol_texts(protocol_uuid, text) VALUES ($1, $2);", &[&protocol_uuid, &text]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Protocols that were saved before the revision history existed get their current state as first revision
+    async fn record_missing_revisions(&self) -> Result<(), DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        let rows = client.query("SELECT DISTINCT protocol_uuid FROM protocols WHERE protocol_uuid NOT IN (SELECT protocol_uuid FROM protocol_revisions);", &[]).await?;
+
+        for row in rows {
+            let protocol_uuid: String = row.get("protocol_uuid");
+
+            let text = match self.blobs.get_text(&text_key(&protocol_uuid)).await {
+                Ok(text) => text,
+                Err(err) => {
+                    println!("Failed to record revision of protocol {}!: {:?}", protocol_uuid, err);
+                    continue;
+                },
+            };
+
+            let filing = match protocol_filing(client, &protocol_uuid).await? {
+                Some(filing) => filing,
+                None => continue,
+            };
+
+            let transaction = client.transaction().await?;
+            insert_revision(&transaction, self.cipher.as_deref(), &protocol_uuid, &filing, &text, None).await?;
+            transaction.commit().await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProtocolStore for PostgresDatabase {
+
+    //Authentication
+
+    async fn save_access_token(&self, email: &str) -> Result<Option<String>, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let uuid = match get_new_token_uuid(client).await {
+            Some(uuid) => uuid,
+            None => {
+                return Ok(None);
+            },
+        };
+
+        client.execute("INSERT INTO sessions(uuid, created, email) VALUES ($1, $2, $3);", &[&uuid, &(get_current_time_seconds() as i64), &email]).await?;
+
+        Ok(Some(uuid))
+    }
+
+    async fn remove_expired_sessions(&self) -> Result<(), DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        client.execute("DELETE FROM sessions WHERE created < $1;", &[&((get_current_time_seconds() - TOKEN_VALID_LENGTH) as i64)]).await?;
+        Ok(())
+    }
+
+    async fn is_session_valid(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let row = client.query_opt("SELECT uuid FROM sessions WHERE uuid = $1;", &[&session_id]).await?;
+
+        match row {
+            Some(row) => Ok(row.get::<_, String>("uuid").eq(session_id)),
+            None => Ok(false),
+        }
+    }
+
+    async fn remove_session(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let removed = client.execute("DELETE FROM sessions WHERE uuid = $1;", &[&session_id]).await?;
+        Ok(removed > 0)
+    }
+
+    async fn get_sessions(&self, email: &str) -> Result<Vec<OutputSession>, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let rows = client.query("SELECT uuid, created FROM sessions WHERE email = $1 AND created >= $2 ORDER BY created, id;", &[&email, &((get_current_time_seconds() - TOKEN_VALID_LENGTH) as i64)]).await?;
+
+        Ok(rows.iter().map(|row| {
+            let created: i64 = row.get("created");
+            OutputSession { session_id: row.get("uuid"), created, expires: created + TOKEN_VALID_LENGTH as i64 }
+        }).collect())
+    }
+
+    async fn remove_sessions(&self, email: &str) -> Result<u64, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        Ok(client.execute("DELETE FROM sessions WHERE email = $1;", &[&email]).await?)
+    }
+
+    async fn check_if_user_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        let row = client.query_opt("SELECT email FROM admins WHERE email = $1;", &[&email]).await?;
+
+        match row {
+            Some(row) => Ok(row.get::<_, String>("email").eq(&email)),
+            None => Ok(false),
+        }
+    }
+
+    //Data Manipulation
+
+    async fn add_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        let transaction = begin_write(client).await?;
+        transaction.execute("INSERT INTO admins(email) SELECT $1::TEXT WHERE NOT EXISTS (SELECT 1 FROM admins WHERE email = $1::TEXT);", &[&email]).await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    async fn remove_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        client.execute("DELETE FROM admins WHERE email = $1;", &[&email]).await?;
+        Ok(true)
+    }
+
+    async fn create_item(&self, entity: CatalogEntity, display_name: String) -> Result<Option<i64>, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        let table_name = entity.table_name();
+
+        if !display_name_is_valid(&display_name) {
+            println!("Got invalid Input: {:?}", display_name);
+            return Ok(None);
+        }
+
+        let transaction = begin_write(client).await?;
+
+        let existing = transaction.query_opt(&format!("SELECT id FROM {} WHERE display_name = $1;", table_name), &[&display_name]).await?;
+
+        if let Some(row) = existing {
+            return Ok(Some(row.get("id")));
+        }
+
+        let created = transaction.query_one(&format!("INSERT INTO {}(display_name) VALUES ($1) RETURNING id;", table_name), &[&display_name]).await?;
+        transaction.commit().await?;
+
+        Ok(Some(created.get("id")))
+    }
+
+    async fn rename_item(&self, entity: CatalogEntity, id: i64, display_name: String) -> Result<EntityChange, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        if !display_name_is_valid(&display_name) {
+            println!("Got invalid Input: {:?}", display_name);
+            return Ok(EntityChange::InvalidName);
+        }
+
+        let transaction = begin_write(client).await?;
+
+        if !entity_exists(&transaction, entity, id).await? {
+            return Ok(EntityChange::NotFound);
+        }
+
+        let taken = transaction.query_opt(&format!("SELECT id FROM {} WHERE display_name = $1 AND id != $2;", entity.table_name()), &[&display_name, &id]).await?;
+
+        if taken.is_some() {
+            return Ok(EntityChange::NameTaken);
+        }
+
+        transaction.execute(&format!("UPDATE {} SET display_name = $1 WHERE id = $2;", entity.table_name()), &[&display_name, &id]).await?;
+        transaction.commit().await?;
+
+        Ok(EntityChange::Done)
+    }
+
+    async fn merge_items(&self, entity: CatalogEntity, from_id: i64, into_id: i64) -> Result<EntityChange, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        let transaction = begin_write(client).await?;
+
+        if !entity_exists(&transaction, entity, from_id).await? || !entity_exists(&transaction, entity, into_id).await? {
+            return Ok(EntityChange::NotFound);
+        }
+
+        if from_id == into_id {
+            return Ok(EntityChange::Done);
+        }
+
+        let column = entity.column_name();
+
+        transaction.execute(&format!("UPDATE subject_relations SET {} = $1 WHERE {} = $2;", column, column), &[&into_id, &from_id]).await?;
+        transaction.execute(&format!("UPDATE {} SET {} = $1 WHERE {} = $2;", entity.revision_table_name(), column, column), &[&into_id, &from_id]).await?;
+        transaction.execute(&format!("DELETE FROM {} WHERE id = $1;", entity.table_name()), &[&from_id]).await?;
+
+        for query in COLLAPSE_DUPLICATES {
+            transaction.batch_execute(query).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(EntityChange::Done)
+    }
+
+    async fn delete_item(&self, entity: CatalogEntity, id: i64) -> Result<EntityChange, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        let transaction = begin_write(client).await?;
+
+        if !entity_exists(&transaction, entity, id).await? {
+            return Ok(EntityChange::NotFound);
+        }
+
+        let column = entity.column_name();
+
+        let usage_queries = [
+            format!("SELECT COUNT(*) AS count FROM protocols JOIN subject_relations ON subject_relations.id = protocols.relation_id WHERE subject_relations.{} = $1;", column),
+            format!("SELECT COUNT(*) AS count FROM {} WHERE {} = $1;", entity.revision_table_name(), column)
+        ];
+
+        for query in usage_queries {
+            let count: i64 = transaction.query_one(&query, &[&id]).await?.get("count");
+
+            if count > 0 {
+                return Ok(EntityChange::InUse);
+            }
+        }
+
+        // Relations without any protocol can be left over from before they were cleaned up
+        transaction.execute(&format!("DELETE FROM subject_relations WHERE {} = $1;", column), &[&id]).await?;
+        transaction.execute(&format!("DELETE FROM {} WHERE id = $1;", entity.table_name()), &[&id]).await?;
+        transaction.commit().await?;
+
+        Ok(EntityChange::Done)
+    }
+
+    async fn save_protocol(&self, examiner_subject_relation_ids: Vec<(i64, i64)>, stex_id: i64, season_id: i64, year: i64, protocol: String, author: &str) -> Result<ProtocolCreation, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        let filing = ProtocolFiling { relation_ids: vec![], examiner_subject_ids: examiner_subject_relation_ids, stex_id, season_id, year };
+
+        // Checked before the text is written as well, so a request with unknown IDs doesn't upload anything
+        for (entity, id) in filing.referenced_entities() {
+            if !entity_exists(client, entity, id).await? {
+                println!("Got unknown {:?}-ID!: {}", entity, id);
+                return Ok(ProtocolCreation::UnknownEntity(entity, id));
+            }
+        }
+
+        let protocol_uuid = match get_new_uuid(client).await {
+            Some(uuid) => uuid,
+            None => return Err(io::Error::other("Failed to generate a Protocol-UUID").into()),
+        };
+
+        // The text only gets its real name right before the commit, so a failed save leaves no blob behind
+        let protocol_key = text_key(&protocol_uuid);
+        let new_key = new_text_key(&protocol_uuid);
+        let blobs = self.blobs.clone();
+
+        blobs.put(&new_key, protocol.clone().into_bytes()).await?;
+
+        let result: Result<ProtocolCreation, DatabaseError> = async {
+            let transaction = begin_write(client).await?;
+
+            // An entity could have been deleted since the check before
+            for (entity, id) in filing.referenced_entities() {
+                if !entity_exists(&transaction, entity, id).await? {
+                    println!("Got unknown {:?}-ID!: {}", entity, id);
+                    return Ok(ProtocolCreation::UnknownEntity(entity, id));
+                }
+            }
+
+            for (examiner_id, subject_id) in &filing.examiner_subject_ids {
+                let relation_id = find_or_create_relation(&transaction, *examiner_id, *subject_id, stex_id, season_id, year).await?;
+                transaction.execute("INSERT INTO protocols(relation_id, protocol_uuid) VALUES ($1, $2);", &[&relation_id, &protocol_uuid]).await?;
+            }
+
+            if full_text_index_allowed(self.cipher.as_deref()) {
+                transaction.execute("INSERT INTO protocol_texts(protocol_uuid, text) VALUES ($1, $2);", &[&protocol_uuid, &protocol]).await?;
+            }
+            insert_revision(&transaction, self.cipher.as_deref(), &protocol_uuid, &filing, &protocol, Some(author)).await?;
+
+            blobs.rename(&new_key, &protocol_key).await?;
+
+            if let Err(err) = transaction.commit().await {
+                let _ = blobs.delete(&protocol_key).await;
+                return Err(err.into());
+            }
+
+            Ok(ProtocolCreation::Saved(protocol_uuid.clone()))
+        }.await;
+
+        // A transaction that is dropped without commit is rolled back
+        if !matches!(result, Ok(ProtocolCreation::Saved(_))) {
+            let _ = blobs.delete(&new_key).await;
+        }
+
+        result
+    }
+
+    async fn update_protocol(&self, protocol_uuid: &str, update: ProtocolUpdate, author: &str) -> Result<ProtocolChange, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        if Uuid::parse_str(protocol_uuid).is_err() {
+            println!("Got invalid Protocol-UUID!: {:?}", protocol_uuid);
+            return Ok(ProtocolChange::NotFound);
+        }
+
+        if protocol_filing(client, protocol_uuid).await?.is_none() {
+            return Ok(ProtocolChange::NotFound);
+        }
+
+        // The new text replaces the old blob right before the commit, and is undone if the commit fails
+        let protocol_key = text_key(protocol_uuid);
+        let new_key = new_text_key(protocol_uuid);
+
+        let previous_text = match latest_revision_text(client, self.cipher.as_deref(), protocol_uuid).await? {
+            Some(text) => text,
+            None => self.blobs.get_text(&protocol_key).await?,
+        };
+
+        let text = match &update.text {
+            Some(text) => {
+                self.blobs.put(&new_key, text.clone().into_bytes()).await?;
+                text.clone()
+            },
+            None => previous_text.clone(),
+        };
+
+        let result: Result<ProtocolChange, DatabaseError> = async {
+            let transaction = begin_write(client).await?;
+
+            // Read again under the lock, so a concurrent update can't be overwritten with stale metadata
+            let mut filing = match protocol_filing(&transaction, protocol_uuid).await? {
+                Some(filing) => filing,
+                None => return Ok(ProtocolChange::NotFound),
+            };
+
+            filing.apply(&update);
+
+            for (entity, id) in filing.referenced_entities() {
+                if !entity_exists(&transaction, entity, id).await? {
+                    println!("Got unknown {:?}-ID!: {}", entity, id);
+                    return Ok(ProtocolChange::UnknownEntity(entity, id));
+                }
+            }
+
+            if update.changes_metadata() {
+                transaction.execute("DELETE FROM protocols WHERE protocol_uuid = $1;", &[&protocol_uuid]).await?;
+
+                for (examiner_id, subject_id) in &filing.examiner_subject_ids {
+                    let relation_id = find_or_create_relation(&transaction, *examiner_id, *subject_id, filing.stex_id, filing.season_id, filing.year).await?;
+                    transaction.execute("INSERT INTO protocols(relation_id, protocol_uuid) VALUES ($1, $2);", &[&relation_id, &protocol_uuid]).await?;
+                }
+
+                remove_unused_relations(&transaction, &filing.relation_ids).await?;
+            }
+
+            if let Some(text) = &update.text {
+                transaction.execute("UPDATE protocol_texts SET text = $1 WHERE protocol_uuid = $2;", &[text, &protocol_uuid]).await?;
+            }
+
+            insert_revision(&transaction, self.cipher.as_deref(), protocol_uuid, &filing, &text, Some(author)).await?;
+
+            if update.text.is_some() {
+                self.blobs.rename(&new_key, &protocol_key).await?;
+            }
+
+            if let Err(err) = transaction.commit().await {
+                // The database still has the previous revision, so its text goes back
+                if update.text.is_some() {
+                    let _ = self.blobs.put(&protocol_key, previous_text.into_bytes()).await;
+                }
+                return Err(err.into());
+            }
+
+            Ok(ProtocolChange::Updated)
+        }.await;
+
+        // A transaction that is dropped without commit is rolled back
+        match result {
+            Ok(ProtocolChange::Updated) => {},
+            Ok(_) | Err(_) => {
+                if update.text.is_some() {
+                    let _ = self.blobs.delete(&new_key).await;
+                }
+                return result;
+            },
+        }
+
+        Ok(ProtocolChange::Updated)
+    }
+
+    async fn delete_protocol(&self, protocol_uuid: &str) -> Result<bool, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        if Uuid::parse_str(protocol_uuid).is_err() {
+            println!("Got invalid Protocol-UUID!: {:?}", protocol_uuid);
+            return Ok(false);
+        }
+
+        if !delete_protocol_rows(client, protocol_uuid).await? {
+            return Ok(false);
+        }
+
+        // The protocol is already gone from the database, a leftover blob is only a waste of space
+        match self.blobs.delete(&text_key(protocol_uuid)).await {
+            Ok(_) => {},
+            Err(err) => println!("Failed to remove protocol file of {}!: {:?}", protocol_uuid, err),
+        }
+
+        Ok(true)
+    }
+
+    //Revisions
+
+    async fn get_revisions(&self, protocol_uuid: &str) -> Result<Option<Vec<OutputRevision>>, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let revisions = read_revisions(client, self.cipher.as_deref(), protocol_uuid, None, false).await?;
+
+        match revisions.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(revisions)),
+        }
+    }
+
+    async fn get_revision(&self, protocol_uuid: &str, revision: i64) -> Result<Option<OutputRevision>, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        Ok(read_revisions(client, self.cipher.as_deref(), protocol_uuid, Some(revision), true).await?.pop())
+    }
+
+    //Data Reading
+
+    async fn search_for_protocol(&self, filter: SearchFilter, page: Pagination) -> Result<SearchResults, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let text = filter.text.filter(|text| !text.trim().is_empty());
+
+        // A filter that is NULL matches everything, the same as leaving it out of the WHERE clause
+        let from_clause = "
+            FROM protocols
+                     JOIN subject_relations ON subject_relations.id = protocols.relation_id
+                     JOIN examiners ON examiner_id = examiners.id
+                     JOIN subjects ON subject_id = subjects.id
+                     JOIN stex ON stex_id = stex.id
+                     JOIN seasons ON season_id = seasons.id
+                     LEFT JOIN protocol_texts ON protocol_texts.protocol_uuid = protocols.protocol_uuid
+            WHERE ($1::BIGINT[] IS NULL OR examiner_id = ANY($1))
+              AND ($2::BIGINT[] IS NULL OR subject_id = ANY($2))
+              AND ($3::BIGINT[] IS NULL OR stex_id = ANY($3))
+              AND ($4::BIGINT[] IS NULL OR season_id = ANY($4))
+              AND ($5::BIGINT[] IS NULL OR year = ANY($5))
+              AND ($6::TEXT IS NULL OR search_vector @@ websearch_to_tsquery('german', $6))
+              AND ($7::BIGINT[] IS NULL OR protocols.protocol_uuid NOT IN (SELECT excluded.protocol_uuid FROM protocols AS excluded JOIN subject_relations AS excluded_relations ON excluded_relations.id = excluded.relation_id WHERE excluded_relations.examiner_id = ANY($7)))
+              AND ($8::BIGINT[] IS NULL OR protocols.protocol_uuid NOT IN (SELECT excluded.protocol_uuid FROM protocols AS excluded JOIN subject_relations AS excluded_relations ON excluded_relations.id = excluded.relation_id WHERE excluded_relations.subject_id = ANY($8)))
+              AND ($9::BIGINT[] IS NULL OR protocols.protocol_uuid NOT IN (SELECT excluded.protocol_uuid FROM protocols AS excluded JOIN subject_relations AS excluded_relations ON excluded_relations.id = excluded.relation_id WHERE excluded_relations.stex_id = ANY($9)))
+              AND ($10::BIGINT[] IS NULL OR protocols.protocol_uuid NOT IN (SELECT excluded.protocol_uuid FROM protocols AS excluded JOIN subject_relations AS excluded_relations ON excluded_relations.id = excluded.relation_id WHERE excluded_relations.season_id = ANY($10)))
+              AND ($11::BIGINT[] IS NULL OR protocols.protocol_uuid NOT IN (SELECT excluded.protocol_uuid FROM protocols AS excluded JOIN subject_relations AS excluded_relations ON excluded_relations.id = excluded.relation_id WHERE excluded_relations.year = ANY($11)))
+              AND ($12::BIGINT IS NULL OR year >= $12)
+              AND ($13::BIGINT IS NULL OR year <= $13)
+        ";
+
+        let filter_params: [&(dyn ToSql + Sync); 13] = [
+            &filter.examiner_ids, &filter.subject_ids, &filter.stex_ids, &filter.seasons, &filter.years, &text,
+            &filter.exclude_examiner_ids, &filter.exclude_subject_ids, &filter.exclude_stex_ids, &filter.exclude_seasons, &filter.exclude_years,
+            &filter.year_from, &filter.year_to
+        ];
+
+        let total: i64 = client.query_one(&format!("SELECT COUNT(DISTINCT protocols.protocol_uuid) AS total {};", from_clause), &filter_params).await?.get("total");
+
+        // The snippets are only built for the protocols of the requested page, as ts_headline is expensive.
+        // A protocol has one row per examiner/subject pair, its creation order is the first of these rows
+        let query = format!("
+            SELECT page.uuid,
+                   page.rank,
+                   CASE WHEN $6::TEXT IS NULL THEN NULL
+                        ELSE ts_headline('german', protocol_texts.text, websearch_to_tsquery('german', $6), 'StartSel={}, StopSel={}, MaxFragments=1')
+                   END AS snippet
+            FROM (SELECT protocols.protocol_uuid     AS uuid,
+                         MAX(year)                   AS sort_year,
+                         MIN(seasons.display_name)   AS sort_season,
+                         MIN(protocols.id)           AS created,
+                         CASE WHEN $6::TEXT IS NULL THEN NULL
+                              ELSE MAX(ts_rank(search_vector, websearch_to_tsquery('german', $6)))::FLOAT8
+                         END                         AS rank
+                  {}
+                  GROUP BY protocols.protocol_uuid
+                  ORDER BY rank DESC NULLS LAST, sort_year DESC, sort_season, created, uuid
+                  LIMIT $14 OFFSET $15) AS page
+                     LEFT JOIN protocol_texts ON protocol_texts.protocol_uuid = page.uuid
+            ORDER BY page.rank DESC NULLS LAST, page.sort_year DESC, page.sort_season, page.created, page.uuid;
+        ", HIGHLIGHT_START, HIGHLIGHT_END, from_clause);
+
+        let mut page_params = filter_params.to_vec();
+        page_params.push(&page.limit);
+        page_params.push(&page.offset);
+
+        let rows = client.query(&query, &page_params).await?;
+
+        let hits: Vec<SearchHit> = rows.iter().map(|row| SearchHit { uuid: row.get("uuid"), snippet: row.get("snippet"), rank: row.get("rank") }).collect();
+
+        let protocol_uuids: Vec<&str> = hits.iter().map(|hit| hit.uuid.as_str()).collect();
+        let protocols = load_output_protocols(client, &protocol_uuids).await?;
+
+        Ok(SearchResults { protocols: order_search_hits(protocols, hits), total })
+    }
+
+    async fn get_protocol(&self, protocol_uuid: &str) -> Result<Option<OutputProtocol>, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        if Uuid::parse_str(protocol_uuid).is_err() {
+            println!("Got invalid Protocol-UUID!: {:?}", protocol_uuid);
+            return Ok(None);
+        }
+
+        let mut protocols = load_output_protocols(client, &[protocol_uuid]).await?;
+
+        Ok(protocols.remove(protocol_uuid))
+    }
+
+    async fn open_protocol_text(&self, protocol_uuid: &str) -> Result<ProtocolText, DatabaseError> {
+        Ok(self.blobs.open(&text_key(protocol_uuid)).await?)
+    }
+
+    async fn get_selection_identifiers(&self) -> Result<SelectionIdentifier, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let identifiers = SelectionIdentifier {
+            examiners: request_selection_identifiers(client, "examiners").await?,
+            subjects: request_selection_identifiers(client, "subjects").await?,
+            stex: request_selection_identifiers(client, "stex").await?,
+            seasons: request_selection_identifiers(client, "seasons").await?,
+        };
+
+        Ok(identifiers)
+    }
+
+    async fn get_admins(&self) -> Result<Vec<String>, DatabaseError> {
+        let connection = self.connection().await?;
+        let client: &Client = &connection;
+        let rows = client.query("SELECT email FROM admins;", &[]).await?;
+
+        Ok(rows.iter().map(|row| row.get("email")).collect())
+    }
+
+    //Maintenance
+
+    async fn check_consistency(&self, repair: Option<RepairMode>) -> Result<ConsistencyReport, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+
+        // The write lock is held until the end, so no save can commit between reading the rows and listing or repairing the blobs
+        let transaction = begin_write(client).await?;
+
+        let rows = transaction.query("SELECT DISTINCT protocol_uuid FROM protocols;", &[]).await?;
+        let protocol_uuids: HashSet<String> = rows.iter().map(|row| row.get("protocol_uuid")).collect();
+
+        let keys = settled_keys(self.blobs.as_ref(), self.blobs.list().await?).await?;
+
+        let rows = transaction.query(UNUSED_RELATIONS_QUERY, &[]).await?;
+        let relations_without_protocols = rows.iter().map(|row| row.get("id")).collect();
+
+        let mut entities_without_relations = vec![];
+
+        for entity in CatalogEntity::ALL {
+            let rows = transaction.query(&unused_entities_query(entity), &[]).await?;
+            entities_without_relations.extend(rows.iter().map(|row| UnusedEntity { kind: entity.label().to_string(), id: row.get("id"), display_name: row.get("display_name") }));
+        }
+
+        let mut report = ConsistencyReport {
+            files_without_rows: files_without_rows(&keys, &protocol_uuids),
+            rows_without_files: rows_without_files(&keys, &protocol_uuids),
+            relations_without_protocols,
+            entities_without_relations,
+            repair,
+            restored_files: vec![]
+        };
+
+        let repair = match repair {
+            Some(repair) => repair,
+            None => return Ok(report),
+        };
+
+        for file_name in &report.files_without_rows {
+            repair_orphaned_file(self.blobs.as_ref(), file_name, repair).await?;
+        }
+
+        for protocol_uuid in &report.rows_without_files {
+            match latest_revision_text(&transaction, self.cipher.as_deref(), protocol_uuid).await? {
+                Some(text) => {
+                    self.blobs.put(&text_key(protocol_uuid), text.into_bytes()).await?;
+                    report.restored_files.push(protocol_uuid.clone());
+                },
+                None if repair == RepairMode::Delete => {
+                    // The text is already missing, so only the rows are left to remove
+                    remove_protocol_rows(&transaction, protocol_uuid).await?;
+                },
+                None => println!("Can't restore the text of {}, there is no revision of it", protocol_uuid),
+            }
+        }
+
+        transaction.execute(DELETE_UNUSED_RELATIONS, &[]).await?;
+
+        if repair == RepairMode::Delete {
+            for entity in CatalogEntity::ALL {
+                let query = delete_unused_entity_query(entity, "$1");
+
+                for unused in report.entities_without_relations.iter().filter(|unused| unused.kind == entity.label()) {
+                    transaction.execute(&query, &[&unused.id]).await?;
+                }
+            }
+        }
+
+        transaction.commit().await?;
+
+        Ok(report)
+    }
+
+    async fn reencrypt_revisions(&self) -> Result<usize, DatabaseError> {
+        let mut connection = self.connection().await?;
+        let client: &mut Client = &mut connection;
+        let cipher = self.cipher.as_deref();
+        let active_key_id = cipher.and_then(|cipher| cipher.active_key_id());
+
+        let transaction = begin_write(client).await?;
+        let rows = transaction.query("SELECT id, protocol_uuid, text, text_key_id FROM protocol_revisions WHERE text_key_id IS DISTINCT FROM $1 ORDER BY id;", &[&active_key_id]).await?;
+
+        for row in &rows {
+            let protocol_uuid: String = row.get("protocol_uuid");
+            let text = open_revision_text(cipher, &protocol_uuid, row.get("text"), row.get("text_key_id"))?;
+            let (text, text_key_id) = seal_revision_text(cipher, &protocol_uuid, &text)?;
+
+            transaction.execute("UPDATE protocol_revisions SET text = $1, text_key_id = $2 WHERE id = $3;", &[&text, &text_key_id, &row.get::<_, i64>("id")]).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(rows.len())
+    }
+}
+
+//Helper Functions
+
+async fn connect(conn_info: &DatabaseConnectionInfo) -> Result<Client, DatabaseError> {
+    let mut config = tokio_postgres::Config::new();
+    config.host(&conn_info.hostname)
+        .port(conn_info.port)
+        .user(&conn_info.username)
+        .password(&conn_info.password)
+        .dbname(&conn_info.database);
+
+    let (client, connection) = match conn_info.sslmode {
+        PostgresSslMode::Disable => {
+            let (client, connection) = config.connect(NoTls).await?;
+            (client, connection.boxed())
+        },
+        PostgresSslMode::Prefer | PostgresSslMode::Require => {
+            config.ssl_mode(match conn_info.sslmode {
+                PostgresSslMode::Require => SslMode::Require,
+                _ => SslMode::Prefer,
+            });
+
+            let (client, connection) = config.connect(tls_connector(conn_info)?).await?;
+            (client, connection.boxed())
+        },
+    };
+
+    // The connection does the actual communication with the server and has to be polled on its own
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            println!("Lost connection to PostgreSQL database!: {:?}", err);
+        }
+    });
+
+    Ok(client)
+}
+
+/// Trusts the CA certificates of the system and the ssl_root_certificate, if there is one
+fn tls_connector(conn_info: &DatabaseConnectionInfo) -> Result<MakeTlsConnector, DatabaseError> {
+    let mut builder = TlsConnector::builder();
+
+    if let Some(path) = &conn_info.ssl_root_certificate {
+        let pem = std::fs::read(path).map_err(|err| io::Error::new(err.kind(), format!("Failed to read ssl_root_certificate {}: {}", path, err)))?;
+        let certificate = Certificate::from_pem(&pem).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{} is no PEM certificate: {}", path, err)))?;
+        builder.add_root_certificate(certificate);
+    }
+
+    match builder.build() {
+        Ok(connector) => Ok(MakeTlsConnector::new(connector)),
+        Err(err) => Err(io::Error::other(format!("Failed to set up TLS for PostgreSQL: {}", err)).into()),
+    }
+}
+
+/// Starts a transaction that only runs while no other one writes, like SQLite only ever has one writer.
+/// Otherwise two requests could both find a relation or name missing and both create it
+async fn begin_write(client: &mut Client) -> Result<Transaction<'_>, Error> {
+    let transaction = client.transaction().await?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1);", &[&WRITE_LOCK]).await?;
+
+    Ok(transaction)
+}
+
+/// Removes the protocol with its texts, revisions and now unused relations, false if there is no such protocol
+async fn delete_protocol_rows(client: &mut Client, protocol_uuid: &str) -> Result<bool, Error> {
+    let transaction = begin_write(client).await?;
+
+    if !remove_protocol_rows(&transaction, protocol_uuid).await? {
+        return Ok(false);
+    }
+
+    transaction.commit().await?;
+
+    Ok(true)
+}
+
+/// Has to run inside a write transaction
+async fn remove_protocol_rows(transaction: &Transaction<'_>, protocol_uuid: &str) -> Result<bool, Error> {
+    let filing = match protocol_filing(transaction, protocol_uuid).await? {
+        Some(filing) => filing,
+        None => return Ok(false),
+    };
+
+    transaction.execute("DELETE FROM protocols WHERE protocol_uuid = $1;", &[&protocol_uuid]).await?;
+    transaction.execute("DELETE FROM protocol_texts WHERE protocol_uuid = $1;", &[&protocol_uuid]).await?;
+    transaction.execute("DELETE FROM protocol_revision_pairs WHERE revision_id IN (SELECT id FROM protocol_revisions WHERE protocol_uuid = $1);", &[&protocol_uuid]).await?;
+    transaction.execute("DELETE FROM protocol_revisions WHERE protocol_uuid = $1;", &[&protocol_uuid]).await?;
+    remove_unused_relations(transaction, &filing.relation_ids).await?;
+
+    Ok(true)
+}
+
+/// Loads the metadata of the given protocols, protocols that don't exist are left out
+async fn load_output_protocols(client: &impl GenericClient, protocol_uuids: &[&str]) -> Result<HashMap<String, OutputProtocol>, Error> {
+    let query = "
+        SELECT protocol_uuid          AS uuid,
+               examiners.display_name AS examiner,
+               subjects.display_name  AS subject,
+               stex.display_name      AS stex,
+               seasons.display_name   AS season,
+               year
+        FROM protocols
+                 JOIN subject_relations ON subject_relations.id = protocols.relation_id
+                 JOIN examiners ON examiner_id = examiners.id
+                 JOIN subjects ON subject_id = subjects.id
+                 JOIN stex ON stex_id = stex.id
+                 JOIN seasons ON season_id = seasons.id
+        WHERE protocol_uuid = ANY($1)
+        ORDER BY protocols.id;
+    ";
+
+    let rows = client.query(query, &[&protocol_uuids]).await?;
+
+    Ok(collect_output_protocols(rows))
+}
+
+async fn request_selection_identifiers(client: &impl GenericClient, target_table: &str) -> Result<Vec<SelectionIdentifierPair>, Error> {
+    let rows = client.query(&format!("SELECT id, display_name FROM {} ORDER BY id;", target_table), &[]).await?;
+
+    Ok(rows.iter().map(|row| SelectionIdentifierPair { id: row.get("id"), display_name: row.get("display_name") }).collect())
+}
+
+/// This method returns a UUID that is Unique (in this database)
+/// Potentially it can loop an infinite amount of times, but thats statistically VERY VERY
+/// VERY UNLIKELY
+async fn get_new_uuid(client: &impl GenericClient) -> Option<String> {
+    get_unused_uuid(client, "SELECT id FROM protocols WHERE protocol_uuid = $1;").await
+}
+
+/// Same as get_new_uuid, but for Session-IDs
+async fn get_new_token_uuid(client: &impl GenericClient) -> Option<String> {
+    get_unused_uuid(client, "SELECT id FROM sessions WHERE uuid = $1;").await
+}
+
+async fn get_unused_uuid(client: &impl GenericClient, query: &str) -> Option<String> {
+    loop {
+        let potential_uuid = Uuid::new_v4().to_string();
+
+        match client.query_opt(query, &[&potential_uuid]).await {
+            Ok(Some(_)) => continue,
+            Ok(None) => return Some(potential_uuid),
+            Err(err) => {
+                println!("Failed to check if uuid exists: {:?}", err);
+                return None;
+            },
+        }
+    }
+}
+
+async fn find_or_create_relation(client: &impl GenericClient, examiner_id: i64, subject_id: i64, stex_id: i64, season_id: i64, year: i64) -> Result<i64, Error> {
+    let existing = client.query_opt(
+        "SELECT id FROM subject_relations WHERE examiner_id = $1 AND subject_id = $2 AND stex_id = $3 AND season_id = $4 AND year = $5;",
+        &[&examiner_id, &subject_id, &stex_id, &season_id, &year]
+    ).await?;
+
+    if let Some(row) = existing {
+        return Ok(row.get("id"));
+    }
+
+    let created = client.query_one(
+        "INSERT INTO subject_relations(examiner_id, subject_id, stex_id, season_id, year) VALUES ($1, $2, $3, $4, $5) RETURNING id;",
+        &[&examiner_id, &subject_id, &stex_id, &season_id, &year]
+    ).await?;
+
+    Ok(created.get("id"))
+}
+
+async fn protocol_filing(client: &impl GenericClient, protocol_uuid: &str) -> Result<Option<ProtocolFiling>, Error> {
+    let rows = client.query("
+        SELECT relation_id, examiner_id, subject_id, stex_id, season_id, year
+        FROM protocols
+                 JOIN subject_relations ON subject_relations.id = protocols.relation_id
+        WHERE protocol_uuid = $1
+        ORDER BY protocols.id;
+    ", &[&protocol_uuid]).await?;
+
+    Ok(ProtocolFiling::from_rows(rows.iter().map(|row| (row.get("relation_id"), row.get("examiner_id"), row.get("subject_id"), row.get("stex_id"), row.get("season_id"), row.get("year"))).collect()))
+}
+
+async fn entity_exists(client: &impl GenericClient, entity: CatalogEntity, id: i64) -> Result<bool, Error> {
+    Ok(client.query_opt(&format!("SELECT id FROM {} WHERE id = $1;", entity.table_name()), &[&id]).await?.is_some())
+}
+
+/// Records the given state of the protocol as its next revision
+async fn insert_revision(client: &impl GenericClient, cipher: Option<&ProtocolCipher>, protocol_uuid: &str, filing: &ProtocolFiling, text: &str, author: Option<&str>) -> Result<(), DatabaseError> {
+    let (text, text_key_id) = seal_revision_text(cipher, protocol_uuid, text)?;
+
+    let created = client.query_one("
+        INSERT INTO protocol_revisions(protocol_uuid, revision, created, author, stex_id, season_id, year, text, text_key_id)
+        VALUES ($1::TEXT, (SELECT COALESCE(MAX(revision), 0) + 1 FROM protocol_revisions WHERE protocol_uuid = $1::TEXT), $2, $3, $4, $5, $6, $7, $8)
+        RETURNING id;
+    ", &[&protocol_uuid, &(get_current_time_seconds() as i64), &author, &filing.stex_id, &filing.season_id, &filing.year, &text, &text_key_id]).await?;
+
+    let revision_id: i64 = created.get("id");
+
+    for (examiner_id, subject_id) in &filing.examiner_subject_ids {
+        client.execute("INSERT INTO protocol_revision_pairs(revision_id, examiner_id, subject_id) VALUES ($1, $2, $3);", &[&revision_id, examiner_id, subject_id]).await?;
+    }
+
+    Ok(())
+}
+
+async fn latest_revision_text(client: &impl GenericClient, cipher: Option<&ProtocolCipher>, protocol_uuid: &str) -> Result<Option<String>, DatabaseError> {
+    let row = client.query_opt("SELECT text, text_key_id FROM protocol_revisions WHERE protocol_uuid = $1 ORDER BY revision DESC LIMIT 1;", &[&protocol_uuid]).await?;
+
+    match row {
+        Some(row) => Ok(Some(open_revision_text(cipher, protocol_uuid, row.get("text"), row.get("text_key_id"))?)),
+        None => Ok(None),
+    }
+}
+
+/// All revisions of the protocol, or only the one with the given number
+async fn read_revisions(client: &impl GenericClient, cipher: Option<&ProtocolCipher>, protocol_uuid: &str, revision: Option<i64>, with_text: bool) -> Result<Vec<OutputRevision>, DatabaseError> {
+    let rows = client.query("
+        SELECT id, revision, created, author, stex_id, season_id, year, text, text_key_id
+        FROM protocol_revisions
+        WHERE protocol_uuid = $1 AND ($2::BIGINT IS NULL OR revision = $2)
+        ORDER BY revision;
+    ", &[&protocol_uuid, &revision]).await?;
+
+    let mut revisions = vec![];
+
+    for row in rows {
+        let revision_id: i64 = row.get("id");
+
+        let pairs = client.query("SELECT examiner_id, subject_id FROM protocol_revision_pairs WHERE revision_id = $1 ORDER BY id;", &[&revision_id]).await?;
+
+        revisions.push(OutputRevision {
+            revision: row.get("revision"),
+            created: row.get("created"),
+            author: row.get("author"),
+            examiner_subject_ids: pairs.iter().map(|pair| (pair.get("examiner_id"), pair.get("subject_id"))).collect(),
+            stex_id: row.get("stex_id"),
+            season_id: row.get("season_id"),
+            year: row.get("year"),
+            text: match with_text {
+                true => Some(open_revision_text(cipher, protocol_uuid, row.get("text"), row.get("text_key_id"))?),
+                false => None,
+            }
+        });
+    }
+
+    Ok(revisions)
+}
+
+/// Removes those of the relations that no protocol belongs to anymore
+async fn remove_unused_relations(client: &impl GenericClient, relation_ids: &[i64]) -> Result<(), Error> {
+    client.execute("DELETE FROM subject_relations WHERE id = ANY($1) AND id NOT IN (SELECT relation_id FROM protocols);", &[&relation_ids]).await?;
+    Ok(())
+}
+
+fn collect_output_protocols(rows: Vec<Row>) -> HashMap<String, OutputProtocol> {
+    let mut working_search_results: HashMap<String, OutputProtocol> = HashMap::new();
+
+    for row in rows {
+        merge_protocol_row(&mut working_search_results, ProtocolRow {
+            uuid: row.get("uuid"),
+            examiner: row.get("examiner"),
+            subject: row.get("subject"),
+            stex: row.get("stex"),
+            season: row.get("season"),
+            year: row.get("year")
+        });
+    }
+
+    working_search_results
+}
+
+/// Need a PostgreSQL server where the user may create databases, each test works in a database of its own.
+/// Run with `cargo test -- --ignored`, the `PROTOCOLDB_TEST_POSTGRES_*` variables point them to the server
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::{storage::{blob_store::LocalBlobStore, testing::{create_catalog, save_protocol, text_filter}}, structs::configuration::Encryption};
+
+    use super::*;
+
+    fn conn_info(database: &str) -> DatabaseConnectionInfo {
+        let setting = |name: &str, default: &str| std::env::var(format!("PROTOCOLDB_TEST_POSTGRES_{}", name)).unwrap_or_else(|_| default.to_string());
+
+        DatabaseConnectionInfo {
+            hostname: setting("HOST", "127.0.0.1"),
+            port: setting("PORT", "5432").parse().unwrap(),
+            username: setting("USER", "postgres"),
+            password: setting("PASSWORD", "postgres"),
+            database: database.to_string(),
+            sslmode: match setting("SSLMODE", "disable").as_str() {
+                "require" => PostgresSslMode::Require,
+                "prefer" => PostgresSslMode::Prefer,
+                _ => PostgresSslMode::Disable,
+            },
+            ssl_root_certificate: std::env::var("PROTOCOLDB_TEST_POSTGRES_SSL_ROOT_CERTIFICATE").ok(),
+        }
+    }
+
+    /// A new database and protocol directory, both are removed when dropped
+    struct TestDatabase {
+        name: String,
+        directory: std::path::PathBuf,
+    }
+
+    impl TestDatabase {
+        async fn create() -> TestDatabase {
+            let name = format!("protocoldb_test_{}", Uuid::new_v4().simple());
+            let client = connect(&conn_info("postgres")).await.unwrap();
+            client.batch_execute(&format!("CREATE DATABASE {};", name)).await.unwrap();
+
+            let directory = std::env::temp_dir().join(&name);
+            std::fs::create_dir_all(&directory).unwrap();
+
+            TestDatabase { name, directory }
+        }
+
+        async fn open(&self) -> PostgresDatabase {
+            self.open_encrypted(None).await
+        }
+
+        async fn open_encrypted(&self, cipher: Option<Arc<ProtocolCipher>>) -> PostgresDatabase {
+            let blobs: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(&self.directory));
+            PostgresDatabase::new(conn_info(&self.name), blobs, cipher).await.unwrap()
+        }
+
+        async fn count_texts(&self) -> i64 {
+            let client = connect(&conn_info(&self.name)).await.unwrap();
+            client.query_one("SELECT COUNT(*) AS count FROM protocol_texts;", &[]).await.unwrap().get("count")
+        }
+
+        async fn remove(self) {
+            let client = connect(&conn_info("postgres")).await.unwrap();
+            client.batch_execute(&format!("DROP DATABASE {} WITH (FORCE);", self.name)).await.unwrap();
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    const PAGE: Pagination = Pagination { limit: 50, offset: 0 };
+
+    #[tokio::test]
+    #[ignore]
+    async fn saves_searches_and_updates_protocols() {
+        let test_database = TestDatabase::create().await;
+        let database = test_database.open().await;
+
+        create_catalog(&database).await;
+
+        assert_eq!(database.save_protocol(vec![(1, 1)], 7, 1, 2023, "Text".to_string(), "admin@example.org").await.unwrap(), ProtocolCreation::UnknownEntity(CatalogEntity::Stex, 7));
+
+        let uuid = save_protocol(&database, 2023, "Gefragt wurde nach dem Plexus brachialis").await;
+
+        let results = database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap();
+        assert_eq!(results.total, 1);
+        assert_eq!(results.protocols[0].examiners, vec!["Dr. Muster".to_string()]);
+
+        let update = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: Some(2024), text: Some("Nur die Niere".to_string()) };
+        assert_eq!(database.update_protocol(&uuid, update, "admin@example.org").await.unwrap(), ProtocolChange::Updated);
+
+        let mut text = String::new();
+        database.open_protocol_text(&uuid).await.unwrap().read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "Nur die Niere");
+        assert_eq!(database.get_revisions(&uuid).await.unwrap().unwrap().len(), 2);
+        assert_eq!(database.get_revision(&uuid, 1).await.unwrap().unwrap().text.as_deref(), Some("Gefragt wurde nach dem Plexus brachialis"));
+        assert_eq!(database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap().total, 0);
+
+        let report = database.check_consistency(None).await.unwrap();
+        assert!(report.files_without_rows.is_empty() && report.rows_without_files.is_empty());
+
+        assert!(database.delete_protocol(&uuid).await.unwrap());
+        assert!(database.get_protocol(&uuid).await.unwrap().is_none());
+
+        drop(database);
+        test_database.remove().await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn encrypted_texts_are_not_indexed() {
+        let test_database = TestDatabase::create().await;
+        let database = test_database.open().await;
+
+        create_catalog(&database).await;
+        save_protocol(&database, 2023, "Plexus brachialis").await;
+        assert_eq!(test_database.count_texts().await, 1);
+        drop(database);
+
+        let encryption = Encryption {
+            token_encryption_secret: "secret".to_string(),
+            protocol_keys: [("2023".to_string(), "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f".to_string())].into_iter().collect(),
+            active_protocol_key: Some("2023".to_string()),
+        };
+        let cipher = Arc::new(ProtocolCipher::from_configuration(&encryption).unwrap().unwrap());
+        let database = test_database.open_encrypted(Some(cipher)).await;
+        assert_eq!(test_database.count_texts().await, 0);
+
+        let uuid = save_protocol(&database, 2023, "Nur die Niere").await;
+        let update = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: None, text: Some("Wieder der Plexus".to_string()) };
+        assert_eq!(database.update_protocol(&uuid, update, "admin@example.org").await.unwrap(), ProtocolChange::Updated);
+
+        assert_eq!(test_database.count_texts().await, 0);
+        assert_eq!(database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap().total, 0);
+        assert_eq!(database.search_for_protocol(text_filter(None), PAGE).await.unwrap().total, 2);
+
+        drop(database);
+        test_database.remove().await;
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn sessions_and_admins() {
+        let test_database = TestDatabase::create().await;
+        let database = test_database.open().await;
+
+        let session_id = database.save_access_token("user@example.org").await.unwrap().unwrap();
+        assert!(database.is_session_valid(&session_id).await.unwrap());
+        assert_eq!(database.remove_sessions("user@example.org").await.unwrap(), 1);
+        assert!(!database.is_session_valid(&session_id).await.unwrap());
+
+        assert!(database.add_admin("admin@example.org").await.unwrap());
+        assert!(database.check_if_user_admin("admin@example.org").await.unwrap());
+        assert!(!database.check_if_user_admin("user@example.org").await.unwrap());
+
+        drop(database);
+        test_database.remove().await;
+    }
+}
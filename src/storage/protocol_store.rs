@@ -0,0 +1,239 @@
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+
+use crate::structs::{get_outputs::{ConsistencyReport, OutputProtocol, OutputRevision, OutputSession, SelectionIdentifier}, post_inputs::{CreateField, RepairMode}};
+
+use super::database::DatabaseError;
+
+/// Everything the HTTP layer needs from a storage backend.
+/// Handlers only ever talk to a `dyn ProtocolStore`, so the backend can be swapped in the config.
+#[async_trait]
+pub trait ProtocolStore: Send + Sync {
+
+    //Sessions
+
+    /// The email is stored with the session, so all sessions of a user can be revoked
+    async fn save_access_token(&self, email: &str) -> Result<Option<String>, DatabaseError>;
+
+    async fn remove_expired_sessions(&self) -> Result<(), DatabaseError>;
+
+    async fn is_session_valid(&self, session_id: &str) -> Result<bool, DatabaseError>;
+
+    /// Ok(false) if there is no such session
+    async fn remove_session(&self, session_id: &str) -> Result<bool, DatabaseError>;
+
+    /// The sessions of the email that haven't expired yet, oldest first
+    async fn get_sessions(&self, email: &str) -> Result<Vec<OutputSession>, DatabaseError>;
+
+    /// Returns how many sessions were removed
+    async fn remove_sessions(&self, email: &str) -> Result<u64, DatabaseError>;
+
+    //Admins
+
+    async fn check_if_user_admin(&self, email: &str) -> Result<bool, DatabaseError>;
+
+    /// Adding an existing admin changes nothing. Ok(false) if the email was rejected
+    async fn add_admin(&self, email: &str) -> Result<bool, DatabaseError>;
+
+    /// Ok(false) if the email was rejected
+    async fn remove_admin(&self, email: &str) -> Result<bool, DatabaseError>;
+
+    async fn get_admins(&self) -> Result<Vec<String>, DatabaseError>;
+
+    //Catalog Entities
+
+    /// Returns the ID of the entity with that display_name, creating it if it doesn't exist yet.
+    /// None if the display_name was rejected.
+    async fn create_item(&self, entity: CatalogEntity, display_name: String) -> Result<Option<i64>, DatabaseError>;
+
+    async fn get_selection_identifiers(&self) -> Result<SelectionIdentifier, DatabaseError>;
+
+    /// Refused with NameTaken if another entity of the same kind already has that display_name, merge them instead
+    async fn rename_item(&self, entity: CatalogEntity, id: i64, display_name: String) -> Result<EntityChange, DatabaseError>;
+
+    /// Re-points every relation and revision from the entity `from_id` to `into_id` and removes `from_id`.
+    /// Relations and protocol links that become identical through this are collapsed into one
+    async fn merge_items(&self, entity: CatalogEntity, from_id: i64, into_id: i64) -> Result<EntityChange, DatabaseError>;
+
+    /// Refused with InUse while protocols or their revisions still reference the entity
+    async fn delete_item(&self, entity: CatalogEntity, id: i64) -> Result<EntityChange, DatabaseError>;
+
+    //Protocols
+
+    /// The author is the email of the admin, it's recorded in the first revision.
+    /// Either the text and all rows are stored or nothing is, unknown IDs are refused before anything is written
+    async fn save_protocol(&self, examiner_subject_relation_ids: Vec<(i64, i64)>, stex_id: i64, season_id: i64, year: i64, protocol: String, author: &str) -> Result<ProtocolCreation, DatabaseError>;
+
+    /// Replaces the text and/or metadata of a protocol and records the result as a new revision.
+    /// Relations that lose their last protocol are removed. Unknown IDs are refused before anything is written
+    async fn update_protocol(&self, protocol_uuid: &str, update: ProtocolUpdate, author: &str) -> Result<ProtocolChange, DatabaseError>;
+
+    /// Removes the protocol with its text and all of its revisions, nothing is kept to restore it from.
+    /// Relations that lose their last protocol are removed. Ok(false) if there is no such protocol
+    async fn delete_protocol(&self, protocol_uuid: &str) -> Result<bool, DatabaseError>;
+
+    //Revisions
+
+    /// Oldest first and without their text. None if there is no such protocol
+    async fn get_revisions(&self, protocol_uuid: &str) -> Result<Option<Vec<OutputRevision>>, DatabaseError>;
+
+    async fn get_revision(&self, protocol_uuid: &str, revision: i64) -> Result<Option<OutputRevision>, DatabaseError>;
+
+    /// Returns one page of the matching protocols and the total number of matches.
+    /// Sorted by relevance for full-text searches, then year (newest first), season and creation order
+    async fn search_for_protocol(&self, filter: SearchFilter, page: Pagination) -> Result<SearchResults, DatabaseError>;
+
+    async fn get_protocol(&self, protocol_uuid: &str) -> Result<Option<OutputProtocol>, DatabaseError>;
+
+    /// Opens the text of a protocol for reading, so it can be streamed without loading it completely
+    async fn open_protocol_text(&self, protocol_uuid: &str) -> Result<ProtocolText, DatabaseError>;
+
+    //Maintenance
+
+    /// Compares the protocol files with the database. Without a repair nothing is changed.
+    /// A repair writes missing files from the latest revision and removes relations without protocols,
+    /// everything else found is handled according to the RepairMode.
+    /// Saves and edits wait for the whole check, temporary files younger than an hour are left out
+    async fn check_consistency(&self, repair: Option<RepairMode>) -> Result<ConsistencyReport, DatabaseError>;
+
+    /// Rewrites the revision texts that aren't encrypted with the active protocol key, without one they are decrypted.
+    /// Returns how many were rewritten
+    async fn reencrypt_revisions(&self) -> Result<usize, DatabaseError>;
+}
+
+pub type ProtocolText = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The parsed parameters of a search. Filters that are None don't restrict the search,
+/// the ID filters match if any of the given IDs matches. A filter without anything set lists all protocols.
+pub struct SearchFilter {
+    pub examiner_ids: Option<Vec<i64>>,
+    pub subject_ids: Option<Vec<i64>>,
+    pub stex_ids: Option<Vec<i64>>,
+    pub seasons: Option<Vec<i64>>,
+    pub years: Option<Vec<i64>>,
+    /// The exclusions leave out a protocol if any of its examiner/subject pairs has one of the IDs
+    pub exclude_examiner_ids: Option<Vec<i64>>,
+    pub exclude_subject_ids: Option<Vec<i64>>,
+    pub exclude_stex_ids: Option<Vec<i64>>,
+    pub exclude_seasons: Option<Vec<i64>>,
+    pub exclude_years: Option<Vec<i64>>,
+    /// Inclusive bounds
+    pub year_from: Option<i64>,
+    pub year_to: Option<i64>,
+    /// Words that all have to appear in the protocol text
+    pub text: Option<String>
+}
+
+/// Changes to an existing protocol, fields that are None stay as they are
+pub struct ProtocolUpdate {
+    pub examiner_subject_ids: Option<Vec<(i64, i64)>>,
+    pub stex_id: Option<i64>,
+    pub season_id: Option<i64>,
+    pub year: Option<i64>,
+    pub text: Option<String>
+}
+
+impl ProtocolUpdate {
+    pub fn changes_metadata(&self) -> bool {
+        self.examiner_subject_ids.is_some() || self.stex_id.is_some() || self.season_id.is_some() || self.year.is_some()
+    }
+}
+
+pub struct Pagination {
+    pub limit: i64,
+    pub offset: i64
+}
+
+pub struct SearchResults {
+    pub protocols: Vec<OutputProtocol>,
+    /// Number of matches over all pages
+    pub total: i64
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum CatalogEntity {
+    Examiner,
+    Subject,
+    Stex,
+    Season
+}
+
+impl CatalogEntity {
+    pub const ALL: [CatalogEntity; 4] = [CatalogEntity::Examiner, CatalogEntity::Subject, CatalogEntity::Stex, CatalogEntity::Season];
+
+    /// How the entity is called in messages and reports
+    pub fn label(&self) -> &'static str {
+        match self {
+            CatalogEntity::Examiner => "examiner",
+            CatalogEntity::Subject => "subject",
+            CatalogEntity::Stex => "stex",
+            CatalogEntity::Season => "season",
+        }
+    }
+
+    pub fn table_name(&self) -> &'static str {
+        match self {
+            CatalogEntity::Examiner => "examiners",
+            CatalogEntity::Subject => "subjects",
+            CatalogEntity::Stex => "stex",
+            CatalogEntity::Season => "seasons",
+        }
+    }
+
+    /// The column referencing the entity, both in subject_relations and in the revision history
+    pub fn column_name(&self) -> &'static str {
+        match self {
+            CatalogEntity::Examiner => "examiner_id",
+            CatalogEntity::Subject => "subject_id",
+            CatalogEntity::Stex => "stex_id",
+            CatalogEntity::Season => "season_id",
+        }
+    }
+
+    /// Examiners and subjects are stored per pair in the revisions, stex and season per revision
+    pub fn revision_table_name(&self) -> &'static str {
+        match self {
+            CatalogEntity::Examiner | CatalogEntity::Subject => "protocol_revision_pairs",
+            CatalogEntity::Stex | CatalogEntity::Season => "protocol_revisions",
+        }
+    }
+}
+
+impl From<&CreateField> for CatalogEntity {
+    fn from(field: &CreateField) -> Self {
+        match field {
+            CreateField::Examiner => CatalogEntity::Examiner,
+            CreateField::Subject => CatalogEntity::Subject,
+            CreateField::Stex => CatalogEntity::Stex,
+            CreateField::Season => CatalogEntity::Season,
+        }
+    }
+}
+
+/// Outcome of saving a new protocol
+#[derive(PartialEq, Debug)]
+pub enum ProtocolCreation {
+    Saved(String),
+    /// Nothing was saved, there is no entity of that kind with the ID
+    UnknownEntity(CatalogEntity, i64)
+}
+
+/// Outcome of updating an existing protocol
+#[derive(PartialEq, Debug)]
+pub enum ProtocolChange {
+    Updated,
+    NotFound,
+    /// Nothing was changed, there is no entity of that kind with the ID
+    UnknownEntity(CatalogEntity, i64)
+}
+
+/// Outcome of renaming, merging or deleting a catalog entity
+#[derive(PartialEq, Debug)]
+pub enum EntityChange {
+    Done,
+    NotFound,
+    /// The display_name was rejected
+    InvalidName,
+    NameTaken,
+    InUse
+}
@@ -0,0 +1,2221 @@
+use std::{collections::{HashMap, HashSet}, io, ops::Deref, path::Path, sync::Arc};
+use async_trait::async_trait;
+use deadpool::unmanaged::{Object, Pool};
+use sqlite::{ConnectionThreadSafe, Error, State, Statement, Value};
+use uuid::Uuid;
+
+use crate::{structs::{get_outputs::{ConsistencyReport, OutputProtocol, OutputRevision, OutputSession, SelectionIdentifier, SelectionIdentifierPair, UnusedEntity}, post_inputs::RepairMode}, TOKEN_VALID_LENGTH};
+
+use super::{blob_store::{new_text_key, text_key, BlobStore}, encryption::{full_text_index_allowed, open_revision_text, seal_revision_text, ProtocolCipher}, consistency::{delete_unused_entity_query, files_without_rows, repair_orphaned_file, rows_without_files, settled_keys, unused_entities_query, DELETE_UNUSED_RELATIONS, UNUSED_RELATIONS_QUERY}, database::{display_name_is_valid, email_is_valid, get_current_time_seconds, merge_protocol_row, order_search_hits, DatabaseError, ProtocolFiling, ProtocolRow, SearchHit, DATABASE_POOL_SIZE, HIGHLIGHT_END, HIGHLIGHT_START, COLLAPSE_DUPLICATES}, migrations::{check_schema_version, pending_migrations, SCHEMA_VERSION_TABLE_SQLITE}, protocol_store::{CatalogEntity, EntityChange, Pagination, ProtocolChange, ProtocolCreation, ProtocolStore, ProtocolText, ProtocolUpdate, SearchFilter, SearchResults}};
+
+/// How long a connection waits for another one to finish writing before it gives up
+const BUSY_TIMEOUT_MILLISECONDS: usize = 5000;
+
+/// Each request borrows one of a fixed number of connections that are opened at startup.
+/// SQLite blocks while it reads or writes, so the queries run on tokio's blocking threads
+pub struct SQLiteDatabase {
+    pool: Pool<SQLiteConnection>,
+    blobs: Arc<dyn BlobStore>
+}
+
+/// One connection to the database file, the queries themselves are run through it.
+/// The cipher encrypts and decrypts the revision texts on the way
+struct SQLiteConnection {
+    connection: ConnectionThreadSafe,
+    cipher: Option<Arc<ProtocolCipher>>
+}
+
+impl SQLiteDatabase {
+    pub async fn new(file_location: &Path, blobs: Arc<dyn BlobStore>, cipher: Option<Arc<ProtocolCipher>>) -> Result<SQLiteDatabase, DatabaseError> {
+        let mut connections = vec![];
+
+        for _ in 0..DATABASE_POOL_SIZE {
+            connections.push(SQLiteConnection::open(file_location, cipher.clone())?);
+        }
+
+        let database = SQLiteDatabase {
+            pool: Pool::from(connections),
+            blobs
+        };
+
+        database.run(|connection| connection.migrate()).await?;
+        database.index_missing_protocol_texts().await?;
+        database.record_missing_revisions().await?;
+
+        Ok(database)
+    }
+
+    async fn connection(&self) -> Result<Object<SQLiteConnection>, DatabaseError> {
+        match self.pool.get().await {
+            Ok(connection) => Ok(connection),
+            Err(err) => Err(io::Error::other(format!("No database connection available: {:?}", err)).into()),
+        }
+    }
+
+    /// Runs the work on one of the connections, the connection goes back to the pool afterwards
+    async fn run<T: Send + 'static>(&self, work: impl FnOnce(&SQLiteConnection) -> Result<T, DatabaseError> + Send + 'static) -> Result<T, DatabaseError> {
+        let connection = self.connection().await?;
+        let (_, result) = blocking(connection, move |connection| work(connection)).await?;
+        result
+    }
+
+    /// Starts a write transaction that can stay open while the protocol text is written
+    async fn begin(&self) -> Result<Transaction, DatabaseError> {
+        let transaction = Transaction { pooled: Some(self.connection().await?) };
+        let (transaction, result) = blocking(transaction, |transaction| transaction.connection.execute("BEGIN IMMEDIATE;")).await?;
+        result?;
+
+        Ok(transaction)
+    }
+
+    /// Adds protocols that were saved before the full-text index existed to it.
+    /// While new texts are encrypted the index is emptied instead, with the texts indexed before
+    async fn index_missing_protocol_texts(&self) -> Result<(), DatabaseError> {
+        if !self.run(|connection| Ok(connection.indexes_texts())).await? {
+            return self.run(|connection| Ok(connection.clear_full_text_index()?)).await;
+        }
+
+        let missing = self.run(|connection| Ok(connection.read_protocol_uuids("SELECT DISTINCT protocol_uuid FROM protocols WHERE protocol_uuid NOT IN (SELECT protocol_uuid FROM protocol_texts);")?)).await?;
+
+        for protocol_uuid in missing {
+            let text = match self.blobs.get_text(&text_key(&protocol_uuid)).await {
+                Ok(text) => text,
+                Err(err) => {
+                    println!("Failed to index protocol {}!: {:?}", protocol_uuid, err);
+                    continue;
+                },
+            };
+
+            self.run(move |connection| Ok(connection.index_protocol_text(&protocol_uuid, &text)?)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Protocols that were saved before the revision history existed get their current state as first revision
+    async fn record_missing_revisions(&self) -> Result<(), DatabaseError> {
+        let missing = self.run(|connection| Ok(connection.read_protocol_uuids("SELECT DISTINCT protocol_uuid FROM protocols WHERE protocol_uuid NOT IN (SELECT protocol_uuid FROM protocol_revisions);")?)).await?;
+
+        for protocol_uuid in missing {
+            let text = match self.blobs.get_text(&text_key(&protocol_uuid)).await {
+                Ok(text) => text,
+                Err(err) => {
+                    println!("Failed to record revision of protocol {}!: {:?}", protocol_uuid, err);
+                    continue;
+                },
+            };
+
+            self.run(move |connection| connection.record_first_revision(&protocol_uuid, &text)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A connection with an open write transaction. It's rolled back if it's dropped before the commit,
+/// e.g. because the request was cancelled while the protocol text was written
+struct Transaction {
+    pooled: Option<Object<SQLiteConnection>>
+}
+
+impl Transaction {
+    async fn commit(self) -> Result<(), DatabaseError> {
+        self.finish("COMMIT;").await
+    }
+
+    async fn rollback(self) -> Result<(), DatabaseError> {
+        self.finish("ROLLBACK;").await
+    }
+
+    async fn finish(self, query: &'static str) -> Result<(), DatabaseError> {
+        let (mut transaction, result) = blocking(self, move |transaction| transaction.connection.execute(query)).await?;
+        result?;
+
+        // Nothing left to roll back, the connection goes back to the pool
+        transaction.pooled = None;
+
+        Ok(())
+    }
+}
+
+impl Deref for Transaction {
+    type Target = SQLiteConnection;
+
+    fn deref(&self) -> &SQLiteConnection {
+        self.pooled.as_ref().expect("Transaction was already finished")
+    }
+}
+
+impl Drop for Transaction {
+    /// The rollback can wait for the disk, so it's moved off the async threads like every other query.
+    /// The connection goes back to the pool once it's done
+    fn drop(&mut self) {
+        if let Some(pooled) = self.pooled.take() {
+            let rollback = move || {
+                let _ = pooled.connection.execute("ROLLBACK;");
+            };
+
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn_blocking(rollback)),
+                Err(_) => rollback(),
+            }
+        }
+    }
+}
+
+/// Encryption errors of the revision texts are reported like the database errors around them
+fn cipher_error(err: io::Error) -> Error {
+    Error { code: None, message: Some(err.to_string()) }
+}
+
+/// Moves the connection to one of tokio's blocking threads for the work and hands it back with the result
+async fn blocking<C: Send + 'static, T: Send + 'static>(connection: C, work: impl FnOnce(&C) -> T + Send + 'static) -> Result<(C, T), DatabaseError> {
+    let task = tokio::task::spawn_blocking(move || {
+        let result = work(&connection);
+        (connection, result)
+    });
+
+    match task.await {
+        Ok(done) => Ok(done),
+        Err(err) => Err(io::Error::other(err).into()),
+    }
+}
+
+impl SQLiteConnection {
+    /// WAL lets searches read while a protocol is written, writers wait for each other up to the busy timeout.
+    /// With encrypted texts deleted content is overwritten, so no plaintext of an earlier version stays behind in the file
+    fn open(file_location: &Path, cipher: Option<Arc<ProtocolCipher>>) -> Result<SQLiteConnection, Error> {
+        let mut connection = sqlite::Connection::open_thread_safe(file_location)?;
+        connection.set_busy_timeout(BUSY_TIMEOUT_MILLISECONDS)?;
+        connection.execute("PRAGMA journal_mode = WAL;")?;
+
+        if !full_text_index_allowed(cipher.as_deref()) {
+            connection.execute("PRAGMA secure_delete = ON;")?;
+        }
+
+        Ok(SQLiteConnection { connection, cipher })
+    }
+
+    fn indexes_texts(&self) -> bool {
+        full_text_index_allowed(self.cipher.as_deref())
+    }
+
+    /// Optimizing merges the index into one empty segment, so the terms are gone from the file as well
+    fn clear_full_text_index(&self) -> Result<(), Error> {
+        self.connection.execute("DELETE FROM protocol_texts;")?;
+        self.connection.execute("INSERT INTO protocol_texts(protocol_texts) VALUES ('optimize');")?;
+        self.checkpoint()
+    }
+
+    /// Until a checkpoint the database file still holds the old pages of everything changed in the WAL,
+    /// which would otherwise only happen when the last connection closes
+    fn checkpoint(&self) -> Result<(), Error> {
+        self.connection.execute("PRAGMA wal_checkpoint(TRUNCATE);")
+    }
+
+    /// Brings the schema up to date, every pending migration is applied in its own transaction
+    fn migrate(&self) -> Result<(), DatabaseError> {
+        self.connection.execute(SCHEMA_VERSION_TABLE_SQLITE)?;
+
+        let mut statement = self.connection.prepare("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version;")?;
+        statement.next()?;
+        let database_version = statement.read::<i64, _>("version")?;
+        drop(statement);
+
+        check_schema_version(database_version)?;
+
+        for migration in pending_migrations(database_version) {
+            println!("Applying database migration {}: {}", migration.version, migration.description);
+
+            self.connection.execute("BEGIN IMMEDIATE;")?;
+
+            let result = self.connection.execute(migration.sqlite).and_then(|_| {
+                let mut statement = self.connection.prepare("INSERT INTO schema_version(version, description, applied) VALUES (?, ?, ?);")?;
+                statement.bind((1, migration.version))?;
+                statement.bind((2, migration.description))?;
+                statement.bind((3, get_current_time_seconds() as i64))?;
+                statement.next().map(|_| ())
+            });
+
+            match result {
+                Ok(_) => self.connection.execute("COMMIT;")?,
+                Err(err) => {
+                    self.connection.execute("ROLLBACK;")?;
+                    return Err(err.into());
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the work in a write transaction that is rolled back if the work fails
+    fn in_transaction<T>(&self, work: impl FnOnce() -> Result<T, DatabaseError>) -> Result<T, DatabaseError> {
+        self.connection.execute("BEGIN IMMEDIATE;")?;
+
+        match work() {
+            Ok(result) => {
+                self.connection.execute("COMMIT;")?;
+                Ok(result)
+            },
+            Err(err) => {
+                self.connection.execute("ROLLBACK;")?;
+                Err(err)
+            },
+        }
+    }
+
+    /// Nothing is indexed while new texts are encrypted
+    fn index_protocol_text(&self, protocol_uuid: &str, text: &str) -> Result<(), Error> {
+        if !self.indexes_texts() {
+            return Ok(());
+        }
+
+        let mut statement = self.connection.prepare("INSERT INTO protocol_texts(protocol_uuid, text) VALUES (?, ?);")?;
+        statement.bind((1, protocol_uuid))?;
+        statement.bind((2, text))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn record_first_revision(&self, protocol_uuid: &str, text: &str) -> Result<(), DatabaseError> {
+        let filing = match self.protocol_filing(protocol_uuid)? {
+            Some(filing) => filing,
+            None => return Ok(()),
+        };
+
+        self.connection.execute("BEGIN IMMEDIATE;")?;
+
+        match self.insert_revision(protocol_uuid, &filing, text, None) {
+            Ok(_) => self.connection.execute("COMMIT;")?,
+            Err(err) => {
+                self.connection.execute("ROLLBACK;")?;
+                return Err(err.into());
+            },
+        }
+
+        Ok(())
+    }
+
+    //Protocols
+
+    /// The first of the referenced entities that doesn't exist
+    fn find_unknown_entity(&self, entities: &[(CatalogEntity, i64)]) -> Result<Option<(CatalogEntity, i64)>, Error> {
+        for (entity, id) in entities {
+            if !self.entity_exists(*entity, *id)? {
+                return Ok(Some((*entity, *id)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Applies the update to the rows and records the new revision. Has to run inside a transaction,
+    /// the filing is read inside of it, so a concurrent update can't be overwritten with stale metadata
+    fn write_update_revision(&self, protocol_uuid: &str, update: &ProtocolUpdate, text: &str, author: &str) -> Result<ProtocolChange, DatabaseError> {
+        let mut filing = match self.protocol_filing(protocol_uuid)? {
+            Some(filing) => filing,
+            None => return Ok(ProtocolChange::NotFound),
+        };
+
+        filing.apply(update);
+
+        // Without foreign keys SQLite would accept the IDs, and the protocol would vanish from every query
+        if let Some((entity, id)) = self.find_unknown_entity(&filing.referenced_entities())? {
+            println!("Got unknown {:?}-ID!: {}", entity, id);
+            return Ok(ProtocolChange::UnknownEntity(entity, id));
+        }
+
+        self.write_protocol_update(protocol_uuid, &filing, update)?;
+        self.insert_revision(protocol_uuid, &filing, text, Some(author))?;
+
+        Ok(ProtocolChange::Updated)
+    }
+
+    /// Removes the rows of the protocol, its text blob is left to the caller
+    fn delete_protocol(&self, protocol_uuid: &str) -> Result<bool, DatabaseError> {
+        let filing = match self.protocol_filing(protocol_uuid)? {
+            Some(filing) => filing,
+            None => return Ok(false),
+        };
+
+        self.connection.execute("BEGIN IMMEDIATE;")?;
+
+        let result = self.delete_protocol_rows(protocol_uuid)
+            .and_then(|_| self.remove_unused_relations(&filing.relation_ids));
+
+        match result {
+            Ok(_) => self.connection.execute("COMMIT;")?,
+            Err(err) => {
+                self.connection.execute("ROLLBACK;")?;
+                return Err(err.into());
+            },
+        }
+
+        Ok(true)
+    }
+
+    //Sessions
+
+    fn save_access_token(&self, email: &str) -> Result<Option<String>, DatabaseError> {
+        let uuid = match self.get_new_token_uuid() {
+            Some(uuid) => uuid,
+            None => {
+                return Ok(None);
+            },
+        };
+        let mut statement = self.connection.prepare("INSERT INTO sessions(uuid, created, email) VALUES (?, ?, ?);")?;
+        statement.bind((1, uuid.as_str()))?;
+        statement.bind((2, get_current_time_seconds() as i64))?;
+        statement.bind((3, email))?;
+
+        match statement.next() {
+            Ok(_) => Ok(Some(uuid)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn remove_expired_sessions(&self) -> Result<(), DatabaseError> {
+        let mut statement = self.connection.prepare("DELETE FROM sessions WHERE created < ?;")?;
+        statement.bind((1, (get_current_time_seconds() - TOKEN_VALID_LENGTH) as i64))?;
+
+        match statement.next() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn is_session_valid(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        let mut statement = self.connection.prepare("SELECT uuid FROM sessions WHERE uuid = ?;")?;
+        statement.bind((1, session_id))?;
+
+        if let Ok(State::Row) = statement.next() {
+            match statement.read::<String, _>("uuid") {
+                Ok(uuid) => {
+                    Ok(uuid.eq(session_id))
+                },
+                Err(err) => Err(err.into()),
+            }
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn remove_session(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        let mut statement = self.connection.prepare("DELETE FROM sessions WHERE uuid = ?;")?;
+        statement.bind((1, session_id))?;
+        statement.next()?;
+
+        Ok(self.connection.change_count() > 0)
+    }
+
+    fn get_sessions(&self, email: &str) -> Result<Vec<OutputSession>, DatabaseError> {
+        let mut statement = self.connection.prepare("SELECT uuid, created FROM sessions WHERE email = ? AND created >= ? ORDER BY created, id;")?;
+        statement.bind((1, email))?;
+        statement.bind((2, (get_current_time_seconds() - TOKEN_VALID_LENGTH) as i64))?;
+
+        let mut sessions = vec![];
+
+        while let Ok(State::Row) = statement.next() {
+            let created = statement.read::<i64, _>("created")?;
+            sessions.push(OutputSession { session_id: statement.read::<String, _>("uuid")?, created, expires: created + TOKEN_VALID_LENGTH as i64 });
+        }
+
+        Ok(sessions)
+    }
+
+    fn remove_sessions(&self, email: &str) -> Result<u64, DatabaseError> {
+        let mut statement = self.connection.prepare("DELETE FROM sessions WHERE email = ?;")?;
+        statement.bind((1, email))?;
+        statement.next()?;
+
+        Ok(self.connection.change_count() as u64)
+    }
+
+    //Admins
+
+    fn check_if_user_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        let mut statement = self.connection.prepare("SELECT email FROM admins WHERE email = ?;")?;
+        statement.bind((1, email))?;
+
+        if let Ok(State::Row) = statement.next() {
+            match statement.read::<String, _>("email") {
+                Ok(database_email) => {
+                    Ok(database_email.eq(&email))
+                },
+                Err(err) => Err(err.into()),
+            }
+        } else {
+            Ok(false) 
+        }
+    }
+
+    fn add_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        let mut statement = self.connection.prepare("INSERT INTO admins(email) SELECT :email WHERE NOT EXISTS (SELECT 1 FROM admins WHERE email = :email);")?;
+        statement.bind((":email", email))?;
+        statement.next()?;
+
+        Ok(true)
+    }
+
+    fn remove_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+
+        if !email_is_valid(email) {
+            println!("Got invalid Email!: {:?}", email);
+            return Ok(false);
+        }
+
+        let mut statement = self.connection.prepare("DELETE FROM admins WHERE email = ?;")?;
+        statement.bind((1, email))?;
+        statement.next()?;
+
+        Ok(true)
+    }
+
+    fn get_admins(&self) -> Result<Vec<String>, DatabaseError> {
+        let mut statement = self.connection.prepare("SELECT email FROM admins;")?;
+
+
+        let mut admins = vec![];
+
+        while let Ok(State::Row) = statement.next() {
+            let mail = statement.read::<String, _>("email")?;
+            admins.push(mail);
+        }
+
+        Ok(admins)
+    }
+
+    //Catalog Entities
+
+    /// Has to run inside a transaction, so two requests can't both create the same name
+    fn create_item(&self, entity: CatalogEntity, display_name: String) -> Result<Option<i64>, DatabaseError> {
+
+        let table_name = entity.table_name();
+
+        if !display_name_is_valid(&display_name) {
+            println!("Got invalid Input: {:?}", display_name);
+            return Ok(None);
+        }
+
+        let query = format!("SELECT id FROM {} WHERE display_name = ?;", table_name);
+
+        let potential_id = self.if_exists(&query, &[display_name.as_str().into()])?;
+
+        if let Some(id) = potential_id {
+            return Result::Ok(Some(id));
+        }
+
+        let mut statement = self.connection.prepare(format!("INSERT INTO {}(display_name) VALUES (?);", table_name))?;
+        statement.bind((1, display_name.as_str()))?;
+
+        match statement.next() {
+            Ok(_) => {},
+            Err(err) => return Result::Err(err.into()),
+        };
+        drop(statement);
+
+        let potential_id = self.if_exists(&query, &[display_name.as_str().into()])?;
+
+        match potential_id {
+            Some(id) => Result::Ok(Some(id)),
+            None => Result::Ok(None),
+        }
+
+    }
+
+    fn get_selection_identifiers(&self) -> Result<SelectionIdentifier, DatabaseError> {
+        
+        let mut identifiers = SelectionIdentifier { examiners: vec![], subjects: vec![], stex: vec![], seasons: vec![] };
+
+        match self.request_selection_identifiers("examiners" , &mut identifiers.examiners) {
+            Ok(_) => {},
+            Err(err) => return Err(err.into()),
+        };
+
+        match self.request_selection_identifiers("subjects" , &mut identifiers.subjects) {
+            Ok(_) => {},
+            Err(err) => return Err(err.into()),
+        };
+
+        match self.request_selection_identifiers("stex" , &mut identifiers.stex) {
+            Ok(_) => {},
+            Err(err) => return Err(err.into()),
+        };
+
+        match self.request_selection_identifiers("seasons" , &mut identifiers.seasons) {
+            Ok(_) => {},
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(identifiers)
+    }
+
+    /// Has to run inside a transaction, the name could be taken between the check and the update otherwise
+    fn rename_item(&self, entity: CatalogEntity, id: i64, display_name: String) -> Result<EntityChange, DatabaseError> {
+
+        if !display_name_is_valid(&display_name) {
+            println!("Got invalid Input: {:?}", display_name);
+            return Ok(EntityChange::InvalidName);
+        }
+
+        if !self.entity_exists(entity, id)? {
+            return Ok(EntityChange::NotFound);
+        }
+
+        let mut statement = self.connection.prepare(format!("SELECT id FROM {} WHERE display_name = ? AND id != ?;", entity.table_name()))?;
+        statement.bind((1, display_name.as_str()))?;
+        statement.bind((2, id))?;
+
+        if let State::Row = statement.next()? {
+            return Ok(EntityChange::NameTaken);
+        }
+        drop(statement);
+
+        let mut statement = self.connection.prepare(format!("UPDATE {} SET display_name = ? WHERE id = ?;", entity.table_name()))?;
+        statement.bind((1, display_name.as_str()))?;
+        statement.bind((2, id))?;
+        statement.next()?;
+
+        Ok(EntityChange::Done)
+    }
+
+    /// Has to run inside a transaction
+    fn merge_items(&self, entity: CatalogEntity, from_id: i64, into_id: i64) -> Result<EntityChange, DatabaseError> {
+
+        if !self.entity_exists(entity, from_id)? || !self.entity_exists(entity, into_id)? {
+            return Ok(EntityChange::NotFound);
+        }
+
+        if from_id == into_id {
+            return Ok(EntityChange::Done);
+        }
+
+        self.write_entity_merge(entity, from_id, into_id)?;
+
+        Ok(EntityChange::Done)
+    }
+
+    /// Has to run inside a transaction
+    fn delete_item(&self, entity: CatalogEntity, id: i64) -> Result<EntityChange, DatabaseError> {
+
+        if !self.entity_exists(entity, id)? {
+            return Ok(EntityChange::NotFound);
+        }
+
+        let usage_queries = [
+            format!("SELECT COUNT(*) AS count FROM protocols JOIN subject_relations ON subject_relations.id = protocols.relation_id WHERE subject_relations.{} = ?;", entity.column_name()),
+            format!("SELECT COUNT(*) AS count FROM {} WHERE {} = ?;", entity.revision_table_name(), entity.column_name())
+        ];
+
+        for query in usage_queries {
+            let mut statement = self.connection.prepare(query)?;
+            statement.bind((1, id))?;
+            statement.next()?;
+
+            if statement.read::<i64, _>("count")? > 0 {
+                return Ok(EntityChange::InUse);
+            }
+        }
+
+        // Relations without any protocol can be left over from before they were cleaned up
+        let queries = [
+            format!("DELETE FROM subject_relations WHERE {} = ?;", entity.column_name()),
+            format!("DELETE FROM {} WHERE id = ?;", entity.table_name())
+        ];
+
+        for query in queries {
+            let mut statement = self.connection.prepare(query)?;
+            statement.bind((1, id))?;
+            statement.next()?;
+        }
+
+        Ok(EntityChange::Done)
+    }
+
+    //Revisions
+
+    fn get_revisions(&self, protocol_uuid: &str) -> Result<Option<Vec<OutputRevision>>, DatabaseError> {
+        let revisions = self.read_revisions(protocol_uuid, None, false)?;
+
+        match revisions.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(revisions)),
+        }
+    }
+
+    fn get_revision(&self, protocol_uuid: &str, revision: i64) -> Result<Option<OutputRevision>, DatabaseError> {
+        Ok(self.read_revisions(protocol_uuid, Some(revision), true)?.pop())
+    }
+
+    //Data Reading
+
+    fn search_for_protocol(&self, filter: SearchFilter, page: Pagination) -> Result<SearchResults, DatabaseError> {
+        
+        let mut conditions = vec![];
+        let mut parameters = vec![];
+
+        self.build_search_criteria(filter.examiner_ids, &mut conditions, &mut parameters, "examiner_id");
+        self.build_search_criteria(filter.subject_ids, &mut conditions, &mut parameters, "subject_id");
+        self.build_search_criteria(filter.stex_ids, &mut conditions, &mut parameters, "stex_id");
+        self.build_search_criteria(filter.seasons, &mut conditions, &mut parameters, "season_id");
+        self.build_search_criteria(filter.years, &mut conditions, &mut parameters, "year");
+
+        self.build_exclusion_criteria(filter.exclude_examiner_ids, &mut conditions, &mut parameters, "examiner_id");
+        self.build_exclusion_criteria(filter.exclude_subject_ids, &mut conditions, &mut parameters, "subject_id");
+        self.build_exclusion_criteria(filter.exclude_stex_ids, &mut conditions, &mut parameters, "stex_id");
+        self.build_exclusion_criteria(filter.exclude_seasons, &mut conditions, &mut parameters, "season_id");
+        self.build_exclusion_criteria(filter.exclude_years, &mut conditions, &mut parameters, "year");
+
+        if let Some(year_from) = filter.year_from {
+            conditions.push(format!("year >= {}", filter_parameter(&mut parameters, year_from)));
+        }
+
+        if let Some(year_to) = filter.year_to {
+            conditions.push(format!("year <= {}", filter_parameter(&mut parameters, year_to)));
+        }
+
+        // Without any condition everything is listed
+        let search_clause = match conditions.is_empty() {
+            true => "1 = 1".to_string(),
+            false => conditions.join(" AND "),
+        };
+
+        // The match expression is bound as a parameter, only the snippet markers are part of the query.
+        // The hits have to be MATERIALIZED, snippet() doesn't work once SQLite flattens it into the GROUP BY
+        let match_expression = filter.text.as_deref().and_then(fts_match_expression);
+        let (text_hits, text_columns, text_join) = match match_expression {
+            Some(_) => (
+                format!("WITH text_hits AS MATERIALIZED (
+                             SELECT protocol_uuid AS text_uuid,
+                                    snippet(protocol_texts, 1, '{}', '{}', '…', 16) AS snippet,
+                                    -bm25(protocol_texts) AS rank
+                             FROM protocol_texts
+                             WHERE protocol_texts MATCH :text)", HIGHLIGHT_START, HIGHLIGHT_END),
+                "MAX(snippet) AS snippet, MAX(rank) AS rank",
+                "JOIN text_hits ON protocol_uuid = text_uuid"
+            ),
+            None => ("".to_string(), "NULL AS snippet, NULL AS rank", ""),
+        };
+
+        let from_clause = format!("
+            FROM protocols
+                     JOIN subject_relations ON subject_relations.id = protocols.relation_id
+                     JOIN examiners ON examiner_id = examiners.id
+                     JOIN subjects ON subject_id = subjects.id
+                     JOIN stex ON stex_id = stex.id
+                     JOIN seasons ON season_id = seasons.id
+                     {}
+            WHERE {}
+        ", text_join, search_clause);
+
+        let mut statement = self.connection.prepare(format!("{} SELECT COUNT(DISTINCT protocol_uuid) AS total {};", text_hits, from_clause))?;
+
+        if let Some(match_expression) = &match_expression {
+            statement.bind((":text", match_expression.as_str()))?;
+        }
+        for (name, value) in &parameters {
+            statement.bind((name.as_str(), *value))?;
+        }
+
+        statement.next()?;
+        let total = statement.read::<i64, _>("total")?;
+        drop(statement);
+
+        // A protocol has one row per examiner/subject pair, its creation order is the first of these rows
+        let query = format!("
+            {}
+            SELECT protocol_uuid             AS uuid,
+                   MAX(year)                 AS sort_year,
+                   MIN(seasons.display_name) AS sort_season,
+                   MIN(protocols.id)         AS created,
+                   {}
+            {}
+            GROUP BY protocol_uuid
+            ORDER BY rank DESC, sort_year DESC, sort_season, created, uuid
+            LIMIT :limit OFFSET :offset;
+        ", text_hits, text_columns, from_clause);
+
+        let mut statement = self.connection.prepare(&query)?;
+
+        if let Some(match_expression) = &match_expression {
+            statement.bind((":text", match_expression.as_str()))?;
+        }
+        for (name, value) in &parameters {
+            statement.bind((name.as_str(), *value))?;
+        }
+        statement.bind((":limit", page.limit))?;
+        statement.bind((":offset", page.offset))?;
+
+        let mut hits = vec![];
+
+        while let Ok(State::Row) = statement.next() {
+            hits.push(SearchHit {
+                uuid: statement.read::<String, _>("uuid")?,
+                snippet: statement.read::<Option<String>, _>("snippet")?,
+                rank: statement.read::<Option<f64>, _>("rank")?
+            });
+        }
+        drop(statement);
+
+        let protocols = self.load_output_protocols(hits.iter().map(|hit| hit.uuid.as_str()))?;
+
+        Ok(SearchResults { protocols: order_search_hits(protocols, hits), total })
+    }
+
+    fn get_protocol(&self, protocol_uuid: &str) -> Result<Option<OutputProtocol>, DatabaseError> {
+
+        if Uuid::parse_str(protocol_uuid).is_err() {
+            println!("Got invalid Protocol-UUID!: {:?}", protocol_uuid);
+            return Ok(None);
+        }
+
+        let mut protocols = self.load_output_protocols([protocol_uuid].into_iter())?;
+
+        Ok(protocols.remove(protocol_uuid))
+    }
+}
+
+#[async_trait]
+impl ProtocolStore for SQLiteDatabase {
+
+    //Sessions
+
+    async fn save_access_token(&self, email: &str) -> Result<Option<String>, DatabaseError> {
+        let email = email.to_string();
+        self.run(move |connection| connection.save_access_token(&email)).await
+    }
+
+    async fn remove_expired_sessions(&self) -> Result<(), DatabaseError> {
+        self.run(|connection| connection.remove_expired_sessions()).await
+    }
+
+    async fn is_session_valid(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        let session_id = session_id.to_string();
+        self.run(move |connection| connection.is_session_valid(&session_id)).await
+    }
+
+    async fn remove_session(&self, session_id: &str) -> Result<bool, DatabaseError> {
+        let session_id = session_id.to_string();
+        self.run(move |connection| connection.remove_session(&session_id)).await
+    }
+
+    async fn get_sessions(&self, email: &str) -> Result<Vec<OutputSession>, DatabaseError> {
+        let email = email.to_string();
+        self.run(move |connection| connection.get_sessions(&email)).await
+    }
+
+    async fn remove_sessions(&self, email: &str) -> Result<u64, DatabaseError> {
+        let email = email.to_string();
+        self.run(move |connection| connection.remove_sessions(&email)).await
+    }
+
+    //Admins
+
+    async fn check_if_user_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        let email = email.to_string();
+        self.run(move |connection| connection.check_if_user_admin(&email)).await
+    }
+
+    async fn add_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        let email = email.to_string();
+        self.run(move |connection| connection.add_admin(&email)).await
+    }
+
+    async fn remove_admin(&self, email: &str) -> Result<bool, DatabaseError> {
+        let email = email.to_string();
+        self.run(move |connection| connection.remove_admin(&email)).await
+    }
+
+    async fn get_admins(&self) -> Result<Vec<String>, DatabaseError> {
+        self.run(|connection| connection.get_admins()).await
+    }
+
+    //Catalog Entities
+
+    async fn create_item(&self, entity: CatalogEntity, display_name: String) -> Result<Option<i64>, DatabaseError> {
+        self.run(move |connection| connection.in_transaction(|| connection.create_item(entity, display_name))).await
+    }
+
+    async fn get_selection_identifiers(&self) -> Result<SelectionIdentifier, DatabaseError> {
+        self.run(|connection| connection.get_selection_identifiers()).await
+    }
+
+    async fn rename_item(&self, entity: CatalogEntity, id: i64, display_name: String) -> Result<EntityChange, DatabaseError> {
+        self.run(move |connection| connection.in_transaction(|| connection.rename_item(entity, id, display_name))).await
+    }
+
+    async fn merge_items(&self, entity: CatalogEntity, from_id: i64, into_id: i64) -> Result<EntityChange, DatabaseError> {
+        self.run(move |connection| connection.in_transaction(|| connection.merge_items(entity, from_id, into_id))).await
+    }
+
+    async fn delete_item(&self, entity: CatalogEntity, id: i64) -> Result<EntityChange, DatabaseError> {
+        self.run(move |connection| connection.in_transaction(|| connection.delete_item(entity, id))).await
+    }
+
+    //Protocols
+
+    async fn save_protocol(&self, examiner_subject_relation_ids: Vec<(i64, i64)>, stex_id: i64, season_id: i64, year: i64, protocol: String, author: &str) -> Result<ProtocolCreation, DatabaseError> {
+        let filing = ProtocolFiling { relation_ids: vec![], examiner_subject_ids: examiner_subject_relation_ids, stex_id, season_id, year };
+
+        // Checked before the text is written as well, so a request with unknown IDs doesn't upload anything
+        let entities = filing.referenced_entities();
+
+        if let Some((entity, id)) = self.run(move |connection| Ok(connection.find_unknown_entity(&entities)?)).await? {
+            println!("Got unknown {:?}-ID!: {}", entity, id);
+            return Ok(ProtocolCreation::UnknownEntity(entity, id));
+        }
+
+        let protocol_uuid = match self.run(|connection| Ok(connection.get_new_uuid())).await? {
+            Some(uuid) => uuid,
+            None => return Err(io::Error::other("Failed to generate a Protocol-UUID").into()),
+        };
+
+        // The text only gets its real name right before the commit, so a failed save leaves no blob behind
+        let protocol_key = text_key(&protocol_uuid);
+        let new_key = new_text_key(&protocol_uuid);
+
+        self.blobs.put(&new_key, protocol.clone().into_bytes()).await?;
+
+        let transaction = match self.begin().await {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                let _ = self.blobs.delete(&new_key).await;
+                return Err(err);
+            },
+        };
+
+        let uuid = protocol_uuid.clone();
+        let author = author.to_string();
+        let (transaction, result) = blocking(transaction, move |transaction| transaction.write_new_protocol(&uuid, &filing, &protocol, &author)).await?;
+
+        let result = match result {
+            Ok(ProtocolCreation::Saved(uuid)) => self.blobs.rename(&new_key, &protocol_key).await.map(|_| ProtocolCreation::Saved(uuid)).map_err(DatabaseError::from),
+            other => other,
+        };
+
+        match result {
+            Ok(ProtocolCreation::Saved(_)) => {},
+            Ok(_) | Err(_) => {
+                transaction.rollback().await?;
+                let _ = self.blobs.delete(&new_key).await;
+                return result;
+            },
+        }
+
+        if let Err(err) = transaction.commit().await {
+            let _ = self.blobs.delete(&protocol_key).await;
+            return Err(err);
+        }
+
+        result
+    }
+
+    async fn update_protocol(&self, protocol_uuid: &str, update: ProtocolUpdate, author: &str) -> Result<ProtocolChange, DatabaseError> {
+
+        if Uuid::parse_str(protocol_uuid).is_err() {
+            println!("Got invalid Protocol-UUID!: {:?}", protocol_uuid);
+            return Ok(ProtocolChange::NotFound);
+        }
+
+        let uuid = protocol_uuid.to_string();
+
+        let latest_text = match self.run(move |connection| Ok(connection.protocol_filing(&uuid)?.map(|_| connection.latest_revision_text(&uuid)))).await? {
+            Some(latest_text) => latest_text?,
+            None => return Ok(ProtocolChange::NotFound),
+        };
+
+        // The new text replaces the old blob right before the commit, and is undone if the commit fails
+        let protocol_key = text_key(protocol_uuid);
+        let new_key = new_text_key(protocol_uuid);
+        let replaces_text = update.text.is_some();
+
+        let previous_text = match latest_text {
+            Some(text) => text,
+            None => self.blobs.get_text(&protocol_key).await?,
+        };
+
+        let text = match &update.text {
+            Some(text) => {
+                self.blobs.put(&new_key, text.clone().into_bytes()).await?;
+                text.clone()
+            },
+            None => previous_text.clone(),
+        };
+
+        let transaction = match self.begin().await {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                if replaces_text {
+                    let _ = self.blobs.delete(&new_key).await;
+                }
+                return Err(err);
+            },
+        };
+
+        let uuid = protocol_uuid.to_string();
+        let author = author.to_string();
+        let (transaction, result) = blocking(transaction, move |transaction| transaction.write_update_revision(&uuid, &update, &text, &author)).await?;
+
+        let result = match result {
+            Ok(ProtocolChange::Updated) if replaces_text => self.blobs.rename(&new_key, &protocol_key).await.map(|_| ProtocolChange::Updated).map_err(DatabaseError::from),
+            other => other,
+        };
+
+        match result {
+            Ok(ProtocolChange::Updated) => {},
+            Ok(_) | Err(_) => {
+                transaction.rollback().await?;
+                if replaces_text {
+                    let _ = self.blobs.delete(&new_key).await;
+                }
+                return result;
+            },
+        }
+
+        if let Err(err) = transaction.commit().await {
+            // The database still has the previous revision, so its text goes back
+            if replaces_text {
+                let _ = self.blobs.put(&protocol_key, previous_text.into_bytes()).await;
+            }
+            return Err(err);
+        }
+
+        Ok(ProtocolChange::Updated)
+    }
+
+    async fn delete_protocol(&self, protocol_uuid: &str) -> Result<bool, DatabaseError> {
+
+        if Uuid::parse_str(protocol_uuid).is_err() {
+            println!("Got invalid Protocol-UUID!: {:?}", protocol_uuid);
+            return Ok(false);
+        }
+
+        let uuid = protocol_uuid.to_string();
+
+        if !self.run(move |connection| connection.delete_protocol(&uuid)).await? {
+            return Ok(false);
+        }
+
+        // The protocol is already gone from the database, a leftover blob is only a waste of space
+        match self.blobs.delete(&text_key(protocol_uuid)).await {
+            Ok(_) => {},
+            Err(err) => println!("Failed to remove protocol file of {}!: {:?}", protocol_uuid, err),
+        }
+
+        Ok(true)
+    }
+
+    //Revisions
+
+    async fn get_revisions(&self, protocol_uuid: &str) -> Result<Option<Vec<OutputRevision>>, DatabaseError> {
+        let protocol_uuid = protocol_uuid.to_string();
+        self.run(move |connection| connection.get_revisions(&protocol_uuid)).await
+    }
+
+    async fn get_revision(&self, protocol_uuid: &str, revision: i64) -> Result<Option<OutputRevision>, DatabaseError> {
+        let protocol_uuid = protocol_uuid.to_string();
+        self.run(move |connection| connection.get_revision(&protocol_uuid, revision)).await
+    }
+
+    //Data Reading
+
+    async fn search_for_protocol(&self, filter: SearchFilter, page: Pagination) -> Result<SearchResults, DatabaseError> {
+        self.run(move |connection| connection.search_for_protocol(filter, page)).await
+    }
+
+    async fn get_protocol(&self, protocol_uuid: &str) -> Result<Option<OutputProtocol>, DatabaseError> {
+        let protocol_uuid = protocol_uuid.to_string();
+        self.run(move |connection| connection.get_protocol(&protocol_uuid)).await
+    }
+
+    async fn open_protocol_text(&self, protocol_uuid: &str) -> Result<ProtocolText, DatabaseError> {
+        Ok(self.blobs.open(&text_key(protocol_uuid)).await?)
+    }
+
+    //Maintenance
+
+    async fn check_consistency(&self, repair: Option<RepairMode>) -> Result<ConsistencyReport, DatabaseError> {
+        // The write lock is held until the end, so no save can commit between reading the rows and listing or repairing the blobs
+        let transaction = self.begin().await?;
+
+        let (transaction, result) = blocking(transaction, |transaction| -> Result<_, Error> {
+            let protocol_uuids: HashSet<String> = transaction.read_protocol_uuids("SELECT DISTINCT protocol_uuid FROM protocols;")?.into_iter().collect();
+            Ok((protocol_uuids, transaction.read_unused_relations()?, transaction.read_unused_entities()?))
+        }).await?;
+        let (protocol_uuids, relations_without_protocols, entities_without_relations) = result?;
+
+        let keys = settled_keys(self.blobs.as_ref(), self.blobs.list().await?).await?;
+
+        let mut report = ConsistencyReport {
+            files_without_rows: files_without_rows(&keys, &protocol_uuids),
+            rows_without_files: rows_without_files(&keys, &protocol_uuids),
+            relations_without_protocols,
+            entities_without_relations,
+            repair,
+            restored_files: vec![]
+        };
+
+        let repair = match repair {
+            Some(repair) => repair,
+            None => return Ok(report),
+        };
+
+        for file_name in &report.files_without_rows {
+            repair_orphaned_file(self.blobs.as_ref(), file_name, repair).await?;
+        }
+
+        let mut transaction = transaction;
+
+        for protocol_uuid in report.rows_without_files.clone() {
+            let uuid = protocol_uuid.clone();
+            let (returned, result) = blocking(transaction, move |transaction| transaction.latest_revision_text(&uuid)).await?;
+            transaction = returned;
+
+            match result? {
+                Some(text) => {
+                    self.blobs.put(&text_key(&protocol_uuid), text.into_bytes()).await?;
+                    report.restored_files.push(protocol_uuid);
+                },
+                None if repair == RepairMode::Delete => {
+                    // The text is already missing, so only the rows are left to remove
+                    let (returned, result) = blocking(transaction, move |transaction| transaction.delete_protocol_rows(&protocol_uuid)).await?;
+                    transaction = returned;
+                    result?;
+                },
+                None => println!("Can't restore the text of {}, there is no revision of it", protocol_uuid),
+            }
+        }
+
+        let (transaction, result) = blocking(transaction, move |transaction| transaction.remove_orphaned_rows(&report, repair).map(|_| report)).await?;
+        let report = result?;
+        transaction.commit().await?;
+
+        Ok(report)
+    }
+
+    async fn reencrypt_revisions(&self) -> Result<usize, DatabaseError> {
+        let transaction = self.begin().await?;
+
+        let (transaction, result) = blocking(transaction, |transaction| transaction.write_reencrypted_revisions()).await?;
+        let reencrypted = result?;
+        transaction.commit().await?;
+
+        // The plaintext revisions are only overwritten in the database file by the checkpoint
+        self.run(|connection| Ok(connection.checkpoint()?)).await?;
+
+        Ok(reencrypted)
+    }
+}
+
+impl SQLiteConnection {
+    //Helper Methods
+
+    /// The protocol_uuid column of the query's rows
+    fn read_protocol_uuids(&self, query: &str) -> Result<Vec<String>, Error> {
+        let mut statement = self.connection.prepare(query)?;
+        let mut protocol_uuids = vec![];
+
+        while let State::Row = statement.next()? {
+            protocol_uuids.push(statement.read::<String, _>("protocol_uuid")?);
+        }
+
+        Ok(protocol_uuids)
+    }
+
+    fn read_unused_relations(&self) -> Result<Vec<i64>, Error> {
+        let mut statement = self.connection.prepare(UNUSED_RELATIONS_QUERY)?;
+        let mut relation_ids = vec![];
+
+        while let State::Row = statement.next()? {
+            relation_ids.push(statement.read::<i64, _>("id")?);
+        }
+
+        Ok(relation_ids)
+    }
+
+    fn read_unused_entities(&self) -> Result<Vec<UnusedEntity>, Error> {
+        let mut unused = vec![];
+
+        for entity in CatalogEntity::ALL {
+            let mut statement = self.connection.prepare(unused_entities_query(entity))?;
+
+            while let State::Row = statement.next()? {
+                unused.push(UnusedEntity { kind: entity.label().to_string(), id: statement.read::<i64, _>("id")?, display_name: statement.read::<String, _>("display_name")? });
+            }
+        }
+
+        Ok(unused)
+    }
+
+    /// Has to run inside a transaction
+    fn remove_orphaned_rows(&self, report: &ConsistencyReport, repair: RepairMode) -> Result<(), Error> {
+        self.connection.execute(DELETE_UNUSED_RELATIONS)?;
+
+        if repair == RepairMode::Delete {
+            for entity in CatalogEntity::ALL {
+                let query = delete_unused_entity_query(entity, "?");
+
+                for unused in report.entities_without_relations.iter().filter(|unused| unused.kind == entity.label()) {
+                    let mut statement = self.connection.prepare(&query)?;
+                    statement.bind((1, unused.id))?;
+                    statement.next()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn protocol_filing(&self, protocol_uuid: &str) -> Result<Option<ProtocolFiling>, Error> {
+        let mut statement = self.connection.prepare("
+            SELECT relation_id, examiner_id, subject_id, stex_id, season_id, year
+            FROM protocols
+                     JOIN subject_relations ON subject_relations.id = protocols.relation_id
+            WHERE protocol_uuid = ?
+            ORDER BY protocols.id;
+        ")?;
+        statement.bind((1, protocol_uuid))?;
+
+        let mut rows = vec![];
+
+        while let Ok(State::Row) = statement.next() {
+            rows.push((
+                statement.read::<i64, _>("relation_id")?,
+                statement.read::<i64, _>("examiner_id")?,
+                statement.read::<i64, _>("subject_id")?,
+                statement.read::<i64, _>("stex_id")?,
+                statement.read::<i64, _>("season_id")?,
+                statement.read::<i64, _>("year")?
+            ));
+        }
+
+        Ok(ProtocolFiling::from_rows(rows))
+    }
+
+    /// Links a new protocol to its relations and stores its text and first revision. Has to run inside a transaction.
+    /// The IDs are checked again in there, an entity could have been deleted since the check before
+    fn write_new_protocol(&self, protocol_uuid: &str, filing: &ProtocolFiling, text: &str, author: &str) -> Result<ProtocolCreation, DatabaseError> {
+        if let Some((entity, id)) = self.find_unknown_entity(&filing.referenced_entities())? {
+            println!("Got unknown {:?}-ID!: {}", entity, id);
+            return Ok(ProtocolCreation::UnknownEntity(entity, id));
+        }
+
+        for (examiner_id, subject_id) in &filing.examiner_subject_ids {
+            let relation_id = match self.find_or_create_relation(*examiner_id, *subject_id, filing.stex_id, filing.season_id, filing.year)? {
+                Some(id) => id,
+                None => return Err(Error { code: None, message: Some("Failed to create relation!".to_string()) }.into()),
+            };
+
+            let mut statement = self.connection.prepare("INSERT INTO protocols(relation_id, protocol_uuid) VALUES (?, ?);")?;
+            statement.bind((1, relation_id))?;
+            statement.bind((2, protocol_uuid))?;
+            statement.next()?;
+        }
+
+        self.index_protocol_text(protocol_uuid, text)?;
+        self.insert_revision(protocol_uuid, filing, text, Some(author))?;
+
+        Ok(ProtocolCreation::Saved(protocol_uuid.to_string()))
+    }
+
+    /// Has to run inside a transaction
+    fn write_protocol_update(&self, protocol_uuid: &str, filing: &ProtocolFiling, update: &ProtocolUpdate) -> Result<(), Error> {
+        if update.changes_metadata() {
+            self.delete_protocol_relations(protocol_uuid)?;
+
+            for (examiner_id, subject_id) in &filing.examiner_subject_ids {
+                let relation_id = match self.find_or_create_relation(*examiner_id, *subject_id, filing.stex_id, filing.season_id, filing.year)? {
+                    Some(id) => id,
+                    None => return Err(Error { code: None, message: Some("Failed to create relation!".to_string()) }),
+                };
+
+                let mut statement = self.connection.prepare("INSERT INTO protocols(relation_id, protocol_uuid) VALUES (?, ?);")?;
+                statement.bind((1, relation_id))?;
+                statement.bind((2, protocol_uuid))?;
+                statement.next()?;
+            }
+
+            self.remove_unused_relations(&filing.relation_ids)?;
+        }
+
+        if let Some(text) = &update.text {
+            let mut statement = self.connection.prepare("UPDATE protocol_texts SET text = ? WHERE protocol_uuid = ?;")?;
+            statement.bind((1, text.as_str()))?;
+            statement.bind((2, protocol_uuid))?;
+            statement.next()?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the rows linking the protocol to its relations
+    fn delete_protocol_relations(&self, protocol_uuid: &str) -> Result<(), Error> {
+        let mut statement = self.connection.prepare("DELETE FROM protocols WHERE protocol_uuid = ?;")?;
+        statement.bind((1, protocol_uuid))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn delete_protocol_rows(&self, protocol_uuid: &str) -> Result<(), Error> {
+        self.delete_protocol_relations(protocol_uuid)?;
+
+        let queries = [
+            "DELETE FROM protocol_texts WHERE protocol_uuid = ?;",
+            "DELETE FROM protocol_revision_pairs WHERE revision_id IN (SELECT id FROM protocol_revisions WHERE protocol_uuid = ?);",
+            "DELETE FROM protocol_revisions WHERE protocol_uuid = ?;"
+        ];
+
+        for query in queries {
+            let mut statement = self.connection.prepare(query)?;
+            statement.bind((1, protocol_uuid))?;
+            statement.next()?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the given state of the protocol as its next revision
+    fn insert_revision(&self, protocol_uuid: &str, filing: &ProtocolFiling, text: &str, author: Option<&str>) -> Result<(), Error> {
+        let mut statement = self.connection.prepare("SELECT COALESCE(MAX(revision), 0) + 1 AS revision FROM protocol_revisions WHERE protocol_uuid = ?;")?;
+        statement.bind((1, protocol_uuid))?;
+        statement.next()?;
+        let revision = statement.read::<i64, _>("revision")?;
+        drop(statement);
+
+        let (text, text_key_id) = seal_revision_text(self.cipher.as_deref(), protocol_uuid, text).map_err(cipher_error)?;
+
+        let mut statement = self.connection.prepare("
+            INSERT INTO protocol_revisions(protocol_uuid, revision, created, author, stex_id, season_id, year, text, text_key_id)
+            VALUES (:uuid, :revision, :created, :author, :stex_id, :season_id, :year, :text, :text_key_id);
+        ")?;
+        statement.bind((":uuid", protocol_uuid))?;
+        statement.bind((":revision", revision))?;
+        statement.bind((":created", get_current_time_seconds() as i64))?;
+        statement.bind((":author", author))?;
+        statement.bind((":stex_id", filing.stex_id))?;
+        statement.bind((":season_id", filing.season_id))?;
+        statement.bind((":year", filing.year))?;
+        statement.bind((":text", text.as_str()))?;
+        statement.bind((":text_key_id", text_key_id.as_deref()))?;
+        statement.next()?;
+        drop(statement);
+
+        let revision_id = match self.if_exists("SELECT last_insert_rowid() AS id;", &[])? {
+            Some(id) => id,
+            None => return Err(Error { code: None, message: Some("Failed to create revision!".to_string()) }),
+        };
+
+        for (examiner_id, subject_id) in &filing.examiner_subject_ids {
+            let mut statement = self.connection.prepare("INSERT INTO protocol_revision_pairs(revision_id, examiner_id, subject_id) VALUES (?, ?, ?);")?;
+            statement.bind((1, revision_id))?;
+            statement.bind((2, *examiner_id))?;
+            statement.bind((3, *subject_id))?;
+            statement.next()?;
+        }
+
+        Ok(())
+    }
+
+    fn latest_revision_text(&self, protocol_uuid: &str) -> Result<Option<String>, Error> {
+        let mut statement = self.connection.prepare("SELECT text, text_key_id FROM protocol_revisions WHERE protocol_uuid = ? ORDER BY revision DESC LIMIT 1;")?;
+        statement.bind((1, protocol_uuid))?;
+
+        match statement.next()? {
+            State::Row => Ok(Some(self.read_revision_text(&statement, protocol_uuid)?)),
+            State::Done => Ok(None),
+        }
+    }
+
+    fn read_revision_text(&self, statement: &Statement, protocol_uuid: &str) -> Result<String, Error> {
+        let text = statement.read::<String, _>("text")?;
+        let text_key_id = statement.read::<Option<String>, _>("text_key_id")?;
+
+        open_revision_text(self.cipher.as_deref(), protocol_uuid, text, text_key_id).map_err(cipher_error)
+    }
+
+    /// Has to run inside a transaction
+    fn write_reencrypted_revisions(&self) -> Result<usize, Error> {
+        let active_key_id = self.cipher.as_ref().and_then(|cipher| cipher.active_key_id().map(str::to_string));
+
+        let mut statement = self.connection.prepare("SELECT id, protocol_uuid, text, text_key_id FROM protocol_revisions WHERE text_key_id IS NOT ? ORDER BY id;")?;
+        statement.bind((1, active_key_id.as_deref()))?;
+
+        let mut revisions = vec![];
+
+        while let State::Row = statement.next()? {
+            let protocol_uuid = statement.read::<String, _>("protocol_uuid")?;
+            let text = self.read_revision_text(&statement, &protocol_uuid)?;
+            revisions.push((statement.read::<i64, _>("id")?, protocol_uuid, text));
+        }
+        drop(statement);
+
+        for (revision_id, protocol_uuid, text) in &revisions {
+            let (text, text_key_id) = seal_revision_text(self.cipher.as_deref(), protocol_uuid, text).map_err(cipher_error)?;
+
+            let mut statement = self.connection.prepare("UPDATE protocol_revisions SET text = ?, text_key_id = ? WHERE id = ?;")?;
+            statement.bind((1, text.as_str()))?;
+            statement.bind((2, text_key_id.as_deref()))?;
+            statement.bind((3, *revision_id))?;
+            statement.next()?;
+        }
+
+        Ok(revisions.len())
+    }
+
+    /// All revisions of the protocol, or only the one with the given number
+    fn read_revisions(&self, protocol_uuid: &str, revision: Option<i64>, with_text: bool) -> Result<Vec<OutputRevision>, Error> {
+        let mut statement = self.connection.prepare("
+            SELECT id, revision, created, author, stex_id, season_id, year, text, text_key_id
+            FROM protocol_revisions
+            WHERE protocol_uuid = :uuid AND (:revision IS NULL OR revision = :revision)
+            ORDER BY revision;
+        ")?;
+        statement.bind((":uuid", protocol_uuid))?;
+        statement.bind((":revision", revision))?;
+
+        let mut revisions = vec![];
+
+        while let Ok(State::Row) = statement.next() {
+            let revision_id = statement.read::<i64, _>("id")?;
+
+            revisions.push((revision_id, OutputRevision {
+                revision: statement.read::<i64, _>("revision")?,
+                created: statement.read::<i64, _>("created")?,
+                author: statement.read::<Option<String>, _>("author")?,
+                examiner_subject_ids: vec![],
+                stex_id: statement.read::<i64, _>("stex_id")?,
+                season_id: statement.read::<i64, _>("season_id")?,
+                year: statement.read::<i64, _>("year")?,
+                text: match with_text {
+                    true => Some(self.read_revision_text(&statement, protocol_uuid)?),
+                    false => None,
+                }
+            }));
+        }
+        drop(statement);
+
+        for (revision_id, revision) in revisions.iter_mut() {
+            let mut statement = self.connection.prepare("SELECT examiner_id, subject_id FROM protocol_revision_pairs WHERE revision_id = ? ORDER BY id;")?;
+            statement.bind((1, *revision_id))?;
+
+            while let Ok(State::Row) = statement.next() {
+                revision.examiner_subject_ids.push((statement.read::<i64, _>("examiner_id")?, statement.read::<i64, _>("subject_id")?));
+            }
+        }
+
+        Ok(revisions.into_iter().map(|(_, revision)| revision).collect())
+    }
+
+    fn entity_exists(&self, entity: CatalogEntity, id: i64) -> Result<bool, Error> {
+        let mut statement = self.connection.prepare(format!("SELECT id FROM {} WHERE id = ?;", entity.table_name()))?;
+        statement.bind((1, id))?;
+
+        Ok(statement.next()? == State::Row)
+    }
+
+    /// Has to run inside a transaction
+    fn write_entity_merge(&self, entity: CatalogEntity, from_id: i64, into_id: i64) -> Result<(), Error> {
+        let column = entity.column_name();
+        let revision_table = entity.revision_table_name();
+
+        let queries = [
+            format!("UPDATE subject_relations SET {} = :into WHERE {} = :from;", column, column),
+            format!("UPDATE {} SET {} = :into WHERE {} = :from;", revision_table, column, column)
+        ];
+
+        for query in queries {
+            let mut statement = self.connection.prepare(query)?;
+            statement.bind((":into", into_id))?;
+            statement.bind((":from", from_id))?;
+            statement.next()?;
+        }
+
+        let mut statement = self.connection.prepare(format!("DELETE FROM {} WHERE id = ?;", entity.table_name()))?;
+        statement.bind((1, from_id))?;
+        statement.next()?;
+        drop(statement);
+
+        for query in COLLAPSE_DUPLICATES {
+            self.connection.execute(query)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes those of the relations that no protocol belongs to anymore
+    fn remove_unused_relations(&self, relation_ids: &[i64]) -> Result<(), Error> {
+        for relation_id in relation_ids {
+            let mut statement = self.connection.prepare("DELETE FROM subject_relations WHERE id = ? AND id NOT IN (SELECT relation_id FROM protocols);")?;
+            statement.bind((1, *relation_id))?;
+            statement.next()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the relation with these IDs, creating it if it doesn't exist yet. Can run inside a transaction
+    fn find_or_create_relation(&self, examiner_id: i64, subject_id: i64, stex_id: i64, season_id: i64, year: i64) -> Result<Option<i64>, Error> {
+        let query = "SELECT id FROM subject_relations WHERE examiner_id = ? AND subject_id = ? AND stex_id = ? AND season_id = ? AND year = ?;";
+        let parameters: [Value; 5] = [examiner_id.into(), subject_id.into(), stex_id.into(), season_id.into(), year.into()];
+
+        let potential_id = self.if_exists(query, &parameters)?;
+
+        if let Some(id) = potential_id {
+            return Result::Ok(Some(id))
+        }
+
+        let mut statement = self.connection.prepare("INSERT INTO subject_relations(examiner_id, subject_id, stex_id, season_id, year) VALUES (?, ?, ?, ?, ?);")?;
+        statement.bind(&parameters[..])?;
+
+        match statement.next() {
+            Ok(_) => {},
+            Err(err) => return Result::Err(err),
+        }
+        drop(statement);
+
+        let potential_id = self.if_exists(query, &parameters)?;
+
+        match potential_id {
+            Some(id) => Result::Ok(Some(id)),
+            None => Result::Ok(None),
+        }
+    }
+
+    fn request_selection_identifiers(&self, target_table: &str, identifiers: &mut Vec<SelectionIdentifierPair>) -> Result<(), Error> {
+        let query = format!("SELECT * FROM {};", target_table);
+        let mut statement = self.connection.prepare(&query)?;
+
+        while let Ok(State::Row) = statement.next() {
+            let display_name = statement.read::<String, _>("display_name")?;
+
+            let id = statement.read::<i64, _>("id")?;
+
+            identifiers.push(SelectionIdentifierPair { id, display_name });
+        }
+
+        Ok(())
+    }
+
+    /// Loads the metadata of the given protocols, protocols that don't exist are left out
+    fn load_output_protocols<'a>(&self, protocol_uuids: impl Iterator<Item = &'a str>) -> Result<HashMap<String, OutputProtocol>, Error> {
+        let protocol_uuids: Vec<&str> = protocol_uuids.collect();
+
+        if protocol_uuids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let placeholders = vec!["?"; protocol_uuids.len()].join(", ");
+
+        let query = format!("
+            SELECT protocol_uuid          AS uuid,
+                   examiners.display_name AS examiner,
+                   subjects.display_name  AS subject,
+                   stex.display_name      AS stex,
+                   seasons.display_name   AS season,
+                   year
+            FROM protocols
+                     JOIN subject_relations ON subject_relations.id = protocols.relation_id
+                     JOIN examiners ON examiner_id = examiners.id
+                     JOIN subjects ON subject_id = subjects.id
+                     JOIN stex ON stex_id = stex.id
+                     JOIN seasons ON season_id = seasons.id
+            WHERE protocol_uuid IN ({})
+            ORDER BY protocols.id;
+        ", placeholders);
+
+        let mut statement = self.connection.prepare(&query)?;
+
+        for (index, protocol_uuid) in protocol_uuids.into_iter().enumerate() {
+            statement.bind((index + 1, protocol_uuid))?;
+        }
+
+        self.collect_output_protocols(&mut statement)
+    }
+
+    /// Folds the rows of a protocol query (uuid, examiner, subject, stex, season, year) into one
+    /// OutputProtocol per UUID, as a protocol has one row per examiner/subject pair
+    fn collect_output_protocols(&self, statement: &mut Statement) -> Result<HashMap<String, OutputProtocol>, Error> {
+        let mut working_search_results: HashMap<String, OutputProtocol> = HashMap::new();
+
+        while let Ok(State::Row) = statement.next() {
+
+            let uuid = statement.read::<String, _>("uuid")?;
+            let examiner = statement.read::<String, _>("examiner")?;
+            let subject = statement.read::<String, _>("subject")?;
+            let stex = statement.read::<String, _>("stex")?;
+            let season = statement.read::<String, _>("season")?;
+            let year = statement.read::<i64, _>("year")?;
+
+            merge_protocol_row(&mut working_search_results, ProtocolRow { uuid, examiner, subject, stex, season, year });
+        }
+
+        Ok(working_search_results)
+    }
+
+    /// Matches rows where the column has any of the IDs
+    fn build_search_criteria(&self, input_ids: Option<Vec<i64>>, conditions: &mut Vec<String>, parameters: &mut Vec<(String, i64)>, search_criteria: &str) {
+        if let Some(ids) = input_ids { 
+            let alternatives: Vec<String> = ids.into_iter().map(|id| format!("{} = {}", search_criteria, filter_parameter(parameters, id))).collect();
+            conditions.push(format!("({})", alternatives.join(" OR ")));
+        }
+    }
+
+    /// Leaves out whole protocols if any of their rows has one of the IDs,
+    /// so excluding an examiner also drops protocols they examined together with someone else
+    fn build_exclusion_criteria(&self, input_ids: Option<Vec<i64>>, conditions: &mut Vec<String>, parameters: &mut Vec<(String, i64)>, search_criteria: &str) {
+        if let Some(ids) = input_ids {
+            let placeholders: Vec<String> = ids.into_iter().map(|id| filter_parameter(parameters, id)).collect();
+            conditions.push(format!("protocol_uuid NOT IN (SELECT protocol_uuid FROM protocols JOIN subject_relations ON subject_relations.id = protocols.relation_id WHERE {} IN ({}))", search_criteria, placeholders.join(", ")));
+        }
+    }
+
+    /// This method returns a UUID that is Unique (in this database)
+    /// Potentially it can recourse an infinite amount of times, but thats statistically VERY VERY
+    /// VERY UNLIKELY
+    fn get_new_uuid(&self) -> Option<String> {
+        let potential_uuid = Uuid::new_v4().to_string();
+
+        let potential_id = match self.if_exists("SELECT id FROM protocols WHERE protocol_uuid = ?;", &[potential_uuid.as_str().into()]) {
+            Ok(exists) => exists,
+            Err(err) => {
+                println!("Failed to check if uuid exists: {:?}", err); 
+                return None;
+            }
+        };
+
+        match potential_id {
+            Some(_) => self.get_new_uuid(),
+            None => Some(potential_uuid),
+        }
+    }
+
+    /// This method returns a UUID that is Unique (in this database)
+    /// Potentially it can recourse an infinite amount of times, but thats statistically VERY VERY
+    /// VERY UNLIKELY
+    fn get_new_token_uuid(&self) -> Option<String> {
+        let potential_uuid = Uuid::new_v4().to_string();
+
+        let potential_id = match self.if_exists("SELECT id FROM sessions WHERE uuid = ?;", &[potential_uuid.as_str().into()]) {
+            Ok(exists) => exists,
+            Err(err) => {
+                println!("Failed to check if uuid exists: {:?}", err); 
+                return None;
+            }
+        };
+
+        match potential_id {
+            Some(_) => self.get_new_token_uuid(),
+            None => Some(potential_uuid),
+        }
+    }
+    
+
+    /// The parameters are bound in order to the `?` of the query
+    fn if_exists(&self, query: &str, parameters: &[Value]) -> Result<Option<i64>, Error> {
+        let mut statement = match self.connection.prepare(query) {
+            Ok(statement) => statement,
+            Err(err) => {
+                return Result::Err(err);
+            },
+        };
+
+        statement.bind(parameters)?;
+
+        if let Ok(State::Row) = statement.next() {
+            Result::Ok(
+                match statement.read::<i64, _>("id") {
+                    Ok(id) => Some(id),
+                    Err(err) => {
+                        return Result::Err(err)
+                    },
+                }
+            )
+        } else {
+            Result::Ok(None)
+        }
+    }
+}
+
+/// Adds a value for the search conditions and returns its placeholder
+fn filter_parameter(parameters: &mut Vec<(String, i64)>, value: i64) -> String {
+    let name = format!(":filter{}", parameters.len());
+    parameters.push((name.clone(), value));
+    name
+}
+
+/// Turns the words of a search into an FTS5 query that requires every word, each also matching as a prefix.
+/// The words are quoted, so no FTS5 syntax can be injected. None if there are no words
+fn fts_match_expression(text: &str) -> Option<String> {
+    let terms: Vec<String> = text.split_whitespace().map(|term| format!("\"{}\"*", term.replace('"', "\"\""))).collect();
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(terms.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use crate::{storage::{blob_store::{LocalBlobStore, QUARANTINE_PREFIX}, testing::{create_catalog, save_protocol, text_filter, TestDirectory}}, structs::configuration::Encryption};
+
+    use super::*;
+
+    #[test]
+    fn match_expression_quotes_every_word() {
+        assert_eq!(fts_match_expression("plexus brach").as_deref(), Some("\"plexus\"* \"brach\"*"));
+        assert_eq!(fts_match_expression("  Niere\n").as_deref(), Some("\"Niere\"*"));
+        assert_eq!(fts_match_expression("a\"b OR NEAR(c").as_deref(), Some("\"a\"\"b\"* \"OR\"* \"NEAR(c\"*"));
+        assert_eq!(fts_match_expression(" \t"), None);
+    }
+
+    async fn database() -> (SQLiteDatabase, TestDirectory) {
+        let directory = TestDirectory::create();
+
+        let database = open(&directory, None).await;
+
+        create_catalog(&database).await;
+
+        (database, directory)
+    }
+
+    async fn open(directory: &TestDirectory, cipher: Option<Arc<ProtocolCipher>>) -> SQLiteDatabase {
+        let blobs: Arc<dyn BlobStore> = Arc::new(LocalBlobStore::new(directory.0.join("protocols")));
+        SQLiteDatabase::new(&directory.0.join("index.db"), blobs, cipher).await.unwrap()
+    }
+
+    const PAGE: Pagination = Pagination { limit: 50, offset: 0 };
+
+    #[tokio::test]
+    async fn full_text_search_finds_prefixes_and_escapes_snippets() {
+        let (database, _directory) = database().await;
+        database.save_protocol(vec![(1, 1)], 1, 1, 2023, "Gefragt wurde nach dem <b>Plexus</b> brachialis".to_string(), "admin@example.org").await.unwrap();
+        database.save_protocol(vec![(1, 1)], 1, 1, 2023, "Nur die Niere".to_string(), "admin@example.org").await.unwrap();
+
+        let results = database.search_for_protocol(text_filter(Some("plexus brach")), PAGE).await.unwrap();
+        assert_eq!(results.total, 1);
+        let snippet = results.protocols[0].snippet.clone().unwrap();
+        assert!(snippet.contains("&lt;b&gt;<mark>Plexus</mark>&lt;/b&gt;"), "{}", snippet);
+
+        assert_eq!(database.search_for_protocol(text_filter(Some("plexus niere")), PAGE).await.unwrap().total, 0);
+        assert_eq!(database.search_for_protocol(text_filter(Some("\"plexus OR NEAR(")), PAGE).await.unwrap().total, 0);
+    }
+
+    #[tokio::test]
+    async fn search_index_follows_text_updates() {
+        let (database, _directory) = database().await;
+        let uuid = save_protocol(&database, 2023, "Plexus brachialis").await;
+
+        let update = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: None, text: Some("Nur die Niere".to_string()) };
+        assert_eq!(database.update_protocol(&uuid, update, "admin@example.org").await.unwrap(), ProtocolChange::Updated);
+
+        assert_eq!(database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap().total, 0);
+        assert_eq!(database.search_for_protocol(text_filter(Some("niere")), PAGE).await.unwrap().total, 1);
+
+        let mut text = String::new();
+        database.open_protocol_text(&uuid).await.unwrap().read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "Nur die Niere");
+
+        assert!(database.delete_protocol(&uuid).await.unwrap());
+        assert_eq!(database.search_for_protocol(text_filter(Some("niere")), PAGE).await.unwrap().total, 0);
+    }
+
+    fn cipher(active_key_id: &str) -> Arc<ProtocolCipher> {
+        let encryption = Encryption {
+            token_encryption_secret: "secret".to_string(),
+            protocol_keys: [("2023", "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"), ("2024", "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100")]
+                .into_iter().map(|(key_id, key)| (key_id.to_string(), key.to_string())).collect(),
+            active_protocol_key: Some(active_key_id.to_string()),
+        };
+        Arc::new(ProtocolCipher::from_configuration(&encryption).unwrap().unwrap())
+    }
+
+    /// The revision texts as they are in the database file, with their key IDs
+    fn stored_revision_texts(directory: &TestDirectory) -> Vec<(String, Option<String>)> {
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        let mut statement = connection.prepare("SELECT text, text_key_id FROM protocol_revisions ORDER BY id;").unwrap();
+        let mut texts = vec![];
+
+        while let State::Row = statement.next().unwrap() {
+            texts.push((statement.read::<String, _>("text").unwrap(), statement.read::<Option<String>, _>("text_key_id").unwrap()));
+        }
+
+        texts
+    }
+
+    #[tokio::test]
+    async fn revision_texts_are_encrypted_and_reencrypted() {
+        let (database, directory) = database().await;
+        drop(database);
+
+        let database = open(&directory, Some(cipher("2023"))).await;
+        let uuid = save_protocol(&database, 2023, "Plexus brachialis").await;
+
+        let stored = stored_revision_texts(&directory);
+        assert_eq!(stored.len(), 1);
+        assert!(!stored[0].0.contains("Plexus"));
+        assert_eq!(stored[0].1.as_deref(), Some("2023"));
+        assert_eq!(database.get_revision(&uuid, 1).await.unwrap().unwrap().text.as_deref(), Some("Plexus brachialis"));
+        drop(database);
+
+        let database = open(&directory, Some(cipher("2024"))).await;
+        assert_eq!(database.reencrypt_revisions().await.unwrap(), 1);
+        assert_eq!(database.reencrypt_revisions().await.unwrap(), 0);
+        assert_eq!(stored_revision_texts(&directory)[0].1.as_deref(), Some("2024"));
+        assert_eq!(database.get_revision(&uuid, 1).await.unwrap().unwrap().text.as_deref(), Some("Plexus brachialis"));
+    }
+
+    /// The number of rows in the table of the database file
+    fn count_rows(directory: &TestDirectory, table: &str) -> i64 {
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        let mut statement = connection.prepare(format!("SELECT COUNT(*) AS count FROM {};", table)).unwrap();
+        statement.next().unwrap();
+        statement.read::<i64, _>("count").unwrap()
+    }
+
+    #[tokio::test]
+    async fn merging_examiners_collapses_duplicates() {
+        let (database, directory) = database().await;
+        assert_eq!(database.create_item(CatalogEntity::Examiner, "Dr. Beispiel".to_string()).await.unwrap(), Some(2));
+        assert_eq!(database.create_item(CatalogEntity::Subject, "Physiologie".to_string()).await.unwrap(), Some(2));
+
+        // Both examiners asked the same subject, once alone and once together
+        let together = match database.save_protocol(vec![(1, 1), (2, 1), (2, 2)], 1, 1, 2023, "Zusammen".to_string(), "admin@example.org").await.unwrap() {
+            ProtocolCreation::Saved(uuid) => uuid,
+            other => panic!("Protocol wasn't saved: {:?}", other),
+        };
+        let alone = match database.save_protocol(vec![(2, 1)], 1, 1, 2023, "Allein".to_string(), "admin@example.org").await.unwrap() {
+            ProtocolCreation::Saved(uuid) => uuid,
+            other => panic!("Protocol wasn't saved: {:?}", other),
+        };
+        assert_eq!(count_rows(&directory, "subject_relations"), 3);
+        assert_eq!(count_rows(&directory, "protocols"), 4);
+        assert_eq!(count_rows(&directory, "protocol_revision_pairs"), 4);
+
+        assert_eq!(database.merge_items(CatalogEntity::Examiner, 2, 1).await.unwrap(), EntityChange::Done);
+
+        assert_eq!(count_rows(&directory, "subject_relations"), 2);
+        assert_eq!(count_rows(&directory, "protocols"), 3);
+        assert_eq!(count_rows(&directory, "protocol_revision_pairs"), 3);
+        assert_eq!(database.get_selection_identifiers().await.unwrap().examiners.len(), 1);
+
+        let protocol = database.get_protocol(&together).await.unwrap().unwrap();
+        assert_eq!(protocol.examiners, vec!["Dr. Muster".to_string()]);
+        assert_eq!(protocol.subjects.len(), 2);
+        assert_eq!(database.get_protocol(&alone).await.unwrap().unwrap().examiners, vec!["Dr. Muster".to_string()]);
+
+        let mut pairs = database.get_revision(&together, 1).await.unwrap().unwrap().examiner_subject_ids;
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 1), (1, 2)]);
+        assert_eq!(database.get_revision(&alone, 1).await.unwrap().unwrap().examiner_subject_ids, vec![(1, 1)]);
+
+        assert_eq!(database.merge_items(CatalogEntity::Examiner, 1, 1).await.unwrap(), EntityChange::Done);
+        assert_eq!(database.merge_items(CatalogEntity::Examiner, 2, 1).await.unwrap(), EntityChange::NotFound);
+        assert_eq!(database.merge_items(CatalogEntity::Examiner, 1, 2).await.unwrap(), EntityChange::NotFound);
+        assert_eq!(count_rows(&directory, "subject_relations"), 2);
+    }
+
+    /// Saves a protocol of examiner 1 and subject 1 in the season and year, returns its UUID
+    async fn save_filed(database: &SQLiteDatabase, season_id: i64, year: i64, text: &str) -> String {
+        match database.save_protocol(vec![(1, 1)], 1, season_id, year, text.to_string(), "admin@example.org").await.unwrap() {
+            ProtocolCreation::Saved(uuid) => uuid,
+            other => panic!("Protocol wasn't saved: {:?}", other),
+        }
+    }
+
+    async fn search_uuids(database: &SQLiteDatabase, filter: SearchFilter, page: Pagination) -> (Vec<String>, i64) {
+        let results = database.search_for_protocol(filter, page).await.unwrap();
+        (results.protocols.into_iter().map(|protocol| protocol.uuid).collect(), results.total)
+    }
+
+    #[tokio::test]
+    async fn search_is_sorted_by_year_season_and_creation() {
+        let (database, _directory) = database().await;
+        assert_eq!(database.create_item(CatalogEntity::Season, "Herbst".to_string()).await.unwrap(), Some(2));
+
+        let spring_2022 = save_filed(&database, 1, 2022, "Niere").await;
+        let autumn_2023 = save_filed(&database, 2, 2023, "Niere").await;
+        let spring_2023 = save_filed(&database, 1, 2023, "Niere").await;
+        let spring_2023_later = save_filed(&database, 1, 2023, "Niere").await;
+        let autumn_2024 = save_filed(&database, 2, 2024, "Niere").await;
+
+        let expected = vec![autumn_2024, spring_2023, spring_2023_later, autumn_2023, spring_2022];
+        assert_eq!(search_uuids(&database, text_filter(None), PAGE).await, (expected.clone(), 5));
+
+        // All texts rank the same, so the full-text search keeps that order
+        let (uuids, _) = search_uuids(&database, text_filter(Some("niere")), PAGE).await;
+        assert_eq!(uuids, expected);
+
+        for offset in [0, 2, 4, 6] {
+            let page = Pagination { limit: 2, offset };
+            let (uuids, total) = search_uuids(&database, text_filter(None), page).await;
+            assert_eq!(total, 5);
+            assert_eq!(uuids, expected.iter().skip(offset as usize).take(2).cloned().collect::<Vec<String>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn full_text_search_is_sorted_by_rank_first() {
+        let (database, _directory) = database().await;
+
+        let mentioned_once = save_filed(&database, 1, 2024, "Gefragt wurde die Niere, danach lange die Leber, das Herz und die Lunge").await;
+        let mentioned_often = save_filed(&database, 1, 2020, "Niere, Niere und nochmal Niere").await;
+        save_filed(&database, 1, 2024, "Nur die Leber").await;
+
+        let results = database.search_for_protocol(text_filter(Some("niere")), PAGE).await.unwrap();
+        assert_eq!(results.total, 2);
+        assert_eq!(results.protocols.iter().map(|protocol| protocol.uuid.clone()).collect::<Vec<String>>(), vec![mentioned_often, mentioned_once]);
+        assert!(results.protocols[0].rank.unwrap() > results.protocols[1].rank.unwrap());
+    }
+
+    #[tokio::test]
+    async fn search_filters_exclude_whole_protocols() {
+        let (database, _directory) = database().await;
+        for (entity, name) in [(CatalogEntity::Examiner, "Dr. Beispiel"), (CatalogEntity::Subject, "Physiologie"), (CatalogEntity::Stex, "M2"), (CatalogEntity::Season, "Herbst")] {
+            assert_eq!(database.create_item(entity, name.to_string()).await.unwrap(), Some(2));
+        }
+
+        // Every protocol differs from the first in one thing, the last one has both examiners
+        let filings = [("first", vec![(1, 1)], 1, 1, 2023), ("examiner", vec![(2, 1)], 1, 1, 2023), ("subject", vec![(1, 2)], 1, 1, 2023), ("stex", vec![(1, 1)], 2, 1, 2023),
+            ("season", vec![(1, 1)], 1, 2, 2023), ("year", vec![(1, 1)], 1, 1, 2020), ("together", vec![(1, 1), (2, 1)], 1, 1, 2023)];
+        let mut uuids = HashMap::new();
+        for (name, pairs, stex_id, season_id, year) in filings {
+            match database.save_protocol(pairs, stex_id, season_id, year, "Text".to_string(), "admin@example.org").await.unwrap() {
+                ProtocolCreation::Saved(uuid) => uuids.insert(uuid, name),
+                other => panic!("Protocol wasn't saved: {:?}", other),
+            };
+        }
+
+        let names = |results: SearchResults| {
+            let mut names: Vec<&str> = results.protocols.iter().map(|protocol| uuids[&protocol.uuid]).collect();
+            names.sort();
+            (names, results.total)
+        };
+
+        let all = names(database.search_for_protocol(text_filter(None), PAGE).await.unwrap());
+        assert_eq!(all, (vec!["examiner", "first", "season", "stex", "subject", "together", "year"], 7));
+
+        let exclusions = [
+            (SearchFilter { exclude_examiner_ids: Some(vec![2]), ..text_filter(None) }, vec!["first", "season", "stex", "subject", "year"]),
+            (SearchFilter { exclude_subject_ids: Some(vec![2]), ..text_filter(None) }, vec!["examiner", "first", "season", "stex", "together", "year"]),
+            (SearchFilter { exclude_stex_ids: Some(vec![2]), ..text_filter(None) }, vec!["examiner", "first", "season", "subject", "together", "year"]),
+            (SearchFilter { exclude_seasons: Some(vec![2]), ..text_filter(None) }, vec!["examiner", "first", "stex", "subject", "together", "year"]),
+            (SearchFilter { exclude_years: Some(vec![2020]), ..text_filter(None) }, vec!["examiner", "first", "season", "stex", "subject", "together"]),
+            (SearchFilter { examiner_ids: Some(vec![1]), exclude_examiner_ids: Some(vec![2]), ..text_filter(None) }, vec!["first", "season", "stex", "subject", "year"]),
+            (SearchFilter { exclude_examiner_ids: Some(vec![1, 2]), ..text_filter(None) }, vec![]),
+        ];
+
+        for (filter, expected) in exclusions {
+            let total = expected.len() as i64;
+            assert_eq!(names(database.search_for_protocol(filter, PAGE).await.unwrap()), (expected, total));
+        }
+    }
+
+    #[tokio::test]
+    async fn search_year_range_includes_its_bounds() {
+        let (database, _directory) = database().await;
+        for year in [2019, 2020, 2021, 2022, 2023] {
+            save_filed(&database, 1, year, "Text").await;
+        }
+
+        let years = |results: SearchResults| results.protocols.iter().map(|protocol| protocol.years[0]).collect::<Vec<i64>>();
+
+        let range = SearchFilter { year_from: Some(2020), year_to: Some(2022), ..text_filter(None) };
+        assert_eq!(years(database.search_for_protocol(range, PAGE).await.unwrap()), vec![2022, 2021, 2020]);
+
+        let from = SearchFilter { year_from: Some(2022), ..text_filter(None) };
+        assert_eq!(years(database.search_for_protocol(from, PAGE).await.unwrap()), vec![2023, 2022]);
+
+        let to = SearchFilter { year_to: Some(2019), ..text_filter(None) };
+        assert_eq!(years(database.search_for_protocol(to, PAGE).await.unwrap()), vec![2019]);
+
+        let empty = SearchFilter { year_from: Some(2023), year_to: Some(2020), ..text_filter(None) };
+        assert_eq!(database.search_for_protocol(empty, PAGE).await.unwrap().total, 0);
+
+        let excluded = SearchFilter { year_from: Some(2020), exclude_years: Some(vec![2021]), ..text_filter(Some("text")) };
+        assert_eq!(years(database.search_for_protocol(excluded, PAGE).await.unwrap()), vec![2023, 2022, 2020]);
+    }
+
+    /// Makes every insert into the table fail, like a full disk would
+    fn fail_inserts(directory: &TestDirectory, table: &str) {
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        connection.execute(format!("CREATE TRIGGER fail_{} BEFORE INSERT ON {} BEGIN SELECT RAISE(ABORT, 'Disk full'); END;", table, table)).unwrap();
+    }
+
+    /// The names of the files in the protocol directory
+    fn protocol_files(directory: &TestDirectory) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(directory.0.join("protocols")).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn failed_update_keeps_previous_text() {
+        let (database, directory) = database().await;
+        let uuid = save_protocol(&database, 2023, "Plexus brachialis").await;
+        fail_inserts(&directory, "protocol_revisions");
+
+        let update = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: Some(2024), text: Some("Nur die Niere".to_string()) };
+        assert!(database.update_protocol(&uuid, update, "admin@example.org").await.is_err());
+
+        let mut text = String::new();
+        database.open_protocol_text(&uuid).await.unwrap().read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "Plexus brachialis");
+        assert_eq!(protocol_files(&directory), vec![format!("{}.txt", uuid)]);
+        assert_eq!(database.get_protocol(&uuid).await.unwrap().unwrap().years, vec![2023]);
+        assert_eq!(database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap().total, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_save_leaves_nothing_behind() {
+        let (database, directory) = database().await;
+        fail_inserts(&directory, "protocol_revisions");
+
+        assert!(database.save_protocol(vec![(1, 1)], 1, 1, 2023, "Plexus brachialis".to_string(), "admin@example.org").await.is_err());
+
+        assert!(protocol_files(&directory).is_empty());
+        for table in ["protocols", "subject_relations", "protocol_texts", "protocol_revisions", "protocol_revision_pairs"] {
+            assert_eq!(count_rows(&directory, table), 0, "{}", table);
+        }
+    }
+
+    #[tokio::test]
+    async fn entities_are_checked_again_in_the_write_transaction() {
+        let (database, directory) = database().await;
+
+        // Like a stex that was deleted after save_protocol checked it
+        let filing = ProtocolFiling { relation_ids: vec![], examiner_subject_ids: vec![(1, 1)], stex_id: 7, season_id: 1, year: 2023 };
+        let creation = database.run(move |connection| connection.in_transaction(|| connection.write_new_protocol("a3c5e7f9-0000-4000-8000-000000000001", &filing, "Text", "admin@example.org"))).await.unwrap();
+
+        assert_eq!(creation, ProtocolCreation::UnknownEntity(CatalogEntity::Stex, 7));
+        assert_eq!(count_rows(&directory, "protocols"), 0);
+        assert_eq!(count_rows(&directory, "protocol_revisions"), 0);
+    }
+
+    /// What `inconsistent_database` broke, as blob keys and protocol UUIDs
+    struct Breakage {
+        orphaned_file: String,
+        old_temporary_file: String,
+        young_temporary_file: String,
+        /// Its file is gone, but it has a revision to restore it from
+        textless: String,
+        /// Rows from before the revisions, without a file
+        lost: String
+    }
+
+    /// One intact protocol and one of every kind of inconsistency the check finds
+    async fn inconsistent_database() -> (SQLiteDatabase, TestDirectory, Breakage) {
+        let (database, directory) = database().await;
+        let protocols = directory.0.join("protocols");
+
+        save_protocol(&database, 2023, "Plexus brachialis").await;
+        let textless = save_protocol(&database, 2023, "Nur die Niere").await;
+        std::fs::remove_file(protocols.join(text_key(&textless))).unwrap();
+
+        // An examiner that only an unused relation refers to
+        assert_eq!(database.create_item(CatalogEntity::Examiner, "Dr. Beispiel".to_string()).await.unwrap(), Some(2));
+        let lost = Uuid::new_v4().to_string();
+        let connection = sqlite::open(directory.0.join("index.db")).unwrap();
+        connection.execute(format!("
+            INSERT INTO subject_relations(examiner_id, subject_id, stex_id, season_id, year) VALUES (2, 1, 1, 1, 2020);
+            INSERT INTO protocols(relation_id, protocol_uuid) VALUES (1, '{}');
+        ", lost)).unwrap();
+
+        let breakage = Breakage {
+            orphaned_file: text_key(&Uuid::new_v4().to_string()),
+            old_temporary_file: new_text_key(&Uuid::new_v4().to_string()),
+            young_temporary_file: new_text_key(&Uuid::new_v4().to_string()),
+            textless,
+            lost
+        };
+
+        for key in [&breakage.orphaned_file, &breakage.old_temporary_file, &breakage.young_temporary_file, &"notes.txt".to_string()] {
+            std::fs::write(protocols.join(key), "Übrig").unwrap();
+        }
+        let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60);
+        std::fs::File::options().write(true).open(protocols.join(&breakage.old_temporary_file)).unwrap().set_modified(two_hours_ago).unwrap();
+
+        (database, directory, breakage)
+    }
+
+    fn sorted(mut keys: Vec<String>) -> Vec<String> {
+        keys.sort();
+        keys
+    }
+
+    fn unused_entities(report: &ConsistencyReport) -> Vec<(String, i64, String)> {
+        report.entities_without_relations.iter().map(|unused| (unused.kind.clone(), unused.id, unused.display_name.clone())).collect()
+    }
+
+    #[tokio::test]
+    async fn consistency_check_finds_without_repairing() {
+        let (database, directory, breakage) = inconsistent_database().await;
+
+        let report = database.check_consistency(None).await.unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(report.files_without_rows, sorted(vec![breakage.orphaned_file.clone(), breakage.old_temporary_file.clone()]));
+        assert_eq!(report.rows_without_files, sorted(vec![breakage.textless.clone(), breakage.lost.clone()]));
+        assert_eq!(report.relations_without_protocols, vec![2]);
+        assert_eq!(unused_entities(&report), vec![("examiner".to_string(), 2, "Dr. Beispiel".to_string())]);
+        assert!(report.restored_files.is_empty());
+
+        // The young temporary file may still belong to a save in progress
+        assert!(!report.files_without_rows.contains(&breakage.young_temporary_file));
+        assert_eq!(protocol_files(&directory).len(), 5);
+        assert_eq!(count_rows(&directory, "subject_relations"), 2);
+    }
+
+    #[tokio::test]
+    async fn quarantine_moves_files_aside_and_keeps_entities() {
+        let (database, directory, breakage) = inconsistent_database().await;
+
+        let report = database.check_consistency(Some(RepairMode::Quarantine)).await.unwrap();
+        assert_eq!(report.restored_files, vec![breakage.textless.clone()]);
+
+        let quarantine = directory.0.join("protocols").join(QUARANTINE_PREFIX);
+        assert!(quarantine.join(&breakage.orphaned_file).exists());
+        assert!(quarantine.join(&breakage.old_temporary_file).exists());
+        assert!(directory.0.join("protocols").join(&breakage.young_temporary_file).exists());
+
+        let mut text = String::new();
+        database.open_protocol_text(&breakage.textless).await.unwrap().read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "Nur die Niere");
+
+        // The lost protocol can't be restored and is only removed for good with the delete mode
+        let report = database.check_consistency(None).await.unwrap();
+        assert!(report.files_without_rows.is_empty());
+        assert_eq!(report.rows_without_files, vec![breakage.lost.clone()]);
+        assert!(report.relations_without_protocols.is_empty());
+        assert_eq!(unused_entities(&report), vec![("examiner".to_string(), 2, "Dr. Beispiel".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn delete_mode_removes_files_rows_and_entities() {
+        let (database, directory, breakage) = inconsistent_database().await;
+
+        let report = database.check_consistency(Some(RepairMode::Delete)).await.unwrap();
+        assert_eq!(report.restored_files, vec![breakage.textless.clone()]);
+
+        let protocols = directory.0.join("protocols");
+        assert!(!protocols.join(&breakage.orphaned_file).exists());
+        assert!(!protocols.join(&breakage.old_temporary_file).exists());
+        assert!(!protocols.join(QUARANTINE_PREFIX).join(&breakage.orphaned_file).exists());
+        assert!(protocols.join(&breakage.young_temporary_file).exists());
+        assert!(database.get_protocol(&breakage.lost).await.unwrap().is_none());
+
+        let report = database.check_consistency(None).await.unwrap();
+        assert!(report.is_consistent());
+        assert!(report.entities_without_relations.is_empty());
+        assert_eq!(database.get_selection_identifiers().await.unwrap().examiners.len(), 1);
+        assert_eq!(count_rows(&directory, "subject_relations"), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_transaction_is_rolled_back() {
+        let (database, directory) = database().await;
+
+        // Like a save whose request was cancelled while the text was written
+        let transaction = database.begin().await.unwrap();
+        let (transaction, result) = blocking(transaction, |transaction| transaction.connection.execute("INSERT INTO admins(email) VALUES ('admin@example.org');")).await.unwrap();
+        result.unwrap();
+        drop(transaction);
+
+        // The connection is back in the pool, and it doesn't hold the write lock anymore
+        let mut connections = vec![];
+        for _ in 0..DATABASE_POOL_SIZE {
+            connections.push(tokio::time::timeout(std::time::Duration::from_secs(5), database.connection()).await.unwrap().unwrap());
+        }
+        drop(connections);
+
+        save_protocol(&database, 2023, "Plexus brachialis").await;
+        assert_eq!(count_rows(&directory, "admins"), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writers_wait_for_each_other() {
+        let (database, directory) = database().await;
+        let database = Arc::new(database);
+        let mut tasks = vec![];
+
+        for writer in 0..(2 * DATABASE_POOL_SIZE) {
+            let database = database.clone();
+            tasks.push(tokio::spawn(async move {
+                for protocol in 0..5 {
+                    let uuid = save_protocol(database.as_ref(), 2023, &format!("Protokoll {} von {}", protocol, writer)).await;
+                    let update = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: Some(2024), text: None };
+                    assert_eq!(database.update_protocol(&uuid, update, "admin@example.org").await.unwrap(), ProtocolChange::Updated);
+                    database.search_for_protocol(text_filter(Some("protokoll")), PAGE).await.unwrap();
+                }
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(count_rows(&directory, "protocol_revisions"), 2 * 5 * 2 * DATABASE_POOL_SIZE as i64);
+        assert!(database.check_consistency(None).await.unwrap().is_consistent());
+    }
+
+    /// Whether the word is anywhere in the database file or its WAL, in any case
+    fn database_file_contains(directory: &TestDirectory, word: &str) -> bool {
+        let word = word.to_lowercase().into_bytes();
+
+        ["index.db", "index.db-wal"].iter()
+            .filter_map(|file_name| std::fs::read(directory.0.join(file_name)).ok())
+            .any(|data| data.to_ascii_lowercase().windows(word.len()).any(|window| window == word.as_slice()))
+    }
+
+    #[tokio::test]
+    async fn encrypted_texts_are_not_indexed() {
+        let (database, directory) = database().await;
+        drop(database);
+
+        let database = open(&directory, Some(cipher("2023"))).await;
+        let uuid = save_protocol(&database, 2023, "Plexus brachialis").await;
+        let update = ProtocolUpdate { examiner_subject_ids: None, stex_id: None, season_id: None, year: None, text: Some("Nur die Niere".to_string()) };
+        assert_eq!(database.update_protocol(&uuid, update, "admin@example.org").await.unwrap(), ProtocolChange::Updated);
+
+        assert_eq!(count_rows(&directory, "protocol_texts"), 0);
+        assert_eq!(database.search_for_protocol(text_filter(Some("niere")), PAGE).await.unwrap().total, 0);
+        assert_eq!(database.search_for_protocol(text_filter(None), PAGE).await.unwrap().total, 1);
+        assert!(!database_file_contains(&directory, "plexus"));
+        assert!(!database_file_contains(&directory, "niere"));
+    }
+
+    #[tokio::test]
+    async fn turning_encryption_on_empties_the_index() {
+        let (database, directory) = database().await;
+        let uuid = save_protocol(&database, 2023, "Plexus brachialis").await;
+        drop(database);
+        assert!(database_file_contains(&directory, "plexus"));
+
+        let database = open(&directory, Some(cipher("2023"))).await;
+        assert_eq!(database.reencrypt_revisions().await.unwrap(), 1);
+        assert_eq!(count_rows(&directory, "protocol_texts"), 0);
+        assert_eq!(database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap().total, 0);
+        assert!(!database_file_contains(&directory, "plexus"));
+        drop(database);
+
+        // Without an active key the index is built again
+        let database = open(&directory, None).await;
+        assert_eq!(database.search_for_protocol(text_filter(Some("plexus")), PAGE).await.unwrap().protocols[0].uuid, uuid);
+    }
+}
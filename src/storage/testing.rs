@@ -0,0 +1,50 @@
+//! Fixtures the tests of the stores and the services share
+
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use super::protocol_store::{CatalogEntity, ProtocolCreation, ProtocolStore, SearchFilter};
+
+/// Creates one examiner, subject, stex and season. In an empty store all of them get the ID 1
+pub async fn create_catalog(database: &dyn ProtocolStore) {
+    for (entity, name) in [(CatalogEntity::Examiner, "Dr. Muster"), (CatalogEntity::Subject, "Anatomie"), (CatalogEntity::Stex, "M1"), (CatalogEntity::Season, "Frühjahr")] {
+        assert_eq!(database.create_item(entity, name.to_string()).await.unwrap(), Some(1));
+    }
+}
+
+/// Saves a protocol of the catalog entities with the ID 1 and returns its UUID
+pub async fn save_protocol(database: &dyn ProtocolStore, year: i64, text: &str) -> String {
+    match database.save_protocol(vec![(1, 1)], 1, 1, year, text.to_string(), "admin@example.org").await.unwrap() {
+        ProtocolCreation::Saved(uuid) => uuid,
+        other => panic!("Protocol wasn't saved: {:?}", other),
+    }
+}
+
+/// A filter searching only for the text, or for nothing at all
+pub fn text_filter(text: Option<&str>) -> SearchFilter {
+    SearchFilter {
+        examiner_ids: None, subject_ids: None, stex_ids: None, seasons: None, years: None,
+        exclude_examiner_ids: None, exclude_subject_ids: None, exclude_stex_ids: None, exclude_seasons: None, exclude_years: None,
+        year_from: None, year_to: None,
+        text: text.map(str::to_string)
+    }
+}
+
+/// A database and protocol directory of their own below the temporary directory, removed when dropped
+pub struct TestDirectory(pub PathBuf);
+
+impl TestDirectory {
+    /// The protocols go into its `protocols` subdirectory
+    pub fn create() -> TestDirectory {
+        let directory = TestDirectory(std::env::temp_dir().join(format!("protocoldb-test-{}", Uuid::new_v4())));
+        std::fs::create_dir_all(directory.0.join("protocols")).unwrap();
+        directory
+    }
+}
+
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
@@ -8,6 +10,9 @@ pub struct Configuration {
     pub authorization: Authorization,
     pub encryption: Encryption,
     pub general: Generals,
+    /// Where the protocol texts are kept, the local protocol_location unless configured otherwise
+    #[serde(default)]
+    pub blob_storage: BlobStorage,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -20,7 +25,46 @@ pub enum DatabaseBackend {
         port: u16, 
         username: String, 
         password: String, 
-        database: String
+        database: String,
+        /// Whether the connection is encrypted with TLS, not at all if it isn't set
+        #[serde(default)]
+        sslmode: PostgresSslMode,
+        /// PEM file with a CA certificate that is trusted besides the ones of the system, e.g. for a self-signed server
+        #[serde(default)]
+        ssl_root_certificate: Option<String>
+    },
+    /// Keeps everything in memory, nothing survives a restart. Meant for testing.
+    InMemory
+}
+
+/// Named like the sslmode of libpq. The server certificate is always verified, including the hostname
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum PostgresSslMode {
+    #[default]
+    #[serde(rename = "disable")]
+    Disable,
+    /// TLS if the server offers it, a plain connection otherwise
+    #[serde(rename = "prefer")]
+    Prefer,
+    #[serde(rename = "require")]
+    Require
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum BlobStorage {
+    /// The files in `general.protocol_location`
+    #[default]
+    Local,
+    /// An S3-compatible bucket, so several instances can share the texts
+    S3 {
+        endpoint: String,
+        region: String,
+        bucket: String,
+        access_key_id: String,
+        secret_access_key: String,
+        /// Put in front of every key, for sharing a bucket
+        #[serde(default)]
+        prefix: String
     }
 }
 
@@ -32,25 +76,84 @@ pub struct APISettings {
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Generals {
-    pub protocol_location: String
+    pub protocol_location: String,
+    /// Granted admin rights on startup as long as there is no admin at all, so a fresh deployment can be set up
+    #[serde(default)]
+    pub initial_admins: Vec<String>
 }
 
+// Exists once per process, so the size of the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Authorization {
     OpenIdConnect {
         client_id: String, 
         self_root_url: String,
-        token_url: String, 
-        auth_url: String, 
-        revoke_url: String, 
-        userinfo_url: String
+        /// The endpoints are discovered from `<issuer>/.well-known/openid-configuration`, ID tokens have to be issued by it
+        issuer: String,
+        /// The endpoints below override the discovered ones
+        #[serde(default)]
+        token_url: Option<String>, 
+        #[serde(default)]
+        auth_url: Option<String>, 
+        #[serde(default)]
+        jwks_url: Option<String>, 
+        #[serde(default)]
+        revoke_url: Option<String>, 
+        #[serde(default)]
+        userinfo_url: Option<String>,
+        #[serde(default)]
+        end_session_url: Option<String>,
+        /// Only for confidential clients, public clients rely on PKCE alone
+        #[serde(default)]
+        client_secret: Option<String>,
+        #[serde(default)]
+        client_auth_method: ClientAuthMethod,
+        /// Where `/login?return_to=` may send the browser back to, an entry ending with a slash allows every URL below it
+        #[serde(default)]
+        return_urls: Vec<String>,
+        #[serde(default)]
+        token_delivery: TokenDelivery,
+        /// `/logout` also hands out the end_session_url of the provider, which ends the login there as well
+        #[serde(default)]
+        idp_logout: bool
     },
     None
 }
 
+/// How the session token gets to the frontend after the login
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum TokenDelivery {
+    /// Appended to the return URL as `#token=...`, the frontend sends it as bearer token
+    #[default]
+    #[serde(rename = "fragment")]
+    Fragment,
+    /// Set as HttpOnly, SameSite cookie, which the API accepts in place of the Authorization header
+    #[serde(rename = "cookie")]
+    Cookie
+}
+
+/// How the client secret is sent to the token and revocation endpoints
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum ClientAuthMethod {
+    /// As HTTP basic authentication
+    #[default]
+    #[serde(rename = "client_secret_basic")]
+    ClientSecretBasic,
+    /// In the form body
+    #[serde(rename = "client_secret_post")]
+    ClientSecretPost
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Encryption {
-    pub token_encryption_secret: String
+    pub token_encryption_secret: String,
+    /// Keys for encrypting the protocol texts at rest by their ID, 64 hex characters each. Without keys the texts are stored as plaintext
+    #[serde(default)]
+    pub protocol_keys: BTreeMap<String, String>,
+    /// The key new texts are encrypted with, the other keys are only used for reading
+    #[serde(default)]
+    pub active_protocol_key: Option<String>
 }
 
 impl Configuration {
@@ -58,9 +161,10 @@ impl Configuration {
         Configuration {
             database_type: DatabaseBackend::SQLLite { file_location: "index.db".to_string() },
             api: APISettings { bind_addr: "127.0.0.1".to_string(), bind_port: 8080 },
-            authorization: Authorization::OpenIdConnect { token_url: "plz".to_owned(), auth_url: "replace".to_string(), revoke_url: "to".to_string(), userinfo_url: "actual_urls".to_string(), client_id: "yikksi".to_string(), self_root_url: "http://127.0.0.1".to_string() },
-            general: Generals { protocol_location: "protocols/".to_string() },
-            encryption: Encryption { token_encryption_secret: thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from).collect() },
+            authorization: Authorization::OpenIdConnect { issuer: "https://plz.replace/realms/with_actual_issuer".to_string(), token_url: None, auth_url: None, jwks_url: None, revoke_url: None, userinfo_url: None, end_session_url: None, client_secret: None, client_auth_method: ClientAuthMethod::ClientSecretBasic, return_urls: vec![], token_delivery: TokenDelivery::Fragment, idp_logout: false, client_id: "yikksi".to_string(), self_root_url: "http://127.0.0.1".to_string() },
+            general: Generals { protocol_location: "protocols/".to_string(), initial_admins: vec![] },
+            encryption: Encryption { token_encryption_secret: thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from).collect(), protocol_keys: BTreeMap::new(), active_protocol_key: None },
+            blob_storage: BlobStorage::Local,
         }
     }
 }
@@ -7,4 +7,21 @@ pub struct Search {
     pub examiners: Option<String>,
     pub seasons: Option<String>,
     pub years: Option<String>,
+    pub exclude_subjects: Option<String>,
+    pub exclude_stex: Option<String>,
+    pub exclude_examiners: Option<String>,
+    pub exclude_seasons: Option<String>,
+    pub exclude_years: Option<String>,
+    pub year_from: Option<i64>,
+    pub year_to: Option<i64>,
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// The two revisions to compare
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DiffRevisions {
+    pub from: i64,
+    pub to: i64
 }
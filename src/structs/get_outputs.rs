@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use super::post_inputs::RepairMode;
+
 #[derive(Serialize, Deserialize)]
 pub struct OutputProtocol {
     pub uuid: String, 
@@ -7,7 +9,85 @@ pub struct OutputProtocol {
     pub subjects: Vec<String>, 
     pub stex: Vec<String>, 
     pub season: Vec<String>, 
-    pub years: Vec<i64>
+    pub years: Vec<i64>,
+    /// Only set for full-text searches: the best matching parts of the text as escaped HTML, matches wrapped in <mark></mark>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// Only set for full-text searches: higher is more relevant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank: Option<f64>
+}
+
+/// One page of search results
+#[derive(Serialize, Deserialize)]
+pub struct SearchResponse {
+    /// Number of matching protocols over all pages
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    pub protocols: Vec<OutputProtocol>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminList {
+    pub admins: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LogoutResponse {
+    /// Where the browser has to go to log out at the identity provider as well, only set with idp_logout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_session_url: Option<String>
+}
+
+/// A login of a user, listed for admins so they can end the sessions of a stolen account
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutputSession {
+    pub session_id: String,
+    /// Unix timestamp in seconds
+    pub created: i64,
+    /// When the token runs out, unless the session is revoked before
+    pub expires: i64
+}
+
+/// One version of a protocol's text and metadata
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutputRevision {
+    /// Counts up from 1 for every protocol
+    pub revision: i64,
+    /// Unix timestamp in seconds
+    pub created: i64,
+    /// Email of the admin that made the change. None for protocols that existed before the history was kept
+    pub author: Option<String>,
+    pub examiner_subject_ids: Vec<(i64, i64)>,
+    pub stex_id: i64,
+    pub season_id: i64,
+    pub year: i64,
+    /// Left out when listing the revisions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevisionDiff {
+    pub from: OutputRevision,
+    pub to: OutputRevision,
+    /// The text of `to` line by line, with the lines of `from` that were removed in between
+    pub lines: Vec<DiffLine>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DiffLine {
+    pub operation: DiffOperation,
+    pub text: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOperation {
+    Equal,
+    Insert,
+    Delete
 }
 
 #[derive(Serialize, Deserialize)]
@@ -23,3 +103,33 @@ pub struct SelectionIdentifierPair {
     pub id: i64, 
     pub display_name: String
 }
+
+/// Findings of the consistency check between the protocol files and the database
+#[derive(Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    /// Files in the protocol directory that no protocol belongs to, including leftovers of saves interrupted more than an hour ago
+    pub files_without_rows: Vec<String>,
+    /// UUIDs of protocols whose text file is missing
+    pub rows_without_files: Vec<String>,
+    pub relations_without_protocols: Vec<i64>,
+    /// Examiners, subjects, stex and seasons that neither a protocol nor a revision refers to
+    pub entities_without_relations: Vec<UnusedEntity>,
+    /// Set if the findings were repaired
+    pub repair: Option<RepairMode>,
+    /// Protocols whose missing file was written again from their latest revision
+    pub restored_files: Vec<String>
+}
+
+impl ConsistencyReport {
+    /// Unused entities don't count, a freshly created examiner simply has no protocols yet
+    pub fn is_consistent(&self) -> bool {
+        self.files_without_rows.is_empty() && self.rows_without_files.is_empty() && self.relations_without_protocols.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnusedEntity {
+    pub kind: String,
+    pub id: i64,
+    pub display_name: String
+}
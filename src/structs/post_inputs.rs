@@ -9,12 +9,38 @@ pub struct Protocol {
     pub text: String
 }
 
+/// Only the fields that are set get changed
+#[derive(Serialize, Deserialize)]
+pub struct EditProtocol {
+    pub examiner_subject_ids: Option<Vec<(i64, i64)>>,
+    pub stex_id: Option<i64>,
+    pub season_id: Option<i64>,
+    pub year: Option<i64>,
+    pub text: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplaceText {
+    pub text: String
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Create {
     pub field:CreateField ,
     pub display_name: String
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct RenameEntity {
+    pub display_name: String
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MergeEntity {
+    /// The entity that is kept
+    pub into_id: i64
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum CreateField {
     Examiner, 
@@ -23,6 +49,21 @@ pub enum CreateField {
     Stex
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct RepairConsistency {
+    pub repair: RepairMode
+}
+
+/// What happens to files and rows the consistency check found orphaned
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RepairMode {
+    /// Orphaned files are moved aside, unused entities are kept
+    Quarantine,
+    /// Orphaned files, protocols and unused entities are removed for good
+    Delete
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ChangeAdmin {
     pub email_addr: String